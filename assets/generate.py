@@ -0,0 +1,47 @@
+#!/usr/bin/env python3
+"""Regenerates the chimes built into the binary and written out by init-config.
+
+They are the built-in chimes of src/chime.rs as 16 bit little endian stereo
+WAVs at 48kHz, the format a room's custom chime has to have, so they can be
+edited and uploaded in the admin UI. Rerunning this script must not change
+any checked in file.
+"""
+import math
+import os
+import struct
+import wave
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+RATE = 48000
+
+
+def write_wav(name, samples):
+    with wave.open(os.path.join(HERE, name), "wb") as wav:
+        wav.setnchannels(2)
+        wav.setsampwidth(2)
+        wav.setframerate(RATE)
+        data = b"".join(
+            struct.pack("<hh", *(max(-32768, min(32767, round(s * 32767))),) * 2)
+            for s in samples
+        )
+        wav.writeframes(data)
+
+
+def chime(first, second):
+    tone_len = RATE // 12
+    samples = []
+    for freq in (first, second):
+        for i in range(tone_len):
+            # a sine envelope per tone, so neither tone clicks
+            envelope = math.sin(math.pi * i / tone_len)
+            samples.append(0.2 * envelope * math.sin(2 * math.pi * freq * i / RATE))
+    return samples
+
+
+def main():
+    write_wav("chimes/join.wav", chime(660, 880))
+    write_wav("chimes/leave.wav", chime(880, 660))
+
+
+if __name__ == "__main__":
+    main()
@@ -1,4 +1,7 @@
 fn main() {
     pkg_config::probe_library("libpulse-simple").unwrap();
     pkg_config::probe_library("opus").unwrap();
+    if std::env::var_os("CARGO_FEATURE_CODEC2").is_some() {
+        pkg_config::probe_library("codec2").unwrap();
+    }
 }
@@ -2,6 +2,9 @@
   lib,
   fetchFromGitHub,
   pkg-config,
+  alsa-lib,
+  codec2,
+  libjack2,
   libopus,
   libpulseaudio,
   rustPlatform,
@@ -13,6 +16,9 @@ rustPlatform.buildRustPackage (finalAttrs: {
 
   src = ./.;
   buildInputs = [
+    alsa-lib
+    codec2
+    libjack2
     libopus
     libpulseaudio
   ];
@@ -21,7 +27,15 @@ rustPlatform.buildRustPackage (finalAttrs: {
     pkg-config
   ];
 
-  cargoHash = "sha256-NYzy58PR7SMY1nlAWiESraPod2Wam1KVtgr16q9jm60=";
+  buildFeatures = [
+    "alsa"
+    "codec2"
+    "cpal"
+    "jack"
+  ];
+
+  # the dependencies as locked, no vendor hash to update when they change
+  cargoLock.lockFile = ./Cargo.lock;
 
   meta = {
     description = "A voice chat application written in Rust";
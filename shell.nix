@@ -21,6 +21,8 @@ pkgs.mkShell rec {
   ];
 
   nativeBuildInputs = with pkgs; [
+    alsa-lib
+    libjack2
     libopus
     libpulseaudio
   ];
@@ -0,0 +1,549 @@
+use std::{
+    fmt::Write as _,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    Error,
+    chime::{MAX_CHIME_BYTES, encode_chime, parse_wav},
+    identity::Identity,
+    quality::{HealthReport, RoomHealth},
+    server::{Cue, RoomInfo, SessionId},
+};
+
+const ADMIN_PAGE: &str = include_str!("admin.html");
+// requests of the admin page are tiny apart from chime uploads, anything
+// bigger is not for us
+const MAX_REQUEST: usize = 8 * 1024 + MAX_CHIME_BYTES;
+/// Header the admin page sends with every change, see `from_admin_page`.
+const PAGE_HEADER: &str = "x-kop-audio-admin";
+
+/// Requests of the admin UI, answered by the server loop which owns the client list.
+#[derive(Debug)]
+pub enum AdminCommand {
+    Status(oneshot::Sender<ServerStatus>),
+    /// removes the client and tells it so
+    Kick(SocketAddr),
+    /// kicks everyone joined from the address and turns it away until unbanned
+    Ban(IpAddr),
+    Unban(IpAddr),
+    /// the server drops the client's voice while muted
+    Mute(SocketAddr, bool),
+    /// shown to everyone as an announcement
+    Announce(String),
+    SetTopic(String),
+    /// an empty value removes the key
+    SetMetadata(String, String),
+    /// whether the server sends join/leave cues to everyone
+    SetCues(bool),
+    /// voices forwarded at once, `None` for no limit
+    SetMaxSpeakers(Option<usize>),
+    /// custom chime for a cue as opus packets, empty for the built-in one
+    SetChime(Cue, Vec<Vec<u8>>),
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientStatus {
+    pub addr: SocketAddr,
+    pub session: SessionId,
+    pub identity: Option<Identity>,
+    pub name: String,
+    pub audio_sink: bool,
+    pub idle: Duration,
+    pub afk: bool,
+    pub muted: bool,
+    pub health: Option<HealthReport>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServerStatus {
+    pub uptime: Duration,
+    pub packets_received: u64,
+    pub packets_forwarded: u64,
+    /// voice and music dropped for not coming from the client at its address
+    pub packets_spoofed: u64,
+    /// dropped for going over the rate limit of their source
+    pub packets_limited: u64,
+    pub room: RoomInfo,
+    pub max_speakers: Option<usize>,
+    pub clients: Vec<ClientStatus>,
+    pub banned: Vec<IpAddr>,
+}
+
+impl ServerStatus {
+    fn to_json(&self) -> String {
+        let mut json = String::new();
+        let _ = write!(
+            json,
+            "{{\"uptime_secs\":{},\"packets_received\":{},\"packets_forwarded\":{},\"packets_spoofed\":{},\"packets_limited\":{},\"room\":{{\"name\":{},\"topic\":{},\"cues\":{},\"max_speakers\":{},\"metadata\":{{",
+            self.uptime.as_secs(),
+            self.packets_received,
+            self.packets_forwarded,
+            self.packets_spoofed,
+            self.packets_limited,
+            json_string(&self.room.name),
+            json_string(&self.room.topic),
+            self.room.cues,
+            match self.max_speakers {
+                Some(max) => max.to_string(),
+                None => "null".to_string(),
+            }
+        );
+        for (i, (key, value)) in self.room.metadata.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = write!(json, "{}:{}", json_string(key), json_string(value));
+        }
+        // the AFK room hears nothing, its members would only water it down
+        let health = RoomHealth::new(
+            self.clients
+                .iter()
+                .filter(|client| !client.afk)
+                .filter_map(|client| client.health.as_ref()),
+        );
+        json.push_str("},\"health\":");
+        match health {
+            Some(health) => {
+                let _ = write!(
+                    json,
+                    "{{\"reporting\":{},\"struggling\":{},\"underrun_percent\":{},\"worst_loss_percent\":{},\"average_rtt_ms\":{}}}",
+                    health.reporting,
+                    health.struggling,
+                    health.underrun_percent,
+                    health.worst_loss_percent,
+                    health.average_rtt_ms
+                );
+            }
+            None => json.push_str("null"),
+        }
+        json.push_str("},\"clients\":[");
+        for (i, client) in self.clients.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            // addresses and hex identities never need escaping
+            let identity = match client.identity {
+                Some(identity) => format!("\"{}\"", identity),
+                None => "null".to_string(),
+            };
+            let health = match client.health {
+                Some(health) => format!(
+                    "{{\"underrun_percent\":{},\"loss_percent\":{},\"rtt_ms\":{},\"struggling\":{}}}",
+                    health.underrun_percent(),
+                    health.loss.percent(),
+                    health.rtt_ms,
+                    health.struggling()
+                ),
+                None => "null".to_string(),
+            };
+            let _ = write!(
+                json,
+                "{{\"addr\":\"{}\",\"identity\":{},\"name\":{},\"audio_sink\":{},\"idle_secs\":{},\"afk\":{},\"muted\":{},\"health\":{}}}",
+                client.addr,
+                identity,
+                json_string(&client.name),
+                client.audio_sink,
+                client.idle.as_secs(),
+                client.afk,
+                client.muted,
+                health
+            );
+        }
+        json.push_str("],\"banned\":[");
+        for (i, ip) in self.banned.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = write!(json, "\"{}\"", ip);
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+/// Serves the admin page and its API on `addr`. Every request has to carry the
+/// admin credential as HTTP basic auth, user name `admin`, and every change has
+/// to come from the admin page itself.
+pub async fn run_admin_server(
+    addr: String,
+    password: String,
+    commands: mpsc::Sender<AdminCommand>,
+) -> Result<(), Error> {
+    let listener = TcpListener::bind(&addr)
+        .await
+        .map_err(|e| Error::Network(format!("Can't listen on {}: {}", addr, e)))?;
+    info!("Admin UI listening on http://{}", addr);
+    let credential = format!("Basic {}", base64(format!("admin:{}", password).as_bytes()));
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Error accepting admin connection: {:?}", e);
+                continue;
+            }
+        };
+        let credential = credential.clone();
+        let commands = commands.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream, &credential, &commands).await {
+                debug!("Admin request from {} failed: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    mut stream: TcpStream,
+    credential: &str,
+    commands: &mpsc::Sender<AdminCommand>,
+) -> Result<(), Error> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    let header_end = loop {
+        if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        read_more(&mut stream, &mut request, &mut buf).await?;
+    };
+    let head = String::from_utf8_lossy(&request[..header_end]).into_owned();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+    let headers: Vec<(&str, &str)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name, value.trim()))
+        .collect();
+    let authorized = headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("authorization") && equal(value.as_bytes(), credential.as_bytes())
+    });
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    // refused before any of it is read, `header_end` is within `MAX_REQUEST`
+    if content_length > MAX_REQUEST - header_end {
+        warn!("Refused admin request {} {} of {} bytes", method, path, content_length);
+        let response = response("413 Content Too Large", "text/plain", "", "too large");
+        return stream
+            .write_all(response.as_bytes())
+            .await
+            .map_err(|e| Error::Network(e.to_string()));
+    }
+    while request.len() < header_end + content_length {
+        read_more(&mut stream, &mut request, &mut buf).await?;
+    }
+    let raw_body = &request[header_end..header_end + content_length];
+    let body = String::from_utf8_lossy(raw_body);
+
+    let response = if !authorized {
+        warn!("Unauthorized admin request {} {}", method, path);
+        response(
+            "401 Unauthorized",
+            "text/plain",
+            "WWW-Authenticate: Basic realm=\"kop-audio\"\r\n",
+            "unauthorized",
+        )
+    } else if method != "GET" && !from_admin_page(&headers) {
+        // the browser sends the credential along with requests other sites
+        // make it send
+        warn!("Rejected cross-site admin request {} {}", method, path);
+        response("403 Forbidden", "text/plain", "", "cross-site request")
+    } else {
+        match (method, path) {
+            ("GET", "/") => response("200 OK", "text/html", "", ADMIN_PAGE),
+            ("GET", "/api/status") => {
+                let (tx, rx) = oneshot::channel();
+                let _ = commands.send(AdminCommand::Status(tx)).await;
+                match rx.await {
+                    Ok(status) => response("200 OK", "application/json", "", &status.to_json()),
+                    Err(_) => response(
+                        "503 Service Unavailable",
+                        "text/plain",
+                        "",
+                        "server stopped",
+                    ),
+                }
+            }
+            ("POST", path) if path.starts_with("/api/kick/") => {
+                match path["/api/kick/".len()..].parse::<SocketAddr>() {
+                    Ok(addr) => {
+                        info!("Admin kicked {}", addr);
+                        let _ = commands.send(AdminCommand::Kick(addr)).await;
+                        response("200 OK", "text/plain", "", "ok")
+                    }
+                    Err(_) => response("400 Bad Request", "text/plain", "", "bad address"),
+                }
+            }
+            ("POST", path) if path.starts_with("/api/ban/") => {
+                match percent_decode(&path["/api/ban/".len()..]).parse::<IpAddr>() {
+                    Ok(ip) => {
+                        info!("Admin banned {}", ip);
+                        let _ = commands.send(AdminCommand::Ban(ip)).await;
+                        response("200 OK", "text/plain", "", "ok")
+                    }
+                    Err(_) => response("400 Bad Request", "text/plain", "", "bad address"),
+                }
+            }
+            ("POST", path) if path.starts_with("/api/unban/") => {
+                match percent_decode(&path["/api/unban/".len()..]).parse::<IpAddr>() {
+                    Ok(ip) => {
+                        info!("Admin unbanned {}", ip);
+                        let _ = commands.send(AdminCommand::Unban(ip)).await;
+                        response("200 OK", "text/plain", "", "ok")
+                    }
+                    Err(_) => response("400 Bad Request", "text/plain", "", "bad address"),
+                }
+            }
+            ("POST", "/api/announce") => match body.trim() {
+                "" => response("400 Bad Request", "text/plain", "", "nothing to announce"),
+                text => {
+                    info!("Admin announced: {}", text);
+                    let _ = commands.send(AdminCommand::Announce(text.to_string())).await;
+                    response("200 OK", "text/plain", "", "ok")
+                }
+            },
+            ("POST", "/api/topic") => {
+                info!("Admin set the topic to {:?}", body);
+                let _ = commands
+                    .send(AdminCommand::SetTopic(body.trim().to_string()))
+                    .await;
+                response("200 OK", "text/plain", "", "ok")
+            }
+            ("POST", path) if path.starts_with("/api/metadata/") => {
+                let key = percent_decode(&path["/api/metadata/".len()..]);
+                let _ = commands
+                    .send(AdminCommand::SetMetadata(key, body.trim().to_string()))
+                    .await;
+                response("200 OK", "text/plain", "", "ok")
+            }
+            ("POST", "/api/max-speakers") => {
+                let limit = match body.trim() {
+                    "" | "off" => Ok(None),
+                    max => max.parse::<usize>().map(|max| Some(max.max(1))),
+                };
+                match limit {
+                    Ok(limit) => {
+                        info!("Admin limited the speakers at once to {:?}", limit);
+                        let _ = commands.send(AdminCommand::SetMaxSpeakers(limit)).await;
+                        response("200 OK", "text/plain", "", "ok")
+                    }
+                    Err(_) => response("400 Bad Request", "text/plain", "", "bad number"),
+                }
+            }
+            ("POST", "/api/cues") => {
+                let cues = body.trim() == "on";
+                info!("Admin turned join/leave cues {}", if cues { "on" } else { "off" });
+                let _ = commands.send(AdminCommand::SetCues(cues)).await;
+                response("200 OK", "text/plain", "", "ok")
+            }
+            ("POST", path) if path.starts_with("/api/chime/") => {
+                let cue = match &path["/api/chime/".len()..] {
+                    "join" => Some(Cue::Join),
+                    "leave" => Some(Cue::Leave),
+                    _ => None,
+                };
+                match cue {
+                    Some(cue) if raw_body.is_empty() => {
+                        let _ = commands.send(AdminCommand::SetChime(cue, Vec::new())).await;
+                        response("200 OK", "text/plain", "", "ok")
+                    }
+                    Some(cue) => match parse_wav(raw_body) {
+                        Ok(samples) => {
+                            info!("Admin uploaded a {:?} chime", cue);
+                            let packets = encode_chime(&samples);
+                            let _ = commands.send(AdminCommand::SetChime(cue, packets)).await;
+                            response("200 OK", "text/plain", "", "ok")
+                        }
+                        Err(Error::Invalid(reason)) => {
+                            response("400 Bad Request", "text/plain", "", &reason)
+                        }
+                        Err(_) => response("400 Bad Request", "text/plain", "", "invalid chime"),
+                    },
+                    None => response("404 Not Found", "text/plain", "", "not found"),
+                }
+            }
+            _ => response("404 Not Found", "text/plain", "", "not found"),
+        }
+    };
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .map_err(|e| Error::Network(e.to_string()))
+}
+
+/// Whether a request that changes something was made by the admin page. Pages
+/// of other sites can't add our header without a CORS preflight, which we never
+/// answer, and browsers tell in `Origin` where a request comes from.
+fn from_admin_page(headers: &[(&str, &str)]) -> bool {
+    let header = |wanted: &str| {
+        headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| *value)
+    };
+    if header(PAGE_HEADER) != Some("1") {
+        return false;
+    }
+    match (header("origin"), header("host")) {
+        (None, _) => true,
+        (Some(origin), Some(host)) => {
+            origin
+                .strip_prefix("http://")
+                .or_else(|| origin.strip_prefix("https://"))
+                == Some(host)
+        }
+        (Some(_), None) => false,
+    }
+}
+
+async fn read_more(
+    stream: &mut TcpStream,
+    request: &mut Vec<u8>,
+    buf: &mut [u8],
+) -> Result<(), Error> {
+    let n = stream.read(buf).await.map_err(|e| Error::Network(e.to_string()))?;
+    if n == 0 || request.len() + n > MAX_REQUEST {
+        return Err(Error::Network("Request cut short or too large".to_string()));
+    }
+    request.extend_from_slice(&buf[..n]);
+    Ok(())
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut out = Vec::new();
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        let decoded = match b {
+            b'%' => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            }
+            _ => Some(b),
+        };
+        out.push(decoded.unwrap_or(b'?'));
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn response(status: &str, content_type: &str, headers: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        headers,
+        body
+    )
+}
+
+// compares without bailing out at the first difference, so the timing doesn't
+// tell how much of the credential was right
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_basic_auth_credential() {
+        assert_eq!(base64(b"admin:secret"), "YWRtaW46c2VjcmV0");
+        assert_eq!(base64(b"admin:pw"), "YWRtaW46cHc=");
+        assert_eq!(base64(b"admin:p"), "YWRtaW46cA==");
+    }
+
+    #[test]
+    fn only_takes_changes_from_the_admin_page() {
+        let page = [
+            ("Host", "10.0.0.1:8080"),
+            ("Origin", "http://10.0.0.1:8080"),
+            ("X-Kop-Audio-Admin", "1"),
+        ];
+        assert!(from_admin_page(&page));
+        // curl and the like send no Origin
+        assert!(from_admin_page(&[("x-kop-audio-admin", "1")]));
+        assert!(!from_admin_page(&page[..2]));
+        let other_site = [
+            ("Host", "10.0.0.1:8080"),
+            ("Origin", "https://evil.example"),
+            ("X-Kop-Audio-Admin", "1"),
+        ];
+        assert!(!from_admin_page(&other_site));
+    }
+
+    #[tokio::test]
+    async fn refuses_bodies_over_the_limit_before_reading_them() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (commands, _commands_rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = handle_request(stream, "Basic x", &commands).await;
+        });
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = format!("POST /api/kick HTTP/1.1\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).await.unwrap();
+        assert!(answer.starts_with("HTTP/1.1 413 "), "{}", answer);
+    }
+
+    #[test]
+    fn escapes_room_topic() {
+        assert_eq!(
+            json_string("raid \"tonight\"\n"),
+            "\"raid \\\"tonight\\\"\\u000a\""
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use crate::{CHANNELS, SAMPLE_RATE};
+
+// length of the echo path modelled after the bulk delay, the direct sound and
+// the early reflections of a small room
+const TAPS: usize = 1024;
+// longest time from playing something to hearing it again, PulseAudio buffers
+// on both sides
+const MAX_DELAY: usize = SAMPLE_RATE as usize / 2;
+// played audio capture hasn't caught up with yet, more means the two sides
+// fell out of step and the delay is found again
+const MAX_QUEUED: usize = SAMPLE_RATE as usize / 4;
+// the delay is found by correlating 2ms energy envelopes over the last second
+const BLOCK: usize = SAMPLE_RATE as usize / 500;
+const ESTIMATE_BLOCKS: usize = 500;
+const ESTIMATE_INTERVAL_BLOCKS: usize = 250;
+// below this the envelopes aren't alike enough to trust the delay, e.g. while
+// both sides talk
+const MIN_CORRELATION: f32 = 0.5;
+// the envelopes find the delay to a block, the filter starts a bit earlier
+const LEAD: usize = TAPS / 8;
+// NLMS step size, smaller converges slower but is disturbed less by the near end
+const STEP: f32 = 0.2;
+// mean square of played audio too quiet to leave an echo
+const SILENCE: f32 = 1e-8;
+// speaker to microphone rarely gains more than this, a louder microphone means
+// someone is talking and the filter mustn't learn from it
+const MAX_ECHO_GAIN: f32 = 4.0;
+
+/// What the speakers play, handed from playback to capture.
+#[derive(Debug, Clone, Default)]
+pub struct EchoReference(Arc<Mutex<VecDeque<f32>>>);
+
+impl EchoReference {
+    /// Called with everything written to the speakers.
+    pub fn push(&self, pcm: &[f32]) {
+        let mut queue = self.0.lock().unwrap();
+        queue.extend(pcm.chunks_exact(CHANNELS).map(mono));
+        let excess = queue.len().saturating_sub(MAX_QUEUED);
+        queue.drain(..excess);
+    }
+
+    /// Appends the next `frames` played, silence where playback is behind.
+    fn take(&self, frames: usize, out: &mut Vec<f32>) {
+        let mut queue = self.0.lock().unwrap();
+        let available = frames.min(queue.len());
+        out.extend(queue.drain(..available));
+        out.resize(out.len() + frames - available, 0.0);
+    }
+}
+
+/// Removes what the speakers played from the microphone, for everyone not
+/// wearing headphones. The bulk delay between the two is found by comparing
+/// their loudness over time, the echo path after it is learned by an NLMS
+/// filter while only the far end talks.
+pub struct EchoCanceller {
+    reference: EchoReference,
+    // played audio in mono, oldest first, covering the delay and the filter
+    far: Vec<f32>,
+    // newest sample last
+    weights: Vec<f32>,
+    delay: usize,
+    // energy of the last blocks of capture and playback
+    near_blocks: VecDeque<f32>,
+    far_blocks: VecDeque<f32>,
+    block: (f32, f32, usize),
+    blocks_since_estimate: usize,
+}
+
+impl EchoCanceller {
+    pub fn new(reference: EchoReference) -> Self {
+        EchoCanceller {
+            reference,
+            far: vec![0.0; MAX_DELAY + TAPS],
+            weights: vec![0.0; TAPS],
+            delay: 0,
+            near_blocks: VecDeque::from(vec![0.0; ESTIMATE_BLOCKS]),
+            far_blocks: VecDeque::from(vec![0.0; ESTIMATE_BLOCKS + MAX_DELAY / BLOCK]),
+            block: (0.0, 0.0, 0),
+            blocks_since_estimate: 0,
+        }
+    }
+
+    /// Subtracts the echo from captured `pcm`.
+    pub fn process(&mut self, pcm: &mut [f32]) {
+        let frames = pcm.len() / CHANNELS;
+        let history = MAX_DELAY + TAPS;
+        if self.far.len() > 2 * history {
+            self.far.drain(..self.far.len() - history);
+        }
+        self.reference.take(frames, &mut self.far);
+        let start = self.far.len() - frames;
+        self.track_delay(pcm, start);
+
+        // the played audio that can be heard in this frame
+        let aligned = &self.far[start - self.delay..start - self.delay + frames];
+        let far_energy = mean_square(aligned);
+        if far_energy < SILENCE {
+            return;
+        }
+        let near_energy = pcm.iter().map(|s| s * s).sum::<f32>() / pcm.len() as f32;
+        let adapt = near_energy < MAX_ECHO_GAIN * far_energy;
+
+        // the filter's window for the first sample, then slid along
+        let first = start - self.delay + 1 - TAPS;
+        let mut energy: f32 = self.far[first..first + TAPS].iter().map(|x| x * x).sum();
+        let mut residual = 0.0;
+        for (i, frame) in pcm.chunks_exact_mut(CHANNELS).enumerate() {
+            let window = &self.far[first + i..first + i + TAPS];
+            let echo: f32 = self.weights.iter().zip(window).map(|(w, x)| w * x).sum();
+            let error = mono(frame) - echo;
+            if adapt && energy > SILENCE {
+                let step = STEP * error / energy;
+                for (w, x) in self.weights.iter_mut().zip(window) {
+                    *w += step * x;
+                }
+            }
+            for sample in frame {
+                *sample -= echo;
+            }
+            residual += error * error;
+            if let Some(next) = self.far.get(first + i + TAPS) {
+                energy = (energy + next * next - window[0] * window[0]).max(0.0);
+            }
+        }
+        // made it louder rather than quieter, start over
+        if residual / frames as f32 > 2.0 * near_energy {
+            self.weights.fill(0.0);
+        }
+    }
+
+    fn track_delay(&mut self, pcm: &[f32], start: usize) {
+        for (frame, far) in pcm.chunks_exact(CHANNELS).zip(&self.far[start..]) {
+            let near = mono(frame);
+            self.block.0 += near * near;
+            self.block.1 += far * far;
+            self.block.2 += 1;
+            if self.block.2 == BLOCK {
+                self.near_blocks.pop_front();
+                self.near_blocks.push_back(self.block.0);
+                self.far_blocks.pop_front();
+                self.far_blocks.push_back(self.block.1);
+                self.block = (0.0, 0.0, 0);
+                self.blocks_since_estimate += 1;
+            }
+        }
+        if self.blocks_since_estimate < ESTIMATE_INTERVAL_BLOCKS {
+            return;
+        }
+        self.blocks_since_estimate = 0;
+        let near = self.near_blocks.make_contiguous();
+        let far = self.far_blocks.make_contiguous();
+        if let Some(lag) = estimate_delay(near, far) {
+            let delay = (lag * BLOCK).saturating_sub(LEAD).min(MAX_DELAY);
+            // small drifts are covered by the filter, it keeps what it learned
+            if delay.abs_diff(self.delay) > LEAD / 2 {
+                self.delay = delay;
+                self.weights.fill(0.0);
+            }
+        }
+    }
+}
+
+/// The lag in blocks at which `far`, which ends at the same time as `near`
+/// and is longer by the lags tried, looks most like `near`.
+fn estimate_delay(near: &[f32], far: &[f32]) -> Option<usize> {
+    let centred = |blocks: &[f32]| {
+        let mean = blocks.iter().sum::<f32>() / blocks.len() as f32;
+        let centred: Vec<f32> = blocks.iter().map(|b| b - mean).collect();
+        let norm = centred.iter().map(|b| b * b).sum::<f32>().sqrt();
+        (centred, norm)
+    };
+    let (near, near_norm) = centred(near);
+    if near_norm == 0.0 {
+        return None;
+    }
+    let mut best = None;
+    let mut best_correlation = MIN_CORRELATION;
+    for lag in 0..=far.len() - near.len() {
+        let (window, norm) = centred(&far[far.len() - near.len() - lag..far.len() - lag]);
+        if norm == 0.0 {
+            continue;
+        }
+        let correlation =
+            near.iter().zip(&window).map(|(a, b)| a * b).sum::<f32>() / (near_norm * norm);
+        if correlation > best_correlation {
+            best_correlation = correlation;
+            best = Some(lag);
+        }
+    }
+    best
+}
+
+fn mono(frame: &[f32]) -> f32 {
+    frame.iter().sum::<f32>() / frame.len() as f32
+}
+
+fn mean_square(samples: &[f32]) -> f32 {
+    samples.iter().map(|s| s * s).sum::<f32>() / samples.len().max(1) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn finds_the_delay_and_cancels_the_echo() {
+        let reference = EchoReference::default();
+        let mut canceller = EchoCanceller::new(reference.clone());
+        // 100ms to the speaker and back, plus a reflection off a wall
+        let paths = [
+            (SAMPLE_RATE as usize / 10, 0.5),
+            (SAMPLE_RATE as usize / 10 + 240, 0.2),
+        ];
+        let mut played = vec![0.0f32; MAX_DELAY];
+        let mut noise = 0x2545_f491u32;
+        let (mut before, mut after) = (0.0, 0.0);
+        for frame in 0..125 {
+            let mut far = Vec::new();
+            for i in 0..960 {
+                noise ^= noise << 13;
+                noise ^= noise >> 17;
+                noise ^= noise << 5;
+                // noise with a syllable-like rhythm, so it has an envelope to follow
+                let t = (frame * 960 + i) as f32 / SAMPLE_RATE as f32;
+                let envelope = (0.5 + 0.5 * (2.0 * PI * 3.0 * t).sin()).powi(2);
+                let sample = 0.3 * envelope * (noise as f32 / u32::MAX as f32 - 0.5);
+                played.push(sample);
+                far.extend([sample; CHANNELS]);
+            }
+            reference.push(&far);
+            let now = played.len() - 960;
+            let mut capture: Vec<f32> = (0..960)
+                .flat_map(|i| {
+                    let echo: f32 = paths
+                        .iter()
+                        .map(|(delay, gain)| gain * played[now + i - delay])
+                        .sum();
+                    [echo; CHANNELS]
+                })
+                .collect();
+            let input = mean_square(&capture);
+            canceller.process(&mut capture);
+            // judged once it had a second and a half to settle
+            if frame >= 75 {
+                before += input;
+                after += mean_square(&capture);
+            }
+        }
+        let reduction = 10.0 * (before / after).log10();
+        assert!(reduction > 20.0, "{}dB", reduction);
+    }
+
+    #[test]
+    fn delay_follows_the_envelope() {
+        let far: Vec<f32> = (0..40)
+            .map(|i| ((i * i * 37 + 11 * i) % 23) as f32)
+            .collect();
+        // what was played 5 blocks ago, at half the level
+        let near: Vec<f32> = far[25..35].iter().map(|b| b / 2.0).collect();
+        assert_eq!(estimate_delay(&near, &far), Some(5));
+        assert_eq!(estimate_delay(&[1.0; 10], &far), None);
+    }
+}
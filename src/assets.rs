@@ -0,0 +1,84 @@
+use std::{fs, path::Path};
+
+use crate::Error;
+
+/// A file built into the binary, so a single installed executable has
+/// everything a first setup needs. Regenerated by `assets/generate.py`.
+#[derive(Debug)]
+pub struct Asset {
+    /// where `init-config` puts it, relative to the config directory
+    pub path: &'static str,
+    pub contents: &'static [u8],
+    pub description: &'static str,
+}
+
+macro_rules! asset {
+    ($path:literal, $description:literal) => {
+        Asset {
+            path: $path,
+            contents: include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/", $path)),
+            description: $description,
+        }
+    };
+}
+
+pub static ASSETS: [Asset; 3] = [
+    asset!(
+        "chimes/join.wav",
+        "the built-in join chime, to edit and upload in the admin UI"
+    ),
+    asset!(
+        "chimes/leave.wav",
+        "the built-in leave chime, to edit and upload in the admin UI"
+    ),
+    asset!("schedule.example", "a sample schedule for --schedule"),
+];
+
+/// Writes the assets into `dir`, leaving files that are already there alone
+/// since they may have been edited. Returns the assets written.
+pub fn write_assets(dir: &Path) -> Result<Vec<&'static Asset>, Error> {
+    let mut written = Vec::new();
+    for asset in &ASSETS {
+        let path = dir.join(asset.path);
+        if path.exists() {
+            continue;
+        }
+        let error = |e: std::io::Error| Error::Io(format!("Can't write {}: {}", path.display(), e));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(error)?;
+        }
+        fs::write(&path, asset.contents).map_err(error)?;
+        written.push(asset);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chime::{default_chime, parse_wav},
+        server::Cue,
+        testutil::max_difference,
+    };
+
+    #[test]
+    fn built_in_chimes_are_valid_uploads() {
+        for (asset, cue) in ASSETS.iter().zip([Cue::Join, Cue::Leave]) {
+            let samples = parse_wav(asset.contents).unwrap();
+            assert!(max_difference(&samples, &default_chime(cue)) < 2.0 / 32768.0);
+        }
+    }
+
+    #[test]
+    fn keeps_files_already_written() {
+        let dir = std::env::temp_dir().join(format!("kop-audio-assets-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(write_assets(&dir).unwrap().len(), ASSETS.len());
+        fs::write(dir.join("schedule.example"), "12:00 Edited").unwrap();
+        assert!(write_assets(&dir).unwrap().is_empty());
+        let schedule = fs::read_to_string(dir.join("schedule.example")).unwrap();
+        assert_eq!(schedule, "12:00 Edited");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,135 +1,1202 @@
+pub mod samples;
+
 use std::{
-    slice,
-    sync::mpsc::{Receiver, Sender},
-    thread::sleep,
-    time::{Duration, SystemTime},
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, sleep},
+    time::{Duration, Instant, SystemTime},
 };
 
-use log::{debug, error};
-use opus::{Channels, Decoder, Encoder};
+use log::{debug, error, warn};
+use opus::{Bitrate, Channels, Decoder, Encoder};
+use rayon::{ThreadPool, ThreadPoolBuilder, prelude::*};
+use tokio::sync::mpsc::{Receiver, Sender, channel, error::TrySendError};
 
 use crate::{
-    AudioProducer, BUF_SIZE, CHANNELS, Consumer, FRAME_SIZE, SAMPLE_RATE,
+    AudioProducer, CHANNELS, Error, SAMPLE_RATE,
+    bus::{EventBus, Recv, Subscriber},
+    chime::default_chime,
     client::ClientMessage,
-    implementations::pulseaudio::{PulseAudioConsumer, PulseAudioProducer}, server::AudioData,
+    codec::{AudioCodec, CodecKind, OpusCodec, PcmCodec, codec2, opus_error},
+    jitter::{JitterBuffer, Playout},
+    listen_along::{MusicBuffer, MusicClock, MusicVote},
+    loudness::{LoudnessNormalizer, MixHeadroom, OutputStage, soft_clip},
+    music::MusicProducer,
+    recorder::Recorder,
+    resampler::StreamResampler,
+    transcribe,
+    playlist::PlaylistCommand,
+    quality::{ArrivalJitter, StreamHealth},
+    implementations::{Capture, Playback, pulseaudio::PulseAudioAppProducer},
+    server::{AudioData, CodecStream, Cue, RoomCodec, SessionId},
+    settings::{AudioSettings, BitrateMode, ProducerMix, capped_bitrate},
 };
-use opus::Application::Voip;
+
+// length of the gain ramp when muting or when the voice activity gate opens/closes
+const FADE_MS: usize = 5;
+// +3dB
+const MUSIC_GAIN_STEP: f32 = 1.4125;
+// how often the microphone level is reported, about as often as a meter redraws
+const LEVEL_REPORT_INTERVAL: Duration = Duration::from_millis(200);
+// how often playback reports its underruns, each report goes on to the server
+const PLAYBACK_STATS_INTERVAL: Duration = Duration::from_secs(5);
+// everyone who lost a stream asks for a reset, one in this time answers them all
+const RESET_INTERVAL: Duration = Duration::from_secs(1);
+// samples per channel of a sender lost in a row, half a second, after which
+// its decoder and encoder start afresh rather than predict from the past
+const LONG_LOSS_SAMPLES: usize = SAMPLE_RATE as usize / 2;
+// frames the capture loop may be ahead of the encoder, more are dropped
+// rather than hold up the microphone
+const SEND_QUEUE_FRAMES: usize = 8;
+
+/// Where the audio shared with the room comes from.
+pub enum SharedAudio {
+    App(PulseAudioAppProducer),
+    Playlist(MusicProducer),
+}
+
+impl AudioProducer for SharedAudio {
+    fn produce(&mut self, data: &mut [f32]) -> Result<(), Error> {
+        match self {
+            SharedAudio::App(app) => app.produce(data),
+            SharedAudio::Playlist(playlist) => playlist.produce(data),
+        }
+    }
+}
 
 pub fn record_audio(
-    tx: Sender<ClientMessage>,
-    producer: &mut PulseAudioProducer,
-    rx: Receiver<ClientMessage>,
-) {
-    let mut data = vec![0u8; BUF_SIZE as usize];
-    let mut encoded_data = [0u8; BUF_SIZE as usize];
-    let mut encoder = opus_encoder();
+    bus: EventBus,
+    producer: &mut Capture,
+    mut rx: Subscriber<ClientMessage>,
+    frames: Sender<OutgoingFrame>,
+    settings: &AudioSettings,
+    running: Arc<AtomicBool>,
+    mut shared: Option<SharedAudio>,
+) -> Result<(), Error> {
+    let mut data = vec![0f32; settings.frame_size * CHANNELS];
+    let mut shared_data = vec![0f32; settings.frame_size * CHANNELS];
     let mut hangover = 0;
     let mut muted = false;
-    let hangover_limit = 10;
-    let mut sequence_number: u32 = 0;
-    let mut timestamp: u64 = 0;
-    loop {
+    let mut held_mute = false;
+    let mut talking = false;
+    let mut vad_threshold = settings.vad_threshold_db;
+    let mut hangover_limit = settings.vad_hangover;
+    // loudest input since the last level report
+    let mut level = f32::NEG_INFINITY;
+    let mut level_frames = 0;
+    let level_report_frames = (LEVEL_REPORT_INTERVAL.as_micros() / settings.frame_duration().as_micros()).max(1);
+    let mut fade = Fade::new(SAMPLE_RATE as usize / 1000 * FADE_MS);
+    // frames the encoder had no room for since the last one it took
+    let mut dropped = 0;
+    // what the listeners voted for
+    let mut music_paused = false;
+    let mut music_gain = 1.0;
+    let mut normalizer = settings.music_loudness.map(LoudnessNormalizer::new);
+    while running.load(Ordering::Relaxed) && !bus.shutdown.is_triggered() {
         match rx.try_recv() {
-            Ok(ClientMessage::ToggleMute) => {
+            Some(ClientMessage::ToggleMute) => {
                 debug!("Got toggle mute in record_audio");
                 muted = !muted;
             }
+            Some(ClientMessage::HoldMute(held)) => held_mute = held,
+            Some(ClientMessage::PushToTalk(held)) => talking = held,
+            Some(ClientMessage::Vad(threshold, hangover)) => {
+                vad_threshold = threshold;
+                hangover_limit = hangover;
+            }
+            Some(ClientMessage::SelectInput(device)) => {
+                let announcement = match producer.switch_to(&device, settings) {
+                    Ok(next) => {
+                        *producer = next;
+                        format!("Now recording from {}", producer.description())
+                    }
+                    Err(e) => {
+                        error!("Can't switch the microphone to {}: {:?}", device, e);
+                        format!("Can't record from {}", device)
+                    }
+                };
+                bus.commands.publish(ClientMessage::Announcement(announcement));
+            }
+            Some(ClientMessage::MusicVoteResult(vote)) => match vote {
+                MusicVote::Pause => music_paused = !music_paused,
+                // 3dB steps, never louder than the application itself
+                MusicVote::Louder => music_gain = (music_gain * MUSIC_GAIN_STEP).min(1.0),
+                MusicVote::Quieter => music_gain /= MUSIC_GAIN_STEP,
+                MusicVote::Skip => {}
+            },
+            Some(ClientMessage::Playlist(playlist)) => {
+                if let Some(SharedAudio::Playlist(music)) = &mut shared {
+                    music.set_playlist(&playlist);
+                }
+            }
             _ => {}
         }
-        match producer.produce(&mut data) {
-            Ok(_) => {}
-            Err(_) => {
-                error!("Error reading from stream");
-                break;
+        if producer.produce(&mut data).is_err() {
+            // the device probably went away, carry on with the next one
+            warn!("Lost microphone {}", producer.description());
+            match producer.fallback(settings) {
+                Ok(next) => {
+                    *producer = next;
+                    bus.commands.publish(ClientMessage::Announcement(format!(
+                        "Microphone lost, now recording from {}",
+                        producer.description()
+                    )));
+                    continue;
+                }
+                Err(e) => {
+                    error!("No microphone to fall back to: {:?}", e);
+                    return Err(Error::Audio(format!(
+                        "Lost {} and no other microphone is available",
+                        producer.description()
+                    )));
+                }
             }
         }
-        if muted {
-            sleep(Duration::from_millis(20));
-            continue;
+        let captured_ms = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+        let input_level = level_db(&data);
+        level = level.max(input_level);
+        level_frames += 1;
+        if level_frames >= level_report_frames {
+            bus.commands.publish(ClientMessage::InputLevel(level));
+            level = f32::NEG_INFINITY;
+            level_frames = 0;
         }
-        let pcm: &[i16] =
-            unsafe { slice::from_raw_parts(data.as_ptr() as *const i16, data.len() / 2) };
-
-        let samples_needed = FRAME_SIZE * CHANNELS;
-        let pcm = &pcm[..samples_needed];
-        if is_silence(pcm, 200.0) {
+        let mut shared_active = false;
+        if let Some(app) = &mut shared {
+            if app.produce(&mut shared_data).is_err() {
+                warn!("Shared application audio went away");
+                shared = None;
+            } else {
+                if let Some(SharedAudio::Playlist(music)) = &mut shared {
+                    for id in music.take_finished() {
+                        bus.commands.publish(ClientMessage::PlaylistCommand(PlaylistCommand::Finished(id)));
+                    }
+                    if let Some(info) = music.take_now_playing() {
+                        bus.commands.publish(ClientMessage::AnnounceTrack(info));
+                    }
+                    // a jump in the music is as good as a lost stretch to the listeners
+                    if music.take_cut() {
+                        bus.commands.publish(ClientMessage::ResetEncoder(CodecStream::Music));
+                    }
+                }
+                // tracks from different sources at one level, before the
+                // sharing gain so that still sets where the music sits
+                if let Some(normalizer) = normalizer.as_mut().filter(|_| settings.listen_along) {
+                    normalizer.process(&mut shared_data);
+                }
+                apply_mix(&settings.shared_mix, &mut shared_data);
+                let silent = is_silence(&shared_data, 200.0 / 32768.0);
+                if settings.listen_along {
+                    // quiet passages aren't sent, listeners hear silence either way
+                    if !silent && !music_paused {
+                        for sample in &mut shared_data {
+                            *sample *= music_gain;
+                        }
+                        let frame = OutgoingFrame::new(CodecStream::Music, &shared_data, captured_ms);
+                        if !queue_frame(&frames, frame, &mut dropped) {
+                            break;
+                        }
+                    }
+                } else {
+                    shared_active = !silent;
+                    for (sample, app_sample) in data.iter_mut().zip(&shared_data) {
+                        *sample = (*sample + app_sample).clamp(-1.0, 1.0);
+                    }
+                }
+            }
+        }
+        let mut open = !muted && !held_mute && (talking || !settings.push_to_talk);
+        // shared audio keeps the gate open on its own, it has no voice to detect
+        if open && settings.vad && !settings.push_to_talk && !shared_active && input_level < vad_threshold {
             if hangover == 0 {
-                let _ = tx.send(ClientMessage::TransmitAudio(false));
-                continue;
+                open = false;
+            } else {
+                hangover -= 1;
             }
-            hangover -= 1;
-        } else {
+        } else if open {
             hangover = hangover_limit;
         }
+        // a closing gate still sends the frame that fades out
+        if !open && fade.is_closed() {
+            bus.commands.publish(ClientMessage::TransmitAudio(false));
+            if muted {
+                sleep(settings.frame_duration());
+            }
+            continue;
+        }
+        fade.apply(&mut data, open);
+        let frame = OutgoingFrame::new(CodecStream::Voice, &data, captured_ms);
+        if !queue_frame(&frames, frame, &mut dropped) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// A frame for the room on its way from the capture loop to the encoder.
+pub struct OutgoingFrame {
+    stream: CodecStream,
+    pcm: Vec<f32>,
+    // milliseconds since the epoch when it was read from the device
+    captured_ms: u64,
+}
+
+impl OutgoingFrame {
+    pub fn new(stream: CodecStream, pcm: &[f32], captured_ms: u64) -> Self {
+        OutgoingFrame {
+            stream,
+            pcm: pcm.to_vec(),
+            captured_ms,
+        }
+    }
+}
+
+/// The queue between `record_audio` and `send_audio`.
+pub fn send_queue() -> (Sender<OutgoingFrame>, Receiver<OutgoingFrame>) {
+    channel(SEND_QUEUE_FRAMES)
+}
+
+/// Hands `frame` to the encoder without waiting for it, a full queue drops
+/// the frame. Returns false once the encoder is gone.
+fn queue_frame(
+    frames: &Sender<OutgoingFrame>,
+    frame: OutgoingFrame,
+    dropped: &mut usize,
+) -> bool {
+    match frames.try_send(frame) {
+        Ok(()) => {
+            if *dropped > 0 {
+                warn!("The encoder fell behind, dropped {} frames", dropped);
+                *dropped = 0;
+            }
+            true
+        }
+        Err(TrySendError::Full(_)) => {
+            *dropped += 1;
+            true
+        }
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+/// Encodes what `record_audio` captured and hands the packets to the network,
+/// in a task of its own so the capture loop never waits for the codec, the
+/// recorder's files or a log line. Ends when the capture loop does or on
+/// shutdown.
+pub async fn send_audio(
+    bus: EventBus,
+    mut rx: Subscriber<ClientMessage>,
+    mut frames: Receiver<OutgoingFrame>,
+    mut encoder: Box<dyn AudioCodec>,
+    settings: AudioSettings,
+) {
+    let mut encoded_data = vec![0u8; settings.buf_size() as usize];
+    let mut sequence_number: u32 = 0;
+    // what the encoder was asked for, the room may hold it lower
+    let mut bitrate = settings.bitrate;
+    let mut expected_loss = settings.expected_loss;
+    // until the server answers the hello
+    let mut room = RoomConverter::new(settings.profile.codec(), settings.frame_size);
+    let mut last_reset: HashMap<CodecStream, Instant> = HashMap::new();
+    let mut recorder = settings.recording.clone().map(Recorder::new);
+    let speech_to_chat = settings
+        .speech_to_chat
+        .clone()
+        .map(|command| transcribe::speech_to_chat(command, &settings, bus.clone()));
+    // the shared application goes out as a stream of its own when listening along
+    let mut music = settings.listen_along.then(|| {
+        let music_settings = settings.clone().music();
+        (
+            opus_encoder(&music_settings),
+            MusicClock::new(music_settings.frame_ms()),
+            0u32,
+        )
+    });
+    let shutdown = bus.shutdown.wait();
+    tokio::pin!(shutdown);
+    loop {
+        // commands are taken as they come, while muted too
+        let (frame, msg) = tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(frame) => (Some(frame), None),
+                None => break,
+            },
+            Some(msg) = rx.recv() => (None, Some(msg)),
+            _ = &mut shutdown => break,
+        };
+        if let Some(msg) = msg {
+            match msg {
+                ClientMessage::AddMarker(name, at) => {
+                    let announcement = match recorder.as_mut().map(|r| r.mark(name.clone(), at)) {
+                        Some(Ok(path)) => format!("Marked \"{}\" in {}", name, path.display()),
+                        Some(Err(e)) => {
+                            error!("{:?}", e);
+                            "Can't save the marker".to_string()
+                        }
+                        None => "Not recording, markers need --record".to_string(),
+                    };
+                    bus.commands.publish(ClientMessage::Announcement(announcement));
+                }
+                ClientMessage::Bitrate(bits) => {
+                    bitrate = Bitrate::Bits(bits as i32);
+                    let capped = capped_bitrate(bitrate, room.codec.max_bitrate);
+                    if let Err(e) = encoder.set_bitrate(capped) {
+                        warn!("Can't change the bitrate to {}: {:?}", bits, e);
+                    }
+                }
+                ClientMessage::RoomCodec(codec) => {
+                    room = RoomConverter::new(codec, settings.frame_size);
+                    let tuned = AudioSettings {
+                        bitrate,
+                        expected_loss,
+                        ..settings.clone()
+                    };
+                    encoder = voice_encoder(&tuned, &room.codec);
+                }
+                ClientMessage::ResetEncoder(stream)
+                    if last_reset.get(&stream).is_none_or(|at| at.elapsed() >= RESET_INTERVAL) =>
+                {
+                    let music_encoder = music.as_mut().map(|(encoder, _, _)| encoder);
+                    match reset_encoder(stream, encoder.as_mut(), music_encoder) {
+                        Ok(true) => {
+                            last_reset.insert(stream, Instant::now());
+                            bus.commands.publish(ClientMessage::EncoderReset(stream));
+                        }
+                        Ok(false) => {}
+                        Err(e) => warn!("Can't reset the {:?} encoder: {:?}", stream, e),
+                    }
+                }
+                ClientMessage::ExpectedLoss(percent) => {
+                    expected_loss = percent;
+                    if let Err(e) = encoder.set_expected_loss(percent) {
+                        warn!("Can't tune FEC for {}% loss: {:?}", percent, e);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let Some(frame) = frame else {
+            continue;
+        };
+        if frame.stream == CodecStream::Music {
+            if let Some((encoder, clock, seq_number)) = &mut music {
+                let n = match encoder.encode_float(&frame.pcm, &mut encoded_data) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        error!("Can't encode the shared music: {:?}", opus_error(e));
+                        continue;
+                    }
+                };
+                *seq_number = seq_number.wrapping_add(1);
+                bus.commands.publish(ClientMessage::Music(AudioData {
+                    timestamp: clock.stamp(frame.captured_ms, *seq_number),
+                    seq_number: *seq_number,
+                    data: encoded_data[..n].to_vec(),
+                }));
+            }
+            continue;
+        }
+        if let Some(recorder) = &mut recorder {
+            recorder.write("me", &frame.pcm);
+        }
+        // behind if full, a dropped frame costs a word at most
+        if let Some(speech) = &speech_to_chat {
+            let _ = speech.try_send(frame.pcm.clone());
+        }
+        let pcm = room.convert(&frame.pcm);
         debug!("Acive audio detected, sending packet");
-        let n = encoder.encode(&pcm, &mut encoded_data).unwrap();
-
-        debug!(
-            "Read {} samples, data has {} samples, encoded to {} bytes,",
-            pcm.len(),
-            data.len() / 2,
-            n,
-        );
-        timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+        let n = match encoder.encode(pcm, &mut encoded_data) {
+            Ok(n) => n,
+            Err(e) => {
+                error!("Can't encode the microphone: {:?}", e);
+                continue;
+            }
+        };
+
+        debug!("Read {} samples, encoded to {} bytes,", pcm.len(), n);
         sequence_number = sequence_number.wrapping_add(1);
-        let _ = tx.send(ClientMessage::TransmitAudio(true));
-        let _ = tx.send(ClientMessage::Audio(AudioData {
-            timestamp,
+        bus.commands.publish(ClientMessage::TransmitAudio(true));
+        bus.commands.publish(ClientMessage::Audio(AudioData {
+            timestamp: frame.captured_ms,
             seq_number: sequence_number,
             data: encoded_data[..n].to_vec(),
         }));
     }
 }
 
-pub fn play_audio(rx: Receiver<ClientMessage>, consumer: &mut PulseAudioConsumer) {
-    let mut decoder = opus_decoder();
-    let mut decoded_data = vec![0i16; FRAME_SIZE * CHANNELS];
+/// Decoder state of one remote sender, dropped once the sender has been quiet
+/// for longer than the stream timeout.
+struct RemoteStream {
+    addr: SocketAddr,
+    decoder: Box<dyn AudioCodec>,
+    jitter: JitterBuffer,
+    last_packet: Instant,
+    // samples per channel of the sender's frames, the length to conceal for a lost one
+    frame_samples: usize,
+    // brings every microphone to the same level, `None` if turned off
+    normalizer: Option<LoudnessNormalizer>,
+    // sequence number of the last packet played, to tell a long loss
+    last_seq: Option<u32>,
+    arrival: ArrivalJitter,
+    // how it played since the last report, graded by the coordinator
+    health: StreamHealth,
+    // what it plays in this frame, each stream has its own to decode in parallel
+    decoded: Vec<f32>,
+}
+
+/// What a sender plays in this frame, decoded once every sender's is known.
+enum Due {
+    Packet(Vec<u8>),
+    /// lost, recovered from the next packet if it has arrived
+    Missing(Option<Vec<u8>>),
+}
+
+impl RemoteStream {
+    fn new(addr: SocketAddr, room: &RoomCodec, settings: &AudioSettings) -> Self {
+        RemoteStream {
+            addr,
+            decoder: voice_decoder(room),
+            jitter: JitterBuffer::new(settings.jitter_target),
+            last_packet: Instant::now(),
+            frame_samples: settings.frame_size,
+            normalizer: settings.voice_loudness.map(LoudnessNormalizer::new),
+            last_seq: None,
+            arrival: ArrivalJitter::default(),
+            health: StreamHealth::default(),
+            decoded: vec![0f32; MAX_FRAME_SAMPLES * CHANNELS],
+        }
+    }
+
+    /// Decodes `due` into `decoded`, returns the samples per channel.
+    fn decode(&mut self, due: &Due) -> Result<usize, Error> {
+        match due {
+            Due::Packet(packet) => {
+                let b = self.decoder.decode(packet, &mut self.decoded, false)?;
+                self.frame_samples = b;
+                Ok(b)
+            }
+            // the next packet carries a low bitrate copy of the lost one if
+            // the sender has in-band FEC on, otherwise the codec conceals it
+            Due::Missing(next) => {
+                let out = &mut self.decoded[..self.frame_samples * CHANNELS];
+                match next {
+                    Some(next) => self.decoder.decode(next, out, true),
+                    None => self.decoder.decode(&[], out, false),
+                }
+            }
+        }
+    }
+}
+
+/// Decodes what the senders play in this frame, on the `pool` once enough of
+/// them talk at once, as they don't depend on each other. Returns the samples
+/// per channel of each, in order.
+fn decode_streams(
+    due: &mut [(&mut RemoteStream, Due)],
+    pool: Option<&ThreadPool>,
+) -> Vec<Result<usize, Error>> {
+    match pool {
+        Some(pool) if due.len() >= PARALLEL_DECODE_STREAMS => pool.install(|| {
+            due.par_iter_mut()
+                .map(|(stream, due)| stream.decode(due))
+                .collect()
+        }),
+        _ => due.iter_mut().map(|(stream, due)| stream.decode(due)).collect(),
+    }
+}
+
+/// Threads that decode for the playback thread, started once with it. `None`
+/// with a single worker, or if they can't be started, everything is decoded
+/// on the playback thread then.
+fn decode_pool(workers: usize) -> Option<ThreadPool> {
+    if workers < 2 {
+        return None;
+    }
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(workers)
+        .thread_name(|i| format!("kop-audio-decode-{}", i))
+        .build();
+    match pool {
+        Ok(pool) => Some(pool),
+        Err(e) => {
+            warn!("Can't start decode threads, decoding on the playback thread: {}", e);
+            None
+        }
+    }
+}
+
+/// Listen-along music of the current host.
+struct ListenAlong {
+    addr: SocketAddr,
+    decoder: Decoder,
+    buffer: MusicBuffer,
+    last_packet: Instant,
+}
+
+// the longest frame opus can produce, 120ms
+const MAX_FRAME_SAMPLES: usize = 5760;
+// threads decoding senders at once, and how many senders have to be due in a
+// frame to hand them to those, fewer are decoded faster on the playback thread
+const MAX_DECODE_WORKERS: usize = 4;
+const PARALLEL_DECODE_STREAMS: usize = 3;
+
+pub fn play_audio(
+    bus: EventBus,
+    mut rx: Subscriber<ClientMessage>,
+    consumer: &mut Playback,
+    settings: &AudioSettings,
+) {
+    let mut decoded_data = vec![0f32; MAX_FRAME_SAMPLES * CHANNELS];
+    let mut mix: Vec<f32> = Vec::with_capacity(MAX_FRAME_SAMPLES * CHANNELS);
+    // the voices of this frame, mixed on their own to keep their sum level
+    let mut voices: Vec<f32> = Vec::with_capacity(MAX_FRAME_SAMPLES * CHANNELS);
+    let mut headroom = MixHeadroom::default();
+    let mut output = OutputStage::new(settings.stereo_width, settings.output_ceiling_db);
     let mut deafened = false;
-    for msg in rx.iter() {
-        match msg {
-            ClientMessage::RecvAudio(_, audio) => {
-                if deafened {
-                    sleep(Duration::from_millis(20));
-                    continue;
+    // keyed by session, so a new client on an old address starts afresh
+    let mut streams: HashMap<SessionId, RemoteStream> = HashMap::new();
+    // gain of the users whose volume was changed, 0 for those muted locally
+    let mut gains: HashMap<SocketAddr, f32> = HashMap::new();
+    let mut output_gain = volume_gain(settings.output_volume);
+    // how the room's voices are encoded, voice messages are recorded from them
+    let mut room = RoomCodec::default();
+    // voice message being played back and its decoder
+    let mut clip: VecDeque<Vec<u8>> = VecDeque::new();
+    let mut clip_decoder = voice_decoder(&room);
+    // the room's custom chimes, cues without one use the built-in chime
+    let mut chimes: HashMap<Cue, Vec<Vec<u8>>> = HashMap::new();
+    // samples of the cue being played
+    let mut tone: VecDeque<f32> = VecDeque::new();
+    // listen-along music being played in step with the other listeners
+    let mut music: Option<ListenAlong> = None;
+    // packets are played on our own frame clock, not when they arrive
+    let mut next_frame = Instant::now() + settings.frame_duration();
+    let mut recorder = settings.recording.clone().map(Recorder::new);
+    // frames of remote streams due since the last report, and how many of them were missing
+    let mut stats = (0u32, 0u32);
+    let mut last_stats = Instant::now();
+    // a worker per core for busy rooms on slow machines
+    let decode_pool = decode_pool(
+        thread::available_parallelism()
+            .map_or(1, |cores| cores.get())
+            .min(MAX_DECODE_WORKERS),
+    );
+    while !bus.shutdown.is_triggered() {
+        let wait = next_frame.saturating_duration_since(Instant::now());
+        match rx.blocking_recv_timeout(wait) {
+            Recv::Message(ClientMessage::RecvAudio(addr, session, audio)) => {
+                let stream = streams
+                    .entry(session)
+                    .or_insert_with(|| RemoteStream::new(addr, &room, settings));
+                stream.last_packet = Instant::now();
+                stream.arrival.push(audio.timestamp, stream.last_packet);
+                if !deafened {
+                    stream.jitter.push(audio);
+                }
+            }
+            Recv::Message(ClientMessage::RecvMusic(addr, frame)) if !deafened => {
+                // the first frame or a new host taking over
+                if music.as_ref().is_none_or(|music| music.addr != addr) {
+                    music = Some(ListenAlong {
+                        addr,
+                        decoder: opus_decoder(),
+                        buffer: MusicBuffer::default(),
+                        last_packet: Instant::now(),
+                    });
+                }
+                if let Some(music) = &mut music {
+                    music.last_packet = Instant::now();
+                    music.buffer.push(frame);
                 }
-                let b = decoder.decode(&audio.data, &mut decoded_data, false).unwrap();
-                match consumer.consume(unsafe {
-                    slice::from_raw_parts(
-                        decoded_data.as_ptr() as *const u8,
-                        b * CHANNELS * std::mem::size_of::<i16>(),
-                    )
-                }) {
-                    Ok(_) => {}
+            }
+            Recv::Message(ClientMessage::ToggleDeafen) => {
+                deafened = !deafened;
+            }
+            Recv::Message(ClientMessage::UserVolume(addr, volume, muted)) => {
+                match (volume, muted) {
+                    (_, true) => gains.insert(addr, 0.0),
+                    (100, false) => gains.remove(&addr),
+                    (volume, false) => gains.insert(addr, volume_gain(volume)),
+                };
+            }
+            Recv::Message(ClientMessage::SelectOutput(device)) => {
+                let announcement = match consumer.switch_to(&device, settings) {
+                    Ok(next) => {
+                        *consumer = next;
+                        format!("Now playing on {}", consumer.description())
+                    }
                     Err(e) => {
-                        error!("Error consuming data: {:?}", e);
+                        error!("Can't switch the output to {}: {:?}", device, e);
+                        format!("Can't play on {}", device)
+                    }
+                };
+                bus.commands.publish(ClientMessage::Announcement(announcement));
+            }
+            Recv::Message(ClientMessage::OutputVolume(volume)) => {
+                output_gain = volume_gain(volume);
+            }
+            Recv::Message(ClientMessage::PlayClip(packets)) => {
+                clip = packets.into();
+                clip_decoder = voice_decoder(&room);
+            }
+            Recv::Message(ClientMessage::ResetDecoder(addr, CodecStream::Voice)) => {
+                for stream in streams.values_mut().filter(|stream| stream.addr == addr) {
+                    if let Err(e) = stream.decoder.reset() {
+                        warn!("Can't reset the decoder of {}: {:?}", addr, e);
                     }
                 }
             }
-            ClientMessage::ToggleDeafen => {
-                deafened = !deafened;
+            Recv::Message(ClientMessage::ResetDecoder(addr, CodecStream::Music)) => {
+                if let Some(music) = music.as_mut().filter(|music| music.addr == addr)
+                    && let Err(e) = music.decoder.reset_state()
+                {
+                    warn!("Can't reset the music decoder of {}: {:?}", addr, e);
+                }
             }
-            _ => {}
+            Recv::Message(ClientMessage::RoomCodec(codec)) => {
+                // declared right after joining, the streams of the last room
+                // can't be decoded with it
+                room = codec;
+                streams.clear();
+                clip_decoder = voice_decoder(&room);
+            }
+            Recv::Message(ClientMessage::SetChime(cue, packets)) => {
+                chimes.insert(cue, packets);
+            }
+            Recv::Message(ClientMessage::PlayCue(cue)) if !deafened => {
+                tone = match chimes.get(&cue).filter(|packets| !packets.is_empty()) {
+                    Some(packets) => decode_chime(packets),
+                    None => default_chime(cue),
+                }
+                .into();
+            }
+            Recv::Message(ClientMessage::Disconnect) | Recv::Closed => break,
+            Recv::Message(_) | Recv::Timeout => {}
+        }
+        let now = Instant::now();
+        if now < next_frame {
+            continue;
+        }
+        next_frame += settings.frame_duration();
+        if now > next_frame + settings.frame_duration() * 4 {
+            // fell far behind, e.g. after a suspend, don't try to catch up
+            next_frame = now + settings.frame_duration();
+        }
+        // everything that plays in this frame, written to the sink at once so
+        // overlapping speakers are heard together rather than one after another
+        mix.clear();
+        if let Some(packet) = clip.pop_front() {
+            match clip_decoder.decode(&packet, &mut decoded_data, false) {
+                Ok(b) => mix_into(&mut mix, &decoded_data[..b * CHANNELS]),
+                Err(e) => error!("Error decoding voice message: {:?}", e),
+            }
+        }
+        if !tone.is_empty() {
+            let n = tone.len().min(settings.frame_size * CHANNELS);
+            let samples: Vec<f32> = tone.drain(..n).collect();
+            mix_into(&mut mix, &samples);
+        }
+        if let Some(listen) = &mut music {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+            let late = listen.buffer.late;
+            while let Some(frame) = listen.buffer.pop(now) {
+                match listen.decoder.decode_float(&frame.data, &mut decoded_data, false) {
+                    Ok(b) => mix_into(&mut mix, &decoded_data[..b * CHANNELS]),
+                    Err(e) => error!("Error decoding music: {:?}", e),
+                }
+            }
+            if listen.buffer.late > late {
+                debug!(
+                    "Music from {} arrived too late to play in sync, {} frames dropped so far",
+                    listen.addr, listen.buffer.late
+                );
+            }
+        }
+        voices.clear();
+        let mut speaking = 0;
+        let mut due = Vec::with_capacity(streams.len());
+        for stream in streams.values_mut() {
+            let frame = match stream.jitter.pop() {
+                Playout::Frame(audio) => {
+                    stats.0 += 1;
+                    stream.health.frames += 1;
+                    let lost = stream
+                        .last_seq
+                        .map_or(0, |last| audio.seq_number.saturating_sub(last + 1));
+                    stream.last_seq = Some(audio.seq_number);
+                    if lost as usize * stream.frame_samples >= LONG_LOSS_SAMPLES {
+                        let addr = stream.addr;
+                        debug!("Lost {} packets of {} in a row, starting afresh", lost, addr);
+                        if let Err(e) = stream.decoder.reset() {
+                            warn!("Can't reset the decoder of {}: {:?}", addr, e);
+                        }
+                        let request = ClientMessage::RequestCodecReset(addr, CodecStream::Voice);
+                        bus.commands.publish(request);
+                    }
+                    Due::Packet(audio.data)
+                }
+                Playout::Missing => {
+                    stats = (stats.0 + 1, stats.1 + 1);
+                    stream.health.frames += 1;
+                    stream.health.underruns += 1;
+                    Due::Missing(stream.jitter.peek().map(|next| next.data.clone()))
+                }
+                Playout::Waiting => continue,
+            };
+            due.push((stream, frame));
+        }
+        let decoded = decode_streams(&mut due, decode_pool.as_ref());
+        for ((stream, _), decoded) in due.into_iter().zip(decoded) {
+            let b = match decoded {
+                Ok(b) => b,
+                Err(e) => {
+                    error!("Error decoding packet: {:?}", e);
+                    continue;
+                }
+            };
+            // recorded as they were heard by the room, not as we chose to hear them
+            if let Some(recorder) = &mut recorder {
+                recorder.write(&stream.addr.to_string(), &stream.decoded[..b * CHANNELS]);
+            }
+            // still decoded while muted, so unmuting doesn't start on a stale state
+            let samples = &mut stream.decoded[..b * CHANNELS];
+            if let Some(normalizer) = &mut stream.normalizer {
+                normalizer.process(samples);
+            }
+            match gains.get(&stream.addr) {
+                Some(0.0) => continue,
+                Some(gain) => samples.iter_mut().for_each(|sample| *sample *= gain),
+                None => {}
+            }
+            mix_into(&mut voices, samples);
+            speaking += 1;
+        }
+        headroom.process(&mut voices, speaking);
+        mix_into(&mut mix, &voices);
+        if !mix.is_empty() {
+            for sample in &mut mix {
+                *sample = soft_clip(*sample * output_gain);
+            }
+            output.process(&mut mix);
+            if let Err(e) = consumer.consume(&mix) {
+                error!("Error consuming data: {:?}", e);
+            }
+        }
+        if last_stats.elapsed() >= PLAYBACK_STATS_INTERVAL {
+            last_stats = Instant::now();
+            // the streams first, so our grade is taken with their latest
+            for stream in streams.values_mut().filter(|stream| stream.health.frames > 0) {
+                let health = StreamHealth {
+                    jitter_ms: stream.arrival.ms(),
+                    ..std::mem::take(&mut stream.health)
+                };
+                bus.commands.publish(ClientMessage::StreamHealth(stream.addr, health));
+            }
+            bus.commands.publish(ClientMessage::PlaybackStats(stats.0, stats.1));
+            stats = (0, 0);
+        }
+        streams.retain(|_, stream| {
+            if stream.last_packet.elapsed() < settings.stream_timeout {
+                return true;
+            }
+            debug!("Stream from {} ended, dropping its decoder", stream.addr);
+            bus.commands.publish(ClientMessage::StreamEnded(stream.addr));
+            false
+        });
+        // frames wait for their playback time, so only an empty buffer means
+        // the host stopped
+        if music
+            .as_ref()
+            .is_some_and(|m| m.buffer.is_empty() && m.last_packet.elapsed() >= settings.stream_timeout)
+        {
+            debug!("Listen-along music ended");
+            music = None;
+        }
+    }
+}
+
+/// Linear gain for a volume in percent, squared so the steps sound about even
+/// rather than bunching up at the quiet end.
+fn volume_gain(volume: u32) -> f32 {
+    (volume as f32 / 100.0).powi(2)
+}
+
+/// Adds `samples` to the frame being mixed, growing it if they are longer,
+/// e.g. a sender using longer frames than the others.
+fn mix_into(mix: &mut Vec<f32>, samples: &[f32]) {
+    if mix.len() < samples.len() {
+        mix.resize(samples.len(), 0.0);
+    }
+    for (sample, other) in mix.iter_mut().zip(samples) {
+        *sample += other;
+    }
+}
+
+fn decode_chime(packets: &[Vec<u8>]) -> Vec<f32> {
+    let mut decoder = opus_decoder();
+    let mut decoded = vec![0f32; MAX_FRAME_SAMPLES * CHANNELS];
+    let mut samples = Vec::new();
+    for packet in packets {
+        match decoder.decode_float(packet, &mut decoded, false) {
+            Ok(n) => samples.extend_from_slice(&decoded[..n * CHANNELS]),
+            Err(e) => error!("Error decoding chime: {:?}", e),
+        }
+    }
+    samples
+}
+
+/// Resets the encoder of `stream` for its listeners to start afresh with,
+/// returns whether there was one.
+fn reset_encoder(
+    stream: CodecStream,
+    voice: &mut dyn AudioCodec,
+    music: Option<&mut Encoder>,
+) -> Result<bool, Error> {
+    match (stream, music) {
+        (CodecStream::Voice, _) => voice.reset().map(|_| true),
+        (CodecStream::Music, Some(music)) => music.reset_state().map(|_| true).map_err(opus_error),
+        (CodecStream::Music, None) => Ok(false),
+    }
+}
+
+/// Linear gain ramp for the outgoing audio, so muting and the voice activity
+/// gate don't cut the signal mid-waveform and click.
+struct Fade {
+    gain: f32,
+    step: f32,
+}
+
+impl Fade {
+    fn new(ramp_samples: usize) -> Self {
+        Fade {
+            gain: 0.0,
+            step: 1.0 / ramp_samples.max(1) as f32,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.gain <= 0.0
+    }
+
+    fn apply(&mut self, pcm: &mut [f32], open: bool) {
+        let target = if open { 1.0 } else { 0.0 };
+        for frame in pcm.chunks_exact_mut(CHANNELS) {
+            if self.gain < target {
+                self.gain = (self.gain + self.step).min(target);
+            } else if self.gain > target {
+                self.gain = (self.gain - self.step).max(target);
+            }
+            for sample in frame {
+                *sample *= self.gain;
+            }
+        }
+    }
+}
+
+/// Turns the microphone's frames into what the room's codec encodes, the
+/// same duration at its sample rate and channel count.
+struct RoomConverter {
+    codec: RoomCodec,
+    resampler: Option<StreamResampler>,
+    resampled: Vec<f32>,
+    downmixed: Vec<f32>,
+}
+
+impl RoomConverter {
+    fn new(mut codec: RoomCodec, frame_size: usize) -> Self {
+        let mut resampler = None;
+        if codec.sample_rate != SAMPLE_RATE {
+            match StreamResampler::new(SAMPLE_RATE, codec.sample_rate, frame_size) {
+                Ok(r) => resampler = Some(r),
+                Err(e) => {
+                    error!(
+                        "Can't resample to {}Hz, encoding at {}Hz: {:?}",
+                        codec.sample_rate, SAMPLE_RATE, e
+                    );
+                    codec.sample_rate = SAMPLE_RATE;
+                }
+            }
+        }
+        RoomConverter {
+            codec,
+            resampler,
+            resampled: Vec::new(),
+            downmixed: Vec::new(),
         }
     }
+
+    fn convert<'a>(&'a mut self, pcm: &'a [f32]) -> &'a [f32] {
+        let pcm = match &mut self.resampler {
+            Some(resampler) => {
+                self.resampled.clear();
+                resampler.process(pcm, &mut self.resampled);
+                &self.resampled[..]
+            }
+            None => pcm,
+        };
+        if self.codec.channels as usize == CHANNELS {
+            return pcm;
+        }
+        self.downmixed.clear();
+        let mono = pcm.chunks_exact(CHANNELS).map(|frame| frame.iter().sum::<f32>() / CHANNELS as f32);
+        self.downmixed.extend(mono);
+        &self.downmixed
+    }
+}
+
+pub fn opus_encoder(settings: &AudioSettings) -> Encoder {
+    room_opus_encoder(settings, &RoomCodec::default())
+}
+
+/// The microphone's encoder for the room's codec, otherwise as `settings` say.
+pub fn voice_encoder(settings: &AudioSettings, codec: &RoomCodec) -> Box<dyn AudioCodec> {
+    match codec.kind {
+        CodecKind::Opus => Box::new(OpusCodec::encoding(room_opus_encoder(settings, codec))),
+        CodecKind::Pcm => Box::new(PcmCodec::new(codec.sample_rate, codec.channels as usize)),
+        CodecKind::Codec2 => codec2(codec.max_bitrate),
+    }
+}
+
+/// A decoder for a voice in the room.
+fn voice_decoder(codec: &RoomCodec) -> Box<dyn AudioCodec> {
+    match codec.kind {
+        CodecKind::Opus => Box::new(OpusCodec::decoding(opus_decoder())),
+        CodecKind::Pcm => Box::new(PcmCodec::new(codec.sample_rate, codec.channels as usize)),
+        CodecKind::Codec2 => codec2(codec.max_bitrate),
+    }
 }
 
-fn opus_encoder() -> Encoder {
-    Encoder::new(SAMPLE_RATE, Channels::Stereo, Voip).unwrap()
+fn room_opus_encoder(settings: &AudioSettings, codec: &RoomCodec) -> Encoder {
+    let channels = match codec.channels {
+        1 => Channels::Mono,
+        _ => Channels::Stereo,
+    };
+    let mut encoder = Encoder::new(codec.sample_rate, channels, settings.application).unwrap();
+    encoder
+        .set_bitrate(capped_bitrate(settings.bitrate, codec.max_bitrate))
+        .unwrap();
+    encoder
+        .set_vbr(settings.bitrate_mode != BitrateMode::Cbr)
+        .unwrap();
+    encoder
+        .set_vbr_constraint(settings.bitrate_mode == BitrateMode::Constrained)
+        .unwrap();
+    if settings.expected_loss > 0 {
+        encoder.set_inband_fec(true).unwrap();
+        encoder
+            .set_packet_loss_perc(settings.expected_loss as i32)
+            .unwrap();
+    }
+    encoder
 }
-fn opus_decoder() -> Decoder {
+
+pub fn opus_decoder() -> Decoder {
     Decoder::new(SAMPLE_RATE, Channels::Stereo).unwrap()
 }
 
-fn is_silence(pcm: &[i16], threshold: f32) -> bool {
+fn apply_mix(mix: &ProducerMix, pcm: &mut [f32]) {
+    for frame in pcm.chunks_exact_mut(CHANNELS) {
+        if mix.mono {
+            let mid = frame.iter().sum::<f32>() / CHANNELS as f32;
+            frame.fill(mid);
+        }
+        for sample in frame {
+            *sample *= mix.gain;
+        }
+    }
+}
+
+/// RMS level of `pcm` in dBFS, -100 for digital silence.
+pub fn level_db(pcm: &[f32]) -> f32 {
+    let sum: f32 = pcm.iter().map(|s| s * s).sum();
+    let rms = (sum / pcm.len().max(1) as f32).sqrt();
+    (20.0 * rms.log10()).max(-100.0)
+}
+
+fn is_silence(pcm: &[f32], threshold: f32) -> bool {
     if pcm.is_empty() {
         return true;
     }
 
-    let mut sum = 0f64;
+    let mut sum = 0f32;
     for &s in pcm {
-        sum += (s as f64) * (s as f64);
+        sum += s * s;
+    }
+
+    let rms = (sum / pcm.len() as f32).sqrt();
+    rms < threshold
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{max_difference, read_fixture};
+
+    const VAD_THRESHOLD: f32 = 200.0 / 32768.0;
+
+    #[test]
+    fn vad_detects_tone() {
+        let wav = read_fixture("sine_440_48k.wav");
+        for frame in wav.samples.chunks_exact(960 * CHANNELS) {
+            assert!(!is_silence(frame, VAD_THRESHOLD));
+        }
+    }
+
+    #[test]
+    fn vad_ignores_noise_floor() {
+        let wav = read_fixture("noise_floor_48k.wav");
+        for frame in wav.samples.chunks_exact(960 * CHANNELS) {
+            assert!(is_silence(frame, VAD_THRESHOLD));
+        }
+        assert!(is_silence(&[], VAD_THRESHOLD));
+    }
+
+    #[test]
+    fn measures_level_in_dbfs() {
+        assert!((level_db(&[0.5; 960]) + 6.02).abs() < 0.01);
+        assert_eq!(level_db(&[0.0; 960]), -100.0);
+        assert_eq!(level_db(&[]), -100.0);
+    }
+
+    #[test]
+    fn volume_steps_are_even_in_loudness() {
+        assert_eq!(volume_gain(100), 1.0);
+        assert_eq!(volume_gain(0), 0.0);
+        // half the volume is about 12dB quieter, double about 12dB louder
+        assert!((20.0 * volume_gain(50).log10() + 12.04).abs() < 0.01);
+        assert!((20.0 * volume_gain(200).log10() - 12.04).abs() < 0.01);
+    }
+
+    #[test]
+    fn mix_folds_to_mono_and_applies_gain() {
+        let mix = ProducerMix {
+            gain: ProducerMix::gain_preset("quiet").unwrap(),
+            mono: true,
+        };
+        let mut frame = vec![1.0, 0.0, 0.0, -1.0];
+        apply_mix(&mix, &mut frame);
+        let expected = 0.5 * mix.gain;
+        assert!(max_difference(&frame, &[expected, expected, -expected, -expected]) < 1e-6);
+        assert!((mix.gain - 0.501).abs() < 0.001);
     }
 
-    let rms = (sum / pcm.len() as f64).sqrt();
-    rms < threshold as f64
+    #[test]
+    fn mixes_streams_of_different_lengths() {
+        let mut mix = Vec::new();
+        mix_into(&mut mix, &[0.5, 0.5]);
+        mix_into(&mut mix, &[0.25, -0.5, 0.25, 0.25]);
+        assert_eq!(mix, vec![0.75, 0.0, 0.25, 0.25]);
+    }
+
+    #[test]
+    fn mix_matches_golden() {
+        let golden = read_fixture("sine_440_660_48k_mix.wav");
+        let mut mix = Vec::new();
+        for voice in ["sine_440_48k.wav", "sine_660_48k.wav"] {
+            mix_into(&mut mix, &read_fixture(voice).samples);
+        }
+        // one quantization step of the 16 bit fixtures
+        assert!(max_difference(&mix, &golden.samples) < 2.0 / 32767.0);
+    }
+
+    #[test]
+    fn fade_in_matches_golden() {
+        let wav = read_fixture("sine_440_48k.wav");
+        let golden = read_fixture("sine_440_48k_fade_in.wav");
+        let mut frame = wav.samples[..960 * CHANNELS].to_vec();
+        let mut fade = Fade::new(SAMPLE_RATE as usize / 1000 * FADE_MS);
+        assert!(fade.is_closed());
+        fade.apply(&mut frame, true);
+        // one quantization step of the 16 bit fixtures
+        assert!(max_difference(&frame, &golden.samples) < 2.0 / 32767.0);
+    }
+
+    #[test]
+    fn fade_out_closes_within_one_frame() {
+        let wav = read_fixture("sine_440_48k.wav");
+        let mut fade = Fade::new(SAMPLE_RATE as usize / 1000 * FADE_MS);
+        let mut frame = wav.samples[..960 * CHANNELS].to_vec();
+        fade.apply(&mut frame, true);
+        assert!(!fade.is_closed());
+
+        let mut frame = wav.samples[960 * CHANNELS..2 * 960 * CHANNELS].to_vec();
+        fade.apply(&mut frame, false);
+        assert!(fade.is_closed());
+        assert!(frame[frame.len() - 2..].iter().all(|s| *s == 0.0));
+        // the ramp starts from full gain, the first sample is barely attenuated
+        let first = wav.samples[960 * CHANNELS];
+        assert!((frame[0] - first).abs() <= first.abs() / 200.0);
+    }
+
+    #[test]
+    fn converts_frames_for_a_mono_narrowband_room() {
+        let wav = read_fixture("sine_440_48k.wav");
+        let codec = RoomCodec::parse("mono,24,16").unwrap();
+        for frame_size in [480, 960, 1920] {
+            let mut room = RoomConverter::new(codec, frame_size);
+            for frame in wav.samples.chunks_exact(frame_size * CHANNELS) {
+                // one frame of the same duration per frame, for the encoder
+                assert_eq!(room.convert(frame).len(), frame_size / 3);
+            }
+        }
+        // the tone keeps its level once the resampler's delay has passed
+        let mut room = RoomConverter::new(codec, 960);
+        let frames: Vec<f32> = wav
+            .samples
+            .chunks_exact(960 * CHANNELS)
+            .flat_map(|frame| room.convert(frame).to_vec())
+            .collect();
+        let (_, settled) = frames.split_at(frames.len() / 2);
+        assert!((level_db(settled) - level_db(&wav.samples)).abs() < 0.5);
+    }
+
+    #[test]
+    fn decodes_senders_in_parallel_as_one_after_another() {
+        let room = RoomCodec::parse("pcm,stereo").unwrap();
+        let settings = AudioSettings::default();
+        let wav = read_fixture("sine_440_48k.wav");
+        let mut encoder = PcmCodec::new(SAMPLE_RATE, CHANNELS);
+        let mut decode = |workers| {
+            let mut streams: Vec<RemoteStream> = (0..6)
+                .map(|i| {
+                    let addr = SocketAddr::from(([10, 0, 0, i], 1234));
+                    RemoteStream::new(addr, &room, &settings)
+                })
+                .collect();
+            let mut due: Vec<_> = streams
+                .iter_mut()
+                .enumerate()
+                .map(|(i, stream)| {
+                    // a different piece of the tone for everyone, one of them lost
+                    let frame = &wav.samples[i * 960 * CHANNELS..(i + 1) * 960 * CHANNELS];
+                    let mut packet = vec![0u8; frame.len() * 2];
+                    let n = encoder.encode(frame, &mut packet).unwrap();
+                    packet.truncate(n);
+                    let frame = match i {
+                        3 => Due::Missing(None),
+                        _ => Due::Packet(packet),
+                    };
+                    (stream, frame)
+                })
+                .collect();
+            let lengths: Vec<usize> = decode_streams(&mut due, decode_pool(workers).as_ref())
+                .into_iter()
+                .map(Result::unwrap)
+                .collect();
+            let samples: Vec<Vec<f32>> = due
+                .iter()
+                .zip(&lengths)
+                .map(|((stream, _), b)| stream.decoded[..b * CHANNELS].to_vec())
+                .collect();
+            (lengths, samples)
+        };
+        let (lengths, samples) = decode(1);
+        assert_eq!(lengths, [960; 6]);
+        assert!(max_difference(&samples[1], &wav.samples[960 * CHANNELS..1920 * CHANNELS]) < 1e-4);
+        assert_eq!(decode(MAX_DECODE_WORKERS), (lengths, samples));
+    }
 }
@@ -0,0 +1,80 @@
+// Samples are f32 in [-1.0, 1.0] everywhere inside the client, devices,
+// files and the PCM codec take 16 bit ones. The byte order is always spelled
+// out, little endian for anything that leaves the machine, native for
+// devices that are told to use it.
+
+/// A 16 bit sample, clipped to its range.
+pub fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+pub fn from_i16(sample: i16) -> f32 {
+    sample as f32 / 32768.0
+}
+
+/// The samples of 16 bit little endian `bytes`, a trailing odd byte is ignored.
+pub fn read_le(bytes: &[u8]) -> impl Iterator<Item = f32> + '_ {
+    read(bytes, i16::from_le_bytes)
+}
+
+/// Like `read_le` for native byte order.
+pub fn read_ne(bytes: &[u8]) -> impl Iterator<Item = f32> + '_ {
+    read(bytes, i16::from_ne_bytes)
+}
+
+/// Writes `pcm` as 16 bit little endian into `out`, as much as fits, and
+/// returns the number of bytes written.
+pub fn write_le(pcm: &[f32], out: &mut [u8]) -> usize {
+    write(pcm, out, i16::to_le_bytes)
+}
+
+/// Like `write_le` for native byte order.
+pub fn write_ne(pcm: &[f32], out: &mut [u8]) -> usize {
+    write(pcm, out, i16::to_ne_bytes)
+}
+
+fn read(bytes: &[u8], from_bytes: fn([u8; 2]) -> i16) -> impl Iterator<Item = f32> + '_ {
+    bytes
+        .chunks_exact(2)
+        .map(move |b| from_i16(from_bytes([b[0], b[1]])))
+}
+
+fn write(pcm: &[f32], out: &mut [u8], to_bytes: fn(i16) -> [u8; 2]) -> usize {
+    let mut written = 0;
+    for (sample, bytes) in pcm.iter().zip(out.chunks_exact_mut(2)) {
+        bytes.copy_from_slice(&to_bytes(to_i16(*sample)));
+        written += 2;
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_in_a_fixed_byte_order() {
+        let pcm = [0.0, 0.5, -0.5, 1.0, -1.0, 2.0];
+        let mut bytes = [0u8; 12];
+        assert_eq!(write_le(&pcm, &mut bytes), 12);
+        assert_eq!(&bytes[2..4], &16383i16.to_le_bytes());
+        // out of range clips rather than wraps
+        assert_eq!(&bytes[10..12], &i16::MAX.to_le_bytes());
+        let read: Vec<f32> = read_le(&bytes).collect();
+        for (read, sample) in read.iter().zip(&pcm) {
+            assert!((read - sample.clamp(-1.0, 1.0)).abs() < 2.0 / 32768.0);
+        }
+        // only what fits, and no half samples
+        assert_eq!(write_le(&pcm, &mut bytes[..5]), 4);
+        assert_eq!(read_le(&bytes[..5]).count(), 2);
+
+        let mut native = [0u8; 12];
+        write_ne(&pcm, &mut native);
+        let swapped: Vec<u8> = bytes.chunks_exact(2).flat_map(|b| [b[1], b[0]]).collect();
+        match cfg!(target_endian = "little") {
+            true => assert_eq!(native, bytes),
+            false => assert_eq!(native[..], swapped[..]),
+        }
+        assert_eq!(read_ne(&native).count(), 6);
+    }
+}
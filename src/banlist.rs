@@ -0,0 +1,135 @@
+use std::{
+    fs,
+    io::{ErrorKind, Write},
+    net::IpAddr,
+    path::PathBuf,
+};
+
+use crate::Error;
+
+/// Addresses turned away for good, kept in the file `--ban-list` names, one
+/// address per line. Bans and unbans go to the file straight away, `#`
+/// comments in it are kept.
+#[derive(Debug, Default)]
+pub struct BanList {
+    path: Option<PathBuf>,
+    addresses: Vec<IpAddr>,
+}
+
+impl BanList {
+    /// Reads the list in `path`, a file that doesn't exist yet is an empty list.
+    pub fn load(path: PathBuf) -> Result<Self, Error> {
+        let addresses = match fs::read_to_string(&path) {
+            Ok(text) => {
+                let mut addresses = Vec::new();
+                for (i, line) in text.lines().enumerate() {
+                    match address(line) {
+                        Some(Ok(ip)) => addresses.push(ip),
+                        Some(Err(e)) => {
+                            return Err(Error::Invalid(format!(
+                                "{}:{}: {}",
+                                path.display(),
+                                i + 1,
+                                e
+                            )));
+                        }
+                        None => {}
+                    }
+                }
+                addresses
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                return Err(Error::Io(format!("Can't read {}: {}", path.display(), e)));
+            }
+        };
+        Ok(BanList {
+            path: Some(path),
+            addresses,
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.addresses.contains(&ip)
+    }
+
+    pub fn addresses(&self) -> &[IpAddr] {
+        &self.addresses
+    }
+
+    /// Adds `ip`, appending it to the file.
+    pub fn ban(&mut self, ip: IpAddr) -> Result<(), Error> {
+        if self.contains(ip) {
+            return Ok(());
+        }
+        self.addresses.push(ip);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", ip))
+            .map_err(|e| Error::Io(format!("Can't write {}: {}", path.display(), e)))
+    }
+
+    /// Removes `ip`, and its lines from the file.
+    pub fn unban(&mut self, ip: IpAddr) -> Result<(), Error> {
+        if !self.contains(ip) {
+            return Ok(());
+        }
+        self.addresses.retain(|banned| *banned != ip);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let error = |e: std::io::Error| Error::Io(format!("Can't write {}: {}", path.display(), e));
+        let text = fs::read_to_string(path).map_err(error)?;
+        let kept: String = text
+            .lines()
+            .filter(|line| address(line) != Some(Ok(ip)))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        fs::write(path, kept).map_err(error)
+    }
+}
+
+/// The address on a line of the file, `None` for a comment or an empty line.
+fn address(line: &str) -> Option<Result<IpAddr, String>> {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    if line.is_empty() {
+        return None;
+    }
+    Some(
+        line.parse()
+            .map_err(|_| format!("not an IP address: {}", line)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_comments_when_the_list_changes() {
+        let path = std::env::temp_dir().join(format!("kop-audio-bans-{}.txt", std::process::id()));
+        fs::write(
+            &path,
+            "# raid on friday\n198.51.100.7\n\n2001:db8::1 # spam bot\n",
+        )
+        .unwrap();
+        let mut bans = BanList::load(path.clone()).unwrap();
+        assert!(bans.contains("2001:db8::1".parse().unwrap()));
+        bans.ban("203.0.113.9".parse().unwrap()).unwrap();
+        bans.unban("198.51.100.7".parse().unwrap()).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            text,
+            "# raid on friday\n\n2001:db8::1 # spam bot\n203.0.113.9\n"
+        );
+        assert_eq!(BanList::load(path.clone()).unwrap().addresses().len(), 2);
+        fs::write(&path, "198.51.100.300\n").unwrap();
+        assert!(BanList::load(path.clone()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+    time::SystemTime,
+};
+
+use log::{info, warn};
+use tokio::{
+    sync::mpsc::{Sender, UnboundedReceiver, unbounded_channel},
+    time::sleep_until,
+};
+
+use crate::{
+    CHANNELS, Error,
+    audio::{OutgoingFrame, send_audio, send_queue, voice_encoder},
+    bus::EventBus,
+    client::ClientMessage,
+    connection::Connection,
+    identity::IdentityKey,
+    music::{FileTrack, TrackSource},
+    server::{ChatMessage, CodecStream, Hello, Message},
+    session::Session,
+    settings::AudioSettings,
+};
+
+/// What `bot tts` runs unless told otherwise, it reads the text from stdin.
+pub const DEFAULT_TTS_COMMAND: &str = "espeak-ng --stdin -w {}";
+
+/// Joins `server` and reads every chat message aloud into the room with
+/// `command`, for those who can only listen, until `ClientMessage::Exit`.
+/// False if the server turned the bot away or can't be reached for good.
+pub async fn run_tts(
+    server: String,
+    settings: AudioSettings,
+    command: String,
+    bus: EventBus,
+) -> bool {
+    // nothing to hear, the server doesn't forward any voices to it either
+    let settings = AudioSettings {
+        audio_sink: false,
+        // it would read its own speech back as chat
+        speech_to_chat: None,
+        ..settings
+    };
+    let mut commands = bus.commands.subscribe();
+    let key = IdentityKey::load_or_create_named("bot-identity");
+    let hello = Hello {
+        identity: key.identity(),
+        audio_sink: false,
+        token: None,
+        proof: None,
+        name: settings.name.clone(),
+        profile: settings.profile.codec(),
+    };
+    let (frames_tx, frames_rx) = send_queue();
+    let encoder = voice_encoder(&settings, &settings.profile.codec());
+    tokio::spawn(send_audio(
+        bus.clone(),
+        bus.record.subscribe(),
+        frames_rx,
+        encoder,
+        settings.clone(),
+    ));
+    let (lines_tx, lines_rx) = unbounded_channel();
+    tokio::spawn(speak(lines_rx, frames_tx, command, settings.clone()));
+
+    info!("Reading the chat of {} aloud", server);
+    let password = settings.password.clone();
+    let session = Session::new(server, settings, bus.clone());
+    let mut connection = Connection::new(session, bus.clone());
+    if connection.start().await {
+        greet(&bus, &hello);
+    } else if connection.restart_at().is_none() {
+        return false;
+    }
+    loop {
+        let cmd = tokio::select! {
+            cmd = commands.recv() => match cmd {
+                Some(cmd) => cmd,
+                None => return true,
+            },
+            _ = sleep_until(connection.restart_at().unwrap_or_else(tokio::time::Instant::now)),
+                if connection.restart_at().is_some() =>
+            {
+                if connection.start().await {
+                    greet(&bus, &hello);
+                } else if connection.restart_at().is_none() {
+                    return false;
+                }
+                continue;
+            }
+        };
+        match cmd {
+            ClientMessage::Connect => connection.connected(),
+            ClientMessage::JoinChallenge(challenge) => {
+                match hello.answer(&key, password.as_deref(), &challenge) {
+                    Ok(answer) => bus.net_out.publish(Message::Hello(answer)),
+                    Err(e) => bus.commands.publish(ClientMessage::SessionFailed(e)),
+                }
+            }
+            ClientMessage::SessionFailed(e) => {
+                warn!("{}", connection.fail(e));
+                if connection.restart_at().is_none() {
+                    return false;
+                }
+            }
+            ClientMessage::Chat(chat) => {
+                let _ = lines_tx.send(spoken(&chat));
+            }
+            ClientMessage::Audio(audio) => bus.net_out.publish(Message::Audio(audio)),
+            ClientMessage::RoomCodec(codec) => bus.record.publish(ClientMessage::RoomCodec(codec)),
+            ClientMessage::ResetEncoder(stream) => {
+                bus.record.publish(ClientMessage::ResetEncoder(stream))
+            }
+            ClientMessage::EncoderReset(stream) => bus.net_out.publish(Message::ResetCodec(stream)),
+            ClientMessage::Exit => {
+                connection.shut_down().await;
+                return true;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn greet(bus: &EventBus, hello: &Hello) {
+    for _ in 0..3 {
+        bus.net_out.publish(Message::Hello(hello.clone()));
+    }
+}
+
+/// What is said for a chat message, who wrote it first since the listener
+/// can't see it.
+fn spoken(chat: &ChatMessage) -> String {
+    format!("{} says {}", chat.name, chat.text)
+}
+
+/// Speaks one line after the other, handing the audio to the encoder at the
+/// pace a microphone would.
+async fn speak(
+    mut lines: UnboundedReceiver<String>,
+    frames: Sender<OutgoingFrame>,
+    command: String,
+    settings: AudioSettings,
+) {
+    let frame_len = settings.frame_size * CHANNELS;
+    while let Some(line) = lines.recv().await {
+        let command = command.clone();
+        let speech = tokio::task::spawn_blocking(move || synthesize(&command, &line)).await;
+        let samples = match speech {
+            Ok(Ok(samples)) => samples,
+            Ok(Err(e)) => {
+                warn!("{}", e);
+                continue;
+            }
+            Err(_) => continue,
+        };
+        let mut interval = tokio::time::interval(settings.frame_duration());
+        let mut frame = vec![0f32; frame_len];
+        for chunk in samples.chunks(frame_len) {
+            interval.tick().await;
+            frame[..chunk.len()].copy_from_slice(chunk);
+            frame[chunk.len()..].fill(0.0);
+            let captured_ms = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64;
+            let frame = OutgoingFrame::new(CodecStream::Voice, &frame, captured_ms);
+            if frames.send(frame).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Runs `command` with `text` on its stdin and `{}` replaced by a WAV file to
+/// write, and reads that back at the call's rate.
+fn synthesize(command: &str, text: &str) -> Result<Vec<f32>, Error> {
+    // only we can read it, and it's gone whichever way this returns
+    let file = tempfile::Builder::new()
+        .prefix("kop-audio-tts-")
+        .suffix(".wav")
+        .tempfile()
+        .map_err(|e| Error::Io(format!("Can't create a temporary file: {}", e)))?;
+    let path = file.path().to_string_lossy().into_owned();
+    let (program, args) = command_line(command, &path)?;
+    let error = |e: &dyn std::fmt::Display| Error::Io(format!("Can't run {}: {}", program, e));
+    let mut child = Command::new(&program)
+        .args(&args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|e| error(&e))?;
+    // closed once written, so it knows the text is complete
+    let written = child
+        .stdin
+        .take()
+        .map(|mut stdin| stdin.write_all(text.as_bytes()));
+    let status = child.wait().map_err(|e| error(&e))?;
+    if let Some(Err(e)) = written {
+        return Err(error(&e));
+    }
+    if !status.success() {
+        return Err(error(&status));
+    }
+    let mut track = FileTrack::open(&path)?;
+    let mut samples = Vec::new();
+    while track.read(&mut samples) {}
+    Ok(samples)
+}
+
+/// The program and arguments of `command`, split at whitespace, with `{}`
+/// standing for `path`.
+pub fn command_line(command: &str, path: &str) -> Result<(String, Vec<String>), Error> {
+    let mut words = command.split_whitespace().map(|word| match word {
+        "{}" => path.to_string(),
+        word => word.to_string(),
+    });
+    let program = words
+        .next()
+        .ok_or_else(|| Error::Invalid("The TTS command is empty".to_string()))?;
+    Ok((program, words.collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speaks_who_wrote_what_through_the_command() {
+        let chat = ChatMessage {
+            from: "127.0.0.1:1234".parse().unwrap(),
+            name: "alice".to_string(),
+            text: "running late".to_string(),
+        };
+        assert_eq!(spoken(&chat), "alice says running late");
+        let (program, args) = command_line(DEFAULT_TTS_COMMAND, "/tmp/line.wav").unwrap();
+        assert_eq!(program, "espeak-ng");
+        assert_eq!(args, ["--stdin", "-w", "/tmp/line.wav"]);
+        assert!(command_line("  ", "/tmp/line.wav").is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::time::Duration;
+
+use log::warn;
+use tokio::runtime::Handle;
+use tokio::sync::{
+    broadcast::{self, error::RecvError, error::TryRecvError},
+    watch,
+};
+
+use crate::{client::ClientMessage, server::Message};
+
+const TOPIC_CAPACITY: usize = 256;
+
+/// A single kind of event on the bus. Every subscriber gets its own copy of
+/// each message published after it subscribed.
+#[derive(Clone, Debug)]
+pub struct Topic<T> {
+    tx: broadcast::Sender<T>,
+}
+
+impl<T: Clone> Topic<T> {
+    fn new() -> Self {
+        let (tx, _) = broadcast::channel(TOPIC_CAPACITY);
+        Topic { tx }
+    }
+
+    /// Publishes a message, messages without subscribers are dropped.
+    pub fn publish(&self, msg: T) {
+        let _ = self.tx.send(msg);
+    }
+
+    pub fn subscribe(&self) -> Subscriber<T> {
+        Subscriber {
+            rx: self.tx.subscribe(),
+        }
+    }
+}
+
+pub enum Recv<T> {
+    Message(T),
+    Timeout,
+    Closed,
+}
+
+#[derive(Debug)]
+pub struct Subscriber<T> {
+    rx: broadcast::Receiver<T>,
+}
+
+impl<T: Clone> Subscriber<T> {
+    /// Waits for the next message, `None` once every publisher is gone.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            match self.rx.recv().await {
+                Ok(msg) => return Some(msg),
+                Err(RecvError::Lagged(n)) => warn!("Subscriber lagged behind, skipped {} messages", n),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Like `recv` but for threads outside of the tokio runtime.
+    pub fn blocking_recv(&mut self) -> Option<T> {
+        loop {
+            match self.rx.blocking_recv() {
+                Ok(msg) => return Some(msg),
+                Err(RecvError::Lagged(n)) => warn!("Subscriber lagged behind, skipped {} messages", n),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Like `blocking_recv` but gives up after `timeout`. Has to be called from a
+    /// thread that knows the runtime, e.g. one started with `spawn_blocking`.
+    pub fn blocking_recv_timeout(&mut self, timeout: Duration) -> Recv<T> {
+        Handle::current().block_on(async {
+            match tokio::time::timeout(timeout, self.recv()).await {
+                Ok(Some(msg)) => Recv::Message(msg),
+                Ok(None) => Recv::Closed,
+                Err(_) => Recv::Timeout,
+            }
+        })
+    }
+
+    /// Returns the next message if one is queued, without waiting.
+    pub fn try_recv(&mut self) -> Option<T> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(msg) => return Some(msg),
+                Err(TryRecvError::Lagged(n)) => warn!("Subscriber lagged behind, skipped {} messages", n),
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+/// Set once when the client quits. Every task of a session watches it and
+/// winds down on its own, the sending one saying bye to the server first.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    tx: watch::Sender<bool>,
+}
+
+impl Shutdown {
+    fn new() -> Self {
+        let (tx, _) = watch::channel(false);
+        Shutdown { tx }
+    }
+
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Resolves once `trigger` was called, right away if it already was.
+    pub async fn wait(&self) {
+        let mut rx = self.tx.subscribe();
+        let _ = rx.wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// All topics of a client. Subsystems take a clone of the bus and subscribe to
+/// what they need instead of getting a dedicated channel threaded through.
+#[derive(Clone, Debug)]
+pub struct EventBus {
+    /// requests and reports handled by the coordinator
+    pub commands: Topic<ClientMessage>,
+    /// state changes for frontends (TUI, control socket)
+    pub events: Topic<ClientMessage>,
+    /// commands for the capture path
+    pub record: Topic<ClientMessage>,
+    /// commands and audio for the playback path
+    pub playback: Topic<ClientMessage>,
+    /// messages to send to the server
+    pub net_out: Topic<Message>,
+    pub shutdown: Shutdown,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus {
+            commands: Topic::new(),
+            events: Topic::new(),
+            record: Topic::new(),
+            playback: Topic::new(),
+            net_out: Topic::new(),
+            shutdown: Shutdown::new(),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::f32::consts::PI;
+
+use opus::{Application, Channels, Encoder};
+
+use crate::{CHANNELS, Error, FRAME_SIZE, SAMPLE_RATE, audio::samples, server::Cue};
+
+/// Largest chime upload accepted, a bit over a second of 16 bit stereo.
+pub const MAX_CHIME_BYTES: usize = 256 * 1024;
+
+/// Reads an uploaded chime, which has to be a 16 bit PCM WAV at the rate and
+/// channel count the call runs at.
+pub fn parse_wav(data: &[u8]) -> Result<Vec<f32>, Error> {
+    let invalid =
+        |reason: &str| Error::Invalid(format!("Invalid chime: {}", reason));
+    if data.len() > MAX_CHIME_BYTES {
+        return Err(invalid("too large"));
+    }
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(invalid("not a WAV file"));
+    }
+    let mut pos = 12;
+    let mut format_ok = false;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let body = data
+            .get(pos + 8..pos + 8 + len)
+            .ok_or_else(|| invalid("truncated"))?;
+        match id {
+            b"fmt " if body.len() >= 16 => {
+                let pcm = u16::from_le_bytes([body[0], body[1]]) == 1;
+                let channels = u16::from_le_bytes([body[2], body[3]]) as usize;
+                let rate = u32::from_le_bytes(body[4..8].try_into().unwrap());
+                let bits = u16::from_le_bytes([body[14], body[15]]);
+                if !pcm || channels != CHANNELS || rate != SAMPLE_RATE || bits != 16 {
+                    return Err(invalid("has to be 16 bit stereo PCM at 48kHz"));
+                }
+                format_ok = true;
+            }
+            b"data" if format_ok => {
+                return Ok(samples::read_le(body).collect());
+            }
+            _ => {}
+        }
+        pos += 8 + len + len % 2;
+    }
+    Err(invalid("no audio data"))
+}
+
+/// Encodes a chime into opus packets of 20ms, so it travels like any other audio.
+pub fn encode_chime(samples: &[f32]) -> Vec<Vec<u8>> {
+    let mut encoder = Encoder::new(SAMPLE_RATE, Channels::Stereo, Application::Audio).unwrap();
+    let mut frame = vec![0f32; FRAME_SIZE * CHANNELS];
+    let mut encoded = vec![0u8; 4000];
+    samples
+        .chunks(FRAME_SIZE * CHANNELS)
+        .filter_map(|chunk| {
+            frame[..chunk.len()].copy_from_slice(chunk);
+            frame[chunk.len()..].fill(0.0);
+            let n = encoder.encode_float(&frame, &mut encoded).ok()?;
+            Some(encoded[..n].to_vec())
+        })
+        .collect()
+}
+
+/// The built-in chime: two short tones, rising on join and falling on leave.
+pub fn default_chime(cue: Cue) -> Vec<f32> {
+    let (first, second) = match cue {
+        Cue::Join => (660.0, 880.0),
+        Cue::Leave => (880.0, 660.0),
+    };
+    let tone_len = SAMPLE_RATE as usize / 12;
+    let mut samples = Vec::with_capacity(tone_len * 2 * CHANNELS);
+    for freq in [first, second] {
+        for i in 0..tone_len {
+            // a sine envelope per tone, so neither tone clicks
+            let envelope = (PI * i as f32 / tone_len as f32).sin();
+            let sample = 0.2 * envelope * (2.0 * PI * freq * i as f32 / SAMPLE_RATE as f32).sin();
+            samples.extend(std::iter::repeat_n(sample, CHANNELS));
+        }
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav(samples: &[i16], rate: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"RIFF");
+        data.extend_from_slice(&(36 + samples.len() as u32 * 2).to_le_bytes());
+        data.extend_from_slice(b"WAVEfmt ");
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&(CHANNELS as u16).to_le_bytes());
+        data.extend_from_slice(&rate.to_le_bytes());
+        data.extend_from_slice(&(rate * CHANNELS as u32 * 2).to_le_bytes());
+        data.extend_from_slice(&(CHANNELS as u16 * 2).to_le_bytes());
+        data.extend_from_slice(&16u16.to_le_bytes());
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&(samples.len() as u32 * 2).to_le_bytes());
+        for sample in samples {
+            data.extend_from_slice(&sample.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn parses_wav_chime() {
+        let samples = parse_wav(&wav(&[0, 16384, -16384, 0], SAMPLE_RATE)).unwrap();
+        assert_eq!(samples, vec![0.0, 0.5, -0.5, 0.0]);
+    }
+
+    #[test]
+    fn rejects_unsuitable_chimes() {
+        assert!(parse_wav(b"not a wav").is_err());
+        assert!(parse_wav(&wav(&[0; 4], 44100)).is_err());
+        assert!(parse_wav(&wav(&vec![0; MAX_CHIME_BYTES / 2], SAMPLE_RATE)).is_err());
+    }
+
+    #[test]
+    fn default_chimes_stay_quiet() {
+        let join = default_chime(Cue::Join);
+        assert!(join.iter().all(|s| s.abs() <= 0.2));
+        assert_eq!(join.len(), default_chime(Cue::Leave).len());
+    }
+}
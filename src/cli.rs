@@ -0,0 +1,758 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use clap::{
+    Args, Parser, Subcommand,
+    builder::{RangedU64ValueParser, TypedValueParser},
+};
+use opus::Bitrate;
+
+use crate::{
+    bot::DEFAULT_TTS_COMMAND,
+    implementations::{self, AudioBackend, BACKENDS},
+    server::RoomCodec,
+    settings::{
+        AudioSettings, BitrateMode, FRAME_MS, IpFamily, ProducerMix, Profile, ServerSettings,
+        parse_bitrate,
+    },
+    telemetry::Endpoint,
+};
+
+/// Joined unless `--ip` or the config file names another server.
+pub const DEFAULT_SERVER: &str = "kopatz.dev:1234";
+
+const CONFIG_HELP: &str = "Settings are read from ~/.config/kop-audio/config.toml first, options \
+    given here override them. It takes servers (a list, the first is joined unless --ip names \
+    another), name, backend, bitrate, mics (a list), output_device, vad_threshold, vad_hangover, \
+    push_to_talk, stereo_width and output_ceiling, and a [keys] table binding TUI actions to \
+    other keys, e.g. mute = \"t\".";
+
+/// Voice chat for small groups, with music everyone listens to in sync.
+#[derive(Parser)]
+#[command(
+    name = "kop-audio",
+    version,
+    args_conflicts_with_subcommands = true,
+    after_help = CONFIG_HELP
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    // without a subcommand the client runs with these
+    #[command(flatten)]
+    pub client: ClientArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Joins a server, what runs without a subcommand
+    Client(ClientArgs),
+    /// Runs a server
+    Server(ServerArgs),
+    /// Opens the terminal user interface for a running daemon
+    Attach {
+        /// Attaches to a daemon on another machine, offering only mute and deafen.
+        /// Needs a copy of its remote-token file, the connection is unencrypted
+        #[arg(long, value_name = "HOST")]
+        remote: Option<String>,
+        /// Writes debug logs to /tmp/log.txt
+        #[arg(long)]
+        debug: bool,
+    },
+    /// Tells a running daemon to leave the call and exit
+    Stop,
+    /// Tells a running daemon to join again after a disconnect
+    Connect,
+    /// Tells a running daemon to leave the call but keep running
+    Disconnect,
+    /// Tells a running daemon to start the connection afresh
+    ///
+    /// E.g. after switching networks. R in the TUI does the same.
+    Reconnect,
+    /// Adds a file or URL to the room's playlist through a running daemon
+    Enqueue {
+        #[arg(value_name = "FILE|URL")]
+        source: String,
+    },
+    /// Checks what a call to the server needs
+    ///
+    /// Name resolution, UDP round trips, the NAT seen by STUN, the audio
+    /// devices and whether the microphone hears the speakers (with the round
+    /// trip in between, played as a short chirp). Prints a report to attach to
+    /// bug reports.
+    Doctor {
+        /// The address and port of the server to check
+        #[arg(long, value_name = "ADDRESS:PORT")]
+        ip: Option<String>,
+        #[command(flatten)]
+        settings: SettingsArgs,
+    },
+    /// Plays audio through opus at several settings, to pick them by ear
+    ///
+    /// A built-in sample of voice and music, or the start of the file, at
+    /// several bitrates and frame sizes. The options, e.g. --bitrate-mode or
+    /// --music-mode, apply.
+    TestCodec {
+        file: Option<String>,
+        #[command(flatten)]
+        settings: SettingsArgs,
+    },
+    /// Writes the settings in effect with the options to a config file
+    ///
+    /// By default the config file read at the start, replacing it.
+    WriteConfig {
+        file: Option<PathBuf>,
+        /// The server to join first
+        #[arg(long, value_name = "ADDRESS:PORT")]
+        ip: Option<String>,
+        #[command(flatten)]
+        settings: SettingsArgs,
+    },
+    /// Writes the files built into the binary to a directory
+    ///
+    /// By default ~/.config/kop-audio: the built-in join and leave chimes as
+    /// WAVs to edit and upload in the admin UI, and a sample schedule. Files
+    /// already there are kept.
+    InitConfig { dir: Option<PathBuf> },
+    /// Runs a bot in the room
+    Bot {
+        #[command(subcommand)]
+        bot: Bot,
+    },
+    /// Starts the daemon at login and again whenever it fails
+    ///
+    /// Writes a systemd user unit, or a launchd agent on macOS, that runs
+    /// client --daemon with the config file's settings, see write-config. Stop,
+    /// connect and disconnect control it like any daemon. Before --remove run
+    /// systemctl --user disable --now kop-audio, or launchctl unload -w with
+    /// the agent's plist. On Windows it installs a service that starts with
+    /// the computer, from an administrator prompt, and sc stop kop-audio stops
+    /// it before --remove.
+    InstallService {
+        /// The server to join
+        #[arg(long, value_name = "ADDRESS:PORT")]
+        ip: Option<String>,
+        /// Removes the service again, stop and disable it first (see --help)
+        #[arg(long, conflicts_with = "ip")]
+        remove: bool,
+    },
+    /// Cuts a recording made with --record into utterances
+    ///
+    /// The recording is given as <dir>/kop-audio-<time>. Each utterance of
+    /// each speaker goes to a file of its own with the silence trimmed, listed
+    /// in order with the markers in manifest.json, e.g. for transcription.
+    Export { recording: PathBuf },
+    /// Lists the capture devices with their form factor
+    ListMics {
+        #[arg(long, value_name = "pulse|alsa|jack|cpal", value_parser = parse_backend)]
+        backend: Option<&'static dyn AudioBackend>,
+    },
+    /// Lists the playback devices
+    ListOutputs {
+        #[arg(long, value_name = "pulse|alsa|jack|cpal", value_parser = parse_backend)]
+        backend: Option<&'static dyn AudioBackend>,
+    },
+    /// Lists the playback streams of running applications
+    ListAppStreams,
+    /// Plays seashore.mp3
+    #[command(hide = true)]
+    TestAudio,
+    /// Runs as the service install-service set up, started by Windows
+    #[cfg(windows)]
+    #[command(hide = true)]
+    WindowsService {
+        #[arg(long)]
+        ip: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum Bot {
+    /// Reads the chat aloud, for those who can only listen, e.g. while driving
+    ///
+    /// Each message is spoken with its sender's name by the TTS command, which
+    /// gets the text on stdin and writes a WAV file where it says {}, e.g.
+    /// "piper --model en_US-amy-medium.onnx --output_file {}". The bot joins
+    /// under an identity of its own, as "Chat reader" unless --name is given.
+    Tts {
+        /// The server whose room to read out
+        #[arg(long, visible_alias = "room", value_name = "ADDRESS:PORT")]
+        ip: Option<String>,
+        /// Speaks the text on its stdin into the WAV file named by {}
+        #[arg(long, value_name = "COMMAND", default_value = DEFAULT_TTS_COMMAND)]
+        tts_command: String,
+        #[command(flatten)]
+        settings: SettingsArgs,
+    },
+}
+
+#[derive(Args)]
+pub struct ClientArgs {
+    /// The address and port of the server to join
+    #[arg(long, value_name = "ADDRESS:PORT")]
+    pub ip: Option<String>,
+    /// Disables the terminal user interface
+    #[arg(long)]
+    pub no_tui: bool,
+    /// Runs the client in the background, controlled over a local socket
+    #[arg(long)]
+    pub daemon: bool,
+    /// Lets remote frontends knowing the remote token attach to the daemon,
+    /// unencrypted, so on a trusted LAN only
+    #[arg(long, value_name = "ADDRESS:PORT", requires = "daemon")]
+    pub remote_listen: Option<String>,
+    /// Rejoins the last session with its server and mute/deafen state
+    #[arg(long, conflicts_with = "ip")]
+    pub resume: bool,
+    /// Writes debug logs to /tmp/log.txt while the TUI is open
+    #[arg(long)]
+    pub debug: bool,
+    /// Posts the call quality to the endpoint every 5 minutes: loss,
+    /// underruns, round trip times in buckets, platform and version, never
+    /// audio, names or addresses (off by default)
+    #[arg(
+        long,
+        value_name = "http://host[:port][/path]",
+        value_parser = |url: &str| Endpoint::parse(url).map_err(|e| e.to_string())
+    )]
+    pub telemetry: Option<Endpoint>,
+    #[command(flatten)]
+    pub settings: SettingsArgs,
+}
+
+/// What the client is told on the command line about how to sound, on top
+/// of the config file.
+#[derive(Args)]
+pub struct SettingsArgs {
+    /// Sets the name the others see in the user list (default: the login name)
+    #[arg(long)]
+    pub name: Option<String>,
+    /// Sets the password to join with
+    #[arg(long)]
+    pub password: Option<String>,
+    /// Talks to the server unencrypted, e.g. to servers without encryption support
+    #[arg(long)]
+    pub no_encryption: bool,
+    /// Connects to the server's IPv4 address only
+    #[arg(short = '4', long, conflicts_with = "ipv6")]
+    pub ipv4: bool,
+    /// Connects to the server's IPv6 address only
+    #[arg(short = '6', long)]
+    pub ipv6: bool,
+    /// Sends a tiny packet after this long without traffic, so routers keep
+    /// the connection open while everyone is silent (default 15)
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub keepalive: Option<u64>,
+    /// Reconnects when the server didn't answer for this long (default 10)
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(3..))]
+    pub server_timeout: Option<u64>,
+    /// Uses 10ms frames and minimal buffering at the cost of robustness
+    #[arg(long, conflicts_with_all = ["frame_ms", "profile"])]
+    pub low_latency: bool,
+    /// Sends high bitrate stereo continuously, e.g. for listening parties
+    #[arg(long, conflicts_with = "profile")]
+    pub music_mode: bool,
+    /// Low sends the voice as mono at 16kHz in 40ms frames at up to 16kbps for
+    /// constrained links, high at 128kbps, normal (default) as stereo at 48kHz
+    /// in 20ms frames. The server narrows the room codec down to it for this
+    /// connection, a PCM room keeps its own format
+    #[arg(
+        long,
+        value_name = "low|normal|high",
+        value_parser = parsed(Profile::parse, "low, normal or high")
+    )]
+    pub profile: Option<Profile>,
+    /// Sets the length of the opus frames sent (default 20): shorter frames
+    /// cut the delay, longer ones the packet rate and header overhead on slow
+    /// links. The jitter and playback buffers keep about the same time, at
+    /// least one frame
+    #[arg(long, value_name = "10|20|40|60", value_parser = parse_frame_ms)]
+    pub frame_ms: Option<usize>,
+    /// Only sends the microphone while space is held in the TUI, instead of
+    /// whenever it hears a voice
+    #[arg(long)]
+    pub push_to_talk: bool,
+    /// Sets the microphone level in dBFS below which nothing is sent (default
+    /// -44), it can be changed in the TUI with ( and ) while watching the input meter
+    #[arg(
+        long,
+        value_name = "dBFS",
+        allow_negative_numbers = true,
+        value_parser = level(-80.0, -10.0)
+    )]
+    pub vad_threshold: Option<f32>,
+    /// Sets how many frames are still sent after the level dropped below the
+    /// threshold (default 10), it can be changed in the TUI with { and }
+    #[arg(long, value_name = "FRAMES", value_parser = clap::value_parser!(u32).range(..=100))]
+    pub vad_hangover: Option<u32>,
+    /// Stops removing what the speakers play from the microphone, e.g. when
+    /// wearing headphones or when the system already cancels echo
+    #[arg(long)]
+    pub no_echo_cancellation: bool,
+    /// Stops deafening from also muting the microphone
+    #[arg(long)]
+    pub deafen_keeps_mic: bool,
+    /// Closes the microphone and speakers while muted and deafened, they take
+    /// a moment to open again
+    #[arg(long)]
+    pub release_devices: bool,
+    /// Sets how long a silent sender keeps its decoder state (default 1000)
+    #[arg(long, value_name = "MS", value_parser = parse_ms)]
+    pub stream_timeout: Option<Duration>,
+    /// Sets how long a sender is shown as speaking after its last packet (default 500)
+    #[arg(long, value_name = "MS", value_parser = parse_ms)]
+    pub speaking_timeout: Option<Duration>,
+    /// Joins without audio, as a linked device of the same identity
+    #[arg(long, conflicts_with_all = ["mic", "output_device", "share_app", "record"])]
+    pub control_only: bool,
+    /// Records and plays through PulseAudio (pulse, default) or directly on
+    /// ALSA devices (alsa), e.g. on a Raspberry Pi without a sound server, or
+    /// as JACK ports (jack) to patch into a session, e.g. with Carla, or through
+    /// cpal (cpal) on the system's own audio API. With jack --mic and
+    /// --output-device name the JACK client connected to at the start.
+    /// Backends other than pulse are there if built with their cargo feature
+    #[arg(long, value_name = "pulse|alsa|jack|cpal", value_parser = parse_backend)]
+    pub backend: Option<&'static dyn AudioBackend>,
+    /// Records from capture devices by index or name, comma separated in
+    /// order of preference, also when the one in use goes away; default is
+    /// PulseAudio's default. Without it a headset is preferred over e.g. a
+    /// webcam
+    #[arg(
+        long,
+        visible_alias = "input-device",
+        value_name = "NAME|INDEX|default",
+        value_delimiter = ','
+    )]
+    pub mic: Vec<String>,
+    /// Plays on a playback device by index or name instead of PulseAudio's
+    /// default. Both devices can also be changed in the TUI with O
+    #[arg(long, value_name = "NAME|INDEX|default")]
+    pub output_device: Option<String>,
+    /// Mixes one application's audio into the microphone, by stream index or name
+    #[arg(long, value_name = "NAME|INDEX")]
+    pub share_app: Option<String>,
+    /// Downmixes the shared application audio to mono
+    #[arg(long, requires = "share_app")]
+    pub share_mono: bool,
+    /// Sets the shared audio level: background (-12dB), quiet (-6dB), full or a gain in dB
+    #[arg(
+        long,
+        value_name = "PRESET|dB",
+        requires = "share_app",
+        allow_negative_numbers = true,
+        value_parser = parsed(ProducerMix::gain_preset, "background, quiet, full or a gain in dB")
+    )]
+    pub share_gain: Option<f32>,
+    /// Broadcasts the application shared with --share-app as music everyone
+    /// hears in sync, instead of mixing it into the microphone
+    #[arg(long, requires = "share_app")]
+    pub listen_along: bool,
+    /// Plays the room playlist's local files as listen-along music, the
+    /// tracks have to exist on this machine
+    #[arg(long)]
+    pub play_queue: bool,
+    /// Sets how long consecutive playlist tracks overlap, 0 plays them back
+    /// to back without a gap (default 2000)
+    #[arg(long, value_name = "MS", value_parser = parse_ms)]
+    pub crossfade: Option<Duration>,
+    /// Sets the loudness listen-along music is normalized to, or off (default -23, EBU R128)
+    #[arg(
+        long,
+        value_name = "LUFS|off",
+        allow_negative_numbers = true,
+        value_parser = parse_loudness
+    )]
+    pub music_loudness: Option<Loudness>,
+    /// Sets the loudness each voice is normalized to before mixing, or off to
+    /// play them as sent (default -23)
+    #[arg(
+        long,
+        value_name = "LUFS|off",
+        allow_negative_numbers = true,
+        value_parser = parse_loudness
+    )]
+    pub voice_loudness: Option<Loudness>,
+    /// Narrows what is played towards mono, from 100 (default, as sent) to 0
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u32).range(..=100))]
+    pub stereo_width: Option<u32>,
+    /// Plays everything on both ears, e.g. for single-ear headsets
+    #[arg(long, conflicts_with = "stereo_width")]
+    pub mono_output: bool,
+    /// Limits playback peaks to this level in dBFS (default 0, full scale),
+    /// e.g. -12 so a broken or malicious stream can't play at full volume.
+    /// Quieter audio is left as it is
+    #[arg(
+        long,
+        value_name = "dBFS",
+        allow_negative_numbers = true,
+        value_parser = level(-30.0, 0.0)
+    )]
+    pub output_ceiling: Option<f32>,
+    /// Sets the bitrate of the microphone in kbps, auto or max (default auto,
+    /// 128 with --music-mode), it can be changed in the TUI with [ and ]
+    #[arg(
+        long,
+        value_name = "KBPS|auto|max",
+        value_parser = parsed(parse_bitrate, "auto, max or a bitrate in kbps between 6 and 510")
+    )]
+    pub bitrate: Option<Bitrate>,
+    /// Sets whether packets vary in size with the signal (vbr), within limits
+    /// (cvbr) or not at all (cbr, default vbr)
+    #[arg(
+        long,
+        value_name = "vbr|cvbr|cbr",
+        value_parser = parsed(BitrateMode::parse, "vbr, cvbr or cbr")
+    )]
+    pub bitrate_mode: Option<BitrateMode>,
+    /// Keeps the bitrate and FEC as configured, instead of trading bitrate for
+    /// FEC while the server reports packet loss
+    #[arg(long)]
+    pub fixed_bitrate: bool,
+    /// Records the call to the directory, one lossless WAV file per speaker
+    /// taken before encoding or after decoding, lined up in time
+    #[arg(long, value_name = "DIR")]
+    pub record: Option<PathBuf>,
+    /// Posts what you say to the chat for those who can't listen, COMMAND
+    /// prints the text spoken in the WAV file named by {}, e.g.
+    /// "whisper-cli -nt -np -f {}"
+    #[arg(long, value_name = "COMMAND")]
+    pub speech_to_chat: Option<String>,
+    /// Sets the packet loss in percent forward error correction is tuned for,
+    /// 0 disables it (default 10)
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(..=100))]
+    pub expected_loss: Option<u8>,
+}
+
+impl SettingsArgs {
+    /// Overrides `settings` with what was given. The presets go first, so an
+    /// option given along with one wins whatever the order.
+    pub fn apply(&self, settings: &mut AudioSettings) {
+        if self.low_latency {
+            *settings = settings.clone().low_latency();
+        }
+        if self.music_mode {
+            *settings = settings.clone().music();
+        }
+        if let Some(profile) = self.profile {
+            *settings = settings.clone().with_profile(profile);
+        }
+        if let Some(ms) = self.frame_ms {
+            *settings = settings.clone().with_frame_ms(ms);
+        }
+        if let Some(name) = &self.name {
+            settings.name = name.clone();
+        }
+        if let Some(password) = &self.password {
+            settings.password = Some(password.clone());
+        }
+        if self.no_encryption {
+            settings.encrypt = false;
+        }
+        if self.ipv4 {
+            settings.ip_family = IpFamily::V4;
+        }
+        if self.ipv6 {
+            settings.ip_family = IpFamily::V6;
+        }
+        if let Some(secs) = self.keepalive {
+            settings.keepalive = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.server_timeout {
+            settings.server_timeout = Duration::from_secs(secs);
+        }
+        if self.push_to_talk {
+            settings.push_to_talk = true;
+        }
+        if let Some(db) = self.vad_threshold {
+            settings.vad_threshold_db = db;
+        }
+        if let Some(frames) = self.vad_hangover {
+            settings.vad_hangover = frames;
+        }
+        if self.no_echo_cancellation {
+            settings.echo_cancellation = false;
+        }
+        if self.deafen_keeps_mic {
+            settings.deafen_mutes = false;
+        }
+        if self.release_devices {
+            settings.release_devices = true;
+        }
+        if let Some(timeout) = self.stream_timeout {
+            settings.stream_timeout = timeout;
+        }
+        if let Some(timeout) = self.speaking_timeout {
+            settings.speaking_timeout = timeout;
+        }
+        if self.control_only {
+            settings.audio_sink = false;
+        }
+        if let Some(backend) = self.backend {
+            settings.backend = backend;
+        }
+        if !self.mic.is_empty() {
+            settings.mics = self.mic.iter().map(|mic| mic.trim().to_string()).collect();
+        }
+        if let Some(device) = &self.output_device {
+            settings.output_device = Some(device.clone());
+        }
+        if let Some(app) = &self.share_app {
+            settings.share_app = Some(app.clone());
+        }
+        if self.share_mono {
+            settings.shared_mix.mono = true;
+        }
+        if let Some(gain) = self.share_gain {
+            settings.shared_mix.gain = gain;
+        }
+        if self.listen_along || self.play_queue {
+            settings.listen_along = true;
+        }
+        if self.play_queue {
+            settings.play_queue = true;
+        }
+        if let Some(crossfade) = self.crossfade {
+            settings.crossfade = crossfade;
+        }
+        if let Some(Loudness(lufs)) = self.music_loudness {
+            settings.music_loudness = lufs;
+        }
+        if let Some(Loudness(lufs)) = self.voice_loudness {
+            settings.voice_loudness = lufs;
+        }
+        if let Some(percent) = self.stereo_width {
+            settings.stereo_width = percent as f32 / 100.0;
+        }
+        if self.mono_output {
+            settings.stereo_width = 0.0;
+        }
+        if let Some(db) = self.output_ceiling {
+            settings.output_ceiling_db = db;
+        }
+        if let Some(bitrate) = self.bitrate {
+            settings.bitrate = bitrate;
+        }
+        if let Some(mode) = self.bitrate_mode {
+            settings.bitrate_mode = mode;
+        }
+        if self.fixed_bitrate {
+            settings.adaptive_bitrate = false;
+        }
+        if let Some(dir) = &self.record {
+            settings.record_dir = Some(dir.clone());
+        }
+        if let Some(command) = &self.speech_to_chat {
+            settings.speech_to_chat = Some(command.clone());
+        }
+        if let Some(percent) = self.expected_loss {
+            settings.expected_loss = percent;
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct ServerArgs {
+    /// The address and port to listen on, e.g. [::]:1234 for IPv6 as well
+    #[arg(long, value_name = "ADDRESS:PORT", default_value = "0.0.0.0:1234")]
+    pub bind: SocketAddr,
+    /// Turns away anyone joining once this many clients are connected
+    #[arg(long, value_name = "N", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_clients: Option<usize>,
+    /// Sets the password clients need to join
+    #[arg(long)]
+    pub password: Option<String>,
+    /// Turns away clients that don't encrypt their traffic
+    #[arg(long)]
+    pub require_encryption: bool,
+    /// Serves the web admin UI, protected by KOP_AUDIO_ADMIN_PASSWORD
+    #[arg(long, value_name = "ADDRESS:PORT")]
+    pub admin_listen: Option<String>,
+    /// Takes admin commands on stdin: list, kick, mute, ban and say
+    #[arg(long)]
+    pub console: bool,
+    /// Announces the daily events in the file, one "HH:MM title" per line
+    #[arg(long, value_name = "FILE")]
+    pub schedule: Option<String>,
+    /// Sets how many minutes before a scheduled event the reminder goes out
+    #[arg(
+        long,
+        value_name = "MINUTES",
+        default_value_t = 10,
+        requires = "schedule"
+    )]
+    pub remind: u32,
+    /// Moves users that stayed silent and idle this long to the AFK room
+    #[arg(long, value_name = "SECONDS")]
+    pub afk_timeout: Option<u64>,
+    /// Sets how long voice messages for offline users are kept (default 86400)
+    #[arg(long, value_name = "SECONDS")]
+    pub voice_message_ttl: Option<u64>,
+    /// Drops clients that sent nothing for this long, clients send something
+    /// at least every few seconds while connected (default 30)
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(5..))]
+    pub client_timeout: Option<u64>,
+    /// Forwards only this many voices at once, those already talking keep the
+    /// floor unless someone is much louder. It can be changed in the admin UI
+    #[arg(long, value_name = "N", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_speakers: Option<usize>,
+    /// Sets how everyone in the room encodes their voice, e.g. mono,24 for a
+    /// radio room or stereo,128,48 for music. The sample rate is 8, 12, 16, 24
+    /// or 48kHz (default stereo, 48kHz, bitrate up to the clients). A pcm,
+    /// prefix sends voices uncompressed without codec delay, e.g. pcm,stereo
+    /// on a LAN, it takes about 1.5Mbit/s per stereo speaker. codec2 sends
+    /// them as Codec2, mono at 8kHz, at 3200 (default), 2400, 1600, 1400,
+    /// 1300, 1200 or 700bit/s, e.g. codec2,1200. The modes below 2400 code
+    /// 40ms at a time and need clients with --frame-ms 40, all need clients
+    /// built with the cargo feature codec2
+    #[arg(
+        long,
+        value_name = "[pcm,]<mono|stereo>[,<kbps>][,<kHz>]|codec2[,<bit/s>]",
+        value_parser = parsed(
+            RoomCodec::parse,
+            "[pcm,]mono or stereo, optionally followed by ,<kbps> and ,<kHz>, or \
+             codec2 optionally followed by ,<bit/s>"
+        )
+    )]
+    pub room_codec: Option<RoomCodec>,
+    /// Sets how far behind the host listen-along music is scheduled, it has to
+    /// cover the slowest listener's network delay (default 400)
+    #[arg(long, value_name = "MS", value_parser = parse_ms)]
+    pub listen_along_delay: Option<Duration>,
+    /// Drops packets beyond this many per second from one IP address, one
+    /// that keeps it up for a few seconds is banned for 10 minutes (default 1000)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_packet_rate: Option<u32>,
+    /// Like --max-packet-rate, in kilobytes per second (default 1024)
+    #[arg(long, value_name = "KB", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_byte_rate: Option<u64>,
+    /// Turns away the IP addresses in the file, one per line. Bans and unbans
+    /// in the console are saved to it
+    #[arg(long, value_name = "FILE")]
+    pub ban_list: Option<PathBuf>,
+}
+
+impl ServerArgs {
+    pub fn settings(&self) -> ServerSettings {
+        let defaults = ServerSettings::default();
+        ServerSettings {
+            afk_timeout: self.afk_timeout.map(Duration::from_secs),
+            voice_message_ttl: self
+                .voice_message_ttl
+                .map_or(defaults.voice_message_ttl, Duration::from_secs),
+            require_encryption: self.require_encryption,
+            password: self.password.clone(),
+            listen_along_delay: self
+                .listen_along_delay
+                .unwrap_or(defaults.listen_along_delay),
+            max_speakers: self.max_speakers,
+            max_clients: self.max_clients,
+            client_timeout: self
+                .client_timeout
+                .map_or(defaults.client_timeout, Duration::from_secs),
+            codec: self.room_codec.unwrap_or(defaults.codec),
+            max_packet_rate: self.max_packet_rate.unwrap_or(defaults.max_packet_rate),
+            max_byte_rate: self
+                .max_byte_rate
+                .map_or(defaults.max_byte_rate, |kb| kb * 1024),
+        }
+    }
+}
+
+/// A loudness in LUFS to normalize to, `None` for off.
+#[derive(Debug, Clone, Copy)]
+pub struct Loudness(Option<f32>);
+
+/// A value parser out of one of the `parse` functions of the settings.
+fn parsed<T: Clone + Send + Sync + 'static>(
+    parse: fn(&str) -> Option<T>,
+    expected: &'static str,
+) -> impl TypedValueParser<Value = T> {
+    move |value: &str| parse(value).ok_or_else(|| format!("expected {}", expected))
+}
+
+/// A level in dBFS from `min` to `max`.
+fn level(min: f32, max: f32) -> impl TypedValueParser<Value = f32> {
+    move |value: &str| match value.parse::<f32>() {
+        Ok(db) if (min..=max).contains(&db) => Ok(db),
+        _ => Err(format!(
+            "expected a level in dBFS between {} and {}",
+            min, max
+        )),
+    }
+}
+
+fn parse_ms(value: &str) -> Result<Duration, String> {
+    value
+        .parse()
+        .map(Duration::from_millis)
+        .map_err(|_| "expected a duration in milliseconds".to_string())
+}
+
+fn parse_frame_ms(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(ms) if FRAME_MS.contains(&ms) => Ok(ms),
+        _ => Err("expected 10, 20, 40 or 60".to_string()),
+    }
+}
+
+fn parse_loudness(value: &str) -> Result<Loudness, String> {
+    match value {
+        "off" => Ok(Loudness(None)),
+        lufs => lufs
+            .parse()
+            .map(|lufs| Loudness(Some(lufs)))
+            .map_err(|_| "expected a loudness in LUFS or off".to_string()),
+    }
+}
+
+fn parse_backend(name: &str) -> Result<&'static dyn AudioBackend, String> {
+    implementations::backend(name).ok_or_else(|| {
+        let names: Vec<_> = BACKENDS.iter().map(|backend| backend.name()).collect();
+        format!("expected one of {}", names.join(", "))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn runs_the_client_without_a_subcommand() {
+        Cli::command().debug_assert();
+        let cli = Cli::try_parse_from(["kop-audio", "--ip", "host:1234", "--no-tui"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.client.ip.as_deref(), Some("host:1234"));
+        assert!(cli.client.no_tui);
+        let cli = Cli::try_parse_from(["kop-audio", "server", "--max-clients", "8"]).unwrap();
+        let Some(Command::Server(server)) = cli.command else {
+            panic!("not the server");
+        };
+        assert_eq!(server.settings().max_clients, Some(8));
+        assert_eq!(server.bind, "0.0.0.0:1234".parse().unwrap());
+    }
+
+    #[test]
+    fn refuses_options_that_conflict_or_dont_belong() {
+        for args in [
+            &["kop-audio", "--mono-output", "--stereo-width", "50"][..],
+            &["kop-audio", "--resume", "--ip", "host:1234"],
+            &["kop-audio", "--share-mono"],
+            &["kop-audio", "--low-latency", "--frame-ms", "40"],
+            &["kop-audio", "--vad-threshold", "-5"],
+            &["kop-audio", "server", "--remind", "5"],
+            &["kop-audio", "server", "--ip", "host:1234"],
+            &["kop-audio", "--ip", "host:1234", "server"],
+        ] {
+            assert!(Cli::try_parse_from(args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn options_win_over_presets_in_any_order() {
+        let cli = Cli::try_parse_from(["kop-audio", "--bitrate", "32", "--music-mode"]).unwrap();
+        let mut settings = AudioSettings::default();
+        cli.client.settings.apply(&mut settings);
+        assert_eq!(settings.bitrate, Bitrate::Bits(32_000));
+        assert!(!settings.vad);
+    }
+}
@@ -1,244 +1,632 @@
+use bincode::{Decode, Encode};
 use log::{debug, error, info, warn};
 use opus::Encoder;
 use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
-use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, SystemTime};
 use tokio::net::{UdpSocket, lookup_host};
+use tokio::task::JoinHandle;
 
-use crate::server::{AudioData, Message, decode_message, encode_message};
-use crate::{BUF_SIZE, ErrorKind, MSG_SIZE, client};
+use crate::bus::{EventBus, Subscriber};
+use crate::connection::{ConnectionCommand, ConnectionState};
+use crate::crypto::{self, SecureSocket};
+use crate::header::{HeaderCompressor, PacketSizes, PacketStats};
+use crate::identity::UserId;
+use crate::listen_along::MusicVote;
+use crate::playlist::{Playlist, PlaylistCommand, TrackInfo};
+use crate::quality::{Grade, HealthReport, LossStats, SmoothedRtt, StreamHealth, StreamQuality};
+use crate::settings::{AudioSettings, IpFamily};
+use crate::server::{
+    AudioData, ChatMessage, ChimeChunk, CodecStream, Cue, JoinChallenge, Message, RoomCodec,
+    RoomInfo, ServerInfo, MAX_MESSAGE, SessionId, VoiceChunk, decode_checked, encode_checked,
+    encode_message,
+};
+use crate::{Error, client};
+
+const LATENCY_PROBE_INTERVAL: Duration = Duration::from_secs(2);
+const BYE_REPEATS: usize = 3;
 
 /// A network consumer that takes audio data and sends it over UDP
 pub struct NetworkClient {
-    pub socket: Arc<UdpSocket>,
+    pub socket: Arc<SecureSocket>,
     hangover: usize,
     hangover_limit: usize,
     muted: bool,
+    keepalive: Duration,
+    server_timeout: Duration,
 
-    // communication with TUI
-    tx: Sender<ClientMessage>,
+    bus: EventBus,
 }
 
+#[derive(Encode, Decode, Debug, Clone)]
 pub enum ClientMessage {
+    /// the server answered our hello
     Connect,
     Disconnect,
+    /// connect, disconnect or reconnect, from the TUI or the control socket
+    Connection(ConnectionCommand),
+    /// the connection changed, see `Connection`
+    ConnectionState(ConnectionState),
     ToggleMute,
     ToggleDeafen,
+    /// mutes only while the key is held, e.g. to cough
+    HoldMute(bool),
+    /// the push-to-talk key went down or up
+    PushToTalk(bool),
+    /// whether push-to-talk is on, the microphone is only sent while its key is held
+    PushToTalkMode(bool),
+    /// moves the voice activity threshold by some dB and the hangover by some frames
+    AdjustVad(f32, i32),
+    /// voice activity threshold in dBFS and hangover in frames now in use
+    Vad(f32, u32),
+    /// loudest microphone level in dBFS over the last moment, for calibrating the threshold
+    InputLevel(f32),
+    /// a named marker in the recording, at a time in milliseconds since the epoch
+    AddMarker(String, u64),
     Audio(AudioData),
-    RecvAudio(std::net::SocketAddr, AudioData),
+    RecvAudio(std::net::SocketAddr, SessionId, AudioData),
+    /// listen-along music, see `listen_along`
+    Music(AudioData),
+    RecvMusic(std::net::SocketAddr, AudioData),
+    MusicVote(MusicVote),
+    MusicVoteFrom(std::net::SocketAddr, MusicVote),
+    MusicVoteResult(MusicVote),
     // TUI messages
-    ShowActive(std::net::SocketAddr),
+    Speaking(std::net::SocketAddr, bool),
+    /// estimated from the packets a sender's stream arrives in
+    StreamQuality(std::net::SocketAddr, StreamQuality),
+    StreamEnded(std::net::SocketAddr),
+    /// changes how loud a user is played here by some percent, nobody else hears a difference
+    AdjustUserVolume(std::net::SocketAddr, i32),
+    /// mutes or unmutes a user for us only
+    ToggleUserMute(std::net::SocketAddr),
+    /// a user's volume in percent now in use and whether we muted them
+    UserVolume(std::net::SocketAddr, u32, bool),
+    /// asks for the capture and playback devices, answered with `Devices`
+    ListDevices,
+    /// name and description of each capture and each playback device
+    Devices(Vec<(String, String)>, Vec<(String, String)>),
+    /// switches the microphone or the speakers to a device by name
+    SelectInput(String),
+    SelectOutput(String),
+    /// changes how loud everything is played by some percent
+    AdjustOutputVolume(i32),
+    /// output volume in percent now in use
+    OutputVolume(u32),
     TransmitAudio(bool),
-    NewClient(std::net::SocketAddr),
+    Muted(bool),
+    Deafened(bool),
+    LatencyEstimate(u32),
+    /// smoothed round trip time to the server
+    Latency(Duration),
+    /// raises (`true`) or lowers the bitrate of the microphone by a step
+    ChangeBitrate(bool),
+    /// bitrate the microphone is encoded at now, in bits per second
+    Bitrate(u32),
+    /// loss on our voice stream, as reported by the server
+    LossStats(LossStats),
+    /// frames of the others' streams played since the last report and how many were missing
+    PlaybackStats(u32, u32),
+    /// how the call went since the last report, as sent to the server
+    Health(HealthReport),
+    /// how a sender's stream played since the last report
+    StreamHealth(std::net::SocketAddr, StreamHealth),
+    /// the grade of our connection, changed
+    Grade(Grade),
+    /// the grade of a sender's stream as we hear it, changed
+    UserGrade(std::net::SocketAddr, Grade),
+    /// the server thinks a lower bitrate would help our call
+    LowerBitrateSuggested,
+    /// packet loss in percent the encoder's FEC is tuned for now
+    ExpectedLoss(u8),
+    PacketStats(PacketStats),
+    /// a client and its display name
+    NewClient(std::net::SocketAddr, String),
     DeleteClient(std::net::SocketAddr),
+    RoomInfo(RoomInfo),
+    /// version and features of the server we are connected to
+    ServerInfo(ServerInfo),
+    /// how the room encodes voice, from the server to the microphone
+    RoomCodec(RoomCodec),
+    /// resets our encoder of a stream, e.g. when a listener asked
+    ResetEncoder(CodecStream),
+    /// our encoder of a stream was reset, the room is told
+    EncoderReset(CodecStream),
+    /// resets the decoder of a sender's stream, after it reset its encoder
+    ResetDecoder(std::net::SocketAddr, CodecStream),
+    /// asks a sender to reset its encoder, after losing a long stretch of its stream
+    RequestCodecReset(std::net::SocketAddr, CodecStream),
+    Announcement(String),
+    MovedToAfk(bool),
+    ClientAfk(std::net::SocketAddr, bool),
+    OfflineUsers(Vec<UserId>),
+    /// starts recording a voice message for an offline user, `None` stops and sends it
+    RecordVoiceMessage(Option<UserId>),
+    RecordingVoiceMessage(bool),
+    VoiceChunk(VoiceChunk),
+    /// received voice messages not played yet, sender and length in seconds
+    VoiceMessages(Vec<(UserId, u32)>),
+    /// plays the oldest received voice message
+    PlayVoiceMessage,
+    PlayClip(Vec<Vec<u8>>),
+    Cue(Cue),
+    Chime(ChimeChunk),
+    /// custom chime for a cue as opus packets, empty for the built-in one
+    SetChime(Cue, Vec<Vec<u8>>),
+    PlayCue(Cue),
+    PlaylistCommand(PlaylistCommand),
+    Playlist(Playlist),
+    /// the track this client's playlist player started, for the room
+    AnnounceTrack(Option<TrackInfo>),
+    /// what the listen-along host plays
+    NowPlaying(Option<TrackInfo>),
+    /// a line typed into the chat input
+    SendChat(String),
+    Chat(ChatMessage),
+    /// a task of the session ended with an error, the coordinator decides what next
+    SessionFailed(Error),
+    /// the server wants our identity, and maybe the password, proven, see
+    /// `Message::JoinChallenge`
+    JoinChallenge(JoinChallenge),
     Exit,
 }
 
 impl NetworkClient {
-    pub async fn new(addr: &str, tx: Sender<ClientMessage>) -> Result<Self, ErrorKind> {
+    pub async fn new(
+        addr: &str,
+        bus: EventBus,
+        settings: &AudioSettings,
+    ) -> Result<Self, Error> {
+        let family = settings.ip_family;
+        let server = addr;
         info!("Connecting to {}", addr);
         let result = lookup_host(addr)
             .await
-            .map_err(|e| ErrorKind::InitializationError2(e.to_string()))?;
-        let addr = result
-            .into_iter()
-            .next()
-            .ok_or(ErrorKind::InitializationError)?;
+            .map_err(|e| Error::Network(e.to_string()))?;
+        let addr = family.pick(result).ok_or_else(|| match family {
+            IpFamily::Any => Error::Network(format!("No address for {}", addr)),
+            family => Error::Network(format!("No {} address for {}", family, addr)),
+        })?;
         debug!("Connecting to {}", addr);
-        let consumer = UdpSocket::bind("0.0.0.0:0")
-            .await
-            .map(|s| NetworkClient {
-                socket: Arc::new(s),
-                hangover: 0,
-                hangover_limit: 10, // number of consecutive silent frames to send before stopping
-                muted: false,
-                tx: tx,
-            })
-            .map_err(|e| ErrorKind::InitializationError2(e.to_string()))?;
-        debug!("Socket bound to {}", consumer.socket.local_addr().unwrap());
-        consumer
-            .socket
-            .connect(addr)
+        let socket = connected_socket(addr)
             .await
-            .map_err(|e| ErrorKind::InitializationError2(e.to_string()))?;
+            .map_err(|e| Error::Network(e.to_string()))?;
+        let local = socket
+            .local_addr()
+            .map_err(|e| Error::Network(e.to_string()))?;
+        debug!("Socket bound to {}", local);
+        let socket = if settings.encrypt {
+            let socket =
+                SecureSocket::connect(socket, crypto::load_or_create_key("client-key")).await?;
+            if let Some(key) = socket.server_key() {
+                crypto::pin_server_key(server, key)?;
+            }
+            socket
+        } else {
+            warn!("Talking to {} unencrypted", addr);
+            SecureSocket::plain(socket)
+        };
 
-        Ok(consumer)
+        Ok(NetworkClient {
+            socket: Arc::new(socket),
+            hangover: 0,
+            hangover_limit: 10, // number of consecutive silent frames to send before stopping
+            muted: false,
+            keepalive: settings.keepalive,
+            server_timeout: settings.server_timeout,
+            bus,
+        })
     }
 
-    pub async fn start(
-        mut self,
-        rx_receive_audio: Receiver<Message>,
-        rx_net_out: Receiver<Message>,
-    ) -> () {
+    pub fn start(&self) -> Vec<JoinHandle<()>> {
         let socket1 = self.socket.clone();
         let socket2 = self.socket.clone();
-        let tx1 = self.tx.clone();
-        let tx2 = self.tx.clone();
+        let rx_net_out = self.bus.net_out.subscribe();
+        let bus = self.bus.clone();
+        let send_bus = self.bus.clone();
+        let probe_bus = self.bus.clone();
+        let keepalive = self.keepalive;
+        let server_timeout = self.server_timeout;
+
+        let fail_send = self.bus.clone();
+        let fail_receive = self.bus.clone();
+
+        vec![
+            tokio::spawn(async move {
+                let result = client::send_udp(socket1, rx_net_out, send_bus, keepalive).await;
+                report_failure(&fail_send, result);
+            }),
+            tokio::spawn(async move {
+                let result = client::receive_udp(socket2, bus, server_timeout).await;
+                report_failure(&fail_receive, result);
+            }),
+            tokio::spawn(async move { client::probe_latency(probe_bus).await }),
+        ]
+    }
+}
+
+/// A socket connected to `addr`, bound to an address of the same family so
+/// IPv6-only hosts can connect too.
+pub async fn connected_socket(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
 
-        tokio::spawn(async move { client::send_udp(socket1, tx1, rx_net_out).await });
-        tokio::spawn(async move { client::receive_udp(socket2, rx_receive_audio, tx2).await });
+/// Hands the error a network task ended with to the coordinator.
+fn report_failure(bus: &EventBus, result: Result<(), Error>) {
+    if let Err(e) = result {
+        error!("{:?}", e);
+        bus.commands.publish(ClientMessage::SessionFailed(e));
     }
 }
 
+/// Microseconds on the local clock, only ever compared with itself.
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u64
+}
+
+/// Asks the server for a reply every few seconds, `receive_udp` turns the
+/// replies into the latency shown in the status bar.
+pub async fn probe_latency(bus: EventBus) {
+    let mut interval = tokio::time::interval(LATENCY_PROBE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => bus.net_out.publish(Message::LatencyProbe(now_us())),
+            _ = bus.shutdown.wait() => return,
+        }
+    }
+}
+
+/// Sends what is published to `net_out` until the bus closes, fails when
+/// the socket does, and a keepalive whenever nothing went out for
+/// `keepalive`. On shutdown it sends what is still queued and says bye.
 pub async fn send_udp(
-    socket: Arc<UdpSocket>,
-    tx: Sender<client::ClientMessage>,
-    rx: Receiver<Message>,
-) {
-    for msg in rx.iter() {
-        match socket.try_send(&encode_message(&msg)) {
-            Ok(bytes_sent) => {
-                debug!(
-                    "Sent {} bytes, msg type {:?}",
-                    bytes_sent,
-                    mem::discriminant(&msg)
-                );
+    socket: Arc<SecureSocket>,
+    mut rx: Subscriber<Message>,
+    bus: EventBus,
+    keepalive: Duration,
+) -> Result<(), Error> {
+    let mut sender = Sender {
+        socket,
+        headers: HeaderCompressor::default(),
+        sizes: PacketSizes::default(),
+        sender_key: None,
+        bus: bus.clone(),
+    };
+    let shutdown = bus.shutdown.wait();
+    tokio::pin!(shutdown);
+    let idle = tokio::time::sleep(keepalive);
+    tokio::pin!(idle);
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => sender.send(msg).await?,
+                None => return Ok(()),
+            },
+            _ = &mut idle => sender.send(Message::Keepalive).await?,
+            _ = &mut shutdown => break,
+        }
+        idle.as_mut().reset(tokio::time::Instant::now() + keepalive);
+    }
+    while let Some(msg) = rx.try_recv() {
+        sender.send(msg).await?;
+    }
+    // a few times in case one gets lost, the server would keep us in the
+    // roster until we time out otherwise
+    for _ in 0..BYE_REPEATS {
+        sender.send(Message::Bye).await?;
+    }
+    Ok(())
+}
+
+/// What `send_udp` keeps between messages.
+struct Sender {
+    socket: Arc<SecureSocket>,
+    headers: HeaderCompressor,
+    sizes: PacketSizes,
+    /// from the server when we don't encrypt, see `Message::Signed`
+    sender_key: Option<[u8; 32]>,
+    bus: EventBus,
+}
+
+impl Sender {
+    async fn send(&mut self, msg: Message) -> Result<(), Error> {
+        let msg = match msg {
+            Message::Audio(audio) => self.headers.compress(audio),
+            // handed over by `receive_udp`, not for the server
+            Message::SenderKey(key) => {
+                self.sender_key = Some(key);
+                return Ok(());
+            }
+            msg => msg,
+        };
+        let mut buf = match encode_checked(&msg) {
+            Ok(buf) => buf,
+            Err(e) => {
+                error!("Not sending {:?}: {:?}", mem::discriminant(&msg), e);
+                return Ok(());
             }
-            Err(e) => error!("{:?}", ErrorKind::WriteError(e.to_string())),
+        };
+        if let Some(key) = &self.sender_key
+            && matches!(msg, Message::AudioDelta(_) | Message::Audio(_) | Message::Music(_))
+        {
+            buf = encode_message(&Message::Signed(crypto::sender_tag(key, &buf), buf));
         }
+        let stats = match &msg {
+            Message::Audio(audio) => self.sizes.add(audio.data.len(), buf.len()),
+            Message::AudioDelta(delta) => self.sizes.add(delta.data.len(), buf.len()),
+            _ => None,
+        };
+        if let Some(stats) = stats {
+            self.bus.commands.publish(ClientMessage::PacketStats(stats));
+        }
+        let bytes_sent = self
+            .socket
+            .send(&buf)
+            .await
+            .map_err(|e| Error::Network(format!("Can't send to the server: {}", e)))?;
+        debug!(
+            "Sent {} bytes, msg type {:?}",
+            bytes_sent,
+            mem::discriminant(&msg)
+        );
+        Ok(())
     }
 }
 
+/// Hands what the server sends to the coordinator until shutdown, fails when
+/// the socket does or the server turns us away.
 pub async fn receive_udp(
-    socket: Arc<UdpSocket>,
-    rx_receive_audio: Receiver<Message>,
-    tx: Sender<client::ClientMessage>,
-) {
-    let mut data = [0u8; MSG_SIZE as usize];
+    socket: Arc<SecureSocket>,
+    bus: EventBus,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut data = vec![0u8; MAX_MESSAGE];
+    let mut rtt = SmoothedRtt::default();
+    let shutdown = bus.shutdown.wait();
+    tokio::pin!(shutdown);
     loop {
-        let (len, addr) = socket.recv_from(&mut data).await.unwrap();
-        let msg = decode_message(&data[..len]);
+        let received = tokio::select! {
+            received = socket.recv_from(&mut data) => received,
+            // it answers the latency probes, so silence means it is gone
+            _ = tokio::time::sleep(timeout) => {
+                return Err(Error::Network(format!(
+                    "The server didn't answer for {}s",
+                    timeout.as_secs()
+                )));
+            }
+            _ = &mut shutdown => return Ok(()),
+        };
+        let (len, addr) =
+            received.map_err(|e| Error::Network(format!("Can't receive from the server: {}", e)))?;
+        let msg = match decode_checked(&data[..len]) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Dropping message from {}: {:?}", addr, e);
+                continue;
+            }
+        };
         debug!("Received message of type {:?}", msg);
         match msg {
-            Message::AudioFrom(addr, data) => {
-                let _ = tx.send(ClientMessage::RecvAudio(addr, data));
+            Message::AudioFrom(addr, session, data) => {
+                bus.commands.publish(ClientMessage::RecvAudio(addr, session, data));
+            }
+            Message::MusicFrom(addr, data) => {
+                bus.commands.publish(ClientMessage::RecvMusic(addr, data));
+            }
+            Message::MusicVoteFrom(addr, vote) => {
+                bus.commands.publish(ClientMessage::MusicVoteFrom(addr, vote));
+            }
+            Message::MusicVoteResult(vote) => {
+                bus.commands.publish(ClientMessage::MusicVoteResult(vote));
+            }
+            Message::LatencyReply(sent) => {
+                let sample = Duration::from_micros(now_us().saturating_sub(sent));
+                bus.commands.publish(ClientMessage::Latency(rtt.update(sample)));
             }
-            Message::NewClient(addr) => {
-                let _ = tx.send(ClientMessage::NewClient(addr));
+            Message::NewClient(addr, name) => {
+                bus.commands.publish(ClientMessage::NewClient(addr, name));
             }
-            Message::DeleteClient(addr) => {
-                let _ = tx.send(ClientMessage::DeleteClient(addr));
+            Message::DeleteClient(addr, name) => {
+                debug!("{} ({}) left", name, addr);
+                bus.commands.publish(ClientMessage::DeleteClient(addr));
             }
-            Message::Hello(addr) => {
-                let _ = tx.send(ClientMessage::Connect);
+            Message::Hello(_) => {
+                bus.commands.publish(ClientMessage::Connect);
+            }
+            Message::SenderKey(key) => {
+                // for `send_udp` to sign our voice and music with
+                bus.net_out.publish(Message::SenderKey(key));
+            }
+            Message::RoomInfo(room) => {
+                bus.commands.publish(ClientMessage::RoomInfo(room));
+            }
+            Message::Announcement(text) => {
+                bus.commands.publish(ClientMessage::Announcement(text));
+            }
+            Message::MovedToAfk(afk) => {
+                bus.commands.publish(ClientMessage::MovedToAfk(afk));
+            }
+            Message::ClientAfk(addr, afk) => {
+                bus.commands.publish(ClientMessage::ClientAfk(addr, afk));
+            }
+            Message::OfflineUsers(users) => {
+                bus.commands.publish(ClientMessage::OfflineUsers(users));
+            }
+            Message::VoiceChunk(chunk) => {
+                bus.commands.publish(ClientMessage::VoiceChunk(chunk));
+            }
+            Message::JoinRejected(rejection) => return Err(Error::Rejected(rejection)),
+            Message::JoinChallenge(challenge) => {
+                bus.commands.publish(ClientMessage::JoinChallenge(challenge));
+            }
+            Message::Kicked => return Err(Error::Kicked),
+            Message::AdminMuted(muted) => {
+                let text = if muted {
+                    "An admin muted you, nobody hears you until they unmute you"
+                } else {
+                    "An admin unmuted you"
+                };
+                bus.commands.publish(ClientMessage::Announcement(text.to_string()));
+            }
+            Message::Rejected(reason) => {
+                return Err(Error::Protocol(format!("Can't join: {}", reason)));
+            }
+            Message::EncryptionRequired => {
+                return Err(Error::Protocol(
+                    "The server requires encryption, reconnect without --no-encryption".to_string(),
+                ));
+            }
+            Message::Cue(cue) => {
+                bus.commands.publish(ClientMessage::Cue(cue));
+            }
+            Message::Chime(chunk) => {
+                bus.commands.publish(ClientMessage::Chime(chunk));
+            }
+            Message::ChatFrom(chat) => {
+                bus.commands.publish(ClientMessage::Chat(chat));
+            }
+            Message::Playlist(playlist) => {
+                bus.commands.publish(ClientMessage::Playlist(playlist));
+            }
+            Message::NowPlaying(info) => {
+                bus.commands.publish(ClientMessage::NowPlaying(info));
+            }
+            Message::Stats(stats) => {
+                bus.commands.publish(ClientMessage::LossStats(stats));
+            }
+            Message::SuggestLowerBitrate => {
+                bus.commands.publish(ClientMessage::LowerBitrateSuggested);
+            }
+            Message::ServerInfo(server) => {
+                info!(
+                    "Server runs kop-audio {}, features: {}",
+                    server.version,
+                    server.features.join(", ")
+                );
+                if server.version != env!("CARGO_PKG_VERSION") {
+                    warn!(
+                        "The server runs kop-audio {}, we run {}",
+                        server.version,
+                        env!("CARGO_PKG_VERSION")
+                    );
+                }
+                bus.commands.publish(ClientMessage::ServerInfo(server));
+            }
+            Message::RoomCodec(codec) => {
+                info!("The room encodes voice as {}", codec.describe());
+                bus.commands.publish(ClientMessage::RoomCodec(codec));
+            }
+            Message::ResetCodecFrom(addr, stream) => {
+                bus.commands.publish(ClientMessage::ResetDecoder(addr, stream));
+            }
+            Message::RequestCodecReset(from, stream) => {
+                debug!("{} asks to reset our {:?} encoder", from, stream);
+                bus.commands.publish(ClientMessage::ResetEncoder(stream));
             }
             _ => {}
         }
     }
 }
 
-//pub async fn receive_udp(
-//    socket: Arc<UdpSocket>,
-//    rx_receive_audio: Receiver<Message>,
-//    tx: Sender<client::ClientMessage>,
-//) {
-
-//let mut audio_consumer = PulseAudioConsumer::new().unwrap();
-//let mut decoder = opus_decoder();
-//let mut data = [0u8; MSG_SIZE as usize];
-//let mut decoded_data = vec![0i16; FRAME_SIZE * CHANNELS];
-//let mut deafened = false;
-//info!("Ready to receive audio");
-//loop {
-//    match receive_client_message(&rx_receive_audio) {
-//        Some(client::ClientMessage::ToggleDeafen) => {
-//            deafened = !deafened;
-//        }
-//        Some(client::ClientMessage::Exit) => {
-//            // TODO: doesn't work
-//            socket.try_send(&encode_message(Message::Bye)).unwrap();
-//            send_client_message(ClientMessage::Disconnect, &tx);
-//            debug!("Exiting receive_audio loop");
-//        }
-//        _ => {}
-//    }
-//    let (len, addr) = socket.recv_from(&mut data).await.unwrap();
-
-//    let msg = decode_message(&data[..len]);
-//    debug!("Received message of type {:?}", msg);
-//    match msg {
-//        Message::Audio(encoded_data) => {
-//            if deafened {
-//                debug!("Client is deafened, not playing audio");
-//                continue;
-//            }
-//            debug!("Received {} bytes from {}", len, addr);
-//            let b = decoder
-//                .decode(&encoded_data, &mut decoded_data, false)
-//                .unwrap();
-//            match audio_consumer.consume(unsafe {
-//                slice::from_raw_parts(
-//                    decoded_data.as_ptr() as *const u8,
-//                    b * CHANNELS * std::mem::size_of::<i16>(),
-//                )
-//            }) {
-//                Ok(_) => {}
-//                Err(e) => {
-//                    error!("Error consuming data: {:?}", e);
-//                }
-//            }
-//        }
-//        Message::NewClient(addr) => {
-//            let _ = send_client_message(client::ClientMessage::NewClient(addr), &tx);
-//        }
-//        Message::DeleteClient(addr) => {
-//            let _ = send_client_message(client::ClientMessage::DeleteClient(addr), &tx);
-//        }
-//        Message::Bye => {
-//            std::process::exit(0);
-//        }
-//        _ => {}
-//    }
-
-//    send_client_message(ClientMessage::Connect, &tx);
-//}
-//}
-
-//impl Consumer for NetworkClient {
-//    fn consume(&mut self, data: &[u8]) -> Result<usize, ErrorKind> {
-//        //match receive_client_message(&self.rx_send_audio) {
-//        //    Some(client::ClientMessage::ToggleMute) => {
-//        //        self.muted = !self.muted;
-//        //    }
-//        //    _ => {}
-//        //}
-//        //if self.muted {
-//        //    debug!("Client is muted, not sending audio");
-//        //    send_client_message(client::ClientMessage::TransmitAudio(false), &self.tx);
-//        //    return Ok(0);
-//        //}
-//        //let pcm: &[i16] =
-//        //    unsafe { slice::from_raw_parts(data.as_ptr() as *const i16, data.len() / 2) };
-//
-//        //let samples_needed = FRAME_SIZE * CHANNELS;
-//        //let pcm = &pcm[..samples_needed];
-//        //if is_silence(pcm, 200.0) {
-//        //    if self.hangover == 0 {
-//        //        send_client_message(client::ClientMessage::TransmitAudio(false), &self.tx);
-//        //        return Ok(0);
-//        //    }
-//        //    self.hangover -= 1;
-//        //} else {
-//        //    self.hangover = self.hangover_limit;
-//        //}
-//        //debug!("Acive audio detected, sending packet");
-//        //let n = self.encoder.encode(&pcm, &mut self.encoded_data).unwrap();
-//
-//        //debug!(
-//        //    "Read {} samples, data has {} samples, encoded to {} bytes,",
-//        //    pcm.len(),
-//        //    data.len() / 2,
-//        //    n,
-//        //);
-//        //send_client_message(client::ClientMessage::TransmitAudio(true), &self.tx);
-//        //match self.socket.try_send(&encode_message(Message::Audio(
-//        //    self.encoded_data[..n].to_vec(),
-//        //))) {
-//        //    Ok(bytes_sent) => {
-//        //        debug!("Sent {} bytes", bytes_sent);
-//        //        Ok(bytes_sent)
-//        //    }
-//        //    Err(e) => Err(ErrorKind::WriteError(e.to_string())),
-//        //}
-//    }
-//}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Recovery;
+    use crate::server::decode_message;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn says_bye_after_what_is_queued_on_shutdown() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server.local_addr().unwrap()).await.unwrap();
+        let bus = EventBus::new();
+        let rx = bus.net_out.subscribe();
+        let sender = tokio::spawn(send_udp(
+            Arc::new(SecureSocket::plain(socket)),
+            rx,
+            bus.clone(),
+            Duration::from_secs(15),
+        ));
+        bus.net_out.publish(Message::LatencyProbe(7));
+        bus.shutdown.trigger();
+        let result = tokio::time::timeout(Duration::from_secs(5), sender).await;
+        assert_eq!(result.unwrap().unwrap(), Ok(()));
+
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        let mut received = Vec::new();
+        for _ in 0..=BYE_REPEATS {
+            let len = server.recv(&mut buf).await.unwrap();
+            received.push(decode_message(&buf[..len]));
+        }
+        assert_eq!(received[0], Message::LatencyProbe(7));
+        assert!(received[1..].iter().all(|msg| *msg == Message::Bye));
+    }
+
+    #[tokio::test]
+    async fn sends_keepalives_while_there_is_nothing_else() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server.local_addr().unwrap()).await.unwrap();
+        let bus = EventBus::new();
+        let rx = bus.net_out.subscribe();
+        tokio::spawn(send_udp(
+            Arc::new(SecureSocket::plain(socket)),
+            rx,
+            bus.clone(),
+            Duration::from_millis(50),
+        ));
+
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        for _ in 0..2 {
+            let len = tokio::time::timeout(Duration::from_secs(5), server.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(decode_message(&buf[..len]), Message::Keepalive);
+        }
+        bus.shutdown.trigger();
+    }
+
+    #[tokio::test]
+    async fn stops_receiving_on_shutdown_while_the_server_is_silent() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server.local_addr().unwrap()).await.unwrap();
+        let bus = EventBus::new();
+        let receiver = tokio::spawn(receive_udp(
+            Arc::new(SecureSocket::plain(socket)),
+            bus.clone(),
+            Duration::from_secs(10),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!receiver.is_finished());
+        bus.shutdown.trigger();
+        let result = tokio::time::timeout(Duration::from_secs(1), receiver).await;
+        assert_eq!(result.unwrap().unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_server_that_stopped_answering() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server.local_addr().unwrap()).await.unwrap();
+        let result = receive_udp(
+            Arc::new(SecureSocket::plain(socket)),
+            EventBus::new(),
+            Duration::from_millis(50),
+        )
+        .await;
+        assert_eq!(result.map_err(|e| e.recovery()), Err(Recovery::Reconnect));
+    }
+}
@@ -0,0 +1,280 @@
+#[cfg(feature = "codec2")]
+pub mod codec2;
+
+use bincode::{Decode, Encode};
+use opus::{Bitrate, Decoder, Encoder};
+
+use crate::{CHANNELS, Error, SAMPLE_RATE, audio::samples, resampler::StreamResampler};
+
+/// The rate Codec2 runs at, mono only.
+pub const CODEC2_RATE: u32 = 8_000;
+/// Bits per second of the Codec2 modes, the first is the default.
+pub const CODEC2_MODES: [u32; 7] = [3200, 2400, 1600, 1400, 1300, 1200, 700];
+
+/// How a room's voices are encoded on the wire.
+#[derive(Encode, Decode, PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum CodecKind {
+    #[default]
+    Opus,
+    /// 16 bit samples as they are, no codec delay or CPU, for a LAN
+    Pcm,
+    /// Codec2 at 8kHz mono in the mode `RoomCodec::max_bitrate` names, for
+    /// links too thin for Opus. Needs the cargo feature codec2 to code it
+    Codec2,
+}
+
+/// Encodes the microphone for a room and decodes what the others send.
+/// Encoders take frames at the room's sample rate and channel count, decoders
+/// always return stereo at 48kHz for the mix.
+pub trait AudioCodec: Send {
+    /// Encodes `pcm` into `out`, returns the number of bytes written.
+    fn encode(&mut self, pcm: &[f32], out: &mut [u8]) -> Result<usize, Error>;
+
+    /// Decodes `packet` into `out`, returns the samples per channel written.
+    /// An empty `packet` conceals a lost frame of the length of `out`, `fec`
+    /// recovers the frame before `packet` from it where the codec can.
+    fn decode(&mut self, packet: &[u8], out: &mut [f32], fec: bool) -> Result<usize, Error>;
+
+    /// Starts afresh, as if nothing had been coded yet. Done on both ends, so
+    /// a decoder doesn't predict from frames it never got.
+    fn reset(&mut self) -> Result<(), Error>;
+
+    fn set_bitrate(&mut self, _bitrate: Bitrate) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Tunes the encoder for `percent` packet loss, 0 for none.
+    fn set_expected_loss(&mut self, _percent: u8) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+pub fn opus_error(e: opus::Error) -> Error {
+    Error::Codec(format!("Opus: {}", e))
+}
+
+/// Opus, one way: an encoder for the microphone or a decoder for a sender.
+pub struct OpusCodec {
+    encoder: Option<Encoder>,
+    decoder: Option<Decoder>,
+}
+
+impl OpusCodec {
+    pub fn encoding(encoder: Encoder) -> Self {
+        OpusCodec {
+            encoder: Some(encoder),
+            decoder: None,
+        }
+    }
+
+    pub fn decoding(decoder: Decoder) -> Self {
+        OpusCodec {
+            encoder: None,
+            decoder: Some(decoder),
+        }
+    }
+
+    fn encoder(&mut self) -> Result<&mut Encoder, Error> {
+        self.encoder
+            .as_mut()
+            .ok_or_else(|| Error::Codec("Opus: not set up to encode".to_string()))
+    }
+}
+
+impl AudioCodec for OpusCodec {
+    fn encode(&mut self, pcm: &[f32], out: &mut [u8]) -> Result<usize, Error> {
+        self.encoder()?.encode_float(pcm, out).map_err(opus_error)
+    }
+
+    fn decode(&mut self, packet: &[u8], out: &mut [f32], fec: bool) -> Result<usize, Error> {
+        let decoder = self
+            .decoder
+            .as_mut()
+            .ok_or_else(|| Error::Codec("Opus: not set up to decode".to_string()))?;
+        decoder.decode_float(packet, out, fec).map_err(opus_error)
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        if let Some(encoder) = &mut self.encoder {
+            encoder.reset_state().map_err(opus_error)?;
+        }
+        if let Some(decoder) = &mut self.decoder {
+            decoder.reset_state().map_err(opus_error)?;
+        }
+        Ok(())
+    }
+
+    fn set_bitrate(&mut self, bitrate: Bitrate) -> Result<(), Error> {
+        self.encoder()?.set_bitrate(bitrate).map_err(opus_error)
+    }
+
+    fn set_expected_loss(&mut self, percent: u8) -> Result<(), Error> {
+        let encoder = self.encoder()?;
+        encoder.set_inband_fec(percent > 0).map_err(opus_error)?;
+        encoder
+            .set_packet_loss_perc(percent as i32)
+            .map_err(opus_error)
+    }
+}
+
+/// Little endian 16 bit samples at the room's rate and channel count. A lost
+/// packet is played as silence, there is nothing to conceal it from.
+pub struct PcmCodec {
+    sample_rate: u32,
+    channels: usize,
+    // brings a lower room rate back to 48kHz, set up for the first packet
+    resampler: Option<(usize, StreamResampler)>,
+    upmixed: Vec<f32>,
+    resampled: Vec<f32>,
+}
+
+impl PcmCodec {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        PcmCodec {
+            sample_rate,
+            channels: channels.clamp(1, CHANNELS),
+            resampler: None,
+            upmixed: Vec::new(),
+            resampled: Vec::new(),
+        }
+    }
+}
+
+impl AudioCodec for PcmCodec {
+    fn encode(&mut self, pcm: &[f32], out: &mut [u8]) -> Result<usize, Error> {
+        let len = pcm.len() * 2;
+        if len > out.len() {
+            return Err(Error::Codec(format!(
+                "PCM: {} bytes don't fit a {} byte packet",
+                len,
+                out.len()
+            )));
+        }
+        Ok(samples::write_le(pcm, out))
+    }
+
+    fn decode(&mut self, packet: &[u8], out: &mut [f32], fec: bool) -> Result<usize, Error> {
+        if packet.is_empty() || fec {
+            out.fill(0.0);
+            return Ok(out.len() / CHANNELS);
+        }
+        self.upmixed.clear();
+        for frame in packet.chunks_exact(2 * self.channels) {
+            let samples = samples::read_le(frame);
+            match self.channels {
+                // the one channel on both sides
+                1 => self.upmixed.extend([samples.sum::<f32>(); CHANNELS]),
+                _ => self.upmixed.extend(samples),
+            }
+        }
+        let decoded = if self.sample_rate == SAMPLE_RATE {
+            &self.upmixed
+        } else {
+            let frames = self.upmixed.len() / CHANNELS;
+            // a sender changing its frame size starts the resampler afresh
+            if self
+                .resampler
+                .as_ref()
+                .is_none_or(|(size, _)| *size != frames)
+            {
+                let resampler = StreamResampler::new(self.sample_rate, SAMPLE_RATE, frames)?;
+                self.resampler = Some((frames, resampler));
+            }
+            let (_, resampler) = self.resampler.as_mut().unwrap();
+            self.resampled.clear();
+            resampler.process(&self.upmixed, &mut self.resampled);
+            &self.resampled
+        };
+        if decoded.len() > out.len() {
+            return Err(Error::Codec(format!(
+                "PCM: {} samples don't fit the {} sample buffer",
+                decoded.len(),
+                out.len()
+            )));
+        }
+        out[..decoded.len()].copy_from_slice(decoded);
+        Ok(decoded.len() / CHANNELS)
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        // only the resampler remembers anything
+        self.resampler = None;
+        Ok(())
+    }
+}
+
+/// Stands in for a codec this build can't code, everything fails with why.
+pub struct Unavailable(pub Error);
+
+impl AudioCodec for Unavailable {
+    fn encode(&mut self, _pcm: &[f32], _out: &mut [u8]) -> Result<usize, Error> {
+        Err(self.0.clone())
+    }
+
+    fn decode(&mut self, _packet: &[u8], _out: &mut [f32], _fec: bool) -> Result<usize, Error> {
+        Err(self.0.clone())
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Codec2 in the room's mode of `bits` per second.
+#[cfg(feature = "codec2")]
+pub fn codec2(bits: Option<u32>) -> Box<dyn AudioCodec> {
+    match codec2::Codec2Codec::new(bits.unwrap_or(CODEC2_MODES[0])) {
+        Ok(codec) => Box::new(codec),
+        Err(e) => Box::new(Unavailable(e)),
+    }
+}
+
+/// Stands in for Codec2, which isn't built in.
+#[cfg(not(feature = "codec2"))]
+pub fn codec2(_bits: Option<u32>) -> Box<dyn AudioCodec> {
+    Box::new(Unavailable(Error::Codec(
+        "Codec2 isn't built in, it needs the cargo feature codec2".to_string(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{max_difference, read_fixture};
+
+    #[test]
+    fn pcm_round_trips_and_upsamples_narrowband_rooms() {
+        let wav = read_fixture("sine_440_48k.wav");
+        let frame = &wav.samples[..960 * CHANNELS];
+        let mut encoded = vec![0u8; 960 * CHANNELS * 2];
+        let mut decoded = vec![0f32; 960 * CHANNELS];
+        let mut pcm = PcmCodec::new(SAMPLE_RATE, CHANNELS);
+        let n = pcm.encode(frame, &mut encoded).unwrap();
+        assert_eq!(n, 960 * CHANNELS * 2);
+        assert_eq!(pcm.decode(&encoded[..n], &mut decoded, false).unwrap(), 960);
+        assert!(max_difference(&decoded, frame) < 2.0 / 32767.0);
+        // a lost frame is silence of the length asked for
+        assert_eq!(
+            pcm.decode(&[], &mut decoded[..480 * CHANNELS], false)
+                .unwrap(),
+            480
+        );
+        assert!(decoded[..480 * CHANNELS].iter().all(|s| *s == 0.0));
+
+        // mono at 16kHz comes back as 20ms of stereo at 48kHz
+        let mut narrow = PcmCodec::new(16_000, 1);
+        let mono: Vec<f32> = (0..320).map(|i| (i as f32 / 10.0).sin() / 2.0).collect();
+        let n = narrow.encode(&mono, &mut encoded).unwrap();
+        assert_eq!(n, 640);
+        assert_eq!(
+            narrow.decode(&encoded[..n], &mut decoded, false).unwrap(),
+            960
+        );
+        assert!(
+            decoded
+                .chunks_exact(CHANNELS)
+                .all(|frame| frame[0] == frame[1])
+        );
+        assert!(pcm.encode(frame, &mut encoded[..100]).is_err());
+    }
+}
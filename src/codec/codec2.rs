@@ -0,0 +1,169 @@
+use std::ffi::{c_int, c_short, c_uchar, c_void};
+use std::ptr::NonNull;
+
+use super::{AudioCodec, CODEC2_RATE, PcmCodec};
+use crate::{Error, audio::samples};
+
+// libcodec2's codec2.h
+unsafe extern "C" {
+    fn codec2_create(mode: c_int) -> *mut c_void;
+    fn codec2_destroy(state: *mut c_void);
+    fn codec2_encode(state: *mut c_void, bytes: *mut c_uchar, speech_in: *mut c_short);
+    fn codec2_decode(state: *mut c_void, speech_out: *mut c_short, bytes: *const c_uchar);
+    fn codec2_samples_per_frame(state: *mut c_void) -> c_int;
+    fn codec2_bytes_per_frame(state: *mut c_void) -> c_int;
+}
+
+/// The number codec2.h gives the mode of `bits` per second.
+fn mode(bits: u32) -> Option<c_int> {
+    match bits {
+        3200 => Some(0),
+        2400 => Some(1),
+        1600 => Some(2),
+        1400 => Some(3),
+        1300 => Some(4),
+        1200 => Some(5),
+        700 => Some(8),
+        _ => None,
+    }
+}
+
+/// The state of one encoder or decoder in libcodec2.
+struct State(NonNull<c_void>);
+
+// only ever used by the thread that owns the codec
+unsafe impl Send for State {}
+
+impl State {
+    fn new(bits: u32) -> Result<Self, Error> {
+        let mode =
+            mode(bits).ok_or_else(|| Error::Codec(format!("Codec2: no {}bit/s mode", bits)))?;
+        let state = unsafe { codec2_create(mode) };
+        NonNull::new(state)
+            .map(State)
+            .ok_or_else(|| Error::Codec("Codec2: can't create the codec".to_string()))
+    }
+
+    fn samples_per_frame(&self) -> usize {
+        unsafe { codec2_samples_per_frame(self.0.as_ptr()) as usize }
+    }
+
+    fn bytes_per_frame(&self) -> usize {
+        unsafe { codec2_bytes_per_frame(self.0.as_ptr()) as usize }
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        unsafe { codec2_destroy(self.0.as_ptr()) }
+    }
+}
+
+/// Codec2 at 8kHz mono, a few hundred bytes a second for links too thin for
+/// Opus, e.g. packet radio. It codes 20 or 40ms at a time depending on the
+/// mode, frames have to be a multiple of that. It can't conceal a lost
+/// packet, that is played as silence.
+pub struct Codec2Codec {
+    bits: u32,
+    state: State,
+    speech: Vec<c_short>,
+    // the decoded speech goes through the PCM decoder, which upmixes it and
+    // brings it to 48kHz
+    pcm: PcmCodec,
+    decoded: Vec<u8>,
+}
+
+impl Codec2Codec {
+    pub fn new(bits: u32) -> Result<Self, Error> {
+        let state = State::new(bits)?;
+        Ok(Codec2Codec {
+            bits,
+            speech: vec![0; state.samples_per_frame()],
+            state,
+            pcm: PcmCodec::new(CODEC2_RATE, 1),
+            decoded: Vec::new(),
+        })
+    }
+}
+
+impl AudioCodec for Codec2Codec {
+    fn encode(&mut self, pcm: &[f32], out: &mut [u8]) -> Result<usize, Error> {
+        let samples = self.state.samples_per_frame();
+        let bytes = self.state.bytes_per_frame();
+        if !pcm.len().is_multiple_of(samples) {
+            return Err(Error::Codec(format!(
+                "Codec2: {} samples aren't whole frames of {}",
+                pcm.len(),
+                samples
+            )));
+        }
+        let len = pcm.len() / samples * bytes;
+        if len > out.len() {
+            return Err(Error::Codec(format!(
+                "Codec2: {} bytes don't fit a {} byte packet",
+                len,
+                out.len()
+            )));
+        }
+        for (frame, out) in pcm.chunks_exact(samples).zip(out.chunks_exact_mut(bytes)) {
+            for (speech, sample) in self.speech.iter_mut().zip(frame) {
+                *speech = samples::to_i16(*sample);
+            }
+            unsafe {
+                codec2_encode(
+                    self.state.0.as_ptr(),
+                    out.as_mut_ptr(),
+                    self.speech.as_mut_ptr(),
+                )
+            };
+        }
+        Ok(len)
+    }
+
+    fn decode(&mut self, packet: &[u8], out: &mut [f32], fec: bool) -> Result<usize, Error> {
+        if packet.is_empty() || fec {
+            return self.pcm.decode(&[], out, fec);
+        }
+        self.decoded.clear();
+        for frame in packet.chunks_exact(self.state.bytes_per_frame()) {
+            unsafe {
+                codec2_decode(
+                    self.state.0.as_ptr(),
+                    self.speech.as_mut_ptr(),
+                    frame.as_ptr(),
+                )
+            };
+            self.decoded
+                .extend(self.speech.iter().flat_map(|sample| sample.to_le_bytes()));
+        }
+        self.pcm.decode(&self.decoded, out, false)
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        // libcodec2 has no reset, a new state starts afresh
+        self.state = State::new(self.bits)?;
+        self.pcm.reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CHANNELS, SAMPLE_RATE, codec::CODEC2_MODES};
+
+    #[test]
+    fn codes_whole_frames_and_decodes_to_the_mix_rate() {
+        let mut encoder = Codec2Codec::new(3200).unwrap();
+        let mut decoder = Codec2Codec::new(3200).unwrap();
+        // 20ms, one frame of 8 bytes at 3200bit/s
+        let speech: Vec<f32> = (0..160).map(|i| (i as f32 / 10.0).sin() / 2.0).collect();
+        let mut packet = [0u8; 64];
+        assert_eq!(encoder.encode(&speech, &mut packet).unwrap(), 8);
+        assert!(encoder.encode(&speech[..100], &mut packet).is_err());
+        let mut out = vec![0f32; 960 * CHANNELS];
+        let frames = decoder.decode(&packet[..8], &mut out, false).unwrap();
+        assert_eq!(frames, (160 * SAMPLE_RATE / CODEC2_RATE) as usize);
+        assert!(Codec2Codec::new(3000).is_err());
+        assert!(CODEC2_MODES.iter().all(|bits| mode(*bits).is_some()));
+    }
+}
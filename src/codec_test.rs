@@ -0,0 +1,202 @@
+use std::f32::consts::PI;
+
+use opus::Bitrate;
+
+use crate::{
+    CHANNELS, Error, SAMPLE_RATE,
+    audio::{opus_decoder, opus_encoder},
+    music::{FileTrack, TrackSource},
+    settings::AudioSettings,
+};
+
+// the bitrates at the default 20ms, then the frame sizes at a bitrate low
+// enough for them to make an audible difference
+const LADDER: [(i32, usize); 9] = [
+    (12_000, 960),
+    (16_000, 960),
+    (24_000, 960),
+    (32_000, 960),
+    (64_000, 960),
+    (128_000, 960),
+    (24_000, 480),
+    (24_000, 1920),
+    (24_000, 2880),
+];
+// how much of a file is played per step
+const MAX_SAMPLE_FRAMES: usize = SAMPLE_RATE as usize * 8;
+// silence between the steps, so they are easy to tell apart
+const GAP_FRAMES: usize = SAMPLE_RATE as usize * 7 / 10;
+// what opus recommends as the output buffer for a packet
+const MAX_PACKET: usize = 4000;
+
+/// Plays `file`, or a built-in sample of voice and music, through opus at
+/// each step of the ladder, so the settings can be judged by ear without
+/// joining a call. Everything else, e.g. the application and bitrate mode,
+/// is taken from `settings`.
+pub fn run(settings: &AudioSettings, file: Option<&str>) -> Result<(), Error> {
+    let sample = match file {
+        Some(path) => read_sample(path)?,
+        None => voice_and_music(),
+    };
+    let seconds = sample.len() as f32 / (SAMPLE_RATE as usize * CHANNELS) as f32;
+    let mut consumer = settings.backend.playback(settings)?;
+    let gap = vec![0.0; GAP_FRAMES * CHANNELS];
+    for (i, &(bitrate, frame_size)) in LADDER.iter().enumerate() {
+        let (decoded, bytes) = round_trip(&sample, bitrate, frame_size, settings)?;
+        println!(
+            "{}/{}: {} kbps with {}ms frames, {:.1} kbps used",
+            i + 1,
+            LADDER.len(),
+            bitrate / 1000,
+            frame_size * 1000 / SAMPLE_RATE as usize,
+            bytes as f32 * 8.0 / seconds / 1000.0
+        );
+        for frame in decoded.chunks(settings.frame_size * CHANNELS) {
+            consumer.consume(frame)?;
+        }
+        consumer.consume(&gap)?;
+    }
+    Ok(())
+}
+
+/// Encodes and decodes `sample` in frames of `frame_size`, returning what the
+/// listener would hear and the size of the packets.
+fn round_trip(
+    sample: &[f32],
+    bitrate: i32,
+    frame_size: usize,
+    settings: &AudioSettings,
+) -> Result<(Vec<f32>, usize), Error> {
+    let error = |e: opus::Error| Error::Codec(format!("Opus: {}", e));
+    let mut encoder = opus_encoder(&AudioSettings {
+        bitrate: Bitrate::Bits(bitrate),
+        ..settings.clone()
+    });
+    let mut decoder = opus_decoder();
+    let mut frame = vec![0f32; frame_size * CHANNELS];
+    let mut packet = vec![0u8; MAX_PACKET];
+    let mut out = vec![0f32; frame_size * CHANNELS];
+    let mut decoded = Vec::with_capacity(sample.len());
+    let mut bytes = 0;
+    for chunk in sample.chunks(frame_size * CHANNELS) {
+        frame[..chunk.len()].copy_from_slice(chunk);
+        frame[chunk.len()..].fill(0.0);
+        let len = encoder.encode_float(&frame, &mut packet).map_err(error)?;
+        bytes += len;
+        let n = decoder
+            .decode_float(&packet[..len], &mut out, false)
+            .map_err(error)?;
+        decoded.extend_from_slice(&out[..n * CHANNELS]);
+    }
+    Ok((decoded, bytes))
+}
+
+fn read_sample(path: &str) -> Result<Vec<f32>, Error> {
+    let mut track = FileTrack::open(path)?;
+    let mut sample = Vec::new();
+    while sample.len() < MAX_SAMPLE_FRAMES * CHANNELS && track.read(&mut sample) {}
+    sample.truncate(MAX_SAMPLE_FRAMES * CHANNELS);
+    Ok(sample)
+}
+
+/// A few seconds of synthetic voice followed by music, enough to hear what
+/// each step does to both without shipping a recording.
+fn voice_and_music() -> Vec<f32> {
+    let rate = SAMPLE_RATE as f32;
+    let mut samples = Vec::new();
+
+    // sung vowels: a harmonic series with a slow pitch glide and vibrato,
+    // shaped by the vowel's first two formants
+    const VOWELS: [(f32, f32); 4] = [
+        (730.0, 1090.0),
+        (270.0, 2290.0),
+        (530.0, 1840.0),
+        (300.0, 870.0),
+    ];
+    let vowel_len = SAMPLE_RATE as usize / 2;
+    let mut phase = 0.0f32;
+    for (v, (f1, f2)) in VOWELS.iter().enumerate() {
+        for i in 0..vowel_len {
+            let t = i as f32 / vowel_len as f32;
+            let vibrato = 3.0 * (2.0 * PI * 5.0 * i as f32 / rate).sin();
+            let pitch = 110.0 + 30.0 * (v as f32 + t) / VOWELS.len() as f32 + vibrato;
+            phase = (phase + pitch / rate).fract();
+            let voice: f32 = (1..)
+                .map(|k| k as f32)
+                .take_while(|k| k * pitch < 8000.0)
+                .map(|k| {
+                    let gain =
+                        resonance(k * pitch, *f1, 90.0) + 0.5 * resonance(k * pitch, *f2, 120.0);
+                    gain / k * (2.0 * PI * k * phase).sin()
+                })
+                .sum();
+            let envelope = (PI * t).sin().sqrt();
+            samples.extend(std::iter::repeat_n(envelope * voice, CHANNELS));
+        }
+    }
+
+    // a plucked chord spread across the stereo field, and a hi-hat
+    let music_len = SAMPLE_RATE as usize * 2;
+    let mut music = vec![0.0f32; music_len * CHANNELS];
+    for (n, freq) in [220.0, 277.18, 329.63, 440.0].into_iter().enumerate() {
+        let onset = n * SAMPLE_RATE as usize / 10;
+        let pan = n as f32 / 3.0;
+        for i in 0..music_len - onset {
+            let t = i as f32 / rate;
+            let note: f32 = (1..=8)
+                .map(|k| k as f32)
+                .map(|k| (-t * (2.0 + k)).exp() / k * (2.0 * PI * k * freq * t).sin())
+                .sum();
+            let frame = (onset + i) * CHANNELS;
+            music[frame] += (1.0 - pan) * note;
+            music[frame + 1] += pan * note;
+        }
+    }
+    let mut noise = 0x2545_f491u32;
+    let mut last = 0.0;
+    for hit in (0..music_len).step_by(SAMPLE_RATE as usize / 4) {
+        for i in 0..(music_len - hit).min(SAMPLE_RATE as usize / 20) {
+            // xorshift, differenced to leave only the highs
+            noise ^= noise << 13;
+            noise ^= noise >> 17;
+            noise ^= noise << 5;
+            let white = noise as f32 / u32::MAX as f32 - 0.5;
+            let hat = (white - last) * (-(i as f32) / (rate / 100.0)).exp();
+            last = white;
+            for sample in &mut music[(hit + i) * CHANNELS..(hit + i + 1) * CHANNELS] {
+                *sample += 0.5 * hat;
+            }
+        }
+    }
+    samples.extend(music);
+
+    let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+    samples.iter_mut().for_each(|s| *s *= 0.5 / peak);
+    samples
+}
+
+/// Gain of a formant at `freq`, 1 at its centre.
+fn resonance(freq: f32, centre: f32, width: f32) -> f32 {
+    1.0 / (1.0 + ((freq - centre) / width).powi(2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_sample_is_stereo_and_leaves_headroom() {
+        let sample = voice_and_music();
+        assert_eq!(sample.len(), SAMPLE_RATE as usize * 4 * CHANNELS);
+        let peak = sample.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!((peak - 0.5).abs() < 1e-3);
+        // the voice sits in the middle, the chord doesn't
+        let (voice, music) = sample.split_at(sample.len() / 2);
+        assert!(voice.chunks_exact(CHANNELS).all(|f| f[0] == f[1]));
+        assert!(
+            music
+                .chunks_exact(CHANNELS)
+                .any(|f| (f[0] - f[1]).abs() > 0.1)
+        );
+    }
+}
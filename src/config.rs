@@ -0,0 +1,410 @@
+use std::{collections::BTreeMap, fmt, fs, ops::RangeInclusive, path::Path};
+
+use opus::Bitrate;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as _, ser::SerializeMap};
+
+use crate::{Error, implementations, settings::AudioSettings};
+
+/// The TUI's rebindable keys by action, with the key each has by default.
+pub const ACTIONS: [(&str, char); 14] = [
+    ("mute", 'm'),
+    ("deafen", 'd'),
+    ("hold_mute", 'c'),
+    ("push_to_talk", ' '),
+    ("voice_message", 'v'),
+    ("play_voice_message", 'p'),
+    ("skip_track", 's'),
+    ("vote_next", 'n'),
+    ("pause", 'x'),
+    ("mark", 'k'),
+    ("users", 'u'),
+    ("devices", 'o'),
+    ("reconnect", 'r'),
+    ("quit", 'q'),
+];
+
+/// Keys the user bound to actions instead of their defaults.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Keys {
+    /// default key of the action and the key bound to it
+    bindings: Vec<(char, char)>,
+}
+
+impl Keys {
+    /// The key the TUI handles for `key`: the default of the action bound to
+    /// it, or none if it is the default of an action bound elsewhere.
+    pub fn translate(&self, key: char) -> Option<char> {
+        let key = key.to_ascii_lowercase();
+        if let Some((default, _)) = self.bindings.iter().find(|(_, bound)| *bound == key) {
+            return Some(*default);
+        }
+        match self.bindings.iter().any(|(default, _)| *default == key) {
+            true => None,
+            false => Some(key),
+        }
+    }
+
+    /// The key that does what `default` does by default, as shown in the TUI.
+    pub fn label(&self, default: char) -> String {
+        let key = self
+            .bindings
+            .iter()
+            .find(|(action, _)| *action == default)
+            .map_or(default, |(_, bound)| *bound);
+        match key {
+            ' ' => "Space".to_string(),
+            key => key.to_ascii_uppercase().to_string(),
+        }
+    }
+
+    fn bind(&mut self, action: &str, key: char) -> Result<(), String> {
+        let Some((_, default)) = ACTIONS.iter().find(|(name, _)| *name == action) else {
+            return Err(format!("no action {}", action));
+        };
+        let key = key.to_ascii_lowercase();
+        self.bindings.retain(|(action, _)| action != default);
+        // an action keeps its default key unless another one took it
+        if key == *default {
+            return Ok(());
+        }
+        if self.bindings.iter().any(|(_, bound)| *bound == key) {
+            return Err(format!("{:?} is bound twice", key));
+        }
+        self.bindings.push((*default, key));
+        Ok(())
+    }
+}
+
+/// Settings from `~/.config/kop-audio/config.toml`, applied before the
+/// command line so its flags win.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// servers to join, the first one unless `--ip` names another
+    pub servers: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// name of the audio backend, see `--backend`
+    #[serde(deserialize_with = "backend", skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    #[serde(with = "bitrate", skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<Bitrate>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mics: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_device: Option<String>,
+    #[serde(
+        deserialize_with = "vad_threshold",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub vad_threshold: Option<f32>,
+    #[serde(
+        deserialize_with = "vad_hangover",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub vad_hangover: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub push_to_talk: Option<bool>,
+    /// in percent, see `--stereo-width`
+    #[serde(
+        deserialize_with = "stereo_width",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub stereo_width: Option<u32>,
+    #[serde(
+        deserialize_with = "output_ceiling",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub output_ceiling: Option<f32>,
+    pub keys: Keys,
+}
+
+impl Config {
+    /// Reads the config at `path`, a missing file is an empty config.
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        match fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(Error::Io(format!("Can't read {}: {}", path.display(), e))),
+        }
+    }
+
+    fn parse(text: &str) -> Result<Config, Error> {
+        toml::from_str(text).map_err(|e| Error::Invalid(format!("Invalid config: {}", e)))
+    }
+
+    /// Sets what the config has in `settings` and returns the server to join.
+    pub fn apply(&self, settings: &mut AudioSettings) -> Option<String> {
+        if let Some(name) = &self.name {
+            settings.name = name.clone();
+        }
+        if let Some(backend) = self.backend.as_deref().and_then(implementations::backend) {
+            settings.backend = backend;
+        }
+        if let Some(bitrate) = self.bitrate {
+            settings.bitrate = bitrate;
+        }
+        if !self.mics.is_empty() {
+            settings.mics = self.mics.clone();
+        }
+        if let Some(device) = &self.output_device {
+            settings.output_device = Some(device.clone());
+        }
+        if let Some(db) = self.vad_threshold {
+            settings.vad_threshold_db = db;
+        }
+        if let Some(frames) = self.vad_hangover {
+            settings.vad_hangover = frames;
+        }
+        if let Some(on) = self.push_to_talk {
+            settings.push_to_talk = on;
+        }
+        if let Some(percent) = self.stereo_width {
+            settings.stereo_width = percent as f32 / 100.0;
+        }
+        if let Some(db) = self.output_ceiling {
+            settings.output_ceiling_db = db;
+        }
+        self.servers.first().cloned()
+    }
+
+    /// The config as it is in effect with the command line, `server` first
+    /// among the servers.
+    pub fn effective(&self, server: &str, settings: &AudioSettings) -> Config {
+        let mut servers = vec![server.to_string()];
+        servers.extend(self.servers.iter().filter(|s| *s != server).cloned());
+        Config {
+            servers,
+            name: Some(settings.name.clone()),
+            backend: Some(settings.backend.name().to_string()),
+            bitrate: Some(settings.bitrate),
+            mics: settings.mics.clone(),
+            output_device: settings.output_device.clone(),
+            vad_threshold: Some(settings.vad_threshold_db),
+            vad_hangover: Some(settings.vad_hangover),
+            push_to_talk: Some(settings.push_to_talk),
+            stereo_width: Some((settings.stereo_width * 100.0).round() as u32),
+            output_ceiling: Some(settings.output_ceiling_db),
+            keys: self.keys.clone(),
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        // nothing in it that TOML can't hold
+        toml::to_string(self).unwrap()
+    }
+}
+
+/// `[keys]` lists every action with the key that does it.
+impl Serialize for Keys {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(ACTIONS.len()))?;
+        for (action, default) in ACTIONS {
+            let key = self
+                .bindings
+                .iter()
+                .find(|(bound, _)| *bound == default)
+                .map_or(default, |(_, key)| *key);
+            map.serialize_entry(action, &key.to_string())?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Keys {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut keys = Keys::default();
+        for (action, Key(key)) in BTreeMap::<String, Key>::deserialize(deserializer)? {
+            keys.bind(&action, key).map_err(D::Error::custom)?;
+        }
+        Ok(keys)
+    }
+}
+
+/// A key in `[keys]`, a single character.
+struct Key(char);
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        let mut chars = key.chars();
+        match (chars.next(), chars.next()) {
+            (Some(key), None) => Ok(Key(key)),
+            _ => Err(D::Error::custom(format!("{:?} isn't a single key", key))),
+        }
+    }
+}
+
+/// `bitrate` in kbit/s, or "auto" or "max" like `--bitrate`.
+mod bitrate {
+    use opus::Bitrate;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error as _};
+
+    use crate::settings::parse_bitrate;
+
+    pub fn serialize<S: Serializer>(
+        bitrate: &Option<Bitrate>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bitrate {
+            Some(Bitrate::Bits(bits)) => serializer.serialize_i32(bits / 1000),
+            Some(Bitrate::Auto) => serializer.serialize_str("auto"),
+            Some(Bitrate::Max) => serializer.serialize_str("max"),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Bitrate>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Setting {
+            Kbps(i64),
+            Named(String),
+        }
+        let bitrate = match Setting::deserialize(deserializer)? {
+            Setting::Kbps(kbps) => kbps.to_string(),
+            Setting::Named(name) => name,
+        };
+        match parse_bitrate(&bitrate) {
+            Some(bitrate) => Ok(Some(bitrate)),
+            None => Err(D::Error::custom(format!("no bitrate {}", bitrate))),
+        }
+    }
+}
+
+fn backend<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    match implementations::backend(&name) {
+        Some(_) => Ok(Some(name)),
+        None => Err(D::Error::custom(format!("no backend {}", name))),
+    }
+}
+
+/// A number that has to be within `range`.
+fn within<'de, D, T>(deserializer: D, range: RangeInclusive<T>) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + PartialOrd + fmt::Display,
+{
+    let value = T::deserialize(deserializer)?;
+    if !range.contains(&value) {
+        return Err(D::Error::custom(format!(
+            "{} isn't within {} and {}",
+            value,
+            range.start(),
+            range.end()
+        )));
+    }
+    Ok(Some(value))
+}
+
+fn vad_threshold<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f32>, D::Error> {
+    within(deserializer, -80.0..=-10.0)
+}
+
+fn vad_hangover<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    within(deserializer, 0..=100)
+}
+
+fn stereo_width<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    within(deserializer, 0..=100)
+}
+
+fn output_ceiling<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f32>, D::Error> {
+    within(deserializer, -30.0..=0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_overrides_the_file() {
+        let config = Config::parse(
+            r#"
+            # where we usually talk
+            servers = ["home.example:1234", "work.example:1234"]
+            name = "Kop \"the\" Audio"
+            bitrate = 64
+            vad_threshold = -50 # a quiet room
+
+            [keys]
+            mute = "t"
+            "#,
+        )
+        .unwrap();
+        let mut settings = AudioSettings::default();
+        assert_eq!(
+            config.apply(&mut settings).as_deref(),
+            Some("home.example:1234")
+        );
+        assert_eq!(settings.name, "Kop \"the\" Audio");
+        assert_eq!(settings.bitrate, Bitrate::Bits(64000));
+        assert_eq!(settings.vad_threshold_db, -50.0);
+        // as if --ip and --bitrate auto followed
+        settings.bitrate = Bitrate::Auto;
+        let effective = config.effective("work.example:1234", &settings);
+        assert_eq!(
+            effective.servers,
+            ["work.example:1234", "home.example:1234"]
+        );
+        let written = Config::parse(&effective.to_toml()).unwrap();
+        assert_eq!(written, effective);
+        assert_eq!(written.bitrate, Some(Bitrate::Auto));
+    }
+
+    #[test]
+    fn rebinds_keys() {
+        let mut keys = Keys::default();
+        keys.bind("mute", 't').unwrap();
+        keys.bind("push_to_talk", 'g').unwrap();
+        assert_eq!(keys.translate('T'), Some('m'));
+        assert_eq!(keys.translate('m'), None);
+        assert_eq!(keys.translate('g'), Some(' '));
+        assert_eq!(keys.translate('d'), Some('d'));
+        assert_eq!(keys.label('m'), "T");
+        assert_eq!(keys.label(' '), "G");
+        assert!(keys.bind("deafen", 't').is_err());
+        assert!(keys.bind("dance", 'z').is_err());
+    }
+
+    #[test]
+    fn reads_any_toml() {
+        let config = Config::parse(
+            r#"
+            "name" = """\
+            Kop\tAudio"""
+            mics = [
+                'alsa_input.usb # the good one',
+            ]
+            keys = { mute = "t", "push_to_talk" = "\u0067" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.name.as_deref(), Some("Kop\tAudio"));
+        assert_eq!(config.mics, ["alsa_input.usb # the good one"]);
+        assert_eq!(config.keys.translate('t'), Some('m'));
+        assert_eq!(config.keys.translate('g'), Some(' '));
+    }
+
+    #[test]
+    fn tells_where_the_file_is_wrong() {
+        for (text, line) in [
+            ("name = \"me\"\nbitrate = 2", 2),
+            ("[audio]", 1),
+            ("\n\nvad_hangover = 3.5", 3),
+            ("servers = [\"a\" \"b\"]", 1),
+            ("[keys]\nmute = \"mm\"", 2),
+        ] {
+            let e = Config::parse(text).unwrap_err();
+            assert!(
+                e.to_string().contains(&format!("line {}", line)),
+                "{}: {}",
+                text,
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::time::Duration;
+
+use bincode::{Decode, Encode};
+use log::{debug, warn};
+use tokio::time::Instant;
+
+use crate::{Error, bus::EventBus, client::ClientMessage, error::Recovery, session::Session};
+
+// a lost connection is tried again after this long, twice as long each time
+// it fails again up to the maximum
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+// restarts in a row for a failing device or codec before leaving the call
+const MAX_RETRIES: u32 = 3;
+
+/// Where the connection to the server is at. Every change is published on
+/// the events topic, so frontends don't have to work it out themselves.
+#[derive(Encode, Decode, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// no session, until asked to connect
+    #[default]
+    Disconnected,
+    /// the session runs, the server hasn't answered the hello yet
+    Connecting,
+    Connected,
+    /// the session failed and is started again after a pause
+    Reconnecting,
+    /// quitting, nothing is started any more
+    ShuttingDown,
+}
+
+/// What the user can ask of the connection, from the TUI or the control
+/// socket.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionCommand {
+    Connect,
+    /// leaves the call but keeps the client running
+    Disconnect,
+    /// starts the session afresh, e.g. after switching networks
+    Reconnect,
+}
+
+/// Owns the session and takes it through the states of the connection. The
+/// coordinator hands it what happens and greets the server whenever a start
+/// returns true.
+pub struct Connection {
+    session: Session,
+    bus: EventBus,
+    state: ConnectionState,
+    /// when a failed session starts again
+    restart_at: Option<Instant>,
+    reconnect_delay: Duration,
+    retries: u32,
+}
+
+impl Connection {
+    pub fn new(session: Session, bus: EventBus) -> Self {
+        Connection {
+            session,
+            bus,
+            state: ConnectionState::Disconnected,
+            restart_at: None,
+            reconnect_delay: RECONNECT_DELAY,
+            retries: 0,
+        }
+    }
+
+    pub fn restart_at(&self) -> Option<Instant> {
+        self.restart_at
+    }
+
+    /// Starts the session, true if it runs and the server is to be greeted.
+    pub async fn start(&mut self) -> bool {
+        if self.state == ConnectionState::ShuttingDown {
+            return false;
+        }
+        self.restart_at = None;
+        match self.session.start().await {
+            Ok(()) => {
+                self.set(ConnectionState::Connecting);
+                true
+            }
+            Err(e) => {
+                let announcement = self.fail(e);
+                self.bus.events.publish(ClientMessage::Announcement(announcement));
+                false
+            }
+        }
+    }
+
+    /// Closes the audio devices, see `Session::suspend_audio`.
+    pub fn suspend_audio(&mut self) {
+        self.session.suspend_audio();
+    }
+
+    /// Opens the audio devices again, true if the audio tasks started. A
+    /// device that can't be opened fails the session like it would on start.
+    pub fn resume_audio(&mut self) -> bool {
+        match self.session.resume_audio() {
+            Ok(started) => started,
+            Err(e) => {
+                let announcement = self.fail(e);
+                self.bus.events.publish(ClientMessage::Announcement(announcement));
+                false
+            }
+        }
+    }
+
+    /// Opens `device` from now on when the audio starts, see `Session::select_input`.
+    pub fn select_input(&mut self, device: &str) {
+        self.session.select_input(device);
+    }
+
+    pub fn select_output(&mut self, device: &str) {
+        self.session.select_output(device);
+    }
+
+    /// The server answered the hello.
+    pub fn connected(&mut self) {
+        if self.state != ConnectionState::Connecting {
+            return;
+        }
+        self.reconnect_delay = RECONNECT_DELAY;
+        self.retries = 0;
+        self.set(ConnectionState::Connected);
+    }
+
+    /// Stops the failed session and decides whether and when it starts
+    /// again. Returns what to tell the user.
+    pub fn fail(&mut self, e: Error) -> String {
+        self.session.stop();
+        if self.state == ConnectionState::ShuttingDown {
+            return e.to_string();
+        }
+        let recovery = match e.recovery() {
+            Recovery::Retry if self.retries >= MAX_RETRIES => Recovery::Exit,
+            recovery => recovery,
+        };
+        warn!("Session failed with {:?}, {:?}", e, recovery);
+        match recovery {
+            Recovery::Retry => {
+                self.retries += 1;
+                self.restart_at = Some(Instant::now());
+                self.set(ConnectionState::Reconnecting);
+                format!("{}, starting again", e)
+            }
+            Recovery::Reconnect => {
+                self.restart_at = Some(Instant::now() + self.reconnect_delay);
+                self.set(ConnectionState::Reconnecting);
+                let announcement =
+                    format!("{}, reconnecting in {}s", e, self.reconnect_delay.as_secs());
+                self.reconnect_delay = (self.reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                announcement
+            }
+            Recovery::Exit => {
+                self.restart_at = None;
+                self.set(ConnectionState::Disconnected);
+                e.to_string()
+            }
+        }
+    }
+
+    /// Does what the user asked for, true if the session started and the
+    /// server is to be greeted.
+    pub async fn command(&mut self, command: ConnectionCommand) -> bool {
+        use ConnectionState::*;
+        match (self.state, command) {
+            (ShuttingDown, _) | (Connecting | Connected, ConnectionCommand::Connect) => false,
+            (Disconnected, ConnectionCommand::Disconnect) => false,
+            (_, ConnectionCommand::Disconnect) => {
+                self.stop();
+                self.set(Disconnected);
+                false
+            }
+            // right away rather than when a pending restart is due
+            (Disconnected | Reconnecting, ConnectionCommand::Connect)
+            | (_, ConnectionCommand::Reconnect) => {
+                self.stop();
+                self.start().await
+            }
+        }
+    }
+
+    /// Says bye and waits for the session to wind down, see `Session::shutdown`.
+    pub async fn shut_down(&mut self) {
+        self.set(ConnectionState::ShuttingDown);
+        self.restart_at = None;
+        self.bus.shutdown.trigger();
+        self.session.shutdown().await;
+    }
+
+    /// Stops the session for good, a user's choice rather than a failure.
+    fn stop(&mut self) {
+        self.session.stop();
+        self.restart_at = None;
+        self.reconnect_delay = RECONNECT_DELAY;
+        self.retries = 0;
+    }
+
+    fn set(&mut self, state: ConnectionState) {
+        if self.state == state {
+            return;
+        }
+        debug!("Connection {:?} -> {:?}", self.state, state);
+        self.state = state;
+        self.bus.events.publish(ClientMessage::ConnectionState(state));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::AudioSettings;
+
+    #[tokio::test]
+    async fn reconnects_until_told_otherwise() {
+        let bus = EventBus::new();
+        let mut events = bus.events.subscribe();
+        // fails to resolve without touching the network
+        let session = Session::new(String::new(), AudioSettings::default(), bus.clone());
+        let mut connection = Connection::new(session, bus);
+        assert!(!connection.start().await);
+        assert!(connection.restart_at().is_some());
+        assert!(!connection.command(ConnectionCommand::Disconnect).await);
+        assert_eq!(connection.restart_at(), None);
+        connection.shut_down().await;
+        assert!(!connection.command(ConnectionCommand::Connect).await);
+
+        let mut states = Vec::new();
+        while let Some(msg) = events.try_recv() {
+            if let ClientMessage::ConnectionState(state) = msg {
+                states.push(state);
+            }
+        }
+        use ConnectionState::*;
+        assert_eq!(states, [Reconnecting, Disconnected, ShuttingDown]);
+    }
+
+    #[tokio::test]
+    async fn starts_without_the_devices_while_they_are_released() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let settings = AudioSettings {
+            encrypt: false,
+            ..AudioSettings::default()
+        };
+        let bus = EventBus::new();
+        let session = Session::new(server.local_addr().unwrap().to_string(), settings, bus.clone());
+        let mut connection = Connection::new(session, bus);
+        connection.suspend_audio();
+        // opening the devices would fail the start where there are none
+        assert!(connection.start().await);
+        assert!(connection.session.is_running());
+        connection.command(ConnectionCommand::Disconnect).await;
+        assert!(!connection.resume_audio());
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    fmt::Write as _,
+    net::{IpAddr, SocketAddr},
+};
+
+use log::info;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    admin::{AdminCommand, ClientStatus, ServerStatus},
+    server::SessionId,
+};
+
+const HELP: &str = "Commands: list, kick <id>, mute <id>, unmute <id>, ban <ip>, unban <ip>, \
+                    say <text>. An <id> is the number list shows or the client's address";
+
+/// A line typed into the server console.
+#[derive(Debug, PartialEq)]
+enum Line {
+    List,
+    Kick(ClientRef),
+    Mute(ClientRef, bool),
+    Ban(IpAddr),
+    Unban(IpAddr),
+    Say(String),
+}
+
+/// A client as the console names it, by the id `list` shows or its address.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ClientRef {
+    Session(SessionId),
+    Addr(SocketAddr),
+}
+
+impl ClientRef {
+    fn parse(s: &str) -> Result<Self, String> {
+        s.parse()
+            .map(ClientRef::Session)
+            .or_else(|_| s.parse().map(ClientRef::Addr))
+            .map_err(|_| format!("No client id or address: {}", s))
+    }
+
+    fn matches(self, client: &ClientStatus) -> bool {
+        match self {
+            ClientRef::Session(session) => client.session == session,
+            ClientRef::Addr(addr) => client.addr == addr,
+        }
+    }
+}
+
+/// Reads admin commands from stdin until it closes, see `HELP`. The server
+/// loop carries them out like those of the admin UI.
+pub async fn run_console(commands: mpsc::Sender<AdminCommand>) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    println!("{}", HELP);
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = match parse(&line) {
+            Ok(Some(line)) => line,
+            Ok(None) => continue,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        let (reply, status) = oneshot::channel();
+        if commands.send(AdminCommand::Status(reply)).await.is_err() {
+            return;
+        }
+        let Ok(status) = status.await else {
+            return;
+        };
+        let find = |client: ClientRef| {
+            let found = status.clients.iter().find(|status| client.matches(status));
+            if found.is_none() {
+                println!("Nobody with the id or address {}", describe(client));
+            }
+            found
+        };
+        let command = match line {
+            Line::List => {
+                print!("{}", list(&status));
+                continue;
+            }
+            Line::Kick(client) => match find(client) {
+                Some(client) => {
+                    info!("Console kicked {} ({})", client.name, client.addr);
+                    AdminCommand::Kick(client.addr)
+                }
+                None => continue,
+            },
+            Line::Mute(client, mute) => match find(client) {
+                Some(client) => {
+                    let verb = if mute { "muted" } else { "unmuted" };
+                    info!("Console {} {} ({})", verb, client.name, client.addr);
+                    AdminCommand::Mute(client.addr, mute)
+                }
+                None => continue,
+            },
+            Line::Ban(ip) => {
+                info!("Console banned {}", ip);
+                AdminCommand::Ban(ip)
+            }
+            Line::Unban(ip) => {
+                info!("Console unbanned {}", ip);
+                AdminCommand::Unban(ip)
+            }
+            Line::Say(text) => {
+                info!("Console announced: {}", text);
+                AdminCommand::Announce(text)
+            }
+        };
+        if commands.send(command).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// The command on `line`, `None` for an empty one.
+fn parse(line: &str) -> Result<Option<Line>, String> {
+    let line = line.trim();
+    let (command, arg) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let arg = arg.trim();
+    let ip = |arg: &str| {
+        arg.parse::<IpAddr>()
+            .map_err(|_| format!("Not an IP address: {}", arg))
+    };
+    let line = match (command, arg) {
+        ("", _) => return Ok(None),
+        ("list", _) => Line::List,
+        ("kick", arg) => Line::Kick(ClientRef::parse(arg)?),
+        ("mute", arg) => Line::Mute(ClientRef::parse(arg)?, true),
+        ("unmute", arg) => Line::Mute(ClientRef::parse(arg)?, false),
+        ("ban", arg) => Line::Ban(ip(arg)?),
+        ("unban", arg) => Line::Unban(ip(arg)?),
+        ("say", "") => return Err("Nothing to say".to_string()),
+        ("say", text) => Line::Say(text.to_string()),
+        _ => return Err(HELP.to_string()),
+    };
+    Ok(Some(line))
+}
+
+fn describe(client: ClientRef) -> String {
+    match client {
+        ClientRef::Session(session) => session.to_string(),
+        ClientRef::Addr(addr) => addr.to_string(),
+    }
+}
+
+/// Who is connected, one client per line, and who is banned.
+fn list(status: &ServerStatus) -> String {
+    let mut out = String::new();
+    if status.clients.is_empty() {
+        out.push_str("Nobody is connected\n");
+    }
+    for client in &status.clients {
+        let mut flags = Vec::new();
+        if client.muted {
+            flags.push("muted");
+        }
+        if client.afk {
+            flags.push("AFK");
+        }
+        if !client.audio_sink {
+            flags.push("not listening");
+        }
+        let flags = if flags.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", flags.join(", "))
+        };
+        let _ = writeln!(
+            out,
+            "{:>4}  {:<24}{}{}",
+            client.session, client.addr, client.name, flags
+        );
+    }
+    if !status.banned.is_empty() {
+        let banned: Vec<String> = status.banned.iter().map(IpAddr::to_string).collect();
+        let _ = writeln!(out, "Banned: {}", banned.join(", "));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_clients_by_id_or_address() {
+        assert_eq!(parse("  ").unwrap(), None);
+        assert_eq!(
+            parse("kick 3").unwrap(),
+            Some(Line::Kick(ClientRef::Session(3)))
+        );
+        let addr = "10.0.0.7:41000".parse().unwrap();
+        assert_eq!(
+            parse("unmute 10.0.0.7:41000").unwrap(),
+            Some(Line::Mute(ClientRef::Addr(addr), false))
+        );
+        assert_eq!(
+            parse("ban ::1").unwrap(),
+            Some(Line::Ban("::1".parse().unwrap()))
+        );
+        assert_eq!(
+            parse("say back in  five").unwrap(),
+            Some(Line::Say("back in  five".to_string()))
+        );
+        assert!(parse("ban 10.0.0.7:41000").is_err());
+        assert!(parse("kick alice").is_err());
+        assert!(parse("shutdown").is_err());
+    }
+}
@@ -0,0 +1,560 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use bincode::config;
+use hmac::{Hmac, Mac};
+use log::{debug, error, info, warn};
+use sha2::Sha256;
+
+use crate::{
+    Error,
+    bus::{EventBus, Subscriber},
+    client::ClientMessage,
+    connection::ConnectionState,
+    header::PacketStats,
+    identity::{UserId, config_file, write_private},
+    playlist::{Playlist, TrackInfo},
+    quality::{Grade, LossStats, StreamQuality},
+    server::{ChatMessage, RoomInfo, ServerInfo},
+};
+
+/// Port remote frontends connect to if `--remote` doesn't name one.
+pub const REMOTE_PORT: u16 = 1235;
+// no command comes anywhere near this, anything larger is garbage
+const MAX_FRAME: usize = 64 * 1024;
+// chat lines a newly attached frontend gets to see
+const CHAT_HISTORY: usize = 50;
+// events go out to every frontend in turn, one that can't take a frame for
+// this long has fallen behind and is dropped instead of holding up the rest
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The connection to an attached frontend, events are written to it.
+trait Frontend: Write + Send {
+    /// Ends the connection, also for the thread reading commands from it.
+    fn close(&self);
+}
+
+impl Frontend for UnixStream {
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl Frontend for TcpStream {
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+/// Frontends attached to a running daemon plus the state a newly attached
+/// frontend needs to render the session without waiting for fresh events.
+#[derive(Default)]
+struct ControlState {
+    frontends: Vec<Box<dyn Frontend>>,
+    connection: ConnectionState,
+    muted: bool,
+    deafened: bool,
+    push_to_talk: bool,
+    vad: Option<(f32, u32)>,
+    output_volume: Option<u32>,
+    latency_estimate: Option<u32>,
+    latency: Option<Duration>,
+    grade: Option<Grade>,
+    bitrate: Option<u32>,
+    loss: Option<LossStats>,
+    packet_stats: Option<PacketStats>,
+    room: Option<RoomInfo>,
+    server: Option<ServerInfo>,
+    afk: bool,
+    users: Vec<(SocketAddr, String)>,
+    afk_users: Vec<SocketAddr>,
+    qualities: Vec<(SocketAddr, StreamQuality)>,
+    grades: Vec<(SocketAddr, Grade)>,
+    user_volumes: Vec<(SocketAddr, u32, bool)>,
+    offline_users: Vec<UserId>,
+    recording: bool,
+    voice_messages: Vec<(UserId, u32)>,
+    playlist: Option<Playlist>,
+    now_playing: Option<TrackInfo>,
+    chat: VecDeque<ChatMessage>,
+}
+
+impl ControlState {
+    fn update(&mut self, msg: &ClientMessage) {
+        match msg {
+            ClientMessage::ConnectionState(state) => {
+                self.connection = *state;
+                if *state != ConnectionState::Connected {
+                    self.grade = None;
+                }
+            }
+            ClientMessage::Muted(muted) => self.muted = *muted,
+            ClientMessage::Deafened(deafened) => self.deafened = *deafened,
+            ClientMessage::PushToTalkMode(on) => self.push_to_talk = *on,
+            ClientMessage::Vad(threshold, hangover) => self.vad = Some((*threshold, *hangover)),
+            ClientMessage::OutputVolume(volume) => self.output_volume = Some(*volume),
+            ClientMessage::LatencyEstimate(ms) => self.latency_estimate = Some(*ms),
+            ClientMessage::Latency(rtt) => self.latency = Some(*rtt),
+            ClientMessage::Grade(grade) => self.grade = Some(*grade),
+            ClientMessage::Bitrate(bits) => self.bitrate = Some(*bits),
+            ClientMessage::LossStats(stats) => self.loss = Some(*stats),
+            ClientMessage::PacketStats(stats) => self.packet_stats = Some(*stats),
+            ClientMessage::RoomInfo(room) => self.room = Some(room.clone()),
+            ClientMessage::ServerInfo(server) => self.server = Some(server.clone()),
+            ClientMessage::MovedToAfk(afk) => self.afk = *afk,
+            ClientMessage::OfflineUsers(users) => self.offline_users = users.clone(),
+            ClientMessage::RecordingVoiceMessage(recording) => self.recording = *recording,
+            ClientMessage::VoiceMessages(messages) => self.voice_messages = messages.clone(),
+            ClientMessage::Playlist(playlist) => self.playlist = Some(playlist.clone()),
+            ClientMessage::NowPlaying(info) => self.now_playing = info.clone(),
+            ClientMessage::Chat(chat) => {
+                if self.chat.len() >= CHAT_HISTORY {
+                    self.chat.pop_front();
+                }
+                self.chat.push_back(chat.clone());
+            }
+            ClientMessage::ClientAfk(addr, afk) => {
+                self.afk_users.retain(|user| user != addr);
+                if *afk {
+                    self.afk_users.push(*addr);
+                }
+            }
+            ClientMessage::StreamQuality(addr, quality) => {
+                self.qualities.retain(|(user, _)| user != addr);
+                self.qualities.push((*addr, *quality));
+            }
+            ClientMessage::UserGrade(addr, grade) => {
+                self.grades.retain(|(user, _)| user != addr);
+                self.grades.push((*addr, *grade));
+            }
+            ClientMessage::UserVolume(addr, volume, muted) => {
+                self.user_volumes.retain(|(user, _, _)| user != addr);
+                self.user_volumes.push((*addr, *volume, *muted));
+            }
+            ClientMessage::NewClient(addr, name) => {
+                self.users.retain(|(user, _)| user != addr);
+                self.users.push((*addr, name.clone()));
+            }
+            ClientMessage::DeleteClient(addr) => {
+                self.users.retain(|(user, _)| user != addr);
+                self.afk_users.retain(|user| user != addr);
+                self.qualities.retain(|(user, _)| user != addr);
+                self.grades.retain(|(user, _)| user != addr);
+                self.user_volumes.retain(|(user, _, _)| user != addr);
+            }
+            _ => {}
+        }
+    }
+
+    fn snapshot(&self) -> Vec<ClientMessage> {
+        let mut messages = vec![ClientMessage::ConnectionState(self.connection)];
+        messages.push(ClientMessage::Muted(self.muted));
+        messages.push(ClientMessage::Deafened(self.deafened));
+        messages.push(ClientMessage::PushToTalkMode(self.push_to_talk));
+        if let Some((threshold, hangover)) = self.vad {
+            messages.push(ClientMessage::Vad(threshold, hangover));
+        }
+        if let Some(volume) = self.output_volume {
+            messages.push(ClientMessage::OutputVolume(volume));
+        }
+        if let Some(ms) = self.latency_estimate {
+            messages.push(ClientMessage::LatencyEstimate(ms));
+        }
+        if let Some(rtt) = self.latency {
+            messages.push(ClientMessage::Latency(rtt));
+        }
+        if let Some(grade) = self.grade {
+            messages.push(ClientMessage::Grade(grade));
+        }
+        if let Some(bits) = self.bitrate {
+            messages.push(ClientMessage::Bitrate(bits));
+        }
+        if let Some(stats) = self.loss {
+            messages.push(ClientMessage::LossStats(stats));
+        }
+        if let Some(stats) = self.packet_stats {
+            messages.push(ClientMessage::PacketStats(stats));
+        }
+        if let Some(room) = &self.room {
+            messages.push(ClientMessage::RoomInfo(room.clone()));
+        }
+        if let Some(server) = &self.server {
+            messages.push(ClientMessage::ServerInfo(server.clone()));
+        }
+        if self.afk {
+            messages.push(ClientMessage::MovedToAfk(true));
+        }
+        for (addr, name) in &self.users {
+            messages.push(ClientMessage::NewClient(*addr, name.clone()));
+        }
+        for addr in &self.afk_users {
+            messages.push(ClientMessage::ClientAfk(*addr, true));
+        }
+        for (addr, quality) in &self.qualities {
+            messages.push(ClientMessage::StreamQuality(*addr, *quality));
+        }
+        for (addr, grade) in &self.grades {
+            messages.push(ClientMessage::UserGrade(*addr, *grade));
+        }
+        for (addr, volume, muted) in &self.user_volumes {
+            messages.push(ClientMessage::UserVolume(*addr, *volume, *muted));
+        }
+        messages.push(ClientMessage::OfflineUsers(self.offline_users.clone()));
+        messages.push(ClientMessage::RecordingVoiceMessage(self.recording));
+        messages.push(ClientMessage::VoiceMessages(self.voice_messages.clone()));
+        if let Some(playlist) = &self.playlist {
+            messages.push(ClientMessage::Playlist(playlist.clone()));
+        }
+        if let Some(info) = &self.now_playing {
+            messages.push(ClientMessage::NowPlaying(Some(info.clone())));
+        }
+        messages.extend(self.chat.iter().cloned().map(ClientMessage::Chat));
+        messages
+    }
+}
+
+pub fn socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("kop-audio.sock"),
+        None => PathBuf::from(format!("/tmp/kop-audio-{}.sock", unsafe { libc::getuid() })),
+    }
+}
+
+fn remote_token_path() -> Result<PathBuf, Error> {
+    config_file("remote-token")
+        .ok_or_else(|| Error::Io("No home directory to keep the remote token in".into()))
+}
+
+/// Loads the secret remote frontends authenticate with, creating one on first
+/// use. Copy the file to the device that should control this one.
+pub fn load_or_create_remote_token() -> Result<[u8; 32], Error> {
+    let path = remote_token_path()?;
+    if path.exists() {
+        return load_remote_token();
+    }
+    let token: [u8; 32] = rand::random();
+    write_private(&path, &token)
+        .map_err(|e| Error::Io(format!("Can't write {}: {}", path.display(), e)))?;
+    info!("Created new remote token in {}", path.display());
+    Ok(token)
+}
+
+/// Loads the remote token copied from the daemon's machine. A new one would
+/// never match the daemon's, so a missing file is an error.
+fn load_remote_token() -> Result<[u8; 32], Error> {
+    let path = remote_token_path()?;
+    let data = fs::read(&path).map_err(|e| {
+        Error::Io(format!(
+            "Can't read the remote token {}: {}. Copy the remote-token file from the \
+             config directory of the daemon's machine there",
+            path.display(),
+            e
+        ))
+    })?;
+    <[u8; 32]>::try_from(data.as_slice())
+        .map_err(|_| Error::Invalid(format!("Malformed remote token {}", path.display())))
+}
+
+/// Serves the control socket of a daemon. Events coming from the coordinator
+/// are fanned out to every attached frontend, commands sent by frontends are
+/// forwarded to the coordinator. With `remote` set, frontends on other
+/// machines can attach over TCP after proving they know the remote token.
+/// Only the token is protected, everything after it goes over the wire in
+/// the clear, so this is meant for a trusted LAN.
+pub fn run_control_server(
+    mut events: Subscriber<ClientMessage>,
+    bus: EventBus,
+    remote: Option<String>,
+) -> Result<(), Error> {
+    let path = socket_path();
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            return Err(Error::Io(format!(
+                "A daemon is already listening on {}",
+                path.display()
+            )));
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+    let listener =
+        UnixListener::bind(&path).map_err(|e| Error::Io(e.to_string()))?;
+    info!("Control socket listening on {}", path.display());
+
+    let state = Arc::new(Mutex::new(ControlState::default()));
+    if let Some(addr) = remote {
+        let token = load_or_create_remote_token()?;
+        let remote_listener = TcpListener::bind(&addr).map_err(|e| {
+            Error::Network(format!("Can't listen on {}: {}", addr, e))
+        })?;
+        warn!(
+            "Remote control listening on {}, its traffic isn't encrypted, keep it on a trusted \
+             network",
+            addr
+        );
+        let remote_state = state.clone();
+        let remote_bus = bus.clone();
+        thread::spawn(move || {
+            for stream in remote_listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let state = remote_state.clone();
+                        let bus = remote_bus.clone();
+                        // authenticate off the accept loop so a stalled peer
+                        // can't keep others out
+                        thread::spawn(move || attach_remote_frontend(stream, &token, &state, bus));
+                    }
+                    Err(e) => error!("Error accepting remote control connection: {:?}", e),
+                }
+            }
+        });
+    }
+
+    let accept_state = state.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => match stream
+                    .set_write_timeout(Some(WRITE_TIMEOUT))
+                    .and_then(|_| stream.try_clone())
+                {
+                    Ok(reader) => attach_frontend(
+                        Box::new(stream),
+                        reader,
+                        &accept_state,
+                        bus.clone(),
+                        |_| true,
+                    ),
+                    Err(e) => error!("Error setting up control connection: {:?}", e),
+                },
+                Err(e) => error!("Error accepting control connection: {:?}", e),
+            }
+        }
+    });
+
+    while let Some(msg) = events.blocking_recv() {
+        let frame = encode_frame(&msg);
+        let mut state = state.lock().unwrap();
+        state.update(&msg);
+        state
+            .frontends
+            .retain_mut(|stream| match stream.write_all(&frame) {
+                Ok(_) => true,
+                Err(e) => {
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) {
+                        warn!("Dropping a frontend that fell behind");
+                    }
+                    stream.close();
+                    false
+                }
+            });
+    }
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
+
+/// Commands a remote frontend may send. It only gets the controls of the TUI,
+/// leaving the call stays up to the machine that is in it.
+fn remote_allowed(msg: &ClientMessage) -> bool {
+    matches!(
+        msg,
+        ClientMessage::ToggleMute
+            | ClientMessage::ToggleDeafen
+            | ClientMessage::HoldMute(_)
+            | ClientMessage::PushToTalk(_)
+            | ClientMessage::AddMarker(_, _)
+            | ClientMessage::AdjustVad(_, _)
+            | ClientMessage::AdjustUserVolume(_, _)
+            | ClientMessage::AdjustOutputVolume(_)
+            | ClientMessage::ListDevices
+            | ClientMessage::SelectInput(_)
+            | ClientMessage::SelectOutput(_)
+            | ClientMessage::ToggleUserMute(_)
+            | ClientMessage::RecordVoiceMessage(_)
+            | ClientMessage::PlayVoiceMessage
+            | ClientMessage::PlaylistCommand(_)
+            | ClientMessage::MusicVote(_)
+            | ClientMessage::SendChat(_)
+            | ClientMessage::ChangeBitrate(_)
+    )
+}
+
+fn attach_remote_frontend(
+    mut stream: TcpStream,
+    token: &[u8; 32],
+    state: &Arc<Mutex<ControlState>>,
+    bus: EventBus,
+) {
+    let peer = stream.peer_addr().ok();
+    let challenge: [u8; 32] = rand::random();
+    let mut response = [0u8; 32];
+    let _ = stream.set_read_timeout(Some(std::time::Duration::from_secs(5)));
+    let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+    if stream.write_all(&challenge).is_err() || stream.read_exact(&mut response).is_err() {
+        return;
+    }
+    let mut mac = HmacSha256::new_from_slice(token).unwrap();
+    mac.update(&challenge);
+    if mac.verify_slice(&response).is_err() {
+        warn!("Rejected remote frontend {:?}, wrong token", peer);
+        return;
+    }
+    let _ = stream.set_read_timeout(None);
+    info!("Remote frontend {:?} authenticated", peer);
+    match stream.try_clone() {
+        Ok(reader) => attach_frontend(Box::new(stream), reader, state, bus, remote_allowed),
+        Err(e) => error!("Error cloning remote control connection: {:?}", e),
+    }
+}
+
+fn attach_frontend(
+    mut stream: Box<dyn Frontend>,
+    reader: impl Read + Send + 'static,
+    state: &Arc<Mutex<ControlState>>,
+    bus: EventBus,
+    allowed: fn(&ClientMessage) -> bool,
+) {
+    let mut state = state.lock().unwrap();
+    for msg in state.snapshot() {
+        if stream.write_all(&encode_frame(&msg)).is_err() {
+            return;
+        }
+    }
+    state.frontends.push(stream);
+    info!(
+        "Frontend attached, {} attached in total",
+        state.frontends.len()
+    );
+
+    thread::spawn(move || {
+        let mut reader = reader;
+        while let Some(msg) = read_frame(&mut reader) {
+            if !allowed(&msg) {
+                warn!("Ignoring control command {:?} from this frontend", msg);
+                continue;
+            }
+            debug!("Got control command {:?}", msg);
+            bus.commands.publish(msg);
+        }
+        debug!("Frontend detached");
+    });
+}
+
+/// Connects a frontend to a running daemon, bridging the socket to the given
+/// channels. `Exit` from the frontend only detaches it and leaves the daemon
+/// running.
+pub fn attach(bus: EventBus, commands: Subscriber<ClientMessage>) -> Result<(), Error> {
+    let path = socket_path();
+    let stream = UnixStream::connect(&path).map_err(|e| {
+        Error::Io(format!("Can't attach to {}: {}", path.display(), e))
+    })?;
+    let reader = stream
+        .try_clone()
+        .map_err(|e| Error::Io(e.to_string()))?;
+    bridge(bus, commands, &stream, reader);
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+    Ok(())
+}
+
+/// Like `attach`, but for a daemon on another machine that was started with
+/// `--remote-listen`. Needs the daemon's remote token in the config directory.
+/// The connection isn't encrypted, see `run_control_server`.
+pub fn attach_remote(
+    host: &str,
+    bus: EventBus,
+    commands: Subscriber<ClientMessage>,
+) -> Result<(), Error> {
+    let token = load_remote_token()?;
+    let addr = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, REMOTE_PORT)
+    };
+    let mut stream = TcpStream::connect(&addr).map_err(|e| {
+        Error::Network(format!("Can't connect to {}: {}", addr, e))
+    })?;
+    let mut challenge = [0u8; 32];
+    stream.read_exact(&mut challenge).map_err(|e| {
+        Error::Network(format!("Handshake with {} failed: {}", addr, e))
+    })?;
+    let mut mac = HmacSha256::new_from_slice(&token).unwrap();
+    mac.update(&challenge);
+    stream
+        .write_all(&mac.finalize().into_bytes())
+        .map_err(|e| Error::Network(e.to_string()))?;
+    let reader = stream
+        .try_clone()
+        .map_err(|e| Error::Network(e.to_string()))?;
+    bridge(bus, commands, &stream, reader);
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+    Ok(())
+}
+
+fn bridge(
+    bus: EventBus,
+    mut commands: Subscriber<ClientMessage>,
+    mut stream: impl Write,
+    mut reader: impl Read + Send + 'static,
+) {
+    let events = bus.events.clone();
+    thread::spawn(move || {
+        while let Some(msg) = read_frame(&mut reader) {
+            events.publish(msg);
+        }
+        warn!("Daemon closed the control connection");
+        events.publish(ClientMessage::ConnectionState(ConnectionState::Disconnected));
+    });
+
+    while let Some(msg) = commands.blocking_recv() {
+        if let ClientMessage::Exit = msg {
+            break;
+        }
+        if stream.write_all(&encode_frame(&msg)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Sends a single command to a running daemon, e.g. `Exit` to stop it.
+pub fn send_command(msg: ClientMessage) -> Result<(), Error> {
+    let path = socket_path();
+    let mut stream = UnixStream::connect(&path).map_err(|e| {
+        Error::Io(format!("Can't connect to {}: {}", path.display(), e))
+    })?;
+    stream
+        .write_all(&encode_frame(&msg))
+        .map_err(|e| Error::Io(e.to_string()))
+}
+
+// frames are a little endian u32 length followed by the bincode encoded message
+fn encode_frame(msg: &ClientMessage) -> Vec<u8> {
+    let payload = bincode::encode_to_vec(msg, config::standard()).unwrap();
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+fn read_frame(stream: &mut impl Read) -> Option<ClientMessage> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).ok()?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return None;
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).ok()?;
+    bincode::decode_from_slice(&payload, config::standard())
+        .map(|(msg, _)| msg)
+        .ok()
+}
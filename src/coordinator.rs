@@ -1,73 +1,633 @@
-use std::{net::SocketAddr, sync::mpsc::{Receiver, Sender}};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
-use crate::{client::ClientMessage, server::Message};
+use log::debug;
+use tokio::time::sleep_until;
+
+use crate::{
+    bus::{EventBus, Subscriber},
+    client::ClientMessage,
+    codec::CodecKind,
+    connection::Connection,
+    identity::{IdentityKey, UserId},
+    listen_along::{MusicVote, VoteTally},
+    mailbox::{ASSEMBLY_TIMEOUT, MAX_CLIP_PACKETS},
+    persistence::{SavedSession, save_output_volume},
+    playlist::PlaylistCommand,
+    quality::{
+        BitrateAdapter, HealthReport, LossStats, QualityEstimator, StreamQuality, Watchdog,
+    },
+    server::{Cue, Hello, Message, RoomCodec, VoiceChunk},
+    session::Session,
+    settings::{AudioSettings, step_bitrate},
+};
+
+// how far the voice activity detection can be tuned, from a quiet room to
+// shouting over a fan, and up to 2s of hangover
+const VAD_THRESHOLD_RANGE: (f32, f32) = (-80.0, -10.0);
+const MAX_VAD_HANGOVER: u32 = 100;
+// twice as loud is enough to bring up a quiet microphone
+const MAX_USER_VOLUME: u32 = 200;
+const MAX_OUTPUT_VOLUME: u32 = 200;
+
+// voice messages received at once, chunks of more are dropped until one is done
+const MAX_INCOMING_CLIPS: usize = 16;
+
+/// The packets of a clip received so far, by index.
+type Chunks = BTreeMap<u16, Vec<u8>>;
 
 pub async fn run_coordinator(
-    rx_msg: Receiver<ClientMessage>,
-    tx_playback: Sender<ClientMessage>,
-    tx_record: Sender<ClientMessage>,
-    tx_tui: Sender<ClientMessage>,
-    tx_net_out: Sender<Message>,
-    tx_net_in: Sender<Message>,
+    bus: EventBus,
+    mut commands: Subscriber<ClientMessage>,
+    session: Session,
+    mut saved: SavedSession,
+    settings: AudioSettings,
 ) {
-    tx_net_out.send(Message::Hello("0.0.0.0:0".parse::<SocketAddr>().unwrap())).unwrap();
-    tx_net_out.send(Message::Hello("0.0.0.0:0".parse::<SocketAddr>().unwrap())).unwrap();
-    tx_net_out.send(Message::Hello("0.0.0.0:0".parse::<SocketAddr>().unwrap())).unwrap();
+    let mut connection = Connection::new(session, bus.clone());
+    // the capture also streams the listen-along music we host, so it stays
+    let release_devices = settings.release_devices
+        && !(settings.play_queue || (settings.listen_along && settings.share_app.is_some()));
+    if release_devices && saved.muted && saved.deafened {
+        connection.suspend_audio();
+    }
+    let started = connection.start().await;
+
+    let key = IdentityKey::load_or_create();
+    let hello = Hello {
+        identity: key.identity(),
+        audio_sink: settings.audio_sink,
+        token: None,
+        proof: None,
+        name: settings.name.clone(),
+        profile: settings.profile.codec(),
+    };
+    if started {
+        greet(&bus, &hello);
+    }
+
+    // restore the state of a resumed session, the roster is only shown until the server
+    // tells us who is actually there
+    if saved.muted {
+        bus.record.publish(ClientMessage::ToggleMute);
+        bus.events.publish(ClientMessage::Muted(true));
+    }
+    if saved.deafened {
+        bus.playback.publish(ClientMessage::ToggleDeafen);
+        bus.events.publish(ClientMessage::Deafened(true));
+    }
+    let mut restored_users = std::mem::take(&mut saved.users);
+    for (addr, name) in &restored_users {
+        bus.events.publish(ClientMessage::NewClient(*addr, name.clone()));
+    }
+    saved.save();
+    bus.events.publish(ClientMessage::PushToTalkMode(settings.push_to_talk));
+    let mut vad = (settings.vad_threshold_db, settings.vad_hangover);
+    bus.events.publish(ClientMessage::Vad(vad.0, vad.1));
+    let mut output_volume = settings.output_volume.min(MAX_OUTPUT_VOLUME);
+    bus.events.publish(ClientMessage::OutputVolume(output_volume));
+    bus.events.publish(ClientMessage::LatencyEstimate(
+        settings.latency_estimate_ms().round() as u32,
+    ));
 
-    for cmd in rx_msg.iter() {
+    // only undo a mute on undeafen if deafening caused it
+    let mut muted_by_deafen = false;
+    // senders we currently show as speaking and when their last packet arrived,
+    // derived from packet arrival so a lost final packet can't leave them lit up
+    let mut speaking: HashMap<SocketAddr, Instant> = HashMap::new();
+    let mut speaking_check = tokio::time::interval(settings.speaking_timeout / 4);
+    // voice message being recorded and its recipient
+    let mut recording: Option<(UserId, Vec<Vec<u8>>)> = None;
+    // voice messages being received, by sender and clip, and when they started
+    let mut incoming: HashMap<(UserId, u32), (Instant, Chunks)> = HashMap::new();
+    let mut inbox: Vec<(UserId, Vec<Vec<u8>>)> = Vec::new();
+    // custom chimes of the room being received
+    let mut chimes: HashMap<Cue, Chunks> = HashMap::new();
+    // what the server told the audio tasks, for when they start again with
+    // the devices, see `Session::resume_audio`
+    let mut room_chimes: HashMap<Cue, Vec<Vec<u8>>> = HashMap::new();
+    let mut room_codec = RoomCodec::default();
+    // per sender, with the estimate shown last so only changes are published
+    let mut qualities: HashMap<SocketAddr, (QualityEstimator, Option<StreamQuality>)> =
+        HashMap::new();
+    // volume in percent and local mute of the users we changed
+    let mut user_volumes: HashMap<SocketAddr, (u32, bool)> = HashMap::new();
+    // votes on our listen-along stream, if we host one
+    let mut votes = VoteTally::default();
+    // what goes into the next stats report besides playback's underruns
+    let mut loss: Option<LossStats> = None;
+    let mut rtt = Duration::ZERO;
+    let mut bitrate = settings.bitrate;
+    let mut watchdog = Watchdog::default();
+    let mut adapter = settings
+        .adaptive_bitrate
+        .then(|| BitrateAdapter::new(settings.bitrate, settings.expected_loss));
+    loop {
+        let cmd = tokio::select! {
+            cmd = commands.recv() => match cmd {
+                Some(cmd) => cmd,
+                None => break,
+            },
+            _ = sleep_until(connection.restart_at().unwrap_or_else(tokio::time::Instant::now)),
+                if connection.restart_at().is_some() =>
+            {
+                if connection.start().await {
+                    greet(&bus, &hello);
+                    // the new audio tasks start out as the settings say, not as
+                    // changed since
+                    restore(&bus, &saved, vad, bitrate, output_volume, &user_volumes);
+                }
+                continue;
+            }
+            _ = speaking_check.tick() => {
+                speaking.retain(|addr, last_packet| {
+                    if last_packet.elapsed() < settings.speaking_timeout {
+                        return true;
+                    }
+                    bus.events.publish(ClientMessage::Speaking(*addr, false));
+                    false
+                });
+                continue;
+            }
+        };
+        let toggled = matches!(cmd, ClientMessage::ToggleMute | ClientMessage::ToggleDeafen);
         match cmd {
             ClientMessage::Connect => {
-                tx_tui.send(ClientMessage::Connect).unwrap();
+                for (addr, _) in restored_users.drain(..) {
+                    bus.events.publish(ClientMessage::DeleteClient(addr));
+                }
+                connection.connected();
+            }
+            ClientMessage::Connection(command) => {
+                watchdog.clear();
+                if connection.command(command).await {
+                    greet(&bus, &hello);
+                    restore(&bus, &saved, vad, bitrate, output_volume, &user_volumes);
+                }
+            }
+            ClientMessage::JoinChallenge(challenge) => {
+                match hello.answer(&key, settings.password.as_deref(), &challenge) {
+                    Ok(answer) => bus.net_out.publish(Message::Hello(answer)),
+                    Err(e) => bus.commands.publish(ClientMessage::SessionFailed(e)),
+                }
+            }
+            ClientMessage::SessionFailed(e) => {
+                watchdog.clear();
+                let announcement = connection.fail(e);
+                bus.events.publish(ClientMessage::Announcement(announcement));
             }
             ClientMessage::Audio(audio) => {
-                tx_tui.send(ClientMessage::TransmitAudio(true)).unwrap();
-                tx_net_out.send(Message::Audio(audio)).unwrap();
+                if let Some((to, packets)) = &mut recording {
+                    packets.push(audio.data);
+                    if packets.len() >= MAX_CLIP_PACKETS as usize {
+                        send_voice_message(&bus, *to, std::mem::take(packets));
+                        recording = None;
+                        bus.events.publish(ClientMessage::RecordingVoiceMessage(false));
+                    }
+                    continue;
+                }
+                bus.events.publish(ClientMessage::TransmitAudio(true));
+                bus.net_out.publish(Message::Audio(audio));
+            }
+            ClientMessage::Music(music) => {
+                bus.net_out.publish(Message::Music(music));
+            }
+            ClientMessage::MusicVote(vote) => {
+                bus.net_out.publish(Message::MusicVote(vote));
+            }
+            ClientMessage::MusicVoteFrom(addr, vote) => {
+                // only the host tallies, everyone but it listens to the stream
+                let passed = settings.listen_along && votes.vote(vote, addr, saved.users.len());
+                if passed {
+                    bus.record.publish(ClientMessage::MusicVoteResult(vote));
+                    bus.net_out.publish(Message::MusicVoteResult(vote));
+                    if vote == MusicVote::Skip {
+                        bus.net_out
+                            .publish(Message::PlaylistCommand(PlaylistCommand::VoteSkip));
+                    }
+                }
+            }
+            ClientMessage::MusicVoteResult(vote) => {
+                bus.events.publish(ClientMessage::Announcement(format!(
+                    "The room voted to {}",
+                    vote.describe()
+                )));
             }
-            ClientMessage::RecvAudio(addr, audio) => {
-                tx_playback.send(ClientMessage::RecvAudio(addr, audio)).unwrap();
-                tx_tui.send(ClientMessage::ShowActive(addr)).unwrap();
+            ClientMessage::RecvMusic(addr, music) => {
+                bus.playback.publish(ClientMessage::RecvMusic(addr, music));
+            }
+            ClientMessage::RecordVoiceMessage(Some(to)) => {
+                recording = Some((to, Vec::new()));
+                bus.events.publish(ClientMessage::RecordingVoiceMessage(true));
+            }
+            ClientMessage::RecordVoiceMessage(None) => {
+                if let Some((to, packets)) = recording.take() {
+                    send_voice_message(&bus, to, packets);
+                    bus.events.publish(ClientMessage::RecordingVoiceMessage(false));
+                }
+            }
+            ClientMessage::OfflineUsers(users) => {
+                bus.events.publish(ClientMessage::OfflineUsers(users));
+            }
+            ClientMessage::VoiceChunk(chunk) => {
+                // the rest of a clip this old isn't coming any more
+                incoming.retain(|_, (started, _)| started.elapsed() < ASSEMBLY_TIMEOUT);
+                let key = (chunk.peer, chunk.clip);
+                if chunk.index >= MAX_CLIP_PACKETS
+                    || !incoming.contains_key(&key) && incoming.len() >= MAX_INCOMING_CLIPS
+                {
+                    continue;
+                }
+                let (_, packets) =
+                    incoming.entry(key).or_insert_with(|| (Instant::now(), Chunks::new()));
+                packets.entry(chunk.index).or_insert(chunk.packet);
+                if chunk.last {
+                    let (_, packets) = incoming.remove(&key).unwrap();
+                    inbox.push((chunk.peer, packets.into_values().collect()));
+                    publish_inbox(&bus, &inbox, &settings);
+                }
+            }
+            ClientMessage::Chime(chunk) => {
+                let packets = chimes.entry(chunk.cue).or_default();
+                if !chunk.packet.is_empty() {
+                    packets.insert(chunk.index, chunk.packet);
+                }
+                if chunk.last {
+                    let packets: Vec<Vec<u8>> =
+                        chimes.remove(&chunk.cue).unwrap().into_values().collect();
+                    room_chimes.insert(chunk.cue, packets.clone());
+                    bus.playback.publish(ClientMessage::SetChime(chunk.cue, packets));
+                }
+            }
+            ClientMessage::Cue(cue) => {
+                bus.playback.publish(ClientMessage::PlayCue(cue));
+            }
+            ClientMessage::PlayVoiceMessage if !inbox.is_empty() => {
+                let (_, packets) = inbox.remove(0);
+                bus.playback.publish(ClientMessage::PlayClip(packets));
+                publish_inbox(&bus, &inbox, &settings);
+            }
+            ClientMessage::RecvAudio(addr, session, audio) => {
+                let (estimator, shown) = qualities.entry(addr).or_default();
+                if let Some(quality) = estimator.push(&audio.data)
+                    && shown.replace(quality) != Some(quality)
+                {
+                    bus.events.publish(ClientMessage::StreamQuality(addr, quality));
+                }
+                bus.playback.publish(ClientMessage::RecvAudio(addr, session, audio));
+                if speaking.insert(addr, Instant::now()).is_none() {
+                    bus.events.publish(ClientMessage::Speaking(addr, true));
+                }
             }
             ClientMessage::ToggleMute => {
-                tx_record.send(ClientMessage::ToggleMute).unwrap();
+                // using a control counts as activity for the server's AFK timer
+                bus.net_out.publish(Message::Ping);
+                let muted = !saved.muted;
+                set_muted(&bus, &mut saved, muted);
+                muted_by_deafen = false;
+                // talking while deafened makes no sense, unmuting also undeafens
+                if !muted && saved.deafened && settings.deafen_mutes {
+                    set_deafened(&bus, &mut saved, false);
+                }
+                saved.save();
             }
             ClientMessage::ToggleDeafen => {
-                tx_playback.send(ClientMessage::ToggleDeafen).unwrap();
+                bus.net_out.publish(Message::Ping);
+                let deafened = !saved.deafened;
+                set_deafened(&bus, &mut saved, deafened);
+                if settings.deafen_mutes {
+                    if deafened && !saved.muted {
+                        set_muted(&bus, &mut saved, true);
+                        muted_by_deafen = true;
+                    } else if !deafened && muted_by_deafen {
+                        set_muted(&bus, &mut saved, false);
+                        muted_by_deafen = false;
+                    }
+                }
+                saved.save();
+            }
+            ClientMessage::HoldMute(held) => {
+                bus.net_out.publish(Message::Ping);
+                bus.record.publish(ClientMessage::HoldMute(held));
+                bus.events.publish(ClientMessage::HoldMute(held));
+            }
+            ClientMessage::AdjustVad(threshold, hangover) => {
+                vad.0 = (vad.0 + threshold).clamp(VAD_THRESHOLD_RANGE.0, VAD_THRESHOLD_RANGE.1);
+                vad.1 = vad.1.saturating_add_signed(hangover).min(MAX_VAD_HANGOVER);
+                bus.record.publish(ClientMessage::Vad(vad.0, vad.1));
+                bus.events.publish(ClientMessage::Vad(vad.0, vad.1));
+            }
+            ClientMessage::AdjustUserVolume(addr, step) => {
+                let (volume, muted) = user_volumes.entry(addr).or_insert((100, false));
+                *volume = volume.saturating_add_signed(step).min(MAX_USER_VOLUME);
+                bus.playback.publish(ClientMessage::UserVolume(addr, *volume, *muted));
+                bus.events.publish(ClientMessage::UserVolume(addr, *volume, *muted));
+                saved.set_user_volume(addr, *volume, *muted);
+                saved.save();
+            }
+            ClientMessage::ListDevices => {
+                let bus = bus.clone();
+                let backend = settings.backend;
+                // talks to PulseAudio through a mainloop of its own
+                tokio::task::spawn_blocking(move || {
+                    let (inputs, outputs) = backend.list_devices();
+                    bus.events.publish(ClientMessage::Devices(inputs, outputs));
+                });
+            }
+            ClientMessage::SelectInput(device) => {
+                connection.select_input(&device);
+                bus.record.publish(ClientMessage::SelectInput(device));
+            }
+            ClientMessage::SelectOutput(device) => {
+                connection.select_output(&device);
+                bus.playback.publish(ClientMessage::SelectOutput(device));
+            }
+            ClientMessage::AdjustOutputVolume(step) => {
+                output_volume = output_volume.saturating_add_signed(step).min(MAX_OUTPUT_VOLUME);
+                save_output_volume(output_volume);
+                bus.playback.publish(ClientMessage::OutputVolume(output_volume));
+                bus.events.publish(ClientMessage::OutputVolume(output_volume));
+            }
+            ClientMessage::ToggleUserMute(addr) => {
+                let (volume, muted) = user_volumes.entry(addr).or_insert((100, false));
+                *muted = !*muted;
+                bus.playback.publish(ClientMessage::UserVolume(addr, *volume, *muted));
+                bus.events.publish(ClientMessage::UserVolume(addr, *volume, *muted));
+                saved.set_user_volume(addr, *volume, *muted);
+                saved.save();
+            }
+            ClientMessage::InputLevel(level) => {
+                bus.events.publish(ClientMessage::InputLevel(level));
+            }
+            ClientMessage::AddMarker(name, at) => {
+                bus.record.publish(ClientMessage::AddMarker(name, at));
+            }
+            ClientMessage::PushToTalk(held) if settings.push_to_talk => {
+                bus.net_out.publish(Message::Ping);
+                bus.record.publish(ClientMessage::PushToTalk(held));
+                bus.events.publish(ClientMessage::PushToTalk(held));
+            }
+            ClientMessage::StreamEnded(addr) if speaking.remove(&addr).is_some() => {
+                bus.events.publish(ClientMessage::Speaking(addr, false));
             }
             ClientMessage::TransmitAudio(status) => {
-                tx_tui.send(ClientMessage::TransmitAudio(status)).unwrap();
+                bus.events.publish(ClientMessage::TransmitAudio(status));
             }
-            ClientMessage::NewClient(addr) => {
-                tx_tui.send(ClientMessage::NewClient(addr)).unwrap();
+            ClientMessage::NewClient(addr, name) => {
+                let user = (addr, name.clone());
+                if !saved.users.contains(&user) {
+                    saved.users.retain(|(user, _)| *user != addr);
+                    saved.users.push(user);
+                    saved.save();
+                }
+                // as we left them last time, under whichever address they're back
+                let volume = saved.user_volume(&name);
+                bus.events.publish(ClientMessage::NewClient(addr, name));
+                if let Some((volume, muted)) = volume {
+                    user_volumes.insert(addr, (volume, muted));
+                    bus.playback.publish(ClientMessage::UserVolume(addr, volume, muted));
+                    bus.events.publish(ClientMessage::UserVolume(addr, volume, muted));
+                }
             }
             ClientMessage::DeleteClient(addr) => {
-                tx_tui.send(ClientMessage::DeleteClient(addr)).unwrap();
+                speaking.remove(&addr);
+                qualities.remove(&addr);
+                watchdog.remove(addr);
+                // whoever gets the address next starts at the usual volume
+                if user_volumes.remove(&addr).is_some() {
+                    bus.playback.publish(ClientMessage::UserVolume(addr, 100, false));
+                }
+                saved.users.retain(|(user, _)| *user != addr);
+                saved.save();
+                bus.events.publish(ClientMessage::DeleteClient(addr));
+            }
+            ClientMessage::RoomInfo(room) => {
+                bus.events.publish(ClientMessage::RoomInfo(room));
+            }
+            ClientMessage::RoomCodec(codec) => {
+                if codec != RoomCodec::default() {
+                    bus.events.publish(ClientMessage::Announcement(format!(
+                        "Sending voice as {}",
+                        codec.describe()
+                    )));
+                }
+                if codec.kind == CodecKind::Codec2 && !cfg!(feature = "codec2") {
+                    bus.events.publish(ClientMessage::Announcement(
+                        "The room uses Codec2, which this build can't send or play, it needs \
+                         the cargo feature codec2"
+                            .to_string(),
+                    ));
+                }
+                room_codec = codec;
+                bus.record.publish(ClientMessage::RoomCodec(codec));
+                bus.playback.publish(ClientMessage::RoomCodec(codec));
+            }
+            ClientMessage::ResetEncoder(stream) => {
+                bus.record.publish(ClientMessage::ResetEncoder(stream));
+            }
+            ClientMessage::EncoderReset(stream) => {
+                bus.net_out.publish(Message::ResetCodec(stream));
+            }
+            ClientMessage::ResetDecoder(addr, stream) => {
+                bus.playback.publish(ClientMessage::ResetDecoder(addr, stream));
+            }
+            ClientMessage::RequestCodecReset(addr, stream) => {
+                bus.net_out.publish(Message::RequestCodecReset(addr, stream));
+            }
+            ClientMessage::ServerInfo(server) => {
+                bus.events.publish(ClientMessage::ServerInfo(server));
+            }
+            ClientMessage::SendChat(text) => {
+                bus.net_out.publish(Message::Chat(text));
+            }
+            ClientMessage::Chat(chat) => {
+                bus.events.publish(ClientMessage::Chat(chat));
+            }
+            ClientMessage::PlaylistCommand(command) => {
+                bus.net_out.publish(Message::PlaylistCommand(command));
+            }
+            ClientMessage::Playlist(playlist) => {
+                // the recording side plays it when this client hosts the queue
+                if settings.play_queue {
+                    bus.record.publish(ClientMessage::Playlist(playlist.clone()));
+                }
+                bus.events.publish(ClientMessage::Playlist(playlist));
+            }
+            ClientMessage::AnnounceTrack(info) => {
+                bus.net_out.publish(Message::NowPlaying(info));
+            }
+            ClientMessage::NowPlaying(info) => {
+                bus.events.publish(ClientMessage::NowPlaying(info));
+            }
+            ClientMessage::Latency(smoothed) => {
+                rtt = smoothed;
+                bus.events.publish(ClientMessage::Latency(smoothed));
+            }
+            ClientMessage::PlaybackStats(frames, underruns) => {
+                let report = HealthReport {
+                    frames,
+                    underruns,
+                    // only reported while we talk
+                    loss: loss.take().unwrap_or_default(),
+                    rtt_ms: rtt.as_millis() as u32,
+                };
+                bus.net_out.publish(Message::ClientStats(report));
+                bus.events.publish(ClientMessage::Health(report));
+                if let Some(grade) = watchdog.link(report) {
+                    bus.events.publish(ClientMessage::Grade(grade));
+                }
+            }
+            ClientMessage::StreamHealth(addr, health) => {
+                if let Some(grade) = watchdog.stream(addr, health) {
+                    bus.events.publish(ClientMessage::UserGrade(addr, grade));
+                }
+            }
+            ClientMessage::LowerBitrateSuggested => match adapter {
+                // it already reacts to the loss the server saw
+                Some(_) => debug!("The server suggests a lower bitrate, leaving it to adaptation"),
+                None => bus.events.publish(ClientMessage::Announcement(
+                    "The connection is struggling, lowering the bitrate with [ may help".to_string(),
+                )),
+            },
+            ClientMessage::ChangeBitrate(up) => {
+                let bits = step_bitrate(bitrate, up);
+                bitrate = opus::Bitrate::Bits(bits);
+                if let Some(adapter) = &mut adapter {
+                    adapter.set_ceiling(bits);
+                }
+                bus.record.publish(ClientMessage::Bitrate(bits as u32));
+                bus.events.publish(ClientMessage::Bitrate(bits as u32));
+            }
+            ClientMessage::LossStats(stats) => {
+                loss = Some(stats);
+                if let Some(adaptation) = adapter.as_mut().and_then(|adapter| adapter.update(stats)) {
+                    let bits = adaptation.bitrate as u32;
+                    bus.record.publish(ClientMessage::Bitrate(bits));
+                    bus.record.publish(ClientMessage::ExpectedLoss(adaptation.expected_loss));
+                    bus.events.publish(ClientMessage::Bitrate(bits));
+                }
+                bus.events.publish(ClientMessage::LossStats(stats));
+            }
+            ClientMessage::PacketStats(stats) => {
+                bus.events.publish(ClientMessage::PacketStats(stats));
+            }
+            ClientMessage::Announcement(text) => {
+                bus.events.publish(ClientMessage::Announcement(text));
+            }
+            ClientMessage::MovedToAfk(afk) => {
+                if afk {
+                    bus.events.publish(ClientMessage::Announcement(
+                        "You were moved to the AFK room".to_string(),
+                    ));
+                }
+                bus.events.publish(ClientMessage::MovedToAfk(afk));
+            }
+            ClientMessage::ClientAfk(addr, afk) => {
+                bus.events.publish(ClientMessage::ClientAfk(addr, afk));
             }
             ClientMessage::Exit => {
-                tx_net_out.send(Message::Bye).unwrap();
-                tx_net_out.send(Message::Bye).unwrap();
-                tx_net_out.send(Message::Bye).unwrap();
-                let _ = tokio::spawn(async move {
-                    tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
-                    std::process::exit(0);
-                }).await;
+                // the session's tasks say bye and wind down on their own
+                connection.shut_down().await;
+                break;
             }
             _ => {}
         }
+        if toggled && release_devices {
+            if saved.muted && saved.deafened {
+                connection.suspend_audio();
+            } else if connection.resume_audio() {
+                restore(&bus, &saved, vad, bitrate, output_volume, &user_volumes);
+                bus.record.publish(ClientMessage::RoomCodec(room_codec));
+                bus.playback.publish(ClientMessage::RoomCodec(room_codec));
+                for (cue, packets) in &room_chimes {
+                    bus.playback.publish(ClientMessage::SetChime(*cue, packets.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// Says hello to the server, a few times in case one gets lost.
+fn greet(bus: &EventBus, hello: &Hello) {
+    for _ in 0..3 {
+        bus.net_out.publish(Message::Hello(hello.clone()));
+    }
+}
+
+/// Tells the audio tasks of a started session what was changed since the
+/// settings were read.
+fn restore(
+    bus: &EventBus,
+    saved: &SavedSession,
+    vad: (f32, u32),
+    bitrate: opus::Bitrate,
+    output_volume: u32,
+    user_volumes: &HashMap<SocketAddr, (u32, bool)>,
+) {
+    if saved.muted {
+        bus.record.publish(ClientMessage::ToggleMute);
+    }
+    if saved.deafened {
+        bus.playback.publish(ClientMessage::ToggleDeafen);
+    }
+    bus.record.publish(ClientMessage::Vad(vad.0, vad.1));
+    if let opus::Bitrate::Bits(bits) = bitrate {
+        bus.record.publish(ClientMessage::Bitrate(bits as u32));
+    }
+    bus.playback.publish(ClientMessage::OutputVolume(output_volume));
+    for (addr, (volume, muted)) in user_volumes {
+        bus.playback.publish(ClientMessage::UserVolume(*addr, *volume, *muted));
     }
 }
 
-pub fn receive_client_message(rx: &Option<Receiver<ClientMessage>>) -> Option<ClientMessage> {
-    if let Some(rx) = rx {
-        match rx.try_recv() {
-            Ok(msg) => Some(msg),
-            Err(_) => None,
+/// Sends a recorded clip to the server one packet at a time, paced so it
+/// neither overruns the bus nor the socket.
+fn send_voice_message(bus: &EventBus, to: UserId, packets: Vec<Vec<u8>>) {
+    if packets.is_empty() {
+        return;
+    }
+    let net_out = bus.net_out.clone();
+    let clip: u32 = rand::random();
+    tokio::spawn(async move {
+        let count = packets.len();
+        for (index, packet) in packets.into_iter().enumerate() {
+            net_out.publish(Message::VoiceChunk(VoiceChunk {
+                peer: to,
+                clip,
+                index: index as u16,
+                last: index + 1 == count,
+                packet,
+            }));
+            tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
         }
-    } else {
-        None
+    });
+}
+
+fn publish_inbox(bus: &EventBus, inbox: &[(UserId, Vec<Vec<u8>>)], settings: &AudioSettings) {
+    let messages = inbox
+        .iter()
+        .map(|(from, packets)| {
+            let secs = packets.len() as f32 * settings.frame_ms() / 1000.0;
+            (*from, secs.round() as u32)
+        })
+        .collect();
+    bus.events.publish(ClientMessage::VoiceMessages(messages));
+}
+
+fn set_muted(bus: &EventBus, saved: &mut SavedSession, muted: bool) {
+    if saved.muted != muted {
+        saved.muted = muted;
+        bus.record.publish(ClientMessage::ToggleMute);
+        bus.events.publish(ClientMessage::Muted(muted));
     }
 }
-pub fn send_client_message(message: ClientMessage, tx: &Option<Sender<ClientMessage>>) {
-    if let Some(tx) = tx {
-        let _ = tx.send(message);
+
+fn set_deafened(bus: &EventBus, saved: &mut SavedSession, deafened: bool) {
+    if saved.deafened != deafened {
+        saved.deafened = deafened;
+        bus.playback.publish(ClientMessage::ToggleDeafen);
+        bus.events.publish(ClientMessage::Deafened(deafened));
     }
 }
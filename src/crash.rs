@@ -0,0 +1,183 @@
+use std::{
+    backtrace::Backtrace, collections::VecDeque, fmt::Write as _, fs, panic::PanicHookInfo,
+    path::PathBuf, sync::Mutex, time::SystemTime,
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::{
+    persistence::state_dir,
+    settings::{AudioSettings, ServerSettings},
+};
+
+// log lines kept for the report
+const RECENT_LINES: usize = 200;
+const REDACTED: &str = "<redacted>";
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+static CONFIG: Mutex<String> = Mutex::new(String::new());
+
+/// Passes records on to the configured logger and remembers the last lines,
+/// including those it doesn't print, e.g. in the TUI.
+struct CrashLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for CrashLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info || self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let secs = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let line = format!(
+            "{:.3} {} {}: {}",
+            secs,
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        if let Ok(mut recent) = RECENT.lock() {
+            if recent.len() == RECENT_LINES {
+                recent.pop_front();
+            }
+            recent.push_back(line);
+        }
+        if self.inner.matches(record) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Installs the logger `builder` describes, keeping at least the info level
+/// for crash reports whatever it filters.
+pub fn init_logger(builder: &mut env_logger::Builder) {
+    let inner = builder.build();
+    log::set_max_level(inner.filter().max(LevelFilter::Info));
+    if log::set_boxed_logger(Box::new(CrashLogger { inner })).is_err() {
+        eprintln!("Logger initialized twice");
+    }
+}
+
+/// The options the report lists, without the passwords.
+pub fn set_config(settings: &AudioSettings, server: &ServerSettings) {
+    let settings = AudioSettings {
+        password: settings.password.as_ref().map(|_| REDACTED.to_string()),
+        ..settings.clone()
+    };
+    let server = ServerSettings {
+        password: server.password.as_ref().map(|_| REDACTED.to_string()),
+        ..server.clone()
+    };
+    if let Ok(mut config) = CONFIG.lock() {
+        *config = format!("{:#?}\n{:#?}", settings, server);
+    }
+}
+
+/// Writes a report to attach to bug reports on a panic, and says where it is.
+pub fn install_panic_hook() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default(info);
+        match write_report(info) {
+            Ok(path) => eprintln!(
+                "kop-audio crashed. A report was written to {}, please attach it to a bug report.",
+                path.display()
+            ),
+            Err(e) => eprintln!("kop-audio crashed and couldn't write a crash report: {}", e),
+        }
+    }));
+}
+
+fn write_report(info: &PanicHookInfo) -> std::io::Result<PathBuf> {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let dir = state_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("crashes");
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("crash-{}-{}.txt", secs, std::process::id()));
+    let thread = std::thread::current();
+    // a panic while holding one of the locks shouldn't lose the rest
+    let config = CONFIG
+        .lock()
+        .map(|config| config.clone())
+        .unwrap_or_default();
+    let recent: Vec<String> = RECENT
+        .lock()
+        .map(|recent| recent.iter().cloned().collect())
+        .unwrap_or_default();
+    let report = report(
+        secs,
+        thread.name().unwrap_or("unnamed"),
+        &info.to_string(),
+        &Backtrace::force_capture().to_string(),
+        &config,
+        &recent,
+    );
+    fs::write(&path, report)?;
+    Ok(path)
+}
+
+fn report(
+    secs: u64,
+    thread: &str,
+    panic: &str,
+    backtrace: &str,
+    config: &str,
+    recent: &[String],
+) -> String {
+    let mut report = String::new();
+    let _ = writeln!(
+        report,
+        "kop-audio {} crash report",
+        env!("CARGO_PKG_VERSION")
+    );
+    let _ = writeln!(report, "time: {}", secs);
+    let _ = writeln!(
+        report,
+        "platform: {} {}",
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    );
+    let _ = writeln!(report, "thread: {}", thread);
+    let _ = writeln!(report, "{}\n", panic);
+    let _ = writeln!(report, "backtrace:\n{}\n", backtrace);
+    let _ = writeln!(report, "configuration:\n{}\n", config);
+    let _ = writeln!(report, "last {} log lines:", recent.len());
+    for line in recent {
+        let _ = writeln!(report, "{}", line);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_leaves_out_passwords() {
+        let settings = AudioSettings {
+            password: Some("hunter2".into()),
+            ..AudioSettings::default()
+        };
+        let server = ServerSettings {
+            password: Some("letmein".into()),
+            ..ServerSettings::default()
+        };
+        set_config(&settings, &server);
+        let config = CONFIG.lock().unwrap().clone();
+        let report = report(0, "main", "panicked at here", "", &config, &[]);
+        assert!(!report.contains("hunter2") && !report.contains("letmein"));
+        assert!(report.contains("password: Some(\n        \"<redacted>\""));
+    }
+}
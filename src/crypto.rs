@@ -0,0 +1,571 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use sha2::Sha256;
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use tokio::net::UdpSocket;
+
+use crate::{
+    Error,
+    identity::{Identity, config_file, write_private},
+    server::{MAX_MESSAGE, Message, decode_message, encode_message},
+};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+// the initiator's first message is just its ephemeral key
+const FIRST_MESSAGE_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+/// Length of the tag on voice and music of a client that doesn't encrypt.
+pub const SENDER_TAG_LEN: usize = 8;
+const HANDSHAKE_ATTEMPTS: usize = 3;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+// handshakes waiting for their last message, more than this and someone is
+// flooding us with first messages
+const MAX_PENDING: usize = 256;
+// how far behind the newest packet one may arrive, a good second of voice and
+// music at once
+const REPLAY_WINDOW: u64 = 128;
+
+/// Keys for one peer once the handshake is done. Every datagram carries its
+/// nonce, so lost and reordered packets don't get the two sides out of step.
+struct Transport {
+    state: StatelessTransportState,
+    next_nonce: AtomicU64,
+    received: Mutex<ReplayWindow>,
+}
+
+impl Transport {
+    fn new(state: StatelessTransportState) -> Self {
+        Transport {
+            state,
+            next_nonce: AtomicU64::new(0),
+            received: Mutex::new(ReplayWindow::default()),
+        }
+    }
+
+    fn seal(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let nonce = self.next_nonce.fetch_add(1, Ordering::Relaxed);
+        let mut sealed = vec![0u8; plain.len() + TAG_LEN];
+        let len = self.state.write_message(nonce, plain, &mut sealed).ok()?;
+        sealed.truncate(len);
+        Some(encode_message(&Message::Sealed(nonce, sealed)))
+    }
+
+    fn open(&self, nonce: u64, sealed: &[u8], buf: &mut [u8]) -> Option<usize> {
+        if sealed.len() > buf.len() + TAG_LEN {
+            return None;
+        }
+        let len = self.state.read_message(nonce, sealed, buf).ok()?;
+        // only once it's authentic, so forged nonces can't move the window
+        self.received.lock().unwrap().accept(nonce).then_some(len)
+    }
+}
+
+/// The nonces received lately. A packet whose nonce was seen already, or
+/// is more than `REPLAY_WINDOW` behind the newest, is a replay.
+#[derive(Default)]
+struct ReplayWindow {
+    // one past the newest nonce accepted
+    next: u64,
+    // bit i is set if nonce `next - 1 - i` was accepted
+    seen: u128,
+}
+
+impl ReplayWindow {
+    /// Whether `nonce` is new, remembering it if so.
+    fn accept(&mut self, nonce: u64) -> bool {
+        if nonce >= self.next {
+            let shift = nonce - self.next + 1;
+            self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.next = nonce.saturating_add(1);
+            return true;
+        }
+        let age = self.next - 1 - nonce;
+        if age >= REPLAY_WINDOW || self.seen & (1 << age) != 0 {
+            return false;
+        }
+        self.seen |= 1 << age;
+        true
+    }
+}
+
+/// A UDP socket that encrypts every message to peers it completed a Noise XX
+/// handshake with. The server answers handshakes of any peer, clients run one
+/// with the server in `connect`. Peers without a handshake talk in plaintext
+/// unless the socket requires encryption.
+pub struct SecureSocket {
+    socket: UdpSocket,
+    private_key: [u8; 32],
+    server: bool,
+    require: bool,
+    // and when their first message came
+    pending: Mutex<HashMap<SocketAddr, (HandshakeState, Instant)>>,
+    peers: Mutex<HashMap<SocketAddr, Arc<Transport>>>,
+    // the key the server proved, on the client side
+    server_key: Option<[u8; 32]>,
+}
+
+impl SecureSocket {
+    /// The server side, `require` turns away peers that don't encrypt.
+    pub fn server(socket: UdpSocket, require: bool) -> Self {
+        SecureSocket::new(socket, load_or_create_key("server-key"), true, require)
+    }
+
+    /// Wraps a client socket without encryption, for servers that don't support it.
+    pub fn plain(socket: UdpSocket) -> Self {
+        SecureSocket::new(socket, [0; 32], false, false)
+    }
+
+    /// Runs the handshake with the server `socket` is connected to. Fails with
+    /// an explanation if the server never answers, e.g. because it predates
+    /// encryption. Whether the server is who it was before is up to the
+    /// caller, see `pin_server_key`.
+    pub async fn connect(socket: UdpSocket, private_key: [u8; 32]) -> Result<Self, Error> {
+        let server = socket
+            .peer_addr()
+            .map_err(|e| Error::Network(e.to_string()))?;
+        let mut secure = SecureSocket::new(socket, private_key, false, true);
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        for attempt in 1..=HANDSHAKE_ATTEMPTS {
+            debug!("Encryption handshake with {}, attempt {}", server, attempt);
+            let mut handshake = secure.builder().build_initiator().map_err(noise_error)?;
+            let len = handshake
+                .write_message(&[], &mut buf)
+                .map_err(noise_error)?;
+            secure
+                .send_plain(&Message::Handshake(buf[..len].to_vec()))
+                .await?;
+            let reply = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+                loop {
+                    let Ok(len) = secure.socket.recv(&mut buf).await else {
+                        continue;
+                    };
+                    if let Message::Handshake(reply) = decode_message(&buf[..len]) {
+                        return reply;
+                    }
+                }
+            })
+            .await;
+            let Ok(reply) = reply else {
+                continue;
+            };
+            if handshake.read_message(&reply, &mut buf).is_err() {
+                continue;
+            }
+            let len = handshake
+                .write_message(&[], &mut buf)
+                .map_err(noise_error)?;
+            let last = Message::Handshake(buf[..len].to_vec());
+            // nothing acknowledges the last message, send it a few times like hello
+            for _ in 0..3 {
+                secure.send_plain(&last).await?;
+            }
+            if let Some(key) = handshake.get_remote_static() {
+                info!(
+                    "Encrypted connection to {}, server key {}",
+                    server,
+                    fingerprint(key)
+                );
+                secure.server_key = key.try_into().ok();
+            }
+            let state = handshake
+                .into_stateless_transport_mode()
+                .map_err(noise_error)?;
+            secure
+                .peers
+                .lock()
+                .unwrap()
+                .insert(server, Arc::new(Transport::new(state)));
+            return Ok(secure);
+        }
+        Err(Error::Protocol(format!(
+            "{} didn't answer the encryption handshake, it may not support encryption. \
+             Connect with --no-encryption to talk to it unencrypted.",
+            server
+        )))
+    }
+
+    fn new(socket: UdpSocket, private_key: [u8; 32], server: bool, require: bool) -> Self {
+        SecureSocket {
+            socket,
+            private_key,
+            server,
+            require,
+            pending: Mutex::new(HashMap::new()),
+            peers: Mutex::new(HashMap::new()),
+            server_key: None,
+        }
+    }
+
+    /// The static key the server proved in the handshake of `connect`.
+    pub fn server_key(&self) -> Option<&[u8; 32]> {
+        self.server_key.as_ref()
+    }
+
+    fn builder(&self) -> Builder<'_> {
+        Builder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(&self.private_key)
+            .unwrap()
+    }
+
+    async fn send_plain(&self, msg: &Message) -> Result<(), Error> {
+        self.socket
+            .send(&encode_message(msg))
+            .await
+            .map(|_| ())
+            .map_err(|e| Error::Network(e.to_string()))
+    }
+
+    /// Sends an encoded message, sealed if `addr` completed a handshake.
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let transport = self.peers.lock().unwrap().get(&addr).cloned();
+        match transport {
+            Some(transport) => {
+                let sealed = transport
+                    .seal(buf)
+                    .ok_or_else(|| io::Error::other("message too large to encrypt"))?;
+                self.socket.send_to(&sealed, addr).await
+            }
+            None => self.socket.send_to(buf, addr).await,
+        }
+    }
+
+    /// Sends to the server a client socket is connected to.
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_to(buf, self.socket.peer_addr()?).await
+    }
+
+    /// Receives the next message, decrypted. Handshakes are answered on the
+    /// way, undecryptable packets and, where encryption is required,
+    /// plaintext ones are dropped. `buf` is received into as well, it takes
+    /// `MAX_MESSAGE` bytes for no datagram to be cut off.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (len, addr) = self.socket.recv_from(buf).await?;
+            match decode_message(&buf[..len]) {
+                Message::Handshake(data) => {
+                    if self.server {
+                        self.respond(addr, &data).await;
+                    }
+                }
+                Message::Sealed(nonce, sealed) => {
+                    let transport = self.peers.lock().unwrap().get(&addr).cloned();
+                    match transport.and_then(|transport| transport.open(nonce, &sealed, buf)) {
+                        Some(len) => return Ok((len, addr)),
+                        None => debug!("Dropping packet from {} that doesn't decrypt", addr),
+                    }
+                }
+                msg => {
+                    let encrypted_peer = self.peers.lock().unwrap().contains_key(&addr);
+                    let refused = !matches!(msg, Message::EncryptionRequired)
+                        && (self.require || encrypted_peer);
+                    if !refused {
+                        return Ok((len, addr));
+                    }
+                    debug!("Dropping unencrypted packet from {}", addr);
+                    // answering every audio packet would only add to the traffic
+                    if self.server && !encrypted_peer && matches!(msg, Message::Hello(_)) {
+                        let refusal = encode_message(&Message::EncryptionRequired);
+                        let _ = self.socket.send_to(&refusal, addr).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Answers the first handshake message and completes the handshake on
+    /// the last one.
+    async fn respond(&self, addr: SocketAddr, data: &[u8]) {
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        let waiting = self.pending.lock().unwrap().remove(&addr);
+        if let Some((mut handshake, _)) = waiting
+            && handshake.read_message(data, &mut buf).is_ok()
+        {
+            if let Ok(state) = handshake.into_stateless_transport_mode() {
+                debug!("Encryption handshake with {} done", addr);
+                self.peers
+                    .lock()
+                    .unwrap()
+                    .insert(addr, Arc::new(Transport::new(state)));
+            }
+            return;
+        }
+        if data.len() != FIRST_MESSAGE_LEN {
+            // e.g. a repeated last message of a finished handshake
+            return;
+        }
+        // a first message, possibly the client trying again
+        let Ok(mut handshake) = self.builder().build_responder() else {
+            return;
+        };
+        let Ok(len) = handshake
+            .read_message(data, &mut buf)
+            .and_then(|_| handshake.write_message(&[], &mut buf))
+        else {
+            debug!("Ignoring malformed handshake from {}", addr);
+            return;
+        };
+        let reply = encode_message(&Message::Handshake(buf[..len].to_vec()));
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.len() >= MAX_PENDING && !pending.contains_key(&addr) {
+                // the oldest is the least likely to still be finished
+                let oldest = pending
+                    .iter()
+                    .min_by_key(|(_, (_, started))| *started)
+                    .map(|(addr, _)| *addr);
+                if let Some(oldest) = oldest {
+                    debug!("Too many pending encryption handshakes, dropping {}'s", oldest);
+                    pending.remove(&oldest);
+                }
+            }
+            pending.insert(addr, (handshake, Instant::now()));
+        }
+        let _ = self.socket.send_to(&reply, addr).await;
+    }
+
+    /// Whether `addr` completed a handshake, so everything it sends is
+    /// known to come from it.
+    pub fn encrypts(&self, addr: &SocketAddr) -> bool {
+        self.peers.lock().unwrap().contains_key(addr)
+    }
+
+    /// The static key `addr` proved in its handshake, the same for every
+    /// connection of a client.
+    pub fn peer_key(&self, addr: &SocketAddr) -> Option<[u8; 32]> {
+        let peers = self.peers.lock().unwrap();
+        peers.get(addr)?.state.get_remote_static()?.try_into().ok()
+    }
+
+    /// Drops the keys of a peer that left.
+    pub fn forget(&self, addr: &SocketAddr) {
+        self.peers.lock().unwrap().remove(addr);
+        self.pending.lock().unwrap().remove(addr);
+    }
+}
+
+/// Proves knowledge of the server password in a join request without sending
+/// the password itself. Covers the server's `challenge`, so a token seen on
+/// the wire can't be used to join again.
+pub fn join_token(password: &str, identity: &Identity, challenge: &[u8; 32]) -> [u8; 32] {
+    join_mac(password, identity, challenge).finalize().into_bytes().into()
+}
+
+pub fn verify_join_token(
+    password: &str,
+    identity: &Identity,
+    challenge: &[u8; 32],
+    token: Option<&[u8; 32]>,
+) -> bool {
+    token.is_some_and(|token| {
+        join_mac(password, identity, challenge)
+            .verify_slice(token)
+            .is_ok()
+    })
+}
+
+fn join_mac(password: &str, identity: &Identity, challenge: &[u8; 32]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(password.as_bytes()).unwrap();
+    mac.update(b"kop-audio join");
+    mac.update(&identity.0);
+    mac.update(challenge);
+    mac
+}
+
+/// Tags an encoded message with the key the server gave the sender when it
+/// joined, so nobody can pass audio off as theirs by faking their address.
+pub fn sender_tag(key: &[u8; 32], message: &[u8]) -> [u8; SENDER_TAG_LEN] {
+    let mac = sender_mac(key, message).finalize().into_bytes();
+    let mut tag = [0; SENDER_TAG_LEN];
+    tag.copy_from_slice(&mac[..SENDER_TAG_LEN]);
+    tag
+}
+
+pub fn verify_sender_tag(key: &[u8; 32], message: &[u8], tag: &[u8; SENDER_TAG_LEN]) -> bool {
+    sender_mac(key, message).verify_truncated_left(tag).is_ok()
+}
+
+fn sender_mac(key: &[u8; 32], message: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(b"kop-audio sender");
+    mac.update(message);
+    mac
+}
+
+fn noise_error(e: snow::Error) -> Error {
+    Error::Protocol(format!("Encryption handshake failed: {}", e))
+}
+
+/// Checks that `server`, as the user named it, proved the key it had the
+/// first time, pinning the key then. Fails if it changed, someone may be
+/// impersonating the server.
+pub fn pin_server_key(server: &str, key: &[u8; 32]) -> Result<(), Error> {
+    let Some(path) = config_file("known-servers") else {
+        warn!("No home directory, can't check the key of {}", server);
+        return Ok(());
+    };
+    check_pin(&path, server, key)
+}
+
+/// `pin_server_key` with the pins in `path`, a `server key` line per server.
+fn check_pin(path: &Path, server: &str, key: &[u8; 32]) -> Result<(), Error> {
+    let hex: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(Error::Io(format!("Can't read {}: {}", path.display(), e)));
+        }
+    };
+    for (i, line) in text.lines().enumerate() {
+        let Some((name, pinned)) = line.trim().split_once(char::is_whitespace) else {
+            continue;
+        };
+        if name != server {
+            continue;
+        }
+        if pinned.trim() == hex {
+            return Ok(());
+        }
+        return Err(Error::Protocol(format!(
+            "The encryption key of {} changed to {}, someone may be impersonating it. If the \
+             server really got a new key, remove line {} of {} to trust the new one",
+            server,
+            fingerprint(key),
+            i + 1,
+            path.display()
+        )));
+    }
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{} {}", server, hex))
+        .map_err(|e| Error::Io(format!("Can't write {}: {}", path.display(), e)))?;
+    info!("Trusting key {} of {} from now on", fingerprint(key), server);
+    Ok(())
+}
+
+fn fingerprint(key: &[u8]) -> String {
+    key[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Loads the static key of this end of the handshake, creating one on first
+/// use. Without a home directory a temporary key is used.
+pub fn load_or_create_key(name: &str) -> [u8; 32] {
+    let Some(path) = config_file(name) else {
+        warn!("No home directory, using a temporary encryption key");
+        return rand::random();
+    };
+    if let Ok(data) = fs::read(&path) {
+        if let Ok(key) = <[u8; 32]>::try_from(data.as_slice()) {
+            return key;
+        }
+        warn!("Ignoring malformed key file {}", path.display());
+    }
+    let key: [u8; 32] = rand::random();
+    match write_private(&path, &key) {
+        Ok(_) => info!("Created new encryption key in {}", path.display()),
+        Err(e) => warn!("Can't store encryption key in {}: {:?}", path.display(), e),
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn encrypts_after_handshake() {
+        let server = SecureSocket::new(
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            rand::random(),
+            true,
+            true,
+        );
+        let server_addr = server.socket.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server_addr).await.unwrap();
+        let server_task = tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_MESSAGE];
+            let (len, addr) = server.recv_from(&mut buf).await.unwrap();
+            assert!(server.peer_key(&addr).is_some());
+            server.send_to(&buf[..len], addr).await.unwrap();
+        });
+        let client = SecureSocket::connect(socket, rand::random()).await.unwrap();
+        let hello = encode_message(&Message::Ping);
+        client.send(&hello).await.unwrap();
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(decode_message(&buf[..len]), Message::Ping);
+        server_task.await.unwrap();
+    }
+
+    #[test]
+    fn join_token_depends_on_password_identity_and_challenge() {
+        let identity = Identity([1; 32]);
+        let challenge = [7; 32];
+        let token = join_token("hunter2", &identity, &challenge);
+        assert!(verify_join_token("hunter2", &identity, &challenge, Some(&token)));
+        assert!(!verify_join_token("hunter3", &identity, &challenge, Some(&token)));
+        assert!(!verify_join_token("hunter2", &Identity([2; 32]), &challenge, Some(&token)));
+        // replayed for a later join
+        assert!(!verify_join_token("hunter2", &identity, &[8; 32], Some(&token)));
+        assert!(!verify_join_token("hunter2", &identity, &challenge, None));
+    }
+
+    #[test]
+    fn rejects_replayed_and_too_old_nonces() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(0));
+        assert!(window.accept(5));
+        // reordered
+        assert!(window.accept(3));
+        assert!(!window.accept(3));
+        assert!(!window.accept(5));
+        assert!(window.accept(5 + REPLAY_WINDOW));
+        assert!(!window.accept(5));
+        assert!(window.accept(6));
+        assert!(!window.accept(0));
+    }
+
+    #[test]
+    fn pins_server_keys_on_first_use() {
+        let path = std::env::temp_dir().join(format!("kop-audio-known-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert!(check_pin(&path, "voice.example:1234", &[1; 32]).is_ok());
+        assert!(check_pin(&path, "voice.example:1234", &[1; 32]).is_ok());
+        assert!(check_pin(&path, "voice.example:1234", &[2; 32]).is_err());
+        assert!(check_pin(&path, "other.example:1234", &[2; 32]).is_ok());
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sender_tag_depends_on_key_and_message() {
+        let key = [1; 32];
+        let tag = sender_tag(&key, b"audio");
+        assert!(verify_sender_tag(&key, b"audio", &tag));
+        assert!(!verify_sender_tag(&[2; 32], b"audio", &tag));
+        assert!(!verify_sender_tag(&key, b"audi0", &tag));
+    }
+}
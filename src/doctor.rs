@@ -0,0 +1,564 @@
+use std::{
+    f32::consts::TAU,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use tokio::net::{UdpSocket, lookup_host};
+
+use crate::{
+    CHANNELS, SAMPLE_RATE,
+    client::connected_socket,
+    crypto::{self, SecureSocket},
+    implementations::{Capture, Playback, pulseaudio::list_sources},
+    server::{MAX_MESSAGE, Message, decode_message, encode_message},
+    settings::AudioSettings,
+};
+
+const PROBES: u32 = 10;
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+// how long after the last probe replies are still waited for
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+// two servers on different addresses, a NAT that maps them to different
+// ports is symmetric
+const STUN_SERVERS: [&str; 2] = ["stun.l.google.com:19302", "stun1.l.google.com:19302"];
+const STUN_TIMEOUT: Duration = Duration::from_secs(2);
+const STUN_MAGIC: u32 = 0x2112_a442;
+// the chirp played to find the speakers in the microphone's signal, a sweep
+// over what even laptop speakers and microphones handle
+const CHIRP_MS: usize = 100;
+const CHIRP_HZ: (f32, f32) = (500.0, 4000.0);
+// silence before the chirp so both devices run, and how long is recorded,
+// the rest is the longest round trip that can be measured
+const CHIRP_DELAY_MS: usize = 200;
+const PASSTHROUGH_MS: usize = 1000;
+// correlation with the chirp above which the microphone heard it
+const LEAK_THRESHOLD: f32 = 0.3;
+
+/// Outcome of one check, printed in front of its line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    fn line(&mut self, status: Status, check: &str, detail: impl AsRef<str>) {
+        let tag = match status {
+            Status::Ok => "[ok]  ",
+            Status::Warn => "[warn]",
+            Status::Fail => "[FAIL]",
+        };
+        self.failed |= status == Status::Fail;
+        println!("{} {}: {}", tag, check, detail.as_ref());
+    }
+}
+
+/// Checks what a call to `server` needs, from name resolution to the audio
+/// devices, and prints a report to attach to bug reports. False if anything
+/// failed.
+pub async fn run(server: &str, settings: &AudioSettings) -> bool {
+    let mut report = Report { failed: false };
+    println!(
+        "kop-audio {} on {} {}",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    );
+    println!("Server: {}", server);
+
+    let started = Instant::now();
+    let addrs: Vec<SocketAddr> = match lookup_host(server).await {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => {
+            report.line(
+                Status::Fail,
+                "DNS",
+                format!("can't resolve {}: {}", server, e),
+            );
+            Vec::new()
+        }
+    };
+    if !addrs.is_empty() {
+        let list: Vec<String> = addrs.iter().map(|addr| addr.to_string()).collect();
+        let detail = format!("{} in {}ms", list.join(", "), started.elapsed().as_millis());
+        match settings.ip_family.pick(addrs.iter().copied()) {
+            Some(addr) => {
+                report.line(Status::Ok, "DNS", detail);
+                check_server(&mut report, server, addr, settings).await;
+            }
+            None => report.line(
+                Status::Fail,
+                "DNS",
+                format!("{}, none of them {}", detail, settings.ip_family),
+            ),
+        }
+    }
+    check_nat(&mut report).await;
+    check_audio(&mut report, settings);
+    !report.failed
+}
+
+/// Exchanges latency probes with the server, it answers them like it does for
+/// the status bar, also to clients that haven't joined.
+async fn check_server(
+    report: &mut Report,
+    server: &str,
+    addr: SocketAddr,
+    settings: &AudioSettings,
+) {
+    let socket = match connected_socket(addr).await {
+        Ok(socket) => socket,
+        Err(e) => return report.line(Status::Fail, "UDP", e.to_string()),
+    };
+    let started = Instant::now();
+    let socket = if settings.encrypt {
+        let connected = SecureSocket::connect(socket, crypto::load_or_create_key("client-key"))
+            .await
+            .and_then(|socket| match socket.server_key() {
+                Some(key) => crypto::pin_server_key(server, key).map(|_| socket),
+                None => Ok(socket),
+            });
+        match connected {
+            Ok(socket) => {
+                let ms = started.elapsed().as_millis();
+                report.line(
+                    Status::Ok,
+                    "Encryption",
+                    format!("handshake done in {}ms", ms),
+                );
+                socket
+            }
+            Err(e) => return report.line(Status::Fail, "Encryption", format!("{:?}", e)),
+        }
+    } else {
+        report.line(Status::Warn, "Encryption", "off, --no-encryption given");
+        SecureSocket::plain(socket)
+    };
+
+    let sender = async {
+        for seq in 0..PROBES {
+            let _ = socket
+                .send(&encode_message(&Message::LatencyProbe(seq as u64)))
+                .await;
+            tokio::time::sleep(PROBE_INTERVAL).await;
+        }
+    };
+    let sent_at = Instant::now();
+    let mut rtts = Vec::new();
+    let receiver = async {
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        while let Ok((len, _)) = socket.recv_from(&mut buf).await {
+            if let Message::LatencyReply(seq) = decode_message(&buf[..len]) {
+                // probes went out at fixed intervals, the reply's seq says which
+                let sent = PROBE_INTERVAL * seq as u32;
+                rtts.push(sent_at.elapsed().saturating_sub(sent));
+            }
+        }
+    };
+    let _ = tokio::time::timeout(PROBE_INTERVAL * PROBES + REPLY_TIMEOUT, async {
+        tokio::join!(sender, receiver)
+    })
+    .await;
+
+    let received = rtts.len() as u32;
+    let status = match received {
+        0 => Status::Fail,
+        n if n < PROBES => Status::Warn,
+        _ => Status::Ok,
+    };
+    let detail = match rtts.iter().max() {
+        Some(max) => {
+            let min = rtts.iter().min().unwrap();
+            let avg = rtts.iter().sum::<Duration>() / received;
+            format!(
+                "{} of {} probes answered, round trip {}/{}/{}ms min/avg/max",
+                received,
+                PROBES,
+                min.as_millis(),
+                avg.as_millis(),
+                max.as_millis()
+            )
+        }
+        None => format!("none of {} probes answered, UDP may be blocked on the way", PROBES),
+    };
+    report.line(status, "UDP", detail);
+}
+
+/// Asks two STUN servers what our address looks like from outside. Calls go
+/// through the server, so any NAT works, but this tells apart a network that
+/// blocks UDP from one that only mangles it.
+async fn check_nat(report: &mut Report) {
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await {
+        Ok(socket) => socket,
+        Err(e) => return report.line(Status::Fail, "NAT", e.to_string()),
+    };
+    let mut mapped = Vec::new();
+    for server in STUN_SERVERS {
+        match stun_binding(&socket, server).await {
+            Some(addr) => mapped.push(addr),
+            None => report.line(Status::Warn, "STUN", format!("no answer from {}", server)),
+        }
+    }
+    let local_ip = local_ip(STUN_SERVERS[0]).await;
+    let port = socket.local_addr().map(|addr| addr.port()).unwrap_or(0);
+    let (status, detail) = match mapped.as_slice() {
+        [] => (
+            Status::Warn,
+            "unknown, UDP to the internet seems to be blocked".to_string(),
+        ),
+        [first, ..] if Some(first.ip()) == local_ip && first.port() == port => {
+            (Status::Ok, format!("none, {} is reachable directly", first))
+        }
+        [first, second, ..] if first == second => (
+            Status::Ok,
+            format!("endpoint independent, seen from outside as {}", first),
+        ),
+        [first, second, ..] => (
+            Status::Ok,
+            format!(
+                "symmetric, seen as {} and {}, fine for calls through a server",
+                first, second
+            ),
+        ),
+        [first] => (Status::Ok, format!("seen from outside as {}", first)),
+    };
+    report.line(status, "NAT", detail);
+}
+
+async fn stun_binding(socket: &UdpSocket, server: &str) -> Option<SocketAddr> {
+    let addr = lookup_host(server)
+        .await
+        .ok()?
+        .find(|addr| addr.is_ipv4())?;
+    let transaction: [u8; 12] = rand::random();
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&0x0001u16.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&STUN_MAGIC.to_be_bytes());
+    request.extend_from_slice(&transaction);
+    let mut buf = [0u8; 512];
+    tokio::time::timeout(STUN_TIMEOUT, async {
+        // UDP, so ask again if the first answer doesn't come
+        for _ in 0..3 {
+            socket.send_to(&request, addr).await.ok()?;
+            let reply = tokio::time::timeout(STUN_TIMEOUT / 3, socket.recv_from(&mut buf)).await;
+            if let Ok(Ok((len, from))) = reply
+                && from == addr
+                && let Some(mapped) = parse_binding_response(&buf[..len], &transaction)
+            {
+                return Some(mapped);
+            }
+        }
+        None
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// The address a STUN server saw, from its binding response (RFC 5389).
+fn parse_binding_response(data: &[u8], transaction: &[u8; 12]) -> Option<SocketAddr> {
+    if data.len() < 20
+        || data[0..2] != [0x01, 0x01]
+        || data[4..8] != STUN_MAGIC.to_be_bytes()
+        || &data[8..20] != transaction
+    {
+        return None;
+    }
+    let mut mapped = None;
+    let mut pos = 20;
+    while pos + 4 <= data.len() {
+        let kind = u16::from_be_bytes([data[pos], data[pos + 1]]);
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let value = data.get(pos + 4..pos + 4 + len)?;
+        match kind {
+            // XOR-MAPPED-ADDRESS, preferred as NATs can't rewrite it
+            0x0020 => return decode_address(value, Some(transaction)),
+            // MAPPED-ADDRESS, from servers predating RFC 5389
+            0x0001 => mapped = decode_address(value, None),
+            _ => {}
+        }
+        // attributes are padded to 4 bytes
+        pos += 4 + len.div_ceil(4) * 4;
+    }
+    mapped
+}
+
+fn decode_address(value: &[u8], xor: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    // XOR-MAPPED-ADDRESS hides the address behind the magic cookie and the
+    // transaction id
+    let mut key = [0u8; 16];
+    if let Some(transaction) = xor {
+        key[..4].copy_from_slice(&STUN_MAGIC.to_be_bytes());
+        key[4..].copy_from_slice(transaction);
+    }
+    let port = u16::from_be_bytes([value.get(2)? ^ key[0], value.get(3)? ^ key[1]]);
+    let ip = match value.get(1)? {
+        0x01 if value.len() >= 8 => {
+            IpAddr::from(std::array::from_fn::<u8, 4, _>(|i| value[4 + i] ^ key[i]))
+        }
+        0x02 if value.len() >= 20 => {
+            IpAddr::from(std::array::from_fn::<u8, 16, _>(|i| value[4 + i] ^ key[i]))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// The address of the interface packets to `remote` leave from.
+async fn local_ip(remote: &str) -> Option<IpAddr> {
+    let addr = lookup_host(remote)
+        .await
+        .ok()?
+        .find(|addr| addr.is_ipv4())?;
+    let socket = connected_socket(addr).await.ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+fn check_audio(report: &mut Report, settings: &AudioSettings) {
+    match settings.backend.name() {
+        #[cfg(feature = "alsa")]
+        "alsa" => {
+            let devices = crate::implementations::alsa::list_devices(true);
+            let status = if devices.is_empty() { Status::Fail } else { Status::Ok };
+            report.line(status, "ALSA", format!("{} capture devices", devices.len()));
+            for device in devices {
+                println!("         {} ({})", device.name, device.description);
+            }
+        }
+        #[cfg(feature = "jack")]
+        "jack" => {
+            // not running, or nothing to record from yet, the ports can
+            // still be patched later
+            let clients = crate::implementations::jack::list_devices(true);
+            let status = if clients.is_empty() { Status::Warn } else { Status::Ok };
+            report.line(status, "JACK", format!("{} clients to record from", clients.len()));
+            for (client, ports) in clients {
+                println!("         {} ({} ports)", client, ports);
+            }
+        }
+        #[cfg(feature = "cpal")]
+        "cpal" => {
+            let devices = crate::implementations::cpal::list_devices(true);
+            let status = if devices.is_empty() { Status::Fail } else { Status::Ok };
+            report.line(status, "cpal", format!("{} capture devices", devices.len()));
+            for (_, description) in devices {
+                println!("         {}", description);
+            }
+        }
+        "pulse" if !check_pulseaudio(report) => return,
+        _ => {}
+    }
+    let producer = match settings.backend.capture(settings) {
+        Ok(producer) => {
+            report.line(
+                Status::Ok,
+                "Microphone",
+                format!("opened {}", producer.description()),
+            );
+            Some(producer)
+        }
+        Err(e) => {
+            report.line(Status::Fail, "Microphone", format!("{:?}", e));
+            None
+        }
+    };
+    let consumer = match settings.backend.playback(settings) {
+        Ok(consumer) => {
+            report.line(
+                Status::Ok,
+                "Playback",
+                format!("opened {}", consumer.description()),
+            );
+            Some(consumer)
+        }
+        Err(e) => {
+            report.line(Status::Fail, "Playback", format!("{:?}", e));
+            None
+        }
+    };
+    if let (Some(mut producer), Some(mut consumer)) = (producer, consumer) {
+        check_passthrough(report, &mut producer, &mut consumer, settings);
+    }
+}
+
+/// Plays a chirp while recording, to tell whether the microphone hears the
+/// speakers, which echo cancellation has to deal with, and how long the
+/// sound takes from one to the other.
+fn check_passthrough(
+    report: &mut Report,
+    producer: &mut Capture,
+    consumer: &mut Playback,
+    settings: &AudioSettings,
+) {
+    let chirp = chirp();
+    let samples_per_ms = SAMPLE_RATE as usize / 1000;
+    let start = CHIRP_DELAY_MS * samples_per_ms;
+    let mut played = vec![0.0; PASSTHROUGH_MS * samples_per_ms * CHANNELS];
+    for (i, sample) in chirp.iter().enumerate() {
+        played[(start + i) * CHANNELS..][..CHANNELS].fill(*sample);
+    }
+    // a frame recorded for every frame played keeps both on the same clock
+    let mut recorded = Vec::with_capacity(played.len() / CHANNELS);
+    let mut frame = vec![0.0; settings.frame_size * CHANNELS];
+    for out in played.chunks(frame.len()) {
+        let result = consumer.consume(out).and_then(|_| producer.produce(&mut frame));
+        if let Err(e) = result {
+            report.line(Status::Fail, "Passthrough", format!("{:?}", e));
+            return;
+        }
+        let mono = frame.chunks_exact(CHANNELS).map(|s| s.iter().sum::<f32>() / CHANNELS as f32);
+        recorded.extend(mono);
+    }
+    match find_chirp(&recorded, &chirp) {
+        Some((at, score)) if score >= LEAK_THRESHOLD => {
+            let heard = format!(
+                "the microphone hears the speakers after ~{}ms",
+                at.saturating_sub(start) / samples_per_ms
+            );
+            match settings.echo_cancellation {
+                true => report.line(
+                    Status::Ok,
+                    "Passthrough",
+                    format!("{}, echo cancellation is on", heard),
+                ),
+                false => report.line(
+                    Status::Warn,
+                    "Passthrough",
+                    format!(
+                        "{}, the others will hear themselves, drop --no-echo-cancellation or use headphones",
+                        heard
+                    ),
+                ),
+            }
+        }
+        _ => report.line(
+            Status::Ok,
+            "Passthrough",
+            "the microphone doesn't hear the speakers (headphones?), no round trip to measure",
+        ),
+    }
+}
+
+/// A mono sweep from the low to the high end of `CHIRP_HZ`, faded in and out.
+fn chirp() -> Vec<f32> {
+    let len = CHIRP_MS * SAMPLE_RATE as usize / 1000;
+    let duration = CHIRP_MS as f32 / 1000.0;
+    let (low, high) = CHIRP_HZ;
+    (0..len)
+        .map(|i| {
+            let t = i as f32 / SAMPLE_RATE as f32;
+            // the frequency rises linearly, the phase is its integral
+            let phase = TAU * (low * t + (high - low) * t * t / (2.0 * duration));
+            let window = 0.5 - 0.5 * (TAU * i as f32 / len as f32).cos();
+            0.5 * window * phase.sin()
+        })
+        .collect()
+}
+
+/// Where `chirp` is in `recorded` and how alike the two are there, by
+/// normalized cross-correlation, 1.0 for an exact copy at any volume or
+/// polarity. `None` if the recording is too short.
+fn find_chirp(recorded: &[f32], chirp: &[f32]) -> Option<(usize, f32)> {
+    let last = recorded.len().checked_sub(chirp.len())?;
+    let chirp_norm = chirp.iter().map(|s| s * s).sum::<f32>().sqrt();
+    // of the window, kept running
+    let mut energy: f64 = recorded[..chirp.len()].iter().map(|s| (s * s) as f64).sum();
+    let mut best: Option<(usize, f32)> = None;
+    for at in 0..=last {
+        if at > 0 {
+            let (gone, new) = (recorded[at - 1], recorded[at + chirp.len() - 1]);
+            energy = (energy + (new * new) as f64 - (gone * gone) as f64).max(0.0);
+        }
+        let dot: f32 = recorded[at..].iter().zip(chirp).map(|(a, b)| a * b).sum();
+        let score = dot.abs() / (chirp_norm * (energy.sqrt() as f32)).max(f32::EPSILON);
+        if best.is_none_or(|(_, best)| score > best) {
+            best = Some((at, score));
+        }
+    }
+    best
+}
+
+/// Whether PulseAudio is reachable, listing its capture devices.
+fn check_pulseaudio(report: &mut Report) -> bool {
+    match list_sources() {
+        Ok(sources) if sources.is_empty() => report.line(
+            Status::Fail,
+            "PulseAudio",
+            "running, but there is no capture device",
+        ),
+        Ok(sources) => {
+            report.line(
+                Status::Ok,
+                "PulseAudio",
+                format!("running, {} capture devices", sources.len()),
+            );
+            for source in sources {
+                let default = if source.is_default { ", default" } else { "" };
+                println!(
+                    "         {} ({}{})",
+                    source.description, source.form_factor, default
+                );
+            }
+        }
+        Err(_) => {
+            report.line(
+                Status::Fail,
+                "PulseAudio",
+                "can't connect, is it or PipeWire's pulse server running? Or try --backend alsa",
+            );
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_mapped_address_of_a_binding_response() {
+        let transaction = [7u8; 12];
+        let mut response = vec![0x01, 0x01, 0x00, 0x18];
+        response.extend_from_slice(&STUN_MAGIC.to_be_bytes());
+        response.extend_from_slice(&transaction);
+        // SOFTWARE, 3 bytes padded to 4
+        response.extend_from_slice(&[0x80, 0x22, 0x00, 0x03, b'a', b'b', b'c', 0]);
+        // XOR-MAPPED-ADDRESS of 203.0.113.5:40000
+        let port = 40000u16 ^ 0x2112;
+        let ip = u32::from(Ipv4Addr::new(203, 0, 113, 5)) ^ STUN_MAGIC;
+        response.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+        response.extend_from_slice(&port.to_be_bytes());
+        response.extend_from_slice(&ip.to_be_bytes());
+        assert_eq!(
+            parse_binding_response(&response, &transaction),
+            Some("203.0.113.5:40000".parse().unwrap())
+        );
+        assert_eq!(parse_binding_response(&response, &[0; 12]), None);
+    }
+
+    #[test]
+    fn finds_the_chirp_in_the_recording() {
+        let chirp = chirp();
+        // a hum the microphone picks up anyway
+        let mut recorded: Vec<f32> =
+            (0..9000).map(|i| 0.05 * (i as f32 * 0.013).sin()).collect();
+        let (at, score) = find_chirp(&recorded, &chirp).unwrap();
+        assert!(score < LEAK_THRESHOLD, "{} at {}", score, at);
+        // quieter and inverted, as speakers and microphones may
+        for (sample, chirp) in recorded[3000..].iter_mut().zip(&chirp) {
+            *sample -= 0.2 * chirp;
+        }
+        let (at, score) = find_chirp(&recorded, &chirp).unwrap();
+        assert_eq!(at, 3000);
+        assert!(score > 0.5);
+        assert_eq!(find_chirp(&recorded[..100], &chirp), None);
+    }
+}
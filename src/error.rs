@@ -0,0 +1,105 @@
+use std::fmt;
+
+use bincode::{Decode, Encode};
+
+use crate::server::JoinRejection;
+
+/// What went wrong, by where it went wrong, so whoever gets it can tell a
+/// failure worth another try from one that ends the call.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub enum Error {
+    /// the socket or the name resolution failed, or the other side is gone
+    Network(String),
+    /// the other side sent something we can't take, or turned us away
+    Protocol(String),
+    /// the server turned away our join request
+    Rejected(JoinRejection),
+    /// an admin removed us from the server
+    Kicked,
+    /// a message of the given size in bytes over the limit of its kind
+    MessageTooLarge(usize, usize),
+    /// an audio device or the sound server can't be opened, read or written
+    Audio(String),
+    Codec(String),
+    /// a local file or socket can't be read or written
+    Io(String),
+    /// a file, an argument or an upload that doesn't make sense
+    Invalid(String),
+}
+
+/// What the coordinator does when the session fails with an error.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Recovery {
+    /// starts the session again right away, e.g. to reopen a device
+    Retry,
+    /// starts the session again after a pause, the server may be back by then
+    Reconnect,
+    /// leaves the call, trying again won't help
+    Exit,
+}
+
+impl Error {
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            // someone may have left by then
+            Error::Network(_) | Error::Rejected(JoinRejection::Full) => Recovery::Reconnect,
+            Error::Audio(_) | Error::Codec(_) => Recovery::Retry,
+            Error::Protocol(_)
+            | Error::Rejected(_)
+            | Error::Kicked
+            | Error::MessageTooLarge(..)
+            | Error::Io(_)
+            | Error::Invalid(_) => Recovery::Exit,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MessageTooLarge(size, limit) => {
+                write!(
+                    f,
+                    "A message of {} bytes is over the limit of {}",
+                    size, limit
+                )
+            }
+            Error::Rejected(rejection) => write!(f, "Can't join: {}", rejection.describe()),
+            Error::Kicked => f.write_str("An admin removed you from the server"),
+            Error::Network(reason)
+            | Error::Protocol(reason)
+            | Error::Audio(reason)
+            | Error::Codec(reason)
+            | Error::Io(reason)
+            | Error::Invalid(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_lost_connections_are_worth_waiting_for() {
+        let refused = Error::Network("Can't receive from the server: refused".to_string());
+        assert_eq!(refused.recovery(), Recovery::Reconnect);
+        assert_eq!(Error::Audio("gone".to_string()).recovery(), Recovery::Retry);
+        let rejected = Error::Protocol("Can't join: wrong password".to_string());
+        assert_eq!(rejected.recovery(), Recovery::Exit);
+        assert_eq!(rejected.to_string(), "Can't join: wrong password");
+        let full = Error::Rejected(JoinRejection::Full);
+        assert_eq!(full.recovery(), Recovery::Reconnect);
+        assert_eq!(full.to_string(), "Can't join: the server is full");
+        assert_eq!(
+            Error::Rejected(JoinRejection::WrongPassword).recovery(),
+            Recovery::Exit
+        );
+        assert_eq!(
+            Error::MessageTooLarge(9000, 8192).to_string(),
+            "A message of 9000 bytes is over the limit of 8192"
+        );
+    }
+}
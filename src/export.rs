@@ -0,0 +1,252 @@
+use std::{
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use crate::{
+    CHANNELS, Error, SAMPLE_RATE,
+    admin::json_string,
+    audio::level_db,
+    music::{FileTrack, TrackSource},
+    recorder::{WavWriter, read_markers},
+};
+
+// the level is judged every 20ms, like the voice activity detection does
+const WINDOW: usize = SAMPLE_RATE as usize / 50;
+// the voice activity detection's default threshold
+const THRESHOLD_DB: f32 = -44.0;
+// kept before and after speech, so onsets and word endings aren't clipped
+const PADDING: usize = SAMPLE_RATE as usize / 5;
+// quiet stretches up to this long stay in the segment, a breath between
+// sentences shouldn't split them
+const MAX_PAUSE: usize = SAMPLE_RATE as usize / 2;
+// anything shorter is a cough or a click rather than something said
+const MIN_SPEECH: usize = SAMPLE_RATE as usize / 4;
+
+/// One utterance of one speaker, in frames since the recording started.
+#[derive(Debug)]
+struct Segment {
+    speaker: String,
+    file: String,
+    start: usize,
+    end: usize,
+}
+
+/// Cuts a recording made with `--record` into what each speaker said, one
+/// file per utterance with the silence around it trimmed, and lists them in
+/// the order they were said along with the markers in a manifest. The files
+/// go to `<recording>-export`, `recording` being the prefix all files of the
+/// call share, e.g. `calls/kop-audio-1760000000`. Returns the manifest's path.
+pub fn export(recording: &Path) -> Result<PathBuf, Error> {
+    let error = |e: &dyn Display| {
+        Error::Io(format!("Can't export {}: {}", recording.display(), e))
+    };
+    let dir = match recording.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = recording
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| error(&"not a recording"))?;
+    let mut tracks: Vec<(String, PathBuf)> = fs::read_dir(dir)
+        .map_err(|e| error(&e))?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            let speaker = name
+                .strip_prefix(prefix)?
+                .strip_prefix('-')?
+                .strip_suffix(".wav")?;
+            Some((speaker.to_string(), entry.path()))
+        })
+        .collect();
+    if tracks.is_empty() {
+        return Err(error(&"no recorded tracks"));
+    }
+    tracks.sort();
+    let markers_path = dir.join(format!("{}-markers.json", prefix));
+    let markers = match markers_path.exists() {
+        true => read_markers(&markers_path)?,
+        false => Vec::new(),
+    };
+
+    let out = dir.join(format!("{}-export", prefix));
+    fs::create_dir_all(&out).map_err(|e| error(&e))?;
+    let mut segments = Vec::new();
+    for (speaker, path) in tracks {
+        let path = path.to_str().ok_or_else(|| error(&"not a recording"))?;
+        let (levels, frames) = measure(path)?;
+        let ranges = voiced(&levels, frames, THRESHOLD_DB);
+        info!("{} said {} things", speaker, ranges.len());
+        segments.extend(cut(path, &ranges, &out, &speaker)?);
+    }
+    segments.sort_by_key(|segment| segment.start);
+
+    let manifest = out.join("manifest.json");
+    fs::write(&manifest, manifest_json(prefix, &segments, &markers))
+        .map_err(|e| Error::Io(format!("Can't write {}: {}", manifest.display(), e)))?;
+    Ok(manifest)
+}
+
+/// The level of every window of the track and its length in frames, a call
+/// is too long to keep its samples around.
+fn measure(path: &str) -> Result<(Vec<f32>, usize), Error> {
+    let mut track = FileTrack::open(path)?;
+    let mut pcm = Vec::new();
+    let mut levels = Vec::new();
+    let mut frames = 0;
+    let mut more = true;
+    while more {
+        more = track.read(&mut pcm);
+        let whole = match more {
+            true => pcm.len() / (WINDOW * CHANNELS) * WINDOW * CHANNELS,
+            false => pcm.len(),
+        };
+        levels.extend(pcm[..whole].chunks(WINDOW * CHANNELS).map(level_db));
+        frames += whole / CHANNELS;
+        pcm.drain(..whole);
+    }
+    Ok((levels, frames))
+}
+
+/// Frame ranges with speech, from the level of each window.
+fn voiced(levels: &[f32], frames: usize, threshold: f32) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for (i, _) in levels
+        .iter()
+        .enumerate()
+        .filter(|(_, level)| **level >= threshold)
+    {
+        let (start, end) = (i * WINDOW, ((i + 1) * WINDOW).min(frames));
+        match ranges.last_mut() {
+            Some(last) if start <= last.1 + MAX_PAUSE => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+    ranges.retain(|(start, end)| end - start >= MIN_SPEECH);
+    for range in &mut ranges {
+        *range = (
+            range.0.saturating_sub(PADDING),
+            (range.1 + PADDING).min(frames),
+        );
+    }
+    ranges
+}
+
+/// Writes the `ranges` of the track to `<speaker>-<n>.wav` in `out`.
+fn cut(
+    path: &str,
+    ranges: &[(usize, usize)],
+    out: &Path,
+    speaker: &str,
+) -> Result<Vec<Segment>, Error> {
+    let mut track = FileTrack::open(path)?;
+    let mut segments = Vec::new();
+    let mut writer: Option<WavWriter> = None;
+    let mut pcm = Vec::new();
+    // frames before the ones in `pcm`
+    let mut position = 0;
+    let mut more = true;
+    while more && segments.len() < ranges.len() {
+        pcm.clear();
+        more = track.read(&mut pcm);
+        let read_to = position + pcm.len() / CHANNELS;
+        while let Some(&(start, end)) = ranges.get(segments.len()) {
+            if start >= read_to {
+                break;
+            }
+            let file = format!("{}-{:03}.wav", speaker, segments.len() + 1);
+            if writer.is_none() {
+                writer = Some(WavWriter::create(&out.join(&file))?);
+            }
+            let (from, to) = (start.max(position), end.min(read_to));
+            if let Some(writer) = &mut writer {
+                writer
+                    .write(&pcm[(from - position) * CHANNELS..(to - position) * CHANNELS])
+                    .map_err(|e| Error::Io(format!("Can't write {}: {}", file, e)))?;
+            }
+            if end > read_to {
+                break;
+            }
+            writer = None;
+            segments.push(Segment {
+                speaker: speaker.to_string(),
+                file,
+                start,
+                end,
+            });
+        }
+        position = read_to;
+    }
+    Ok(segments)
+}
+
+fn manifest_json(prefix: &str, segments: &[Segment], markers: &[(String, u32)]) -> String {
+    let seconds = |frame: usize| frame as f64 / SAMPLE_RATE as f64;
+    let segments: Vec<String> = segments
+        .iter()
+        .map(|segment| {
+            // what was marked while it was said, e.g. to find it again
+            let marked: Vec<String> = markers
+                .iter()
+                .filter(|(_, frame)| (segment.start..segment.end).contains(&(*frame as usize)))
+                .map(|(name, _)| json_string(name))
+                .collect();
+            format!(
+                "{{\"speaker\":{},\"file\":{},\"start\":{:.3},\"end\":{:.3},\"markers\":[{}]}}",
+                json_string(&segment.speaker),
+                json_string(&segment.file),
+                seconds(segment.start),
+                seconds(segment.end),
+                marked.join(",")
+            )
+        })
+        .collect();
+    let markers: Vec<String> = markers
+        .iter()
+        .map(|(name, frame)| {
+            format!(
+                "{{\"name\":{},\"seconds\":{:.3}}}",
+                json_string(name),
+                seconds(*frame as usize)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"recording\":{},\"sample_rate\":{},\"segments\":[{}],\"markers\":[{}]}}\n",
+        json_string(prefix),
+        SAMPLE_RATE,
+        segments.join(","),
+        markers.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_speech_bridges_pauses_and_drops_clicks() {
+        let quiet = -70.0;
+        let mut levels = vec![quiet; 500];
+        // a sentence from 1s to 2s with a short breath in it
+        levels[50..70].fill(-20.0);
+        levels[80..100].fill(-30.0);
+        // a click
+        levels[200] = -10.0;
+        // a long pause, then more until the end
+        levels[450..].fill(-25.0);
+        let frames = 500 * WINDOW - 100;
+        assert_eq!(
+            voiced(&levels, frames, THRESHOLD_DB),
+            vec![
+                (50 * WINDOW - PADDING, 100 * WINDOW + PADDING),
+                (450 * WINDOW - PADDING, frames),
+            ]
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+// a speaker keeps the floor through pauses between words, after this long
+// without a packet it goes to the next one
+const HOLD: Duration = Duration::from_millis(400);
+// how much louder someone has to be to take the floor from the quietest
+// speaker, so two voices of similar level don't keep swapping
+const PREEMPT_RATIO: f32 = 1.5;
+// weight of the newest packet in the running loudness estimate, about 200ms
+// of 20ms frames
+const SMOOTHING: f32 = 0.1;
+
+#[derive(Debug)]
+struct Speaker {
+    // running average of the packet size, see `Floor`
+    loudness: f32,
+    last_packet: Instant,
+    holds_floor: bool,
+}
+
+/// Decides whose voice the server forwards when a room allows only a few
+/// speakers at once. Those already talking keep the floor, a newcomer only
+/// gets it when a place is free or it is clearly louder than the quietest of
+/// them. The server doesn't decode audio, opus spends more bits on louder,
+/// busier speech, so the packet size stands in for the energy.
+#[derive(Debug, Default)]
+pub struct Floor {
+    limit: Option<usize>,
+    speakers: HashMap<SocketAddr, Speaker>,
+}
+
+impl Floor {
+    pub fn new(limit: Option<usize>) -> Self {
+        Floor {
+            limit,
+            speakers: HashMap::new(),
+        }
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+        self.speakers.clear();
+    }
+
+    /// Takes a voice packet of `bytes` from `addr`, returns whether it is
+    /// forwarded.
+    pub fn admit(&mut self, addr: SocketAddr, bytes: usize, now: Instant) -> bool {
+        let Some(limit) = self.limit else {
+            return true;
+        };
+        // whoever went quiet starts afresh the next time
+        self.speakers
+            .retain(|_, speaker| now.duration_since(speaker.last_packet) <= HOLD);
+        let speaker = self.speakers.entry(addr).or_insert(Speaker {
+            loudness: bytes as f32,
+            last_packet: now,
+            holds_floor: false,
+        });
+        speaker.loudness += SMOOTHING * (bytes as f32 - speaker.loudness);
+        speaker.last_packet = now;
+        if speaker.holds_floor {
+            return true;
+        }
+        let loudness = speaker.loudness;
+
+        let holders = self.speakers.values().filter(|s| s.holds_floor).count();
+        if holders < limit {
+            self.speakers.get_mut(&addr).unwrap().holds_floor = true;
+            return true;
+        }
+        let quietest = self
+            .speakers
+            .iter()
+            .filter(|(_, s)| s.holds_floor)
+            .min_by(|(_, a), (_, b)| a.loudness.total_cmp(&b.loudness))
+            .map(|(addr, s)| (*addr, s.loudness));
+        match quietest {
+            Some((quietest, quiet)) if loudness > quiet * PREEMPT_RATIO => {
+                self.speakers.get_mut(&quietest).unwrap().holds_floor = false;
+                self.speakers.get_mut(&addr).unwrap().holds_floor = true;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_the_floor_and_yields_to_louder_voices() {
+        let addr = |port| SocketAddr::from(([10, 0, 0, 1], port));
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut floor = Floor::new(Some(2));
+        assert!(floor.admit(addr(1), 60, at(0)));
+        assert!(floor.admit(addr(2), 40, at(0)));
+        // a third voice at a similar level waits its turn
+        assert!(!floor.admit(addr(3), 50, at(20)));
+        // a much louder one takes the place of the quietest
+        for ms in (40..400).step_by(20) {
+            floor.admit(addr(1), 60, at(ms));
+            floor.admit(addr(2), 40, at(ms));
+        }
+        assert!(floor.admit(addr(4), 120, at(400)));
+        assert!(!floor.admit(addr(2), 40, at(420)));
+        assert!(floor.admit(addr(1), 60, at(420)));
+        // once someone stops talking the next one gets through
+        assert!(floor.admit(addr(3), 50, at(1000)));
+        floor.set_limit(None);
+        assert!(floor.admit(addr(2), 40, at(1020)));
+    }
+}
@@ -0,0 +1,184 @@
+use bincode::{Decode, Encode};
+
+use crate::server::{AudioData, Message};
+
+// a full header goes out at least this often, so a lost one costs little
+const REFERENCE_INTERVAL_MS: u64 = 500;
+// packets per telemetry report, 5s of 20ms frames
+const STATS_PACKETS: u32 = 250;
+
+/// An audio packet whose header is given relative to the last full one the
+/// sender sent. In steady state this saves most of the header, which matters at
+/// low bitrates where packets are only a few dozen bytes.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct AudioDelta {
+    /// low bits of the sequence number, the rest is taken from the reference
+    pub seq: u16,
+    /// milliseconds since the reference's timestamp
+    pub ts_delta: u16,
+    pub data: Vec<u8>,
+}
+
+/// Sequence number and timestamp of the last full header.
+#[derive(Debug, Default, Clone, Copy)]
+struct Reference {
+    seq_number: u32,
+    timestamp: u64,
+}
+
+/// Turns outgoing audio packets into `AudioDelta`s where possible.
+#[derive(Debug, Default)]
+pub struct HeaderCompressor {
+    reference: Option<Reference>,
+}
+
+impl HeaderCompressor {
+    pub fn compress(&mut self, audio: AudioData) -> Message {
+        if let Some(reference) = self.reference {
+            let ts_delta = audio.timestamp.wrapping_sub(reference.timestamp);
+            let seq_delta = audio.seq_number.wrapping_sub(reference.seq_number);
+            // a talk spurt starting after silence gets a fresh reference
+            if ts_delta < REFERENCE_INTERVAL_MS && seq_delta < u16::MAX as u32 / 2 {
+                return Message::AudioDelta(AudioDelta {
+                    seq: audio.seq_number as u16,
+                    ts_delta: ts_delta as u16,
+                    data: audio.data,
+                });
+            }
+        }
+        self.reference = Some(Reference {
+            seq_number: audio.seq_number,
+            timestamp: audio.timestamp,
+        });
+        Message::Audio(audio)
+    }
+}
+
+/// Restores full headers on the receiving side. If a full header got lost, the
+/// sequence number still comes out right, the timestamp is off until the next
+/// one arrives.
+#[derive(Debug, Default)]
+pub struct HeaderExpander {
+    reference: Option<Reference>,
+}
+
+impl HeaderExpander {
+    /// Remembers a full header.
+    pub fn full(&mut self, audio: &AudioData) {
+        self.reference = Some(Reference {
+            seq_number: audio.seq_number,
+            timestamp: audio.timestamp,
+        });
+    }
+
+    /// `None` until a full header arrived.
+    pub fn expand(&self, delta: AudioDelta) -> Option<AudioData> {
+        let reference = self.reference?;
+        // the sequence number closest to the reference with the same low bits
+        let offset = delta.seq.wrapping_sub(reference.seq_number as u16) as i16;
+        Some(AudioData {
+            timestamp: reference.timestamp + delta.ts_delta as u64,
+            seq_number: reference.seq_number.wrapping_add_signed(offset as i32),
+            data: delta.data,
+        })
+    }
+}
+
+/// Sizes of the audio packets sent over the last few seconds.
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy, Default)]
+pub struct PacketStats {
+    /// encoded audio per packet, in bytes
+    pub payload_avg: u32,
+    pub payload_max: u32,
+    /// everything else in the datagram, in bytes
+    pub overhead_avg: u32,
+}
+
+/// Collects sizes of outgoing audio packets and reports them every few seconds.
+#[derive(Debug, Default)]
+pub struct PacketSizes {
+    packets: u32,
+    payload: u32,
+    payload_max: u32,
+    overhead: u32,
+}
+
+impl PacketSizes {
+    pub fn add(&mut self, payload: usize, datagram: usize) -> Option<PacketStats> {
+        self.packets += 1;
+        self.payload += payload as u32;
+        self.payload_max = self.payload_max.max(payload as u32);
+        self.overhead += datagram.saturating_sub(payload) as u32;
+        if self.packets < STATS_PACKETS {
+            return None;
+        }
+        let stats = PacketStats {
+            payload_avg: self.payload / self.packets,
+            payload_max: self.payload_max,
+            overhead_avg: self.overhead / self.packets,
+        };
+        *self = PacketSizes::default();
+        Some(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::encode_message;
+
+    fn packet(seq_number: u32, timestamp: u64) -> AudioData {
+        AudioData {
+            timestamp,
+            seq_number,
+            data: vec![0; 40],
+        }
+    }
+
+    #[test]
+    fn compresses_steady_state_and_restores_headers() {
+        let mut compressor = HeaderCompressor::default();
+        let mut expander = HeaderExpander::default();
+        let start = 1_760_000_000_000;
+        let first = compressor.compress(packet(70_000, start));
+        let Message::Audio(first) = first else {
+            panic!("first packet needs a full header");
+        };
+        expander.full(&first);
+        let second = compressor.compress(packet(70_001, start + 20));
+        let full_len = encode_message(&Message::Audio(packet(70_001, start + 20))).len();
+        assert!(encode_message(&second).len() + 8 <= full_len);
+        let Message::AudioDelta(delta) = second else {
+            panic!("steady state packet should be compact");
+        };
+        assert_eq!(expander.expand(delta), Some(packet(70_001, start + 20)));
+        // silence in between, the next talk spurt starts with a full header
+        assert!(matches!(
+            compressor.compress(packet(70_002, start + 5000)),
+            Message::Audio(_)
+        ));
+    }
+
+    #[test]
+    fn restores_sequence_across_low_bits_wrap() {
+        let mut expander = HeaderExpander::default();
+        expander.full(&packet(0x1_fffe, 100));
+        let delta = AudioDelta {
+            seq: 0x0001,
+            ts_delta: 60,
+            data: vec![0; 40],
+        };
+        assert_eq!(expander.expand(delta), Some(packet(0x2_0001, 160)));
+    }
+
+    #[test]
+    fn reports_packet_sizes() {
+        let mut sizes = PacketSizes::default();
+        for i in 0..STATS_PACKETS - 1 {
+            assert_eq!(sizes.add(40 + (i % 2) as usize * 20, 60), None);
+        }
+        let stats = sizes.add(50, 60).unwrap();
+        assert_eq!(stats.payload_max, 60);
+        assert_eq!(stats.payload_avg, 49);
+    }
+}
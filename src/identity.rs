@@ -0,0 +1,191 @@
+use std::{
+    fmt,
+    fs::{self, OpenOptions, Permissions},
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use bincode::{Decode, Encode};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+
+/// Identifies a user across all of their devices. Endpoints presenting the same
+/// identity belong to the same person, so the server never forwards audio
+/// between them. It is the public half of the user's `IdentityKey`, a client
+/// only gets in under it by proving it has the key, see `Hello::answer`.
+#[derive(Encode, Decode, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub struct Identity(pub [u8; 32]);
+
+/// The key of an `Identity`, it never leaves the user's devices.
+pub struct IdentityKey(SigningKey);
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the first bytes are plenty to tell identities apart in logs
+        for byte in &self.0[..6] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Identity({})", self)
+    }
+}
+
+/// A user as the other clients know them, see `Identity::user_id`.
+#[derive(Encode, Decode, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub struct UserId(pub [u8; 16]);
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0[..6] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UserId({})", self)
+    }
+}
+
+/// The kop-audio config directory.
+pub fn config_dir() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("kop-audio"))
+}
+
+/// Path of a file in the kop-audio config directory.
+pub fn config_file(name: &str) -> Option<PathBuf> {
+    Some(config_dir()?.join(name))
+}
+
+/// Writes a secret to `path`, creating its directory. Only we can read the
+/// file, whatever the umask, also if it was there before.
+pub fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    file.write_all(data)
+}
+
+/// What a client signs to prove its identity to a server that sent `nonce`.
+fn proof_message(nonce: &[u8; 32]) -> [u8; 46] {
+    let mut message = [0u8; 46];
+    message[..14].copy_from_slice(b"kop-audio join");
+    message[14..].copy_from_slice(nonce);
+    message
+}
+
+impl IdentityKey {
+    /// Loads the key of this machine's user, creating one on first use. Copy
+    /// the file to other devices to link them to the same identity.
+    pub fn load_or_create() -> IdentityKey {
+        IdentityKey::load_or_create_named("identity")
+    }
+
+    /// Loads the key kept in `file` of the config directory, e.g. one of a bot
+    /// that shouldn't count as the user's own device.
+    pub fn load_or_create_named(file: &str) -> IdentityKey {
+        let Some(path) = config_file(file) else {
+            warn!("No home directory, using a temporary identity");
+            return IdentityKey::generate();
+        };
+        if let Ok(data) = fs::read(&path) {
+            if let Ok(bytes) = <[u8; 32]>::try_from(data.as_slice()) {
+                return IdentityKey(SigningKey::from_bytes(&bytes));
+            }
+            warn!("Ignoring malformed identity file {}", path.display());
+        }
+        let key = IdentityKey::generate();
+        match write_private(&path, key.0.as_bytes()) {
+            Ok(_) => info!("Created new identity {} in {}", key.identity(), path.display()),
+            Err(e) => warn!("Can't store identity in {}: {:?}", path.display(), e),
+        }
+        key
+    }
+
+    /// A new key, e.g. for an identity that lasts as long as the process.
+    pub fn generate() -> IdentityKey {
+        IdentityKey(SigningKey::from_bytes(&rand::random()))
+    }
+
+    /// The identity others know this key by.
+    pub fn identity(&self) -> Identity {
+        Identity(self.0.verifying_key().to_bytes())
+    }
+
+    /// Signs a server's join challenge, see `Identity::verify`.
+    pub fn prove(&self, nonce: &[u8; 32]) -> [u8; 64] {
+        self.0.sign(&proof_message(nonce)).to_bytes()
+    }
+}
+
+impl Identity {
+    /// Whether `proof` was made with the key of this identity for `nonce`,
+    /// see `IdentityKey::prove`.
+    pub fn verify(&self, nonce: &[u8; 32], proof: &[u8; 64]) -> bool {
+        VerifyingKey::from_bytes(&self.0).is_ok_and(|key| {
+            key.verify_strict(&proof_message(nonce), &Signature::from_bytes(proof))
+                .is_ok()
+        })
+    }
+
+    /// What the server tells others this identity is. `salt` is the server's
+    /// own, so ids can't be compared across servers or traced back to the
+    /// identity.
+    pub fn user_id(&self, salt: &[u8; 32]) -> UserId {
+        let hash = Sha256::new()
+            .chain_update(b"kop-audio user")
+            .chain_update(salt)
+            .chain_update(self.0)
+            .finalize();
+        UserId(hash[..16].try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_only_readable_by_us() {
+        let path = std::env::temp_dir().join(format!("kop-audio-secret-{}", std::process::id()));
+        fs::write(&path, b"old").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        write_private(&path, b"secret").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"secret");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn proves_identity_only_for_the_nonce() {
+        let key = IdentityKey(SigningKey::from_bytes(&[1; 32]));
+        let proof = key.prove(&[7; 32]);
+        assert!(key.identity().verify(&[7; 32], &proof));
+        assert!(!key.identity().verify(&[8; 32], &proof));
+        let other = IdentityKey(SigningKey::from_bytes(&[2; 32]));
+        assert!(!other.identity().verify(&[7; 32], &proof));
+        // the identity gives nothing of the key away
+        assert_ne!(key.identity().0, [1; 32]);
+    }
+}
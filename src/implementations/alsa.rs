@@ -0,0 +1,338 @@
+use alsa::device_name::HintIter;
+use alsa::pcm::{Access, Format, Frames, HwParams, IO, PCM};
+use alsa::{Direction, ValueOr};
+use log::{debug, info, warn};
+
+use super::{AudioBackend, Capture, CaptureDevice, Devices, Playback, PlaybackDevice, is_device};
+use crate::aec::{EchoCanceller, EchoReference};
+use crate::audio::samples;
+use crate::settings::AudioSettings;
+use crate::{AudioProducer, CHANNELS, Consumer, Error, SAMPLE_RATE};
+
+/// An open PCM device, configured for interleaved 16 bit stereo at the rate
+/// opus runs at.
+struct Pcm {
+    pcm: PCM,
+    name: String,
+}
+
+impl Pcm {
+    /// Opens `name`, one period per frame and `periods` of them in the
+    /// buffer. ALSA's plug layer converts the rate and format where the
+    /// hardware can't do them, `hw:` devices without it may refuse.
+    fn open(name: &str, capture: bool, frame_size: usize, periods: u32) -> Result<Pcm, Error> {
+        let error =
+            |what: &str, e: alsa::Error| Error::Audio(format!("ALSA {}: {}: {}", name, what, e));
+        let direction = match capture {
+            true => Direction::Capture,
+            false => Direction::Playback,
+        };
+        let pcm = PCM::new(name, direction, false).map_err(|e| error("open", e))?;
+        let (period, buffer) =
+            configure(&pcm, frame_size, periods).map_err(|(what, e)| error(what, e))?;
+        debug!(
+            "Opened ALSA device {} with periods of {} frames and a buffer of {}",
+            name, period, buffer
+        );
+        Ok(Pcm {
+            pcm,
+            name: name.to_string(),
+        })
+    }
+
+    /// Reads and writes the samples, in the format `configure` set.
+    fn io(&self) -> Result<IO<'_, i16>, Error> {
+        self.pcm
+            .io_i16()
+            .map_err(|e| Error::Audio(format!("ALSA {}: {}", self.name, e)))
+    }
+
+    /// Retries after an overrun or underrun, `Err` if the device is gone.
+    fn recover(&self, e: alsa::Error) -> Result<(), String> {
+        self.pcm.try_recover(e, true).map_err(|e| e.to_string())
+    }
+}
+
+/// Sets up interleaved 16 bit stereo at SAMPLE_RATE with the period and
+/// buffer size closest to the ones asked for, returning those. On failure the
+/// step and ALSA's error.
+fn configure(
+    pcm: &PCM,
+    frame_size: usize,
+    periods: u32,
+) -> Result<(Frames, Frames), (&'static str, alsa::Error)> {
+    let period = frame_size as Frames;
+    let buffer = period * periods.max(2) as Frames;
+    let params = HwParams::any(pcm).map_err(|e| ("any", e))?;
+    params
+        .set_rate_resample(true)
+        .map_err(|e| ("resample", e))?;
+    params
+        .set_access(Access::RWInterleaved)
+        .map_err(|e| ("access", e))?;
+    params
+        .set_format(Format::s16())
+        .map_err(|e| ("format", e))?;
+    params
+        .set_channels(CHANNELS as u32)
+        .map_err(|e| ("channels", e))?;
+    params
+        .set_rate(SAMPLE_RATE, ValueOr::Nearest)
+        .map_err(|e| ("rate", e))?;
+    let period = params
+        .set_period_size_near(period, ValueOr::Nearest)
+        .map_err(|e| ("period size", e))?;
+    let buffer = params
+        .set_buffer_size_near(buffer)
+        .map_err(|e| ("buffer size", e))?;
+    pcm.hw_params(&params).map_err(|e| ("hw params", e))?;
+    Ok((period, buffer))
+}
+
+/// A PCM device as ALSA lists it, e.g. `default` or `plughw:CARD=USB,DEV=0`.
+#[derive(Debug, Clone)]
+pub struct AlsaDevice {
+    pub name: String,
+    pub description: String,
+}
+
+/// Lists the PCM devices usable for capture or playback, see `list-mics`
+/// and `list-outputs` with `--backend alsa`.
+pub fn list_devices(capture: bool) -> Vec<AlsaDevice> {
+    let wanted = match capture {
+        true => Direction::Capture,
+        false => Direction::Playback,
+    };
+    let Ok(hints) = HintIter::new_str(None, "pcm") else {
+        return Vec::new();
+    };
+    hints
+        // no direction means both
+        .filter(|hint| hint.direction.is_none_or(|direction| direction == wanted))
+        .filter_map(|hint| {
+            Some(AlsaDevice {
+                name: hint.name?,
+                // the second line is e.g. "Default Audio Device"
+                description: hint.desc.unwrap_or_default().replace('\n', ", "),
+            })
+        })
+        .collect()
+}
+
+/// The ALSA device `query` means, an index into `list_devices`, a part of a
+/// listed name or description, or any PCM name ALSA understands.
+fn resolve(query: &str, capture: bool) -> String {
+    let devices = list_devices(capture);
+    // e.g. hw:CARD=USB is also part of plughw:CARD=USB
+    if query == "default" || devices.iter().any(|device| device.name == query) {
+        return query.to_string();
+    }
+    devices
+        .into_iter()
+        .enumerate()
+        .find(|(index, device)| {
+            is_device(
+                query,
+                *index as u32,
+                &device.name,
+                &device.description,
+                false,
+            )
+        })
+        .map(|(_, device)| device.name)
+        .unwrap_or_else(|| query.to_string())
+}
+
+/// ALSA PCM devices directly, for systems without a sound server.
+#[derive(Debug)]
+pub struct AlsaBackend;
+
+impl AudioBackend for AlsaBackend {
+    fn name(&self) -> &'static str {
+        "alsa"
+    }
+
+    fn capture(&self, settings: &AudioSettings) -> Result<Capture, Error> {
+        Ok(Box::new(AlsaProducer::new(settings)?))
+    }
+
+    fn playback(&self, settings: &AudioSettings) -> Result<Playback, Error> {
+        Ok(Box::new(AlsaConsumer::new(settings)?))
+    }
+
+    fn list_devices(&self) -> (Devices, Devices) {
+        let list = |capture| {
+            list_devices(capture)
+                .into_iter()
+                .map(|device| (device.name, device.description))
+                .collect()
+        };
+        (list(true), list(false))
+    }
+}
+
+/// Records straight from an ALSA PCM device, for systems without a sound
+/// server, e.g. a Raspberry Pi.
+pub struct AlsaProducer {
+    pcm: Pcm,
+    buf: Vec<i16>,
+    echo: Option<EchoCanceller>,
+}
+
+impl AlsaProducer {
+    pub fn new(settings: &AudioSettings) -> Result<Self, Error> {
+        AlsaProducer::open(settings, None)
+    }
+
+    fn open(settings: &AudioSettings, exclude: Option<&str>) -> Result<Self, Error> {
+        let default = ["default".to_string()];
+        let mics = match settings.mics.is_empty() {
+            true => &default[..],
+            false => &settings.mics[..],
+        };
+        let mut last_error = Error::Audio("ALSA: no microphone".to_string());
+        for mic in mics {
+            let name = resolve(mic, true);
+            if Some(name.as_str()) == exclude {
+                continue;
+            }
+            // a few periods, capture is read as soon as a frame is in
+            match Pcm::open(&name, true, settings.frame_size, 4) {
+                Ok(pcm) => {
+                    info!("Recording from ALSA device {}", name);
+                    return Ok(AlsaProducer {
+                        pcm,
+                        buf: vec![0; settings.frame_size * CHANNELS],
+                        echo: None,
+                    });
+                }
+                Err(e) => {
+                    warn!("Microphone {} isn't available: {:?}", mic, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+impl CaptureDevice for AlsaProducer {
+    /// Opens the next device of `--mic` after this one failed.
+    fn fallback(&mut self, settings: &AudioSettings) -> Result<Capture, Error> {
+        let mut next = AlsaProducer::open(settings, Some(&self.pcm.name))?;
+        next.echo = self.echo.take();
+        Ok(Box::new(next))
+    }
+
+    fn switch_to(&mut self, device: &str, settings: &AudioSettings) -> Result<Capture, Error> {
+        let settings = AudioSettings {
+            mics: vec![device.to_string()],
+            ..settings.clone()
+        };
+        let mut next = AlsaProducer::open(&settings, None)?;
+        next.echo = self.echo.take();
+        Ok(Box::new(next))
+    }
+
+    fn set_echo_canceller(&mut self, echo: EchoCanceller) {
+        self.echo = Some(echo);
+    }
+
+    fn description(&self) -> &str {
+        &self.pcm.name
+    }
+}
+
+impl AudioProducer for AlsaProducer {
+    fn produce(&mut self, data: &mut [f32]) -> Result<(), Error> {
+        let frames = data.len() / CHANNELS;
+        if self.buf.len() < data.len() {
+            self.buf.resize(data.len(), 0);
+        }
+        let io = self.pcm.io()?;
+        let mut read = 0;
+        while read < frames {
+            match io.readi(&mut self.buf[read * CHANNELS..frames * CHANNELS]) {
+                Ok(n) => read += n,
+                Err(e) => {
+                    if let Err(e) = self.pcm.recover(e) {
+                        warn!("Reading from {} failed: {}", self.pcm.name, e);
+                        return Err(Error::Audio(format!("ALSA {}: {}", self.pcm.name, e)));
+                    }
+                }
+            }
+        }
+        for (sample, read) in data.iter_mut().zip(&self.buf) {
+            *sample = samples::from_i16(*read);
+        }
+        if let Some(echo) = &mut self.echo {
+            echo.process(data);
+        }
+        Ok(())
+    }
+}
+
+/// Plays straight to an ALSA PCM device.
+pub struct AlsaConsumer {
+    pcm: Pcm,
+    echo: Option<EchoReference>,
+}
+
+impl AlsaConsumer {
+    pub fn new(settings: &AudioSettings) -> Result<Self, Error> {
+        let name = resolve(
+            settings.output_device.as_deref().unwrap_or("default"),
+            false,
+        );
+        // as many periods as PulseAudio would be asked to buffer, the writes
+        // block once it is full and pace playback
+        let pcm = Pcm::open(
+            &name,
+            false,
+            settings.frame_size,
+            settings.playback_frames + 1,
+        )?;
+        info!("Playing on ALSA device {}", name);
+        Ok(AlsaConsumer { pcm, echo: None })
+    }
+}
+
+impl PlaybackDevice for AlsaConsumer {
+    fn switch_to(&mut self, device: &str, settings: &AudioSettings) -> Result<Playback, Error> {
+        let settings = AudioSettings {
+            output_device: Some(device.to_string()),
+            ..settings.clone()
+        };
+        let mut next = AlsaConsumer::new(&settings)?;
+        next.echo = self.echo.take();
+        Ok(Box::new(next))
+    }
+
+    fn set_echo_reference(&mut self, echo: EchoReference) {
+        self.echo = Some(echo);
+    }
+
+    fn description(&self) -> &str {
+        &self.pcm.name
+    }
+}
+
+impl Consumer for AlsaConsumer {
+    fn consume(&mut self, data: &[f32]) -> Result<usize, Error> {
+        if let Some(echo) = &self.echo {
+            echo.push(data);
+        }
+        let pcm: Vec<i16> = data.iter().map(|sample| samples::to_i16(*sample)).collect();
+        let frames = data.len() / CHANNELS;
+        let io = self.pcm.io()?;
+        let mut written = 0;
+        while written < frames {
+            match io.writei(&pcm[written * CHANNELS..frames * CHANNELS]) {
+                Ok(n) => written += n,
+                // an underrun, e.g. after a gap in the call, starts over
+                Err(e) => self.pcm.recover(e).map_err(Error::Audio)?,
+            }
+        }
+        Ok(data.len())
+    }
+}
@@ -0,0 +1,510 @@
+use std::collections::VecDeque;
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use ::cpal::{
+    Device, FromSample, Host, SampleFormat, SizedSample, Stream, StreamConfig,
+    SupportedStreamConfig,
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+};
+use log::{info, warn};
+
+use super::{AudioBackend, Capture, CaptureDevice, Devices, Playback, PlaybackDevice, is_device};
+use crate::aec::{EchoCanceller, EchoReference};
+use crate::resampler::StreamResampler;
+use crate::settings::AudioSettings;
+use crate::{AudioProducer, CHANNELS, Consumer, Error, SAMPLE_RATE};
+
+// how often the ring is checked while waiting for the stream's thread
+const POLL: Duration = Duration::from_millis(1);
+// the stream's thread runs every few milliseconds, this long without it the
+// device is taken to be gone
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Interleaved stereo at the device's rate, between the stream's thread and
+/// the producer or consumer.
+type Ring = Arc<Mutex<VecDeque<f32>>>;
+
+fn cpal_error(e: impl std::fmt::Display) -> Error {
+    Error::Audio(format!("cpal: {}", e))
+}
+
+/// Our stereo frame from a frame of the device, a mono one feeds both sides.
+fn to_stereo(frame: &[f32]) -> [f32; CHANNELS] {
+    match frame {
+        [mono] => [*mono; CHANNELS],
+        [left, right, ..] => [*left, *right],
+        [] => [0.0; CHANNELS],
+    }
+}
+
+/// A frame of the device from our stereo one, a mono device gets the mix and
+/// channels past the second are silent.
+fn from_stereo(left: f32, right: f32, frame: &mut [f32]) {
+    match frame {
+        [mono] => *mono = (left + right) / 2.0,
+        [first, second, rest @ ..] => {
+            *first = left;
+            *second = right;
+            rest.fill(0.0);
+        }
+        [] => {}
+    }
+}
+
+/// The devices to record from, or to play to if not `capture`, with the one
+/// the system picks as default.
+fn devices(host: &Host, capture: bool) -> (Vec<Device>, Option<Device>) {
+    let (devices, default) = match capture {
+        true => (host.input_devices(), host.default_input_device()),
+        false => (host.output_devices(), host.default_output_device()),
+    };
+    let devices = devices.map(|devices| devices.collect()).unwrap_or_default();
+    (devices, default)
+}
+
+/// Name and description of a device, as listed.
+fn describe(device: &Device) -> (String, String) {
+    match device.description() {
+        Ok(description) => (description.name().to_string(), description.to_string()),
+        Err(_) => ("unknown".to_string(), "unknown".to_string()),
+    }
+}
+
+/// The devices of the system's audio API to record from, or to play to if
+/// not `capture`, by name and description.
+pub fn list_devices(capture: bool) -> Vec<(String, String)> {
+    let (devices, _) = devices(&::cpal::default_host(), capture);
+    devices.iter().map(describe).collect()
+}
+
+/// The device `query` means, an index into `list_devices`, a part of a
+/// listed name or description, or the system's default for `default`.
+fn find(host: &Host, query: &str, capture: bool) -> Result<Device, Error> {
+    let (devices, default) = devices(host, capture);
+    if query == "default" {
+        return default.ok_or_else(|| cpal_error("no default device"));
+    }
+    let default_id = default.and_then(|device| device.id().ok());
+    devices
+        .into_iter()
+        .enumerate()
+        .find(|(index, device)| {
+            let (name, description) = describe(device);
+            let is_default = default_id.is_some() && device.id().ok() == default_id;
+            is_device(query, *index as u32, &name, &description, is_default)
+        })
+        .map(|(_, device)| device)
+        .ok_or_else(|| cpal_error(format!("no device {}", query)))
+}
+
+/// Our rate in a format we convert if the device has it, its default
+/// otherwise.
+fn pick_config(device: &Device, capture: bool) -> Result<SupportedStreamConfig, Error> {
+    let supported = match capture {
+        true => device.supported_input_configs().map(Iterator::collect),
+        false => device.supported_output_configs().map(Iterator::collect),
+    };
+    let supported: Vec<_> = supported.unwrap_or_else(|_| Vec::new());
+    let ours = supported
+        .into_iter()
+        .filter(|range| [SampleFormat::F32, SampleFormat::I16].contains(&range.sample_format()))
+        .filter(|range| range.channels() as usize >= CHANNELS)
+        .find_map(|range| range.try_with_sample_rate(SAMPLE_RATE));
+    match ours {
+        Some(config) => Ok(config),
+        None => match capture {
+            true => device.default_input_config().map_err(cpal_error),
+            false => device.default_output_config().map_err(cpal_error),
+        },
+    }
+}
+
+/// Stops a stream's ring when it reports an error, it has to be opened again.
+fn on_error(running: Arc<AtomicBool>) -> impl FnMut(::cpal::StreamError) + Send + 'static {
+    move |e| {
+        warn!("cpal stream failed: {}", e);
+        running.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Starts recording into `ring`, at most `capacity` samples are kept.
+fn capture_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    ring: Ring,
+    capacity: usize,
+    running: Arc<AtomicBool>,
+) -> Result<Stream, Error>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let channels = config.channels as usize;
+    let mut frame = Vec::with_capacity(channels);
+    let data = move |data: &[T], _: &::cpal::InputCallbackInfo| {
+        let Ok(mut ring) = ring.lock() else {
+            return;
+        };
+        for samples in data.chunks_exact(channels) {
+            // a reader that fell behind loses the newest audio, the device
+            // doesn't wait
+            if ring.len() + CHANNELS > capacity {
+                break;
+            }
+            frame.clear();
+            frame.extend(samples.iter().map(|sample| sample.to_sample::<f32>()));
+            ring.extend(to_stereo(&frame));
+        }
+    };
+    let stream = device
+        .build_input_stream(config, data, on_error(running), None)
+        .map_err(cpal_error)?;
+    stream.play().map_err(cpal_error)?;
+    Ok(stream)
+}
+
+/// Starts playing from `ring`.
+fn playback_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    ring: Ring,
+    running: Arc<AtomicBool>,
+) -> Result<Stream, Error>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    let mut frame = vec![0.0; channels];
+    let data = move |data: &mut [T], _: &::cpal::OutputCallbackInfo| {
+        let mut ring = ring.lock().ok();
+        for samples in data.chunks_exact_mut(channels) {
+            // silence until the next frame is mixed
+            let (left, right) = match &mut ring {
+                Some(ring) if ring.len() >= CHANNELS => {
+                    (ring.pop_front().unwrap(), ring.pop_front().unwrap())
+                }
+                _ => (0.0, 0.0),
+            };
+            from_stereo(left, right, &mut frame);
+            for (sample, value) in samples.iter_mut().zip(&frame) {
+                *sample = T::from_sample(*value);
+            }
+        }
+    };
+    let stream = device
+        .build_output_stream(config, data, on_error(running), None)
+        .map_err(cpal_error)?;
+    stream.play().map_err(cpal_error)?;
+    Ok(stream)
+}
+
+fn unsupported(config: &SupportedStreamConfig) -> Error {
+    cpal_error(format!(
+        "unsupported sample format {}",
+        config.sample_format()
+    ))
+}
+
+/// The audio API of the system through cpal, CoreAudio on macOS and ALSA
+/// elsewhere.
+#[derive(Debug)]
+pub struct CpalBackend;
+
+impl AudioBackend for CpalBackend {
+    fn name(&self) -> &'static str {
+        "cpal"
+    }
+
+    fn capture(&self, settings: &AudioSettings) -> Result<Capture, Error> {
+        Ok(Box::new(CpalProducer::new(settings)?))
+    }
+
+    fn playback(&self, settings: &AudioSettings) -> Result<Playback, Error> {
+        Ok(Box::new(CpalConsumer::new(settings)?))
+    }
+
+    fn list_devices(&self) -> (Devices, Devices) {
+        (list_devices(true), list_devices(false))
+    }
+}
+
+/// Records from a device of the system's audio API. The stream runs on
+/// cpal's thread and fills a ring of a few frames that `produce` reads.
+pub struct CpalProducer {
+    // dropping it stops recording
+    _stream: Stream,
+    ring: Ring,
+    running: Arc<AtomicBool>,
+    // from the device's rate to ours, if they differ
+    resampler: Option<StreamResampler>,
+    native: Vec<f32>,
+    pending: Vec<f32>,
+    description: String,
+    echo: Option<EchoCanceller>,
+}
+
+impl CpalProducer {
+    pub fn new(settings: &AudioSettings) -> Result<Self, Error> {
+        CpalProducer::open(settings, None)
+    }
+
+    fn open(settings: &AudioSettings, exclude: Option<&str>) -> Result<Self, Error> {
+        let host = ::cpal::default_host();
+        let default = ["default".to_string()];
+        let mics = match settings.mics.is_empty() {
+            true => &default[..],
+            false => &settings.mics[..],
+        };
+        let mut last_error = cpal_error("no microphone");
+        for mic in mics {
+            let opened = find(&host, mic, true).and_then(|device| {
+                let (name, _) = describe(&device);
+                if Some(name.as_str()) == exclude {
+                    return Err(cpal_error(format!("{} just failed", name)));
+                }
+                CpalProducer::start(&device, name, settings)
+            });
+            match opened {
+                Ok(producer) => return Ok(producer),
+                Err(e) => {
+                    warn!("Microphone {} isn't available: {:?}", mic, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    fn start(device: &Device, name: String, settings: &AudioSettings) -> Result<Self, Error> {
+        let supported = pick_config(device, true)?;
+        let config = supported.config();
+        let rate = config.sample_rate;
+        let resampler = match rate {
+            SAMPLE_RATE => None,
+            _ => {
+                let chunk = settings.frame_size * rate as usize / SAMPLE_RATE as usize;
+                Some(StreamResampler::new(rate, SAMPLE_RATE, chunk)?)
+            }
+        };
+        // a few frames, the microphone is read as soon as one is in
+        let frame = settings.frame_size * rate as usize / SAMPLE_RATE as usize;
+        let capacity = 8 * frame * CHANNELS;
+        let ring: Ring = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+        let running = Arc::new(AtomicBool::new(true));
+        let (ring_tx, running_tx) = (ring.clone(), running.clone());
+        let stream = match supported.sample_format() {
+            SampleFormat::F32 => {
+                capture_stream::<f32>(device, &config, ring_tx, capacity, running_tx)?
+            }
+            SampleFormat::I16 => {
+                capture_stream::<i16>(device, &config, ring_tx, capacity, running_tx)?
+            }
+            _ => return Err(unsupported(&supported)),
+        };
+        info!("Recording through cpal from {} at {}Hz", name, rate);
+        Ok(CpalProducer {
+            _stream: stream,
+            ring,
+            running,
+            resampler,
+            native: Vec::new(),
+            pending: Vec::new(),
+            description: name,
+            echo: None,
+        })
+    }
+}
+
+impl CaptureDevice for CpalProducer {
+    /// Opens the next device of `--mic` after this one failed.
+    fn fallback(&mut self, settings: &AudioSettings) -> Result<Capture, Error> {
+        let mut next = CpalProducer::open(settings, Some(&self.description))?;
+        next.echo = self.echo.take();
+        Ok(Box::new(next))
+    }
+
+    fn switch_to(&mut self, device: &str, settings: &AudioSettings) -> Result<Capture, Error> {
+        let settings = AudioSettings {
+            mics: vec![device.to_string()],
+            ..settings.clone()
+        };
+        let mut next = CpalProducer::open(&settings, None)?;
+        next.echo = self.echo.take();
+        Ok(Box::new(next))
+    }
+
+    fn set_echo_canceller(&mut self, echo: EchoCanceller) {
+        self.echo = Some(echo);
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+}
+
+impl AudioProducer for CpalProducer {
+    fn produce(&mut self, data: &mut [f32]) -> Result<(), Error> {
+        let started = Instant::now();
+        while self.pending.len() < data.len() {
+            let taken = {
+                let mut ring = self.ring.lock().map_err(cpal_error)?;
+                self.native.clear();
+                self.native.extend(ring.drain(..));
+                self.native.len()
+            };
+            if taken == 0 {
+                if !self.running.load(Ordering::Relaxed) || started.elapsed() > TIMEOUT {
+                    return Err(Error::Audio("cpal stopped recording".to_string()));
+                }
+                sleep(POLL);
+                continue;
+            }
+            match &mut self.resampler {
+                Some(resampler) => resampler.process(&self.native, &mut self.pending),
+                None => self.pending.extend_from_slice(&self.native),
+            }
+        }
+        data.copy_from_slice(&self.pending[..data.len()]);
+        self.pending.drain(..data.len());
+        if let Some(echo) = &mut self.echo {
+            echo.process(data);
+        }
+        Ok(())
+    }
+}
+
+/// Plays to a device of the system's audio API, `--output-device` names it.
+pub struct CpalConsumer {
+    // dropping it stops playback
+    _stream: Stream,
+    ring: Ring,
+    capacity: usize,
+    running: Arc<AtomicBool>,
+    // from our rate to the device's, if they differ
+    resampler: Option<StreamResampler>,
+    native: Vec<f32>,
+    description: String,
+    echo: Option<EchoReference>,
+}
+
+impl CpalConsumer {
+    pub fn new(settings: &AudioSettings) -> Result<Self, Error> {
+        let host = ::cpal::default_host();
+        let output = settings.output_device.as_deref().unwrap_or("default");
+        let device = find(&host, output, false)?;
+        let (name, _) = describe(&device);
+        let supported = pick_config(&device, false)?;
+        let config = supported.config();
+        let rate = config.sample_rate;
+        let resampler = match rate {
+            SAMPLE_RATE => None,
+            _ => Some(StreamResampler::new(
+                SAMPLE_RATE,
+                rate,
+                settings.frame_size,
+            )?),
+        };
+        // as much as PulseAudio would be asked to buffer, writes wait once it
+        // is full and pace playback
+        let frame = settings.frame_size * rate as usize / SAMPLE_RATE as usize;
+        let capacity = (settings.playback_frames as usize + 1) * frame * CHANNELS;
+        let ring: Ring = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+        let running = Arc::new(AtomicBool::new(true));
+        let (ring_rx, running_tx) = (ring.clone(), running.clone());
+        let stream = match supported.sample_format() {
+            SampleFormat::F32 => playback_stream::<f32>(&device, &config, ring_rx, running_tx)?,
+            SampleFormat::I16 => playback_stream::<i16>(&device, &config, ring_rx, running_tx)?,
+            _ => return Err(unsupported(&supported)),
+        };
+        info!("Playing through cpal on {} at {}Hz", name, rate);
+        Ok(CpalConsumer {
+            _stream: stream,
+            ring,
+            capacity,
+            running,
+            resampler,
+            native: Vec::new(),
+            description: name,
+            echo: None,
+        })
+    }
+}
+
+impl PlaybackDevice for CpalConsumer {
+    fn switch_to(&mut self, device: &str, settings: &AudioSettings) -> Result<Playback, Error> {
+        let settings = AudioSettings {
+            output_device: Some(device.to_string()),
+            ..settings.clone()
+        };
+        let mut next = CpalConsumer::new(&settings)?;
+        next.echo = self.echo.take();
+        Ok(Box::new(next))
+    }
+
+    fn set_echo_reference(&mut self, echo: EchoReference) {
+        self.echo = Some(echo);
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+}
+
+impl Consumer for CpalConsumer {
+    fn consume(&mut self, data: &[f32]) -> Result<usize, Error> {
+        if let Some(echo) = &self.echo {
+            echo.push(data);
+        }
+        let samples = match &mut self.resampler {
+            Some(resampler) => {
+                self.native.clear();
+                resampler.process(data, &mut self.native);
+                &self.native[..]
+            }
+            None => data,
+        };
+        let started = Instant::now();
+        let mut written = 0;
+        while written < samples.len() {
+            let space = {
+                let mut ring = self.ring.lock().map_err(cpal_error)?;
+                let space = self
+                    .capacity
+                    .saturating_sub(ring.len())
+                    .min(samples.len() - written);
+                ring.extend(&samples[written..written + space]);
+                space
+            };
+            written += space;
+            if space == 0 {
+                if !self.running.load(Ordering::Relaxed) || started.elapsed() > TIMEOUT {
+                    return Err(Error::Audio("cpal stopped playing".to_string()));
+                }
+                sleep(POLL);
+            }
+        }
+        Ok(data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_device_channels_to_stereo() {
+        assert_eq!(to_stereo(&[0.5]), [0.5, 0.5]);
+        assert_eq!(to_stereo(&[0.1, 0.2, 0.3, 0.4]), [0.1, 0.2]);
+        let mut mono = [1.0];
+        from_stereo(0.2, 0.4, &mut mono);
+        assert!((mono[0] - 0.3).abs() < 1e-6);
+        let mut surround = [1.0; 6];
+        from_stereo(0.2, 0.4, &mut surround);
+        assert_eq!(surround, [0.2, 0.4, 0.0, 0.0, 0.0, 0.0]);
+    }
+}
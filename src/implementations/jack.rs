@@ -0,0 +1,542 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use ::jack::{
+    AsyncClient, AudioIn, AudioOut, Client, ClientOptions, ClientStatus, Control,
+    NotificationHandler, Port, PortFlags, ProcessHandler, ProcessScope, RingBuffer,
+    RingBufferReader, RingBufferWriter,
+};
+use log::{info, warn};
+
+use super::{AudioBackend, Capture, CaptureDevice, Devices, Playback, PlaybackDevice, is_device};
+use crate::aec::{EchoCanceller, EchoReference};
+use crate::resampler::StreamResampler;
+use crate::settings::AudioSettings;
+use crate::{AudioProducer, CHANNELS, Consumer, Error, SAMPLE_RATE};
+
+// the names the two sides show up under in the patchbay
+const CAPTURE_CLIENT: &str = "kop-audio-in";
+const PLAYBACK_CLIENT: &str = "kop-audio-out";
+const AUDIO_PORT: &str = "32 bit float mono audio";
+const SAMPLE_BYTES: usize = std::mem::size_of::<f32>();
+// how often the ring buffers are checked while waiting for the JACK thread
+const POLL: Duration = Duration::from_millis(1);
+// the JACK thread runs every few milliseconds, this long without it the
+// server is taken to be gone
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Notices the server shutting the client down, it has to be opened again.
+struct Notifications(Arc<AtomicBool>);
+
+impl NotificationHandler for Notifications {
+    fn shutdown(&mut self, _status: ClientStatus, reason: &str) {
+        warn!("JACK shut the client down: {}", reason);
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Runs on the JACK thread, interleaves the input ports into the ring buffer.
+struct CaptureHandler {
+    ports: [Port<AudioIn>; CHANNELS],
+    ring: RingBufferWriter,
+}
+
+impl ProcessHandler for CaptureHandler {
+    fn process(&mut self, _: &Client, scope: &ProcessScope) -> Control {
+        let [left, right] = &self.ports;
+        for (left, right) in left.as_slice(scope).iter().zip(right.as_slice(scope)) {
+            // a reader that fell behind loses the newest audio, JACK doesn't wait
+            if self.ring.space() < CHANNELS * SAMPLE_BYTES {
+                break;
+            }
+            self.ring.write_buffer(&left.to_ne_bytes());
+            self.ring.write_buffer(&right.to_ne_bytes());
+        }
+        Control::Continue
+    }
+}
+
+/// Runs on the JACK thread, spreads the ring buffer over the output ports.
+struct PlaybackHandler {
+    ports: [Port<AudioOut>; CHANNELS],
+    ring: RingBufferReader,
+}
+
+impl ProcessHandler for PlaybackHandler {
+    fn process(&mut self, _: &Client, scope: &ProcessScope) -> Control {
+        let [left, right] = &mut self.ports;
+        let mut bytes = [0u8; SAMPLE_BYTES];
+        for (left, right) in left
+            .as_mut_slice(scope)
+            .iter_mut()
+            .zip(right.as_mut_slice(scope))
+        {
+            // silence until the next frame is mixed
+            if self.ring.space() < CHANNELS * SAMPLE_BYTES {
+                *left = 0.0;
+                *right = 0.0;
+                continue;
+            }
+            self.ring.read_buffer(&mut bytes);
+            *left = f32::from_ne_bytes(bytes);
+            self.ring.read_buffer(&mut bytes);
+            *right = f32::from_ne_bytes(bytes);
+        }
+        Control::Continue
+    }
+}
+
+fn jack_error(e: ::jack::Error) -> Error {
+    Error::Audio(format!("JACK: {}", e))
+}
+
+fn closed() -> Error {
+    Error::Audio("JACK client closed".to_string())
+}
+
+fn open_client(name: &str) -> Result<(Client, Arc<AtomicBool>), Error> {
+    // a JACK setup has its server started by the user, e.g. through Carla
+    let (client, _) = Client::new(name, ClientOptions::NO_START_SERVER).map_err(jack_error)?;
+    Ok((client, Arc::new(AtomicBool::new(true))))
+}
+
+/// The JACK clients with audio ports to record from, or to play to if not
+/// `capture`, and how many ports each has.
+pub fn list_devices(capture: bool) -> Vec<(String, usize)> {
+    match Client::new("kop-audio-list", ClientOptions::NO_START_SERVER) {
+        Ok((client, _)) => group_by_client(&their_ports(&client, capture)),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// The audio ports of everyone else to connect to, outputs to record from
+/// them, inputs to play to them.
+fn their_ports(client: &Client, capture: bool) -> Vec<String> {
+    let flags = match capture {
+        true => PortFlags::IS_OUTPUT,
+        false => PortFlags::IS_INPUT,
+    };
+    client
+        .ports(None, Some(AUDIO_PORT), flags)
+        .into_iter()
+        .filter(|port| ![CAPTURE_CLIENT, PLAYBACK_CLIENT].contains(&port_client(port)))
+        .collect()
+}
+
+/// The client part of `client:port`.
+fn port_client(port: &str) -> &str {
+    port.split_once(':').map_or(port, |(client, _)| client)
+}
+
+fn group_by_client(ports: &[String]) -> Vec<(String, usize)> {
+    let mut clients: Vec<(String, usize)> = Vec::new();
+    for port in ports {
+        let name = port_client(port);
+        match clients.iter_mut().find(|(client, _)| client == name) {
+            Some((_, count)) => *count += 1,
+            None => clients.push((name.to_string(), 1)),
+        }
+    }
+    clients
+}
+
+/// Connects `ours` to the ports of the client `query` means, an index or part
+/// of a name of `list_devices`, or the sound card for `default`. Returns the
+/// client connected to.
+fn connect(client: &Client, ours: &[String], query: &str, capture: bool) -> Result<String, Error> {
+    let (device, theirs): (String, Vec<String>) = if query == "default" {
+        let physical = PortFlags::IS_PHYSICAL
+            | match capture {
+                true => PortFlags::IS_OUTPUT,
+                false => PortFlags::IS_INPUT,
+            };
+        let theirs = client.ports(None, Some(AUDIO_PORT), physical);
+        let device = theirs.first().map_or("system", |port| port_client(port));
+        (device.to_string(), theirs)
+    } else {
+        let ports = their_ports(client, capture);
+        let clients = group_by_client(&ports);
+        let device = clients
+            .iter()
+            .find(|(name, _)| name == query)
+            .or_else(|| {
+                clients
+                    .iter()
+                    .enumerate()
+                    .find(|(index, (name, _))| is_device(query, *index as u32, name, name, false))
+                    .map(|(_, device)| device)
+            })
+            .map(|(name, _)| name.clone())
+            .ok_or_else(|| Error::Audio(format!("JACK: no client {}", query)))?;
+        let theirs = ports
+            .into_iter()
+            .filter(|port| port_client(port) == device)
+            .collect();
+        (device, theirs)
+    };
+    if theirs.is_empty() {
+        return Err(Error::Audio(format!("JACK: {} has no audio ports", device)));
+    }
+    for port in ours {
+        if let Some(port) = client.port_by_name(port) {
+            client.disconnect(&port).map_err(jack_error)?;
+        }
+    }
+    // a mono source feeds both sides, a stereo one left to left
+    for (index, port) in ours.iter().enumerate() {
+        let other = &theirs[index.min(theirs.len() - 1)];
+        let connected = match capture {
+            true => client.connect_ports_by_name(other, port),
+            false => client.connect_ports_by_name(port, other),
+        };
+        connected.map_err(jack_error)?;
+    }
+    Ok(device)
+}
+
+/// Ports of a JACK client, patched into the session by the user.
+#[derive(Debug)]
+pub struct JackBackend;
+
+impl AudioBackend for JackBackend {
+    fn name(&self) -> &'static str {
+        "jack"
+    }
+
+    fn capture(&self, settings: &AudioSettings) -> Result<Capture, Error> {
+        Ok(Box::new(JackProducer::new(settings)?))
+    }
+
+    fn playback(&self, settings: &AudioSettings) -> Result<Playback, Error> {
+        Ok(Box::new(JackConsumer::new(settings)?))
+    }
+
+    fn list_devices(&self) -> (Devices, Devices) {
+        let list = |capture| {
+            list_devices(capture)
+                .into_iter()
+                .map(|(client, ports)| (client, format!("{} ports", ports)))
+                .collect()
+        };
+        (list(true), list(false))
+    }
+}
+
+/// Records from JACK input ports, for setups routing audio through a
+/// patchbay. `--mic` names the client connected to at the start, the ports
+/// can be repatched any time.
+pub struct JackProducer {
+    // moved on to the producer a switch returns, so the ports keep their name
+    jack: Option<(AsyncClient<Notifications, CaptureHandler>, RingBufferReader)>,
+    ports: Vec<String>,
+    running: Arc<AtomicBool>,
+    // from the server's rate to ours, if they differ
+    resampler: Option<StreamResampler>,
+    bytes: Vec<u8>,
+    native: Vec<f32>,
+    pending: Vec<f32>,
+    description: String,
+    echo: Option<EchoCanceller>,
+}
+
+impl JackProducer {
+    pub fn new(settings: &AudioSettings) -> Result<Self, Error> {
+        let (client, running) = open_client(CAPTURE_CLIENT)?;
+        let rate = client.sample_rate() as u32;
+        let resampler = match rate {
+            SAMPLE_RATE => None,
+            _ => {
+                let chunk = settings.frame_size * rate as usize / SAMPLE_RATE as usize;
+                Some(StreamResampler::new(rate, SAMPLE_RATE, chunk)?)
+            }
+        };
+        let ports = [
+            client
+                .register_port("in_left", AudioIn)
+                .map_err(jack_error)?,
+            client
+                .register_port("in_right", AudioIn)
+                .map_err(jack_error)?,
+        ];
+        let names: Vec<String> = ports.iter().filter_map(|port| port.name().ok()).collect();
+        // a few frames, the microphone is read as soon as one is in
+        let frames = 4 * settings.frame_size + client.buffer_size() as usize;
+        let (ring, writer) = RingBuffer::new(frames * CHANNELS * SAMPLE_BYTES)
+            .map_err(jack_error)?
+            .into_reader_writer();
+        let client = client
+            .activate_async(
+                Notifications(running.clone()),
+                CaptureHandler {
+                    ports,
+                    ring: writer,
+                },
+            )
+            .map_err(jack_error)?;
+        let default = ["default".to_string()];
+        let mics = match settings.mics.is_empty() {
+            true => &default[..],
+            false => &settings.mics[..],
+        };
+        let mut description = "not connected".to_string();
+        for mic in mics {
+            match connect(client.as_client(), &names, mic, true) {
+                Ok(device) => {
+                    description = device;
+                    break;
+                }
+                Err(e) => warn!("Microphone {} isn't available: {:?}", mic, e),
+            }
+        }
+        info!("Recording through JACK from {} at {}Hz", description, rate);
+        Ok(JackProducer {
+            jack: Some((client, ring)),
+            ports: names,
+            running,
+            resampler,
+            bytes: Vec::new(),
+            native: Vec::new(),
+            pending: Vec::new(),
+            description,
+            echo: None,
+        })
+    }
+}
+
+impl CaptureDevice for JackProducer {
+    /// Opens the client again after the server shut it down.
+    fn fallback(&mut self, settings: &AudioSettings) -> Result<Capture, Error> {
+        // the old client has to go first to give the new one its name
+        self.jack = None;
+        let mut next = JackProducer::new(settings)?;
+        next.echo = self.echo.take();
+        Ok(Box::new(next))
+    }
+
+    /// Connects to `device` instead, given like an entry of `--mic`.
+    fn switch_to(&mut self, device: &str, _settings: &AudioSettings) -> Result<Capture, Error> {
+        let (client, _) = self.jack.as_ref().ok_or_else(closed)?;
+        let description = connect(client.as_client(), &self.ports, device, true)?;
+        Ok(Box::new(JackProducer {
+            jack: self.jack.take(),
+            ports: std::mem::take(&mut self.ports),
+            running: self.running.clone(),
+            resampler: self.resampler.take(),
+            bytes: Vec::new(),
+            native: Vec::new(),
+            pending: std::mem::take(&mut self.pending),
+            description,
+            echo: self.echo.take(),
+        }))
+    }
+
+    fn set_echo_canceller(&mut self, echo: EchoCanceller) {
+        self.echo = Some(echo);
+    }
+
+    /// Name of the client connected to, shown to the user.
+    fn description(&self) -> &str {
+        &self.description
+    }
+}
+
+impl AudioProducer for JackProducer {
+    fn produce(&mut self, data: &mut [f32]) -> Result<(), Error> {
+        let started = Instant::now();
+        while self.pending.len() < data.len() {
+            let Some((_, ring)) = &mut self.jack else {
+                return Err(closed());
+            };
+            // whole frames only, the JACK thread may be halfway through one
+            let available = ring.space() / (CHANNELS * SAMPLE_BYTES) * CHANNELS * SAMPLE_BYTES;
+            if available == 0 {
+                if !self.running.load(Ordering::Relaxed) || started.elapsed() > TIMEOUT {
+                    return Err(Error::Audio("JACK stopped recording".to_string()));
+                }
+                sleep(POLL);
+                continue;
+            }
+            self.bytes.resize(available, 0);
+            ring.read_buffer(&mut self.bytes);
+            let samples = self
+                .bytes
+                .chunks_exact(SAMPLE_BYTES)
+                .map(|bytes| f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+            match &mut self.resampler {
+                Some(resampler) => {
+                    self.native.clear();
+                    self.native.extend(samples);
+                    resampler.process(&self.native, &mut self.pending);
+                }
+                None => self.pending.extend(samples),
+            }
+        }
+        data.copy_from_slice(&self.pending[..data.len()]);
+        self.pending.drain(..data.len());
+        if let Some(echo) = &mut self.echo {
+            echo.process(data);
+        }
+        Ok(())
+    }
+}
+
+/// Plays to JACK output ports, `--output-device` names the client connected
+/// to at the start.
+pub struct JackConsumer {
+    jack: Option<(
+        AsyncClient<Notifications, PlaybackHandler>,
+        RingBufferWriter,
+    )>,
+    ports: Vec<String>,
+    running: Arc<AtomicBool>,
+    // from our rate to the server's, if they differ
+    resampler: Option<StreamResampler>,
+    native: Vec<f32>,
+    description: String,
+    echo: Option<EchoReference>,
+}
+
+impl JackConsumer {
+    pub fn new(settings: &AudioSettings) -> Result<Self, Error> {
+        let (client, running) = open_client(PLAYBACK_CLIENT)?;
+        let rate = client.sample_rate() as u32;
+        let resampler = match rate {
+            SAMPLE_RATE => None,
+            _ => Some(StreamResampler::new(
+                SAMPLE_RATE,
+                rate,
+                settings.frame_size,
+            )?),
+        };
+        let ports = [
+            client
+                .register_port("out_left", AudioOut)
+                .map_err(jack_error)?,
+            client
+                .register_port("out_right", AudioOut)
+                .map_err(jack_error)?,
+        ];
+        let names: Vec<String> = ports.iter().filter_map(|port| port.name().ok()).collect();
+        // as much as PulseAudio would be asked to buffer, writes wait once it
+        // is full and pace playback
+        let frame = settings.frame_size * rate as usize / SAMPLE_RATE as usize;
+        let frames =
+            (settings.playback_frames as usize + 1) * frame + client.buffer_size() as usize;
+        let (reader, ring) = RingBuffer::new(frames * CHANNELS * SAMPLE_BYTES)
+            .map_err(jack_error)?
+            .into_reader_writer();
+        let client = client
+            .activate_async(
+                Notifications(running.clone()),
+                PlaybackHandler {
+                    ports,
+                    ring: reader,
+                },
+            )
+            .map_err(jack_error)?;
+        let output = settings.output_device.as_deref().unwrap_or("default");
+        let description = match connect(client.as_client(), &names, output, false) {
+            Ok(device) => device,
+            Err(e) => {
+                warn!("Output {} isn't available: {:?}", output, e);
+                "not connected".to_string()
+            }
+        };
+        info!("Playing through JACK on {} at {}Hz", description, rate);
+        Ok(JackConsumer {
+            jack: Some((client, ring)),
+            ports: names,
+            running,
+            resampler,
+            native: Vec::new(),
+            description,
+            echo: None,
+        })
+    }
+}
+
+impl PlaybackDevice for JackConsumer {
+    /// Connects to `device` instead, given like `--output-device`.
+    fn switch_to(&mut self, device: &str, _settings: &AudioSettings) -> Result<Playback, Error> {
+        let (client, _) = self.jack.as_ref().ok_or_else(closed)?;
+        let description = connect(client.as_client(), &self.ports, device, false)?;
+        Ok(Box::new(JackConsumer {
+            jack: self.jack.take(),
+            ports: std::mem::take(&mut self.ports),
+            running: self.running.clone(),
+            resampler: self.resampler.take(),
+            native: Vec::new(),
+            description,
+            echo: self.echo.take(),
+        }))
+    }
+
+    fn set_echo_reference(&mut self, echo: EchoReference) {
+        self.echo = Some(echo);
+    }
+
+    /// Name of the client connected to, shown to the user.
+    fn description(&self) -> &str {
+        &self.description
+    }
+}
+
+impl Consumer for JackConsumer {
+    fn consume(&mut self, data: &[f32]) -> Result<usize, Error> {
+        if let Some(echo) = &self.echo {
+            echo.push(data);
+        }
+        let samples = match &mut self.resampler {
+            Some(resampler) => {
+                self.native.clear();
+                resampler.process(data, &mut self.native);
+                &self.native[..]
+            }
+            None => data,
+        };
+        let Some((_, ring)) = &mut self.jack else {
+            return Err(closed());
+        };
+        let started = Instant::now();
+        for sample in samples {
+            while ring.space() < SAMPLE_BYTES {
+                if !self.running.load(Ordering::Relaxed) || started.elapsed() > TIMEOUT {
+                    return Err(Error::Audio("JACK stopped playing".to_string()));
+                }
+                sleep(POLL);
+            }
+            ring.write_buffer(&sample.to_ne_bytes());
+        }
+        Ok(data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_ports_by_client() {
+        let ports: Vec<String> = [
+            "system:capture_1",
+            "system:capture_2",
+            "Carla:audio-out1",
+            "system:capture_3",
+        ]
+        .map(String::from)
+        .to_vec();
+        assert_eq!(
+            group_by_client(&ports),
+            vec![("system".to_string(), 3), ("Carla".to_string(), 1)]
+        );
+        assert_eq!(
+            port_client("PulseAudio JACK Sink:front-left"),
+            "PulseAudio JACK Sink"
+        );
+        assert_eq!(port_client("unnamed"), "unnamed");
+    }
+}
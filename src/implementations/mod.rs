@@ -1 +1,144 @@
+#[cfg(feature = "alsa")]
+pub mod alsa;
+#[cfg(feature = "cpal")]
+pub mod cpal;
+#[cfg(feature = "jack")]
+pub mod jack;
 pub mod pulseaudio;
+
+use std::fmt::Debug;
+
+use crate::{
+    AudioProducer, Consumer, Error,
+    aec::{EchoCanceller, EchoReference},
+    settings::AudioSettings,
+};
+
+#[cfg(feature = "alsa")]
+use self::alsa::AlsaBackend;
+#[cfg(feature = "cpal")]
+use self::cpal::CpalBackend;
+#[cfg(feature = "jack")]
+use self::jack::JackBackend;
+use pulseaudio::PulseBackend;
+
+/// Every backend `--backend` can pick, the first is the default. The others
+/// are only built with their cargo feature.
+pub static BACKENDS: &[&dyn AudioBackend] = &[
+    &PulseBackend,
+    #[cfg(feature = "alsa")]
+    &AlsaBackend,
+    #[cfg(feature = "jack")]
+    &JackBackend,
+    #[cfg(feature = "cpal")]
+    &CpalBackend,
+];
+
+/// The backend `--backend` calls `name`.
+pub fn backend(name: &str) -> Option<&'static dyn AudioBackend> {
+    BACKENDS.iter().copied().find(|backend| backend.name() == name)
+}
+
+/// Name and description of each device.
+pub type Devices = Vec<(String, String)>;
+
+/// A way to the sound hardware. A new one implements this and is added to
+/// `BACKENDS`, the rest of the client only sees the one in the settings.
+pub trait AudioBackend: Debug + Sync {
+    /// What `--backend` calls it.
+    fn name(&self) -> &'static str;
+
+    /// Opens the microphone of `--mic`.
+    fn capture(&self, settings: &AudioSettings) -> Result<Capture, Error>;
+
+    /// Opens the speakers of `--output-device`.
+    fn playback(&self, settings: &AudioSettings) -> Result<Playback, Error>;
+
+    /// The capture and playback devices, for the device picker.
+    fn list_devices(&self) -> (Devices, Devices);
+}
+
+/// The microphone, on the backend picked with `--backend`.
+pub type Capture = Box<dyn CaptureDevice>;
+
+/// A microphone, producing 48kHz stereo whatever the device runs at.
+pub trait CaptureDevice: AudioProducer + Send {
+    /// Opens the next microphone after this one failed.
+    fn fallback(&mut self, settings: &AudioSettings) -> Result<Capture, Error>;
+
+    /// Opens `device` instead of this one, given like an entry of `--mic`.
+    fn switch_to(&mut self, device: &str, settings: &AudioSettings) -> Result<Capture, Error>;
+
+    /// Removes what the speakers play from the microphone.
+    fn set_echo_canceller(&mut self, echo: EchoCanceller);
+
+    /// Name of the device shown to the user.
+    fn description(&self) -> &str;
+}
+
+/// The speakers, on the backend picked with `--backend`.
+pub type Playback = Box<dyn PlaybackDevice>;
+
+/// Speakers, consuming 48kHz stereo.
+pub trait PlaybackDevice: Consumer + Send {
+    /// Opens `device` instead of this one, given like `--output-device`.
+    fn switch_to(&mut self, device: &str, settings: &AudioSettings)
+    -> Result<Playback, Error>;
+
+    /// Hands everything played to the echo canceller of the microphone.
+    fn set_echo_reference(&mut self, echo: EchoReference);
+
+    /// Name of the device shown to the user.
+    fn description(&self) -> &str;
+}
+
+/// Whether `query`, an index, a part of the name or description or `default`,
+/// means this device.
+fn is_device(query: &str, index: u32, name: &str, description: &str, is_default: bool) -> bool {
+    let query_lower = query.to_lowercase();
+    match query {
+        "default" => is_default,
+        _ => {
+            query.parse() == Ok(index)
+                || name.to_lowercase().contains(&query_lower)
+                || description.to_lowercase().contains(&query_lower)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_devices_by_index_name_or_description() {
+        let name = "alsa_output.pci-0000_01_00.1.hdmi-stereo";
+        let hdmi = |query| is_device(query, 2, name, "HDMI Audio", false);
+        assert!(hdmi("2") && hdmi("hdmi") && hdmi("hdmi audio"));
+        assert!(!hdmi("usb") && !hdmi("default") && !hdmi("20"));
+    }
+
+    #[test]
+    fn finds_backends_by_name() {
+        let built = [
+            "pulse",
+            #[cfg(feature = "alsa")]
+            "alsa",
+            #[cfg(feature = "jack")]
+            "jack",
+            #[cfg(feature = "cpal")]
+            "cpal",
+        ];
+        for name in built {
+            assert_eq!(backend(name).map(|backend| backend.name()), Some(name));
+        }
+        assert!(backend("oss").is_none());
+        #[cfg(not(feature = "alsa"))]
+        assert!(backend("alsa").is_none());
+        #[cfg(not(feature = "jack"))]
+        assert!(backend("jack").is_none());
+        #[cfg(not(feature = "cpal"))]
+        assert!(backend("cpal").is_none());
+        assert_eq!(AudioSettings::default().backend.name(), "pulse");
+    }
+}
@@ -1,97 +1,767 @@
-use crate::{AudioProducer, BUF_SIZE, CHANNELS, Consumer, SAMPLE_RATE};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 
-use crate::ErrorKind;
+use log::{info, warn};
+
+use super::{AudioBackend, Capture, CaptureDevice, Devices, Playback, PlaybackDevice, is_device};
+use crate::aec::{EchoCanceller, EchoReference};
+use crate::audio::samples;
+use crate::resampler::StreamResampler;
+use crate::settings::AudioSettings;
+use crate::{AudioProducer, CHANNELS, Consumer, SAMPLE_RATE};
+
+use crate::Error;
 use crate::psimple::Simple;
+use crate::pulse::callbacks::ListResult;
+use crate::pulse::context::{Context, FlagSet, State};
 use crate::pulse::def::BufferAttr;
+use crate::pulse::mainloop::standard::{IterateResult, Mainloop};
+use crate::pulse::operation::{self, Operation};
+use crate::pulse::proplist::properties;
 use crate::pulse::sample::{Format, Spec};
-use crate::pulse::stream::Direction;
+use crate::pulse::stream::{self, Direction, PeekResult, Stream};
+
+/// PulseAudio, or PipeWire's pulse server.
+#[derive(Debug)]
+pub struct PulseBackend;
+
+impl AudioBackend for PulseBackend {
+    fn name(&self) -> &'static str {
+        "pulse"
+    }
+
+    fn capture(&self, settings: &AudioSettings) -> Result<Capture, Error> {
+        Ok(Box::new(PulseAudioProducer::new(settings)?))
+    }
+
+    fn playback(&self, settings: &AudioSettings) -> Result<Playback, Error> {
+        Ok(Box::new(PulseAudioConsumer::new(settings)?))
+    }
+
+    fn list_devices(&self) -> (Devices, Devices) {
+        let inputs = list_sources()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|source| (source.name, source.description))
+            .collect();
+        let outputs = list_sinks()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|sink| (sink.name, sink.description))
+            .collect();
+        (inputs, outputs)
+    }
+}
 
 pub struct PulseAudioProducer {
     endpoint: Simple,
+    // only set when the device doesn't run at SAMPLE_RATE
+    resampler: Option<StreamResampler>,
+    device_buf: Vec<u8>,
+    // samples already read from the device but not handed out yet
+    pending: Vec<f32>,
+    // None for PulseAudio's default source
+    source: Option<Source>,
+    echo: Option<EchoCanceller>,
 }
 
 impl PulseAudioProducer {
-    pub fn new() -> Result<Self, ErrorKind> {
+    pub fn new(settings: &AudioSettings) -> Result<Self, Error> {
+        PulseAudioProducer::open(settings, None)
+    }
+
+    fn open(settings: &AudioSettings, exclude: Option<&str>) -> Result<Self, Error> {
+        let source = choose_source(&settings.mics, exclude)?;
+        let rate = match &source {
+            Some(source) => source.rate,
+            None => native_rate(Direction::Record).unwrap_or(SAMPLE_RATE),
+        };
+        let resampler = stream_resampler(rate, SAMPLE_RATE, settings, "capture")?;
+        let device_frame = match &resampler {
+            Some(resampler) => resampler.input_frames(),
+            None => settings.frame_size,
+        };
+        let device_buf_size = (device_frame * CHANNELS * std::mem::size_of::<i16>()) as u32;
         let spec = Spec {
             format: Format::S16NE,
             channels: CHANNELS as u8,
-            rate: SAMPLE_RATE,
+            rate,
         };
         let record_attr = BufferAttr {
             maxlength: u32::MAX, // maximum length of the buffer
             tlength: u32::MAX,   // playback-only: target length of the buffer
             prebuf: u32::MAX,    // playback-only: prebuffering size
             minreq: u32::MAX,    // minimum request size
-            fragsize: BUF_SIZE,  // record-only: fragment size
+            fragsize: device_buf_size, // record-only: fragment size
         };
 
+        let device = source.as_ref().map(|source| source.name.as_str());
         let rec = Simple::new(
             None,                 // Use the default server
             "Rustaudio Recorder", // Our application’s name
             Direction::Record,    // We want a recording stream
-            None,                 // Use the default device
+            device,               // None is the default device
             "Record",             // Description of our stream
             &spec,                // Our sample format
             None,                 // Use default channel map
             Some(&record_attr),   // Use default buffering attributes
         );
         match rec {
-            Ok(endpoint) => Ok(PulseAudioProducer { endpoint }),
-            Err(_) => Err(ErrorKind::InitializationError),
+            Ok(endpoint) => Ok(PulseAudioProducer {
+                endpoint,
+                resampler,
+                device_buf: vec![0u8; device_buf_size as usize],
+                pending: Vec::new(),
+                source,
+                echo: None,
+            }),
+            Err(e) => Err(Error::Audio(format!("PulseAudio: can't record: {}", e))),
+        }
+    }
+}
+
+impl CaptureDevice for PulseAudioProducer {
+    /// Opens the next microphone after this one failed, e.g. because it was
+    /// unplugged.
+    fn fallback(&mut self, settings: &AudioSettings) -> Result<Capture, Error> {
+        let failed = self.source.as_ref().map(|source| source.name.as_str());
+        let mut next = PulseAudioProducer::open(settings, failed)?;
+        next.echo = self.echo.take();
+        Ok(Box::new(next))
+    }
+
+    fn switch_to(&mut self, device: &str, settings: &AudioSettings) -> Result<Capture, Error> {
+        let settings = AudioSettings {
+            mics: vec![device.to_string()],
+            ..settings.clone()
+        };
+        let mut next = PulseAudioProducer::open(&settings, None)?;
+        next.echo = self.echo.take();
+        Ok(Box::new(next))
+    }
+
+    fn set_echo_canceller(&mut self, echo: EchoCanceller) {
+        self.echo = Some(echo);
+    }
+
+    fn description(&self) -> &str {
+        match &self.source {
+            Some(source) => &source.description,
+            None => "the default source",
         }
     }
 }
 
 impl AudioProducer for PulseAudioProducer {
-    fn produce(&mut self, data: &mut [u8]) -> Result<(), ErrorKind> {
-        match self.endpoint.read(data) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(ErrorKind::ReadError),
+    fn produce(&mut self, data: &mut [f32]) -> Result<(), Error> {
+        while self.pending.len() < data.len() {
+            if let Err(e) = self.endpoint.read(&mut self.device_buf) {
+                return Err(Error::Audio(format!("PulseAudio: can't record: {}", e)));
+            }
+            let samples: Vec<f32> = samples::read_ne(&self.device_buf).collect();
+            match &mut self.resampler {
+                Some(resampler) => resampler.process(&samples, &mut self.pending),
+                None => self.pending.extend_from_slice(&samples),
+            }
+        }
+        let len = data.len();
+        for (sample, pending) in data.iter_mut().zip(self.pending.drain(..len)) {
+            *sample = pending;
+        }
+        if let Some(echo) = &mut self.echo {
+            echo.process(data);
         }
+        Ok(())
     }
 }
 
 pub struct PulseAudioConsumer {
     endpoint: Simple,
+    // only set when the device doesn't run at SAMPLE_RATE
+    resampler: Option<StreamResampler>,
+    resampled: Vec<f32>,
+    // None for PulseAudio's default sink
+    sink: Option<Sink>,
+    echo: Option<EchoReference>,
 }
 
 impl PulseAudioConsumer {
-    pub fn new() -> Result<Self, ErrorKind> {
+    pub fn new(settings: &AudioSettings) -> Result<Self, Error> {
+        let sink = choose_sink(settings.output_device.as_deref())?;
+        let rate = match &sink {
+            Some(sink) => sink.rate,
+            None => native_rate(Direction::Playback).unwrap_or(SAMPLE_RATE),
+        };
+        let resampler = stream_resampler(SAMPLE_RATE, rate, settings, "playback")?;
+        let device_frame = match &resampler {
+            Some(resampler) => resampler.output_frames(),
+            None => settings.frame_size,
+        };
         let spec = Spec {
             format: Format::S16NE,
             channels: CHANNELS as u8,
-            rate: SAMPLE_RATE,
+            rate,
         };
+        let buf_size = (device_frame * CHANNELS * std::mem::size_of::<i16>()) as u32;
         let playback_attr = BufferAttr {
-            maxlength: u32::MAX,   // maximum length of the buffer
-            tlength: BUF_SIZE * 3, // playback-only: target length of the buffer
-            prebuf: BUF_SIZE * 2,  // playback-only: prebuffering size
-            minreq: BUF_SIZE,      // minimum request size
-            fragsize: u32::MAX,    // record-only: fragment size
+            maxlength: u32::MAX, // maximum length of the buffer
+            tlength: buf_size * settings.playback_frames, // playback-only: target length of the buffer
+            prebuf: buf_size * settings.prebuf_frames, // playback-only: prebuffering size
+            minreq: buf_size,    // minimum request size
+            fragsize: u32::MAX,  // record-only: fragment size
         };
 
+        let device = sink.as_ref().map(|sink| sink.name.as_str());
         let out = Simple::new(
             None,
             "Rustaudio Player",
             Direction::Playback,
-            None,
+            device,
             "Play",
             &spec,
             None,
             Some(&playback_attr),
         );
         match out {
-            Ok(endpoint) => Ok(PulseAudioConsumer { endpoint }),
-            Err(_) => Err(ErrorKind::InitializationError),
+            Ok(endpoint) => Ok(PulseAudioConsumer {
+                endpoint,
+                resampler,
+                resampled: Vec::new(),
+                sink,
+                echo: None,
+            }),
+            Err(e) => Err(Error::Audio(format!("PulseAudio: can't play: {}", e))),
+        }
+    }
+}
+
+impl PlaybackDevice for PulseAudioConsumer {
+    fn switch_to(&mut self, device: &str, settings: &AudioSettings) -> Result<Playback, Error> {
+        let settings = AudioSettings {
+            output_device: Some(device.to_string()),
+            ..settings.clone()
+        };
+        let mut next = PulseAudioConsumer::new(&settings)?;
+        next.echo = self.echo.take();
+        Ok(Box::new(next))
+    }
+
+    fn set_echo_reference(&mut self, echo: EchoReference) {
+        self.echo = Some(echo);
+    }
+
+    fn description(&self) -> &str {
+        match &self.sink {
+            Some(sink) => &sink.description,
+            None => "the default sink",
         }
     }
 }
 
 impl Consumer for PulseAudioConsumer {
-    fn consume(&mut self, data: &[u8]) -> Result<usize, ErrorKind> {
-        match self.endpoint.write(data) {
+    fn consume(&mut self, data: &[f32]) -> Result<usize, Error> {
+        if let Some(echo) = &self.echo {
+            echo.push(data);
+        }
+        let samples: &[f32] = match &mut self.resampler {
+            Some(resampler) => {
+                self.resampled.clear();
+                resampler.process(data, &mut self.resampled);
+                &self.resampled
+            }
+            None => data,
+        };
+        if samples.is_empty() {
+            return Ok(data.len());
+        }
+        let mut bytes = vec![0u8; samples.len() * 2];
+        samples::write_ne(samples, &mut bytes);
+        match self.endpoint.write(&bytes) {
             Ok(_) => Ok(data.len()),
-            Err(e) => Err(ErrorKind::WriteError(format!("{:?}", e))),
+            Err(e) => Err(Error::Audio(format!("{:?}", e))),
+        }
+    }
+}
+
+fn stream_resampler(
+    from_rate: u32,
+    to_rate: u32,
+    settings: &AudioSettings,
+    stream: &str,
+) -> Result<Option<StreamResampler>, Error> {
+    if from_rate == to_rate {
+        info!("Running {} at {}Hz", stream, from_rate);
+        return Ok(None);
+    }
+    info!(
+        "Device runs at a different rate, resampling {} from {}Hz to {}Hz",
+        stream, from_rate, to_rate
+    );
+    let chunk_size = settings.frame_size * from_rate as usize / SAMPLE_RATE as usize;
+    StreamResampler::new(from_rate, to_rate, chunk_size).map(Some)
+}
+
+/// A capture device, one of the candidates for the microphone.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub index: u32,
+    pub name: String,
+    pub description: String,
+    /// e.g. headset, webcam or internal, empty if the driver doesn't say
+    pub form_factor: String,
+    pub is_default: bool,
+    rate: u32,
+}
+
+/// Lists the capture devices, see `list-mics`.
+pub fn list_sources() -> Result<Vec<Source>, Error> {
+    let (mut mainloop, mut context) = connect_context().ok_or_else(no_server)?;
+    let sources = sources(&mut mainloop, &context);
+    context.disconnect();
+    Ok(sources)
+}
+
+/// Names of the default source and sink.
+fn defaults(mainloop: &mut Mainloop, context: &Context) -> (Option<String>, Option<String>) {
+    let defaults = Rc::new(RefCell::new((None, None)));
+    let defaults_cb = defaults.clone();
+    let op = context.introspect().get_server_info(move |info| {
+        *defaults_cb.borrow_mut() = (
+            info.default_source_name.as_ref().map(|name| name.to_string()),
+            info.default_sink_name.as_ref().map(|name| name.to_string()),
+        );
+    });
+    wait_for(mainloop, &op);
+    defaults.take()
+}
+
+fn sources(mainloop: &mut Mainloop, context: &Context) -> Vec<Source> {
+    let (default, _) = defaults(mainloop, context);
+
+    let sources = Rc::new(RefCell::new(Vec::new()));
+    let sources_cb = sources.clone();
+    let op = context.introspect().get_source_info_list(move |result| {
+        // monitors of playback devices aren't microphones
+        if let ListResult::Item(info) = result
+            && info.monitor_of_sink.is_none()
+        {
+            let name = info.name.as_deref().unwrap_or_default().to_string();
+            sources_cb.borrow_mut().push(Source {
+                index: info.index,
+                is_default: default.as_deref() == Some(name.as_str()),
+                name,
+                description: info.description.as_deref().unwrap_or_default().to_string(),
+                form_factor: info
+                    .proplist
+                    .get_str(properties::DEVICE_FORM_FACTOR)
+                    .unwrap_or_default(),
+                rate: info.sample_spec.rate,
+            });
+        }
+    });
+    wait_for(mainloop, &op);
+    sources.take()
+}
+
+/// Picks the first available device of `mics`, each given by index, a part of
+/// its name or description, or `default` for PulseAudio's default source.
+/// Without `mics` a microphone is picked with `pick_microphone`. `exclude` is
+/// a device that just failed. `None` means PulseAudio's default.
+fn choose_source(mics: &[String], exclude: Option<&str>) -> Result<Option<Source>, Error> {
+    if exclude.is_none() && mics.first().is_some_and(|mic| mic == "default") {
+        info!("Recording from the default source as requested");
+        return Ok(None);
+    }
+    let Some((mut mainloop, mut context)) = connect_context() else {
+        warn!("Can't list capture devices, recording from the default source");
+        return Ok(None);
+    };
+    let mut candidates = sources(&mut mainloop, &context);
+    context.disconnect();
+    candidates.retain(|source| Some(source.name.as_str()) != exclude);
+    choose_from(&candidates, mics)
+        .cloned()
+        .map(Some)
+        .ok_or_else(|| {
+            Error::Audio(format!(
+                "None of the microphones {} is available",
+                mics.join(", ")
+            ))
+        })
+}
+
+fn choose_from<'a>(candidates: &'a [Source], mics: &[String]) -> Option<&'a Source> {
+    if mics.is_empty() {
+        let source = pick_microphone(candidates)?;
+        let form_factor = match source.form_factor.as_str() {
+            "" => "unknown form factor",
+            form_factor => form_factor,
+        };
+        info!(
+            "Recording from {} ({}), pick another one with --mic",
+            source.description, form_factor
+        );
+        return Some(source);
+    }
+    for mic in mics {
+        let source = candidates.iter().find(|source| {
+            is_device(mic, source.index, &source.name, &source.description, source.is_default)
+        });
+        match source {
+            Some(source) => {
+                info!("Recording from {} as requested", source.description);
+                return Some(source);
+            }
+            None => info!("Microphone {} isn't available, trying the next one", mic),
+        }
+    }
+    None
+}
+
+/// A playback device.
+#[derive(Debug, Clone)]
+pub struct Sink {
+    pub index: u32,
+    pub name: String,
+    pub description: String,
+    pub is_default: bool,
+    rate: u32,
+}
+
+/// Lists the playback devices, see `list-outputs`.
+pub fn list_sinks() -> Result<Vec<Sink>, Error> {
+    let (mut mainloop, mut context) = connect_context().ok_or_else(no_server)?;
+    let sinks = sinks(&mut mainloop, &context);
+    context.disconnect();
+    Ok(sinks)
+}
+
+fn sinks(mainloop: &mut Mainloop, context: &Context) -> Vec<Sink> {
+    let (_, default) = defaults(mainloop, context);
+    let sinks = Rc::new(RefCell::new(Vec::new()));
+    let sinks_cb = sinks.clone();
+    let op = context.introspect().get_sink_info_list(move |result| {
+        if let ListResult::Item(info) = result {
+            let name = info.name.as_deref().unwrap_or_default().to_string();
+            sinks_cb.borrow_mut().push(Sink {
+                index: info.index,
+                is_default: default.as_deref() == Some(name.as_str()),
+                name,
+                description: info.description.as_deref().unwrap_or_default().to_string(),
+                rate: info.sample_spec.rate,
+            });
+        }
+    });
+    wait_for(mainloop, &op);
+    sinks.take()
+}
+
+/// The playback device `output` means, see `is_device`. `None` means
+/// PulseAudio's default, which follows e.g. headphones being plugged in.
+fn choose_sink(output: Option<&str>) -> Result<Option<Sink>, Error> {
+    let Some(output) = output.filter(|output| *output != "default") else {
+        return Ok(None);
+    };
+    let Some((mut mainloop, mut context)) = connect_context() else {
+        warn!("Can't list playback devices, playing on the default sink");
+        return Ok(None);
+    };
+    let candidates = sinks(&mut mainloop, &context);
+    context.disconnect();
+    let sink = candidates
+        .into_iter()
+        .find(|sink| is_device(output, sink.index, &sink.name, &sink.description, sink.is_default))
+        .ok_or_else(|| {
+            Error::Audio(format!("Output device {} isn't available", output))
+        })?;
+    info!("Playing on {} as requested", sink.description);
+    Ok(Some(sink))
+}
+
+/// Prefers headsets and other devices made for talking over the default source
+/// when that is e.g. a webcam, which is rarely the microphone people mean to
+/// use. Among equally suitable devices the default wins.
+fn pick_microphone(sources: &[Source]) -> Option<&Source> {
+    let suitability = |source: &Source| match source.form_factor.as_str() {
+        "headset" | "handset" => 3,
+        "microphone" => 2,
+        "webcam" => 0,
+        _ => 1,
+    };
+    sources
+        .iter()
+        .max_by_key(|source| (suitability(source), source.is_default))
+}
+
+/// A playback stream of another application, one of the candidates for
+/// sharing its audio.
+#[derive(Debug, Clone)]
+pub struct AppStream {
+    /// index of the sink input
+    pub index: u32,
+    pub application: String,
+    pub media: String,
+    sink: u32,
+}
+
+/// Lists the playback streams of all applications, see `list-app-streams`.
+pub fn list_app_streams() -> Result<Vec<AppStream>, Error> {
+    let (mut mainloop, mut context) = connect_context().ok_or_else(no_server)?;
+    let streams = app_streams(&mut mainloop, &context);
+    context.disconnect();
+    Ok(streams)
+}
+
+fn app_streams(mainloop: &mut Mainloop, context: &Context) -> Vec<AppStream> {
+    let streams = Rc::new(RefCell::new(Vec::new()));
+    let streams_cb = streams.clone();
+    let op = context
+        .introspect()
+        .get_sink_input_info_list(move |result| {
+            if let ListResult::Item(info) = result {
+                streams_cb.borrow_mut().push(AppStream {
+                    index: info.index,
+                    application: info
+                        .proplist
+                        .get_str(properties::APPLICATION_NAME)
+                        .unwrap_or_default(),
+                    media: info
+                        .proplist
+                        .get_str(properties::MEDIA_NAME)
+                        .unwrap_or_default(),
+                    sink: info.sink,
+                });
+            }
+        });
+    wait_for(mainloop, &op);
+    streams.take()
+}
+
+/// Captures the audio a single application plays, e.g. just the game and not
+/// the music player, by monitoring its playback stream. Never blocks: when the
+/// application plays nothing, silence is produced.
+pub struct PulseAudioAppProducer {
+    // declared before the context and mainloop so it is dropped first
+    stream: Stream,
+    context: Context,
+    mainloop: Mainloop,
+    pending: Vec<f32>,
+}
+
+impl PulseAudioAppProducer {
+    /// `app` is the index of the application's stream or a part of its name
+    pub fn new(app: &str, settings: &AudioSettings) -> Result<Self, Error> {
+        let (mut mainloop, mut context) = connect_context().ok_or_else(no_server)?;
+        let candidates = app_streams(&mut mainloop, &context);
+        let app_lower = app.to_lowercase();
+        let target = candidates
+            .iter()
+            .find(|candidate| app.parse() == Ok(candidate.index))
+            .or_else(|| {
+                candidates
+                    .iter()
+                    .find(|candidate| candidate.application.to_lowercase().contains(&app_lower))
+            })
+            .ok_or_else(|| {
+                Error::Audio(format!("No application stream matches {}", app))
+            })?;
+        info!(
+            "Sharing audio of {} ({}, stream {})",
+            target.application, target.media, target.index
+        );
+
+        let monitor = Rc::new(RefCell::new(None));
+        let monitor_cb = monitor.clone();
+        let op = context
+            .introspect()
+            .get_sink_info_by_index(target.sink, move |result| {
+                if let ListResult::Item(info) = result {
+                    *monitor_cb.borrow_mut() =
+                        info.monitor_source_name.as_ref().map(|name| name.to_string());
+                }
+            });
+        wait_for(&mut mainloop, &op);
+        let monitor = monitor
+            .take()
+            .ok_or_else(|| Error::Audio(format!("No monitor for {}", app)))?;
+
+        // PulseAudio resamples monitor streams, so ask for the rate opus runs at
+        let spec = Spec {
+            format: Format::FLOAT32NE,
+            channels: CHANNELS as u8,
+            rate: SAMPLE_RATE,
+        };
+        let fragsize = (settings.frame_size * CHANNELS * std::mem::size_of::<f32>()) as u32;
+        let attr = BufferAttr {
+            maxlength: u32::MAX,
+            tlength: u32::MAX,
+            prebuf: u32::MAX,
+            minreq: u32::MAX,
+            fragsize,
+        };
+        let mut stream = Stream::new(&mut context, "Shared application audio", &spec, None)
+            .ok_or_else(|| Error::Audio("PulseAudio: can't create a stream".to_string()))?;
+        stream
+            .set_monitor_stream(target.index)
+            .map_err(|e| Error::Audio(format!("{:?}", e)))?;
+        stream
+            .connect_record(Some(&monitor), Some(&attr), stream::FlagSet::ADJUST_LATENCY)
+            .map_err(|e| Error::Audio(format!("{:?}", e)))?;
+        loop {
+            match mainloop.iterate(true) {
+                IterateResult::Success(_) => {}
+                _ => return Err(no_server()),
+            }
+            match stream.get_state() {
+                stream::State::Ready => break,
+                stream::State::Failed | stream::State::Terminated => {
+                    return Err(Error::Audio(format!("PulseAudio: can't record {}", app)));
+                }
+                _ => {}
+            }
         }
+        Ok(PulseAudioAppProducer {
+            stream,
+            context,
+            mainloop,
+            pending: Vec::new(),
+        })
+    }
+}
+
+impl AudioProducer for PulseAudioAppProducer {
+    fn produce(&mut self, data: &mut [f32]) -> Result<(), Error> {
+        while let IterateResult::Success(n) = self.mainloop.iterate(false) {
+            if n == 0 {
+                break;
+            }
+        }
+        if self.context.get_state() != State::Ready {
+            return Err(no_server());
+        }
+        loop {
+            match self.stream.peek() {
+                Ok(PeekResult::Data(bytes)) => {
+                    self.pending.extend(
+                        bytes
+                            .chunks_exact(4)
+                            .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]])),
+                    );
+                    let _ = self.stream.discard();
+                }
+                Ok(PeekResult::Hole(_)) => {
+                    let _ = self.stream.discard();
+                }
+                Ok(PeekResult::Empty) => break,
+                Err(e) => return Err(Error::Audio(format!("PulseAudio: {}", e))),
+            }
+        }
+        // the application's clock drifts against the microphone's, don't let
+        // the backlog grow into audible delay
+        if self.pending.len() > data.len() * 4 {
+            let excess = self.pending.len() - data.len() * 2;
+            self.pending.drain(..excess);
+        }
+        let available = self.pending.len().min(data.len());
+        data[..available].copy_from_slice(&self.pending[..available]);
+        data[available..].fill(0.0);
+        self.pending.drain(..available);
+        Ok(())
+    }
+}
+
+fn no_server() -> Error {
+    Error::Audio("Can't talk to the PulseAudio server".to_string())
+}
+
+fn connect_context() -> Option<(Mainloop, Context)> {
+    let mut mainloop = Mainloop::new()?;
+    let mut context = Context::new(&mainloop, "kop-audio")?;
+    context.connect(None, FlagSet::NOFLAGS, None).ok()?;
+    loop {
+        match mainloop.iterate(true) {
+            IterateResult::Success(_) => {}
+            _ => return None,
+        }
+        match context.get_state() {
+            State::Ready => return Some((mainloop, context)),
+            State::Failed | State::Terminated => return None,
+            _ => {}
+        }
+    }
+}
+
+/// Asks the PulseAudio server for the sample rate of the default source or sink,
+/// so streams can be opened without PulseAudio resampling them internally.
+pub fn native_rate(direction: Direction) -> Option<u32> {
+    let (mut mainloop, mut context) = connect_context()?;
+
+    let rate = Rc::new(Cell::new(None));
+    let rate_cb = rate.clone();
+    let introspector = context.introspect();
+    match direction {
+        Direction::Playback => {
+            let op = introspector.get_sink_info_by_name("@DEFAULT_SINK@", move |result| {
+                if let ListResult::Item(info) = result {
+                    rate_cb.set(Some(info.sample_spec.rate));
+                }
+            });
+            wait_for(&mut mainloop, &op);
+        }
+        _ => {
+            let op = introspector.get_source_info_by_name("@DEFAULT_SOURCE@", move |result| {
+                if let ListResult::Item(info) = result {
+                    rate_cb.set(Some(info.sample_spec.rate));
+                }
+            });
+            wait_for(&mut mainloop, &op);
+        }
+    }
+    context.disconnect();
+    rate.get()
+}
+
+fn wait_for<C: ?Sized>(mainloop: &mut Mainloop, op: &Operation<C>) {
+    while op.get_state() == operation::State::Running {
+        if let IterateResult::Success(_) = mainloop.iterate(true) {
+            continue;
+        }
+        warn!("Lost connection while querying PulseAudio");
+        break;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(index: u32, form_factor: &str, is_default: bool) -> Source {
+        Source {
+            index,
+            name: format!("source{}", index),
+            description: format!("Source {}", index),
+            form_factor: form_factor.to_string(),
+            is_default,
+            rate: SAMPLE_RATE,
+        }
+    }
+
+    #[test]
+    fn prefers_headset_over_default_webcam() {
+        let sources = [source(0, "webcam", true), source(1, "headset", false)];
+        assert_eq!(pick_microphone(&sources).unwrap().index, 1);
+        // unknown devices don't beat the default
+        let sources = [source(0, "", false), source(1, "", true)];
+        assert_eq!(pick_microphone(&sources).unwrap().index, 1);
+    }
+
+    #[test]
+    fn falls_back_along_the_microphone_list() {
+        let sources = [source(0, "webcam", true), source(1, "headset", false)];
+        let mics = ["usb".to_string(), "Source 1".to_string()];
+        assert_eq!(choose_from(&sources, &mics).unwrap().index, 1);
+        let mics = ["usb".to_string(), "default".to_string()];
+        assert_eq!(choose_from(&sources, &mics).unwrap().index, 0);
+        assert!(choose_from(&sources, &["usb".to_string()]).is_none());
     }
 }
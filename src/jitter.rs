@@ -1,6 +1,130 @@
+use std::collections::BTreeMap;
+
 use crate::server::AudioData;
 
-struct JitterBuffer {
-    buffer: Vec<AudioData>,
+/// What the playback path should do for the next frame of a stream.
+#[derive(Debug, PartialEq)]
+pub enum Playout {
+    Frame(AudioData),
+    /// the packet for this frame was lost or is too late
+    Missing,
+    /// still filling up, nothing to play yet
+    Waiting,
+}
+
+/// Reorders the packets of one sender by sequence number and delays playback
+/// by `target` frames, so packets that arrive out of order or a bit late still
+/// make it. Packets older than the one played last are dropped.
+pub struct JitterBuffer {
+    // sequence numbers are assumed not to wrap, that takes years at 50 packets/s
+    buffer: BTreeMap<u32, AudioData>,
     max_size: usize,
+    target: usize,
+    next_seq: Option<u32>,
+    playing: bool,
+}
+
+impl JitterBuffer {
+    pub fn new(target: usize) -> Self {
+        JitterBuffer {
+            buffer: BTreeMap::new(),
+            max_size: target.max(1) * 4,
+            target: target.max(1),
+            next_seq: None,
+            playing: false,
+        }
+    }
+
+    pub fn push(&mut self, packet: AudioData) {
+        if self.next_seq.is_some_and(|next| packet.seq_number < next) {
+            // its slot has been played already
+            return;
+        }
+        self.buffer.insert(packet.seq_number, packet);
+        // don't let a burst after a stall turn into permanent delay
+        while self.buffer.len() > self.max_size {
+            if let Some((seq, _)) = self.buffer.pop_first() {
+                self.next_seq = Some(seq + 1);
+            }
+        }
+    }
+
+    /// The packet `pop` hands out next, if it already arrived.
+    pub fn peek(&self) -> Option<&AudioData> {
+        self.buffer.get(&self.next_seq?)
+    }
+
+    pub fn pop(&mut self) -> Playout {
+        if !self.playing {
+            if self.buffer.len() < self.target {
+                return Playout::Waiting;
+            }
+            self.playing = true;
+            self.next_seq = self.buffer.keys().next().copied();
+        }
+        let Some(next) = self.next_seq else {
+            return Playout::Waiting;
+        };
+        let Some((&first, _)) = self.buffer.first_key_value() else {
+            // the talk spurt ended or the sender stalled, buffer up again
+            self.playing = false;
+            return Playout::Waiting;
+        };
+        self.next_seq = Some(next + 1);
+        if first == next {
+            let (_, packet) = self.buffer.pop_first().unwrap();
+            Playout::Frame(packet)
+        } else {
+            Playout::Missing
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(seq_number: u32) -> AudioData {
+        AudioData {
+            timestamp: seq_number as u64 * 20,
+            seq_number,
+            data: vec![seq_number as u8],
+        }
+    }
+
+    #[test]
+    fn reorders_packets() {
+        let mut jitter = JitterBuffer::new(3);
+        jitter.push(packet(2));
+        assert_eq!(jitter.pop(), Playout::Waiting);
+        jitter.push(packet(1));
+        jitter.push(packet(3));
+        assert_eq!(jitter.pop(), Playout::Frame(packet(1)));
+        assert_eq!(jitter.pop(), Playout::Frame(packet(2)));
+        assert_eq!(jitter.pop(), Playout::Frame(packet(3)));
+        assert_eq!(jitter.pop(), Playout::Waiting);
+    }
+
+    #[test]
+    fn reports_gaps_and_drops_late_packets() {
+        let mut jitter = JitterBuffer::new(2);
+        jitter.push(packet(1));
+        jitter.push(packet(3));
+        assert_eq!(jitter.pop(), Playout::Frame(packet(1)));
+        assert_eq!(jitter.pop(), Playout::Missing);
+        // the packet after the gap is there to recover the lost one from
+        assert_eq!(jitter.peek(), Some(&packet(3)));
+        jitter.push(packet(2));
+        assert_eq!(jitter.pop(), Playout::Frame(packet(3)));
+        assert_eq!(jitter.pop(), Playout::Waiting);
+    }
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let mut jitter = JitterBuffer::new(1);
+        for seq in 1..=6 {
+            jitter.push(packet(seq));
+        }
+        assert_eq!(jitter.pop(), Playout::Frame(packet(3)));
+    }
 }
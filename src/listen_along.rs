@@ -0,0 +1,199 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bincode::{Decode, Encode};
+
+use crate::server::AudioData;
+
+// a stamp further than this off the wall clock starts a new timeline, e.g.
+// after a quiet passage nothing was sent for
+const RESYNC_MS: u64 = 100;
+// how long after its playback time a frame is still worth playing
+const LATE_MS: u64 = 60;
+// a vote that didn't find a majority in this time is forgotten
+const VOTE_TTL: Duration = Duration::from_secs(120);
+
+/// Stamps the frames of a listen-along stream with when they were captured.
+/// Frames are spaced by their length rather than by when the capture loop got
+/// to them, so scheduling jitter on the sender doesn't end up in the playback.
+#[derive(Debug)]
+pub struct MusicClock {
+    frame_ms: f64,
+    // wall clock time and sequence number the current timeline started at
+    anchor: Option<(u64, u32)>,
+}
+
+impl MusicClock {
+    pub fn new(frame_ms: f32) -> Self {
+        MusicClock {
+            frame_ms: frame_ms as f64,
+            anchor: None,
+        }
+    }
+
+    /// Timestamp for frame `seq_number` captured at about `now`, both in
+    /// milliseconds since the epoch.
+    pub fn stamp(&mut self, now: u64, seq_number: u32) -> u64 {
+        if let Some((start, first)) = self.anchor {
+            let frames = seq_number.wrapping_sub(first) as f64;
+            let expected = start + (frames * self.frame_ms).round() as u64;
+            if expected.abs_diff(now) <= RESYNC_MS {
+                return expected;
+            }
+        }
+        self.anchor = Some((now, seq_number));
+        now
+    }
+}
+
+/// Holds the frames of a listen-along stream until their playback time, which
+/// the server set to the same wall clock time for every listener. Unlike the
+/// voice jitter buffer it doesn't adapt to the network, a frame that arrives
+/// too late is dropped so this listener stays in step with the others.
+#[derive(Debug, Default)]
+pub struct MusicBuffer {
+    buffer: BTreeMap<u32, AudioData>,
+    // frames before this one have been played or dropped already
+    next_seq: u32,
+    // frames dropped for arriving after their playback time
+    pub late: u32,
+}
+
+impl MusicBuffer {
+    pub fn push(&mut self, frame: AudioData) {
+        if frame.seq_number < self.next_seq {
+            return;
+        }
+        self.buffer.insert(frame.seq_number, frame);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// The next frame due at `now`, milliseconds since the epoch. Call until it
+    /// returns `None`, a listener with shorter frames than the sender gets a
+    /// frame only every few calls, one with longer frames several per call.
+    pub fn pop(&mut self, now: u64) -> Option<AudioData> {
+        while let Some(entry) = self.buffer.first_entry() {
+            let timestamp = entry.get().timestamp;
+            if timestamp > now {
+                return None;
+            }
+            let frame = entry.remove();
+            self.next_seq = frame.seq_number + 1;
+            if timestamp + LATE_MS >= now {
+                return Some(frame);
+            }
+            self.late += 1;
+        }
+        None
+    }
+}
+
+/// What listeners can vote for on the listen-along stream.
+#[derive(Encode, Decode, PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum MusicVote {
+    Skip,
+    /// pauses the stream, or resumes it if it is paused
+    Pause,
+    Louder,
+    Quieter,
+}
+
+impl MusicVote {
+    pub fn describe(self) -> &'static str {
+        match self {
+            MusicVote::Skip => "skip the track",
+            MusicVote::Pause => "pause or resume the music",
+            MusicVote::Louder => "turn the music up",
+            MusicVote::Quieter => "turn the music down",
+        }
+    }
+}
+
+/// Counts the votes of the listeners on the host, which acts on them.
+#[derive(Debug, Default)]
+pub struct VoteTally {
+    votes: HashMap<MusicVote, HashMap<SocketAddr, Instant>>,
+}
+
+impl VoteTally {
+    /// Counts a vote of `voter`, true once a majority of the `listeners`
+    /// agrees, which starts the count for that vote afresh.
+    pub fn vote(&mut self, vote: MusicVote, voter: SocketAddr, listeners: usize) -> bool {
+        let voters = self.votes.entry(vote).or_default();
+        voters.retain(|_, at| at.elapsed() < VOTE_TTL);
+        voters.insert(voter, Instant::now());
+        if voters.len() < listeners / 2 + 1 {
+            return false;
+        }
+        voters.clear();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(seq_number: u32, timestamp: u64) -> AudioData {
+        AudioData {
+            timestamp,
+            seq_number,
+            data: vec![seq_number as u8],
+        }
+    }
+
+    #[test]
+    fn clock_spaces_frames_evenly_and_resyncs_after_gaps() {
+        let mut clock = MusicClock::new(20.0);
+        let start = 1_760_000_000_000;
+        assert_eq!(clock.stamp(start, 1), start);
+        // the capture loop ran late, the stamp doesn't
+        assert_eq!(clock.stamp(start + 27, 2), start + 20);
+        assert_eq!(clock.stamp(start + 38, 3), start + 40);
+        // nothing sent during a quiet passage
+        assert_eq!(clock.stamp(start + 5000, 4), start + 5000);
+        assert_eq!(clock.stamp(start + 5021, 5), start + 5020);
+    }
+
+    #[test]
+    fn plays_frames_at_their_time_and_drops_late_ones() {
+        let mut buffer = MusicBuffer::default();
+        buffer.push(frame(2, 1020));
+        buffer.push(frame(1, 1000));
+        assert_eq!(buffer.pop(990), None);
+        assert_eq!(buffer.pop(1001), Some(frame(1, 1000)));
+        assert_eq!(buffer.pop(1005), None);
+        assert_eq!(buffer.pop(1021), Some(frame(2, 1020)));
+        buffer.push(frame(3, 1040));
+        buffer.push(frame(4, 1060));
+        buffer.push(frame(5, 1080));
+        // a stall on this listener, it skips ahead rather than falling behind
+        assert_eq!(buffer.pop(1110), Some(frame(4, 1060)));
+        assert_eq!(buffer.late, 1);
+        assert_eq!(buffer.pop(1110), Some(frame(5, 1080)));
+        assert!(buffer.is_empty());
+        // its slot is gone
+        buffer.push(frame(3, 1040));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn votes_pass_with_a_majority_of_listeners() {
+        let voter = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let mut tally = VoteTally::default();
+        assert!(!tally.vote(MusicVote::Pause, voter(1), 4));
+        assert!(!tally.vote(MusicVote::Pause, voter(1), 4));
+        assert!(!tally.vote(MusicVote::Skip, voter(2), 4));
+        assert!(!tally.vote(MusicVote::Pause, voter(2), 4));
+        assert!(tally.vote(MusicVote::Pause, voter(3), 4));
+        // counted afresh after it passed
+        assert!(!tally.vote(MusicVote::Pause, voter(3), 4));
+        assert!(tally.vote(MusicVote::Louder, voter(1), 1));
+    }
+}
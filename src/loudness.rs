@@ -0,0 +1,393 @@
+use std::collections::VecDeque;
+
+use crate::{CHANNELS, SAMPLE_RATE};
+
+// ITU-R BS.1770 measures in 100ms steps and averages 3s for short-term loudness
+const BLOCK_SAMPLES: usize = SAMPLE_RATE as usize / 10;
+const SHORT_TERM_BLOCKS: usize = 30;
+// EBU R128 absolute gate, anything quieter is treated as silence
+const GATE_LUFS: f32 = -70.0;
+// how far the gain may go, and how fast, so quiet intros aren't blown up and
+// changes sound like a fader rather than pumping
+const MAX_GAIN_DB: f32 = 12.0;
+// per call, 2.5dB/s with 20ms frames
+const GAIN_STEP_DB: f32 = 0.05;
+// how fast the mix makes room for another voice and gives it back, per call.
+// Quick enough that the sum doesn't stay loud for long, slow enough that a
+// short "yeah" from someone else doesn't duck whoever is talking
+const HEADROOM_ATTACK_DB: f32 = 0.1;
+const HEADROOM_RELEASE_DB: f32 = 0.05;
+// the limiter is linear up to here, above it peaks are rounded off towards
+// full scale
+const KNEE: f32 = 0.8;
+// how fast the output limiter gives back gain once a loud burst is over
+const LIMITER_RELEASE_DB_PER_SEC: f32 = 10.0;
+
+/// Target loudness of listen-along music, EBU R128's reference level.
+pub const DEFAULT_MUSIC_LOUDNESS: f32 = -23.0;
+/// Target loudness of each voice, the same so speech sits next to music.
+pub const DEFAULT_VOICE_LOUDNESS: f32 = -23.0;
+
+/// Second order IIR section in direct form I.
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    const fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// The K-weighting of BS.1770 at 48kHz, a high shelf modelling the head
+/// followed by a high-pass.
+fn k_weighting() -> [Biquad; 2] {
+    [
+        Biquad::new(
+            [1.53512485958697, -2.69169618940638, 1.19839281085285],
+            [-1.69065929318241, 0.73248077421585],
+        ),
+        Biquad::new([1.0, -2.0, 1.0], [-1.99004745483398, 0.99007225036621]),
+    ]
+}
+
+/// Short-term loudness of interleaved stereo, as defined by EBU R128.
+#[derive(Debug, Clone)]
+pub struct LoudnessMeter {
+    filters: [[Biquad; 2]; CHANNELS],
+    sum: f64,
+    samples: usize,
+    // mean square of the last few blocks, summed over the channels
+    blocks: VecDeque<f64>,
+}
+
+impl Default for LoudnessMeter {
+    fn default() -> Self {
+        LoudnessMeter {
+            filters: [k_weighting(); CHANNELS],
+            sum: 0.0,
+            samples: 0,
+            blocks: VecDeque::with_capacity(SHORT_TERM_BLOCKS),
+        }
+    }
+}
+
+impl LoudnessMeter {
+    pub fn push(&mut self, pcm: &[f32]) {
+        for frame in pcm.chunks_exact(CHANNELS) {
+            for (sample, filters) in frame.iter().zip(&mut self.filters) {
+                let weighted = filters
+                    .iter_mut()
+                    .fold(*sample as f64, |x, filter| filter.process(x));
+                self.sum += weighted * weighted;
+            }
+            self.samples += 1;
+            if self.samples == BLOCK_SAMPLES {
+                if self.blocks.len() == SHORT_TERM_BLOCKS {
+                    self.blocks.pop_front();
+                }
+                self.blocks.push_back(self.sum / BLOCK_SAMPLES as f64);
+                self.sum = 0.0;
+                self.samples = 0;
+            }
+        }
+    }
+
+    /// LUFS over the last 3s, `None` before the first block is complete.
+    pub fn short_term(&self) -> Option<f32> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+        let mean = self.blocks.iter().sum::<f64>() / self.blocks.len() as f64;
+        Some((-0.691 + 10.0 * mean.max(1e-12).log10()) as f32)
+    }
+}
+
+/// Steers music or a voice towards a target loudness, so tracks from
+/// different sources, or a quiet and a loud microphone, sit at the same level
+/// next to each other.
+#[derive(Debug, Clone)]
+pub struct LoudnessNormalizer {
+    target: f32,
+    meter: LoudnessMeter,
+    gain_db: f32,
+}
+
+impl LoudnessNormalizer {
+    pub fn new(target: f32) -> Self {
+        LoudnessNormalizer {
+            target,
+            meter: LoudnessMeter::default(),
+            gain_db: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, pcm: &mut [f32]) {
+        self.meter.push(pcm);
+        let from = db_to_gain(self.gain_db);
+        // silence keeps the gain where it was, the next track starts from there
+        if let Some(loudness) = self.meter.short_term().filter(|l| *l > GATE_LUFS) {
+            let wanted = (self.target - loudness).clamp(-MAX_GAIN_DB, MAX_GAIN_DB);
+            self.gain_db += (wanted - self.gain_db).clamp(-GAIN_STEP_DB, GAIN_STEP_DB);
+        }
+        let to = db_to_gain(self.gain_db);
+        // ramp over the frame so a gain change doesn't click
+        let frames = (pcm.len() / CHANNELS).max(1) as f32;
+        for (i, frame) in pcm.chunks_exact_mut(CHANNELS).enumerate() {
+            let gain = from + (to - from) * (i + 1) as f32 / frames;
+            for sample in frame {
+                *sample = (*sample * gain).clamp(-1.0, 1.0);
+            }
+        }
+    }
+}
+
+/// Keeps the mix of several voices at about the level of one. Voices don't
+/// line up, so they add up in power and the mix is brought down by the square
+/// root of their number, gradually so that someone joining in or stopping
+/// isn't heard as the others ducking away. Summing them as they are makes the
+/// call louder the more people talk and clips when they talk over each other.
+#[derive(Debug, Clone, Default)]
+pub struct MixHeadroom {
+    gain_db: f32,
+}
+
+impl MixHeadroom {
+    /// Scales `mix`, the sum of `voices` voices in this frame.
+    pub fn process(&mut self, mix: &mut [f32], voices: usize) {
+        let from = db_to_gain(self.gain_db);
+        // nobody talking keeps the gain, a pause doesn't start over at full level
+        if voices > 0 {
+            let wanted = -10.0 * (voices as f32).log10();
+            let step = if wanted < self.gain_db {
+                HEADROOM_ATTACK_DB
+            } else {
+                HEADROOM_RELEASE_DB
+            };
+            self.gain_db += (wanted - self.gain_db).clamp(-step, step);
+        }
+        let to = db_to_gain(self.gain_db);
+        let frames = (mix.len() / CHANNELS).max(1) as f32;
+        for (i, frame) in mix.chunks_exact_mut(CHANNELS).enumerate() {
+            let gain = from + (to - from) * (i + 1) as f32 / frames;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+}
+
+/// The last stage before the speakers: narrows the stereo image, down to
+/// mono for single-ear headsets, and keeps peaks under a ceiling so a broken
+/// or malicious stream can't blast the ears at full scale.
+#[derive(Debug, Clone)]
+pub struct OutputStage {
+    /// 1 leaves stereo as it is, 0 plays the same on both sides
+    width: f32,
+    ceiling: f32,
+    /// of the limiter, 1 while nothing is too loud
+    gain: f32,
+    /// per sample the gain recovers by
+    release: f32,
+}
+
+impl OutputStage {
+    pub fn new(width: f32, ceiling_db: f32) -> Self {
+        OutputStage {
+            width: width.clamp(0.0, 1.0),
+            ceiling: db_to_gain(ceiling_db.min(0.0)),
+            gain: 1.0,
+            release: db_to_gain(LIMITER_RELEASE_DB_PER_SEC / SAMPLE_RATE as f32),
+        }
+    }
+
+    pub fn process(&mut self, pcm: &mut [f32]) {
+        for frame in pcm.chunks_exact_mut(CHANNELS) {
+            let [left, right] = frame else {
+                continue;
+            };
+            // mid and side, the side scaled by the width
+            let mid = (*left + *right) / 2.0;
+            let side = (*left - *right) / 2.0 * self.width;
+            let peak = (mid.abs() + side.abs()) * self.gain;
+            // down at once, so not even one sample gets through too loud
+            if peak > self.ceiling {
+                self.gain *= self.ceiling / peak;
+            }
+            *left = (mid + side) * self.gain;
+            *right = (mid - side) * self.gain;
+            self.gain = (self.gain * self.release).min(1.0);
+        }
+    }
+}
+
+/// Limits `sample` to full scale, linear below the knee and rounding peaks
+/// off above it, so a loud moment in a busy call is squashed rather than
+/// crackling the way a hard clamp does.
+pub fn soft_clip(sample: f32) -> f32 {
+    let level = sample.abs();
+    if level <= KNEE {
+        return sample;
+    }
+    let over = (level - KNEE) / (1.0 - KNEE);
+    (KNEE + (1.0 - KNEE) * over.tanh()).copysign(sample)
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{max_difference, read_fixture};
+    use std::f32::consts::PI;
+
+    // one quantization step of the 16 bit fixtures
+    const FIXTURE_STEP: f32 = 2.0 / 32767.0;
+
+    fn sine(amplitude: f32, secs: usize) -> Vec<f32> {
+        tone(amplitude, 1000.0, secs)
+    }
+
+    fn tone(amplitude: f32, freq: f32, secs: usize) -> Vec<f32> {
+        (0..SAMPLE_RATE as usize * secs)
+            .flat_map(|i| {
+                let s = amplitude * (2.0 * PI * freq * i as f32 / SAMPLE_RATE as f32).sin();
+                [s; CHANNELS]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn measures_reference_tone() {
+        // a 1kHz stereo tone at -18dBFS reads about -18 LUFS
+        let mut meter = LoudnessMeter::default();
+        meter.push(&sine(10f32.powf(-18.0 / 20.0), 3));
+        let loudness = meter.short_term().unwrap();
+        assert!((loudness + 18.0).abs() < 0.5, "{}", loudness);
+    }
+
+    #[test]
+    fn brings_quiet_and_loud_tracks_to_the_target() {
+        for amplitude in [0.03, 0.3] {
+            let mut normalizer = LoudnessNormalizer::new(DEFAULT_MUSIC_LOUDNESS);
+            let mut meter = LoudnessMeter::default();
+            for frame in sine(amplitude, 20).chunks(960 * CHANNELS) {
+                let mut frame = frame.to_vec();
+                normalizer.process(&mut frame);
+                meter.push(&frame);
+            }
+            let loudness = meter.short_term().unwrap();
+            assert!(
+                (loudness - DEFAULT_MUSIC_LOUDNESS).abs() < 1.0,
+                "{}",
+                loudness
+            );
+        }
+    }
+
+    #[test]
+    fn mix_of_many_voices_stays_at_the_level_of_one() {
+        let level = |voices: usize| {
+            let mut mix = vec![0.0; SAMPLE_RATE as usize * 4 * CHANNELS];
+            for v in 0..voices {
+                let voice = tone(0.3, 300.0 + 170.0 * v as f32, 4);
+                mix.iter_mut().zip(voice).for_each(|(m, s)| *m += s);
+            }
+            let mut headroom = MixHeadroom::default();
+            let mut meter = LoudnessMeter::default();
+            for frame in mix.chunks_mut(960 * CHANNELS) {
+                headroom.process(frame, voices);
+                frame.iter_mut().for_each(|s| *s = soft_clip(*s));
+                meter.push(frame);
+            }
+            let peak = mix.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+            assert!(peak <= 1.0);
+            meter.short_term().unwrap()
+        };
+        let one = level(1);
+        let five = level(5);
+        assert!((one - five).abs() < 1.5, "{} {}", one, five);
+    }
+
+    #[test]
+    fn soft_clip_rounds_off_peaks() {
+        assert_eq!(soft_clip(0.5), 0.5);
+        assert_eq!(soft_clip(-0.8), -0.8);
+        assert!(soft_clip(1.5) < 1.0 && soft_clip(1.5) > soft_clip(1.0));
+        assert!(soft_clip(-20.0) >= -1.0);
+    }
+
+    #[test]
+    fn headroom_matches_golden() {
+        let mut mix = read_fixture("sine_440_660_48k_mix.wav").samples;
+        let golden = read_fixture("sine_440_660_48k_headroom.wav");
+        let mut headroom = MixHeadroom::default();
+        for frame in mix.chunks_mut(960 * CHANNELS) {
+            headroom.process(frame, 2);
+        }
+        assert!(max_difference(&mix, &golden.samples) < FIXTURE_STEP);
+    }
+
+    #[test]
+    fn soft_clip_matches_golden() {
+        let mut mix = read_fixture("sine_440_660_48k_mix.wav").samples;
+        let golden = read_fixture("sine_440_660_48k_soft_clip.wav");
+        assert!(mix.iter().any(|sample| sample.abs() > KNEE));
+        mix.iter_mut().for_each(|sample| *sample = soft_clip(*sample));
+        assert!(max_difference(&mix, &golden.samples) < FIXTURE_STEP);
+    }
+
+    #[test]
+    fn output_stage_narrows_and_stays_under_the_ceiling() {
+        let mut mono = OutputStage::new(0.0, 0.0);
+        let mut frame = [0.5, -0.1, 0.2, 0.4];
+        mono.process(&mut frame);
+        assert_eq!(frame, [0.2, 0.2, 0.3, 0.3]);
+
+        let mut stage = OutputStage::new(1.0, -6.0);
+        let ceiling = db_to_gain(-6.0);
+        let mut burst = sine(1.0, 1);
+        stage.process(&mut burst);
+        assert!(burst.iter().all(|sample| sample.abs() <= ceiling + 1e-6));
+        // gives the gain back once it is quiet again
+        let mut quiet = sine(0.1, 3);
+        stage.process(&mut quiet);
+        let end = &quiet[quiet.len() - 960 * CHANNELS..];
+        assert!(end.iter().fold(0f32, |peak, s| peak.max(s.abs())) > 0.099);
+    }
+
+    #[test]
+    fn output_stage_matches_golden() {
+        let left = read_fixture("sine_440_48k.wav");
+        let right = read_fixture("sine_660_48k.wav");
+        let golden = read_fixture("sine_440_660_48k_output.wav");
+        let mut stereo: Vec<f32> = left
+            .samples
+            .chunks_exact(CHANNELS)
+            .zip(right.samples.chunks_exact(CHANNELS))
+            .flat_map(|(left, right)| [left[0], right[0]])
+            .collect();
+        OutputStage::new(0.5, -9.0).process(&mut stereo);
+        assert!(max_difference(&stereo, &golden.samples) < FIXTURE_STEP);
+    }
+}
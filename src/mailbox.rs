@@ -0,0 +1,191 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{identity::UserId, server::VoiceChunk};
+
+/// Longest voice message in packets, 30s of 20ms frames.
+pub const MAX_CLIP_PACKETS: u16 = 1500;
+const MAX_STORED_CLIPS: usize = 50;
+// clips one sender can have in flight, chunks of more are dropped
+const MAX_PENDING_CLIPS: usize = 2;
+/// A clip whose last chunk got lost is given up on after this long.
+pub const ASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A voice message waiting for its recipient to join.
+#[derive(Debug, Clone)]
+pub struct StoredClip {
+    pub to: UserId,
+    pub from: UserId,
+    pub packets: Vec<Vec<u8>>,
+    stored: Instant,
+}
+
+struct PendingClip {
+    to: UserId,
+    from: UserId,
+    // by index, so a clip never holds more than `MAX_CLIP_PACKETS`
+    packets: BTreeMap<u16, Vec<u8>>,
+    started: Instant,
+}
+
+/// Voice messages the server keeps for users that aren't connected. Clips
+/// arrive as one chunk per opus packet and are delivered the same way.
+/// Clips of a user only go to the client key that first collected them, so
+/// knowing someone's identity isn't enough to listen to their messages.
+#[derive(Default)]
+pub struct Mailbox {
+    pending: HashMap<(SocketAddr, u32), PendingClip>,
+    stored: Vec<StoredClip>,
+    // the encryption key each user proved when they first joined
+    keys: HashMap<UserId, [u8; 32]>,
+}
+
+impl Mailbox {
+    /// Adds a chunk sent by `addr`, whose chunk names the recipient.
+    pub fn add_chunk(&mut self, addr: SocketAddr, from: UserId, chunk: VoiceChunk) {
+        if chunk.index >= MAX_CLIP_PACKETS {
+            return;
+        }
+        let key = (addr, chunk.clip);
+        if !self.pending.contains_key(&key)
+            && self.pending.keys().filter(|(sender, _)| *sender == addr).count()
+                >= MAX_PENDING_CLIPS
+        {
+            return;
+        }
+        let clip = self.pending.entry(key).or_insert_with(|| PendingClip {
+            to: chunk.peer,
+            from,
+            packets: BTreeMap::new(),
+            started: Instant::now(),
+        });
+        // a resent packet doesn't replace the one already there
+        clip.packets.entry(chunk.index).or_insert(chunk.packet);
+        if chunk.last {
+            let clip = self.pending.remove(&(addr, chunk.clip)).unwrap();
+            self.store(clip);
+        }
+    }
+
+    fn store(&mut self, clip: PendingClip) {
+        if self.stored.len() >= MAX_STORED_CLIPS {
+            self.stored.remove(0);
+        }
+        self.stored.push(StoredClip {
+            to: clip.to,
+            from: clip.from,
+            packets: clip.packets.into_values().collect(),
+            stored: Instant::now(),
+        });
+    }
+
+    /// Removes and returns the clips waiting for `user`, who joined with the
+    /// encryption `key` it proved in the handshake. Nothing for a client that
+    /// doesn't encrypt or that has another key than the user's first one.
+    pub fn take_for(&mut self, user: UserId, key: Option<[u8; 32]>) -> Vec<StoredClip> {
+        let Some(key) = key else {
+            return Vec::new();
+        };
+        if *self.keys.entry(user).or_insert(key) != key {
+            return Vec::new();
+        }
+        let (clips, rest) = self.stored.drain(..).partition(|clip| clip.to == user);
+        self.stored = rest;
+        clips
+    }
+
+    /// Drops clips nobody picked up within `ttl` and stores clips whose last
+    /// chunk never arrived.
+    pub fn expire(&mut self, ttl: Duration) {
+        self.stored.retain(|clip| clip.stored.elapsed() < ttl);
+        let stale: Vec<(SocketAddr, u32)> = self
+            .pending
+            .iter()
+            .filter(|(_, clip)| clip.started.elapsed() >= ASSEMBLY_TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in stale {
+            let clip = self.pending.remove(&key).unwrap();
+            self.store(clip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(to: UserId, index: u16, last: bool) -> VoiceChunk {
+        VoiceChunk {
+            peer: to,
+            clip: 7,
+            index,
+            last,
+            packet: vec![index as u8],
+        }
+    }
+
+    #[test]
+    fn assembles_clip_in_order_for_recipient() {
+        let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let from = UserId([1; 16]);
+        let to = UserId([2; 16]);
+        let mut mailbox = Mailbox::default();
+        mailbox.add_chunk(addr, from, chunk(to, 1, false));
+        mailbox.add_chunk(addr, from, chunk(to, 0, false));
+        mailbox.add_chunk(addr, from, chunk(to, 2, true));
+
+        assert!(mailbox.take_for(from, Some([1; 32])).is_empty());
+        let clips = mailbox.take_for(to, Some([2; 32]));
+        assert_eq!(clips.len(), 1);
+        assert_eq!(clips[0].from, from);
+        assert_eq!(clips[0].packets, vec![vec![0], vec![1], vec![2]]);
+        assert!(mailbox.take_for(to, Some([2; 32])).is_empty());
+    }
+
+    #[test]
+    fn keeps_clips_from_other_keys() {
+        let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let to = UserId([2; 16]);
+        let mut mailbox = Mailbox::default();
+        // the recipient's first join binds its key
+        assert!(mailbox.take_for(to, Some([2; 32])).is_empty());
+        mailbox.add_chunk(addr, UserId([1; 16]), chunk(to, 0, true));
+        // someone who got hold of the identity, with or without encryption
+        assert!(mailbox.take_for(to, None).is_empty());
+        assert!(mailbox.take_for(to, Some([3; 32])).is_empty());
+        assert_eq!(mailbox.take_for(to, Some([2; 32])).len(), 1);
+    }
+
+    #[test]
+    fn expires_old_clips() {
+        let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let to = UserId([2; 16]);
+        let mut mailbox = Mailbox::default();
+        mailbox.add_chunk(addr, UserId([1; 16]), chunk(to, 0, true));
+        mailbox.expire(Duration::ZERO);
+        assert!(mailbox.take_for(to, Some([2; 32])).is_empty());
+    }
+
+    #[test]
+    fn bounds_what_a_sender_has_in_flight() {
+        let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let to = UserId([2; 16]);
+        let mut mailbox = Mailbox::default();
+        for _ in 0..3 {
+            mailbox.add_chunk(addr, UserId([1; 16]), chunk(to, 0, false));
+        }
+        for clip in 8..8 + MAX_PENDING_CLIPS as u32 {
+            mailbox.add_chunk(addr, UserId([1; 16]), VoiceChunk { clip, ..chunk(to, 0, false) });
+        }
+        assert_eq!(mailbox.pending.len(), MAX_PENDING_CLIPS);
+        mailbox.add_chunk(addr, UserId([1; 16]), chunk(to, 1, true));
+
+        let clips = mailbox.take_for(to, Some([2; 32]));
+        assert_eq!(clips.len(), 1);
+        assert_eq!(clips[0].packets, vec![vec![0], vec![1]]);
+    }
+}
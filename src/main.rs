@@ -1,198 +1,514 @@
 use std::fs::File;
 use std::io::Write;
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{Arc, mpsc};
+use std::time::Duration;
 
 use libpulse_binding as pulse;
 use libpulse_simple_binding as psimple;
-use log::{LevelFilter, info};
+use log::{LevelFilter, error, info};
 use tokio::net::UdpSocket;
-use tokio::signal;
+use tokio::signal::{self, unix::SignalKind};
 
-use crate::audio::{play_audio, record_audio};
-use crate::client::NetworkClient;
+use clap::Parser;
+
+use crate::banlist::BanList;
+use crate::bus::EventBus;
+use crate::cli::{Bot, Cli, Command, DEFAULT_SERVER, ServerArgs};
+use crate::client::ClientMessage;
+use crate::config::Config;
+use crate::connection::{ConnectionCommand, ConnectionState};
 use crate::coordinator::run_coordinator;
-use crate::implementations::pulseaudio::{PulseAudioConsumer, PulseAudioProducer};
+use crate::crypto::SecureSocket;
+use crate::error::Error;
 use crate::mp3player::decode_mp3;
+use crate::persistence::SavedSession;
+use crate::quality::Grade;
+use crate::playlist::PlaylistCommand;
+use crate::schedule::Schedule;
+use crate::session::Session;
+use crate::settings::{AudioSettings, ServerSettings};
+use crate::telemetry::{Endpoint, run_telemetry};
 
+mod admin;
+mod banlist;
+mod aec;
+mod assets;
 mod audio;
+mod bot;
+mod bus;
+mod chime;
+mod cli;
 mod client;
+mod codec;
+mod codec_test;
+mod config;
+mod connection;
+mod console;
+mod control;
 mod coordinator;
+mod crash;
+mod crypto;
+mod doctor;
+mod error;
+mod export;
+mod floor;
+mod header;
+mod identity;
 mod implementations;
 mod server;
+mod service;
+mod session;
 mod tui;
 mod mp3player;
 mod jitter;
+mod listen_along;
+mod loudness;
+mod mailbox;
+mod music;
+mod persistence;
+mod playlist;
+mod quality;
+mod ratelimit;
+mod recorder;
+mod resampler;
+mod schedule;
+mod settings;
+mod telemetry;
+#[cfg(test)]
+mod testutil;
+mod transcribe;
 
 const SAMPLE_RATE: u32 = 48000;
 const CHANNELS: usize = 2;
-const BUF_SIZE: u32 = 3840; // 20ms of stereo 48kHz 16-bit audio = 48000 samples/sec * 0.02 sec * 2 channels * 2 bytes/sample = 3840 bytes
-const MSG_SIZE: u32 = BUF_SIZE + 1;
+// largest audio message, room for 60ms of stereo 48kHz 16-bit audio = 11520 bytes
+// in a PCM room plus headers, opus packets are far smaller
+const BUF_SIZE: u32 = 16384;
 const FRAME_SIZE: usize = 960; // for opus - 20ms at 48kHz. Per channel, so total samples = FRAME_SIZE * CHANNELS = 1920
 
-#[derive(Debug)]
-enum ErrorKind {
-    InitializationError,
-    InitializationError2(String),
-    WriteError(String),
-    ReadError,
-}
-
 #[derive(Debug, Default)]
 pub struct ClientState {
     sending_audio: bool,
-    connected: bool,
+    connection: ConnectionState,
     mute: bool,
+    /// muted while the push-to-mute key is held
+    held_mute: bool,
+    /// the microphone is only sent while the push-to-talk key is held
+    push_to_talk: bool,
+    talking: bool,
     deafen: bool,
+    afk: bool,
+    recording_voice: bool,
+    /// round trip time to the server
+    latency: Option<Duration>,
+    /// how our connection does, from A to F
+    grade: Option<Grade>,
+    /// set once changed at runtime, the encoder's choice until then
+    bitrate: Option<u32>,
+    /// master playback volume in percent
+    output_volume: Option<u32>,
     exit: bool,
 }
 
+// Audio is passed around as interleaved f32 samples in the range [-1.0, 1.0],
+// conversion to integer samples only happens at the device boundary.
 trait AudioProducer {
-    fn produce(&mut self, data: &mut [u8]) -> Result<(), ErrorKind>;
+    fn produce(&mut self, data: &mut [f32]) -> Result<(), Error>;
 }
 
 trait Consumer {
-    fn consume(&mut self, data: &[u8]) -> Result<usize, ErrorKind>;
+    fn consume(&mut self, data: &[f32]) -> Result<usize, Error>;
 }
 
 //mod external;
 fn main() {
+    crash::install_panic_hook();
+    let Cli { command, client: client_args } = Cli::parse();
     let rt = tokio::runtime::Runtime::new().unwrap();
     rt.block_on(async {
-        let mut server = false;
         let mut client = true;
-        let mut test_audio = false;
         let mut tui = true;
         let mut debug = false;
-        let mut ip = "kopatz.dev:1234".to_string();
-        let mut args = std::env::args().skip(1).peekable();
-        let (tx_msg, rx_msg): (
-            Sender<client::ClientMessage>,
-            Receiver<client::ClientMessage>,
-        ) = mpsc::channel();
-        let (tx_tui, rx_tui): (
-            Sender<client::ClientMessage>,
-            Receiver<client::ClientMessage>,
-        ) = mpsc::channel();
-        let (tx_record, rx_record): (
-            Sender<client::ClientMessage>,
-            Receiver<client::ClientMessage>,
-        ) = mpsc::channel();
-        let (tx_playback, rx_playback): (
-            Sender<client::ClientMessage>,
-            Receiver<client::ClientMessage>,
-        ) = mpsc::channel();
-        let (tx_net_out, rx_net_out): (Sender<server::Message>, Receiver<server::Message>) =
-            mpsc::channel();
-
-        let (tx_net_in, rx_net_in): (Sender<server::Message>, Receiver<server::Message>) =
-            mpsc::channel();
-
-        while let Some(arg) = args.next() {
-            match arg.as_str() {
-                "--test-audio" => {
-                    test_audio = true;
-                    client = false;
-                }
-                "--server" => {
-                    server = true;
-                    client = false;
-                }
-                "--no-tui" => {
-                    tui = false;
-                }
-                "--ip" => {
-                    if let Some(val) = args.next() {
-                        ip = val;
-                    } else {
-                        eprintln!("--ip requires an address argument");
+        let mut daemon = false;
+        let mut attach = false;
+        let mut remote: Option<String> = None;
+        let mut remote_listen: Option<String> = None;
+        let mut resume = false;
+        let mut telemetry: Option<Endpoint> = None;
+        let mut settings = AudioSettings {
+            output_volume: persistence::load_output_volume().unwrap_or(100),
+            ..AudioSettings::default()
+        };
+        let bus = EventBus::new();
+        // read before the options are applied, so they override it
+        let config = match identity::config_file("config.toml").map(|path| Config::load(&path)) {
+            Some(Ok(config)) => config,
+            Some(Err(e)) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            None => Config::default(),
+        };
+        let mut ip = config
+            .apply(&mut settings)
+            .unwrap_or_else(|| DEFAULT_SERVER.to_string());
+        match command.unwrap_or(Command::Client(client_args)) {
+            Command::Client(args) => {
+                args.settings.apply(&mut settings);
+                if let Some(server) = args.ip {
+                    ip = server;
+                }
+                tui = !args.no_tui && !args.daemon;
+                daemon = args.daemon;
+                remote_listen = args.remote_listen;
+                resume = args.resume;
+                debug = args.debug;
+                telemetry = args.telemetry;
+            }
+            Command::Server(args) => {
+                let server_settings = args.settings();
+                crash::set_config(&settings, &server_settings);
+                run_server(args, server_settings).await;
+                return;
+            }
+            Command::Attach { remote: host, debug: log } => {
+                attach = true;
+                client = false;
+                remote = host;
+                debug = log;
+            }
+            Command::Stop => {
+                send_to_daemon(ClientMessage::Exit);
+                return;
+            }
+            Command::Connect => {
+                send_to_daemon(ClientMessage::Connection(ConnectionCommand::Connect));
+                return;
+            }
+            Command::Disconnect => {
+                send_to_daemon(ClientMessage::Connection(ConnectionCommand::Disconnect));
+                return;
+            }
+            Command::Reconnect => {
+                send_to_daemon(ClientMessage::Connection(ConnectionCommand::Reconnect));
+                return;
+            }
+            Command::Enqueue { source } => {
+                send_to_daemon(ClientMessage::PlaylistCommand(PlaylistCommand::Enqueue(source)));
+                return;
+            }
+            Command::Doctor { ip: server, settings: args } => {
+                args.apply(&mut settings);
+                if let Some(server) = server {
+                    ip = server;
+                }
+                crash::set_config(&settings, &ServerSettings::default());
+                let healthy = doctor::run(&ip, &settings).await;
+                std::process::exit(if healthy { 0 } else { 1 });
+            }
+            Command::Bot { bot: Bot::Tts { ip: server, tts_command, settings: args } } => {
+                // not the user's name from the config, unless --name says so
+                settings.name = "Chat reader".to_string();
+                args.apply(&mut settings);
+                if let Some(server) = server {
+                    ip = server;
+                }
+                crash::init_logger(&mut env_logger::Builder::from_env(
+                    env_logger::Env::default().filter_or("RUST_LOG", "info"),
+                ));
+                crash::set_config(&settings, &ServerSettings::default());
+                exit_on_signal(bus.clone());
+                let stopped = bot::run_tts(ip, settings, tts_command, bus).await;
+                std::process::exit(if stopped { 0 } else { 1 });
+            }
+            Command::TestCodec { file, settings: args } => {
+                args.apply(&mut settings);
+                if let Err(e) = codec_test::run(&settings, file.as_deref()) {
+                    eprintln!("{:?}", e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+            Command::WriteConfig { file, ip: server, settings: args } => {
+                args.apply(&mut settings);
+                if let Some(server) = server {
+                    ip = server;
+                }
+                let Some(path) = file.or_else(|| identity::config_file("config.toml")) else {
+                    eprintln!("No home directory, write-config requires a file");
+                    std::process::exit(1);
+                };
+                let toml = config.effective(&ip, &settings).to_toml();
+                let dir = path.parent().unwrap_or(std::path::Path::new("."));
+                let written =
+                    std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, toml));
+                if let Err(e) = written {
+                    eprintln!("Can't write {}: {}", path.display(), e);
+                    std::process::exit(1);
+                }
+                println!("Wrote {}", path.display());
+                return;
+            }
+            Command::InitConfig { dir } => {
+                let Some(dir) = dir.or_else(identity::config_dir) else {
+                    eprintln!("No home directory, init-config requires a directory");
+                    std::process::exit(1);
+                };
+                match assets::write_assets(&dir) {
+                    Ok(written) if written.is_empty() => {
+                        println!("Everything is already in {}", dir.display());
+                    }
+                    Ok(written) => {
+                        for asset in written {
+                            let path = dir.join(asset.path);
+                            println!("Wrote {}, {}", path.display(), asset.description);
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("{:?}", e);
                         std::process::exit(1);
                     }
                 }
-                "--debug" => debug = true,
-                "--help" => help(),
-                "--h" => help(),
-                other => {
-                    eprintln!("Unknown argument: {}", other);
+                return;
+            }
+            #[cfg(windows)]
+            Command::InstallService { ip: server, remove } => {
+                let done = if remove {
+                    service::windows::remove()
+                } else {
+                    service::windows::install(server)
+                };
+                match done {
+                    Ok(()) if remove => println!("Removed the kop-audio service"),
+                    Ok(()) => println!("Installed, start it with sc start kop-audio"),
+                    Err(e) => {
+                        eprintln!("{:?}", e);
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+            #[cfg(windows)]
+            Command::WindowsService { ip: server } => {
+                if let Err(e) = service::windows::run(server) {
+                    eprintln!("{:?}", e);
                     std::process::exit(1);
                 }
+                return;
             }
+            #[cfg(not(windows))]
+            Command::InstallService { ip: server, remove } => {
+                let Some(path) = service::path() else {
+                    eprintln!("No home directory to install the service in");
+                    std::process::exit(1);
+                };
+                if remove {
+                    if let Err(e) = std::fs::remove_file(&path) {
+                        eprintln!("Can't remove {}: {}", path.display(), e);
+                        std::process::exit(1);
+                    }
+                    println!("Removed {}", path.display());
+                    return;
+                }
+                let exe = match std::env::current_exe() {
+                    Ok(exe) => exe,
+                    Err(e) => {
+                        eprintln!("Can't tell where kop-audio is installed: {}", e);
+                        std::process::exit(1);
+                    }
+                };
+                let mut args = vec!["client".to_string(), "--daemon".to_string()];
+                if let Some(server) = server {
+                    args.extend(["--ip".to_string(), server]);
+                }
+                let definition = service::definition(&exe.to_string_lossy(), &args);
+                let dir = path.parent().unwrap_or(std::path::Path::new("."));
+                let written =
+                    std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, definition));
+                if let Err(e) = written {
+                    eprintln!("Can't write {}: {}", path.display(), e);
+                    std::process::exit(1);
+                }
+                let start = service::start_command(&path);
+                println!("Wrote {}, start it with {}", path.display(), start);
+                return;
+            }
+            Command::Export { recording } => {
+                match export::export(&recording) {
+                    Ok(manifest) => println!("Exported to {}", manifest.display()),
+                    Err(e) => {
+                        eprintln!("{:?}", e);
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+            Command::ListMics { backend } => {
+                let backend = backend.unwrap_or(settings.backend);
+                if backend.name() != "pulse" {
+                    let (devices, _) = backend.list_devices();
+                    for (index, (name, description)) in devices.iter().enumerate() {
+                        println!("{}\t{}\t{}", index, name, description);
+                    }
+                    return;
+                }
+                match implementations::pulseaudio::list_sources() {
+                    Ok(sources) => {
+                        for source in sources {
+                            let default = if source.is_default { " (default)" } else { "" };
+                            println!(
+                                "{}\t{}\t{}{}",
+                                source.index, source.form_factor, source.description, default
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("{:?}", e);
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+            Command::ListOutputs { backend } => {
+                let backend = backend.unwrap_or(settings.backend);
+                if backend.name() != "pulse" {
+                    let (_, devices) = backend.list_devices();
+                    for (index, (name, description)) in devices.iter().enumerate() {
+                        println!("{}\t{}\t{}", index, name, description);
+                    }
+                    return;
+                }
+                match implementations::pulseaudio::list_sinks() {
+                    Ok(sinks) => {
+                        for sink in sinks {
+                            let default = if sink.is_default { " (default)" } else { "" };
+                            println!("{}\t{}{}", sink.index, sink.description, default);
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("{:?}", e);
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+            Command::ListAppStreams => {
+                match implementations::pulseaudio::list_app_streams() {
+                    Ok(streams) => {
+                        for stream in streams {
+                            println!("{}\t{}\t{}", stream.index, stream.application, stream.media);
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("{:?}", e);
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+            Command::TestAudio => client = false,
         }
-
-        if server && client {
-            eprintln!("Cannot be both client and server");
-            return;
-        }
-        if !client && tui {
+        crash::set_config(&settings, &ServerSettings::default());
+        if !client && !attach && tui {
             tui = false;
         }
         if !tui {
-            env_logger::Builder::from_env(env_logger::Env::default().filter_or("RUST_LOG", "info"))
-                .init();
+            crash::init_logger(&mut env_logger::Builder::from_env(
+                env_logger::Env::default().filter_or("RUST_LOG", "info"),
+            ));
         } else {
             if debug {
                 let target = Box::new(File::create("/tmp/log.txt").expect("Can't create file"));
-                env_logger::Builder::new()
-                    .filter(None, LevelFilter::Debug)
-                    .target(env_logger::Target::Pipe(target))
-                    .format(|buf, record| {
-                        writeln!(
-                            buf,
-                            "[{} {} {}:{}] {}",
-                            "now",
-                            record.level(),
-                            record.file().unwrap_or("unknown"),
-                            record.line().unwrap_or(0),
-                            record.args()
-                        )
-                    })
-                    .init();
+                crash::init_logger(
+                    env_logger::Builder::new()
+                        .filter(None, LevelFilter::Debug)
+                        .target(env_logger::Target::Pipe(target))
+                        .format(|buf, record| {
+                            writeln!(
+                                buf,
+                                "[{} {} {}:{}] {}",
+                                "now",
+                                record.level(),
+                                record.file().unwrap_or("unknown"),
+                                record.line().unwrap_or(0),
+                                record.args()
+                            )
+                        }),
+                );
             } else {
-                env_logger::Builder::new()
-                    .filter_level(log::LevelFilter::Off)
-                    .init();
+                crash::init_logger(
+                    env_logger::Builder::new().filter_level(log::LevelFilter::Off),
+                );
             }
         }
-        if client {
+        if attach {
+            let commands = bus.commands.subscribe();
+            let events = bus.events.subscribe();
+            let attach_bus = bus.clone();
+            std::thread::spawn(move || {
+                let result = match remote {
+                    Some(host) => control::attach_remote(&host, attach_bus, commands),
+                    None => control::attach(attach_bus, commands),
+                };
+                if let Err(e) = result {
+                    ratatui::restore();
+                    eprintln!("{:?}", e);
+                    std::process::exit(1);
+                }
+                std::process::exit(0);
+            });
+            tui::App::new(events, bus, config.keys);
+        } else if client {
+            let saved = match resume.then(SavedSession::load).flatten() {
+                Some(saved) => {
+                    info!("Resuming session on {}", saved.server);
+                    ip = saved.server.clone();
+                    saved
+                }
+                // the volumes we gave people on this server still apply
+                None => SavedSession {
+                    volumes: SavedSession::load()
+                        .filter(|saved| saved.server == ip)
+                        .map(|saved| saved.volumes)
+                        .unwrap_or_default(),
+                    server: ip.clone(),
+                    ..Default::default()
+                },
+            };
             //todo: some way to mute and deafen
-            let mut audio_consumer = PulseAudioConsumer::new().unwrap();
-            let mut audio_producer = PulseAudioProducer::new().unwrap();
-            let tx_msg_clone = tx_msg.clone();
-            tokio::spawn(async move { record_audio(tx_msg_clone, &mut audio_producer, rx_record) });
-            tokio::spawn(async move { play_audio(rx_playback, &mut audio_consumer) });
-            let network_client = NetworkClient::new(&ip, tx_msg.clone()).await.unwrap();
-            network_client.start(rx_net_in, rx_net_out).await;
+            let commands = bus.commands.subscribe();
+            let session = Session::new(ip.clone(), settings.clone(), bus.clone());
+            if let Some(endpoint) = telemetry {
+                tokio::spawn(run_telemetry(endpoint, bus.events.subscribe()));
+            }
+            // quits like the TUI does, with a bye to the server
+            exit_on_signal(bus.clone());
+            let mut frontend = None;
             if tui {
-                tokio::spawn(async move { tui::App::new(rx_tui, tx_msg) });
-            }
-            run_coordinator(
-                rx_msg,
-                tx_playback.clone(),
-                tx_record.clone(),
-                tx_tui.clone(),
-                tx_net_out.clone(),
-                tx_net_in.clone(),
-            )
-            .await;
-            // TODO: wait for ctrl-c in non-tui mode, send Bye to server
-            // TODO: probably need a mpmc channel for that
-            //match signal::ctrl_c().await {
-            //    Ok(()) => {
-            //        std::process::exit(0);
-            //    }
-            //    Err(err) => {
-            //        eprintln!("Unable to listen for shutdown signal: {}", err);
-            //        // we also shut down in case of error
-            //    }
-            //}
-        } else if server {
-            let listener = UdpSocket::bind("0.0.0.0:1234").await.unwrap();
-            info!("Listening on 0.0.0.0:1234");
-            //receive_audio(Arc::new(listener)).await;
-            server::server_loop(listener).await;
-        } else if test_audio {
+                let events = bus.events.subscribe();
+                let tui_bus = bus.clone();
+                let keys = config.keys.clone();
+                let app = tokio::task::spawn_blocking(move || tui::App::new(events, tui_bus, keys));
+                frontend = Some(app);
+            } else if daemon {
+                // keep running when the terminal that started the daemon goes away
+                unsafe { libc::signal(libc::SIGHUP, libc::SIG_IGN) };
+                let events = bus.events.subscribe();
+                let control_bus = bus.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = control::run_control_server(events, control_bus, remote_listen) {
+                        error!("Control socket failed: {:?}", e);
+                        std::process::exit(1);
+                    }
+                });
+            }
+            run_coordinator(bus, commands, session, saved, settings).await;
+            // the TUI sees the shutdown and restores the terminal before we exit
+            if let Some(frontend) = frontend {
+                let _ = frontend.await;
+            }
+            std::process::exit(0);
+        } else {
             println!("Playing test audio from seashore.mp3");
-            let mut audio_consumer = PulseAudioConsumer::new().unwrap();
+            let mut audio_consumer = settings.backend.playback(&settings).unwrap();
             let data = decode_mp3("seashore.mp3");
             println!("Decoded {} samples", data.len());
             let data = mp3player::resample_to_48k(&data, 44100);
@@ -209,19 +525,88 @@ fn main() {
             //    }
             //    audio_consumer.consume(&buf).unwrap();
             //}
-        } else {
-            eprintln!("Must specify either --client or --server");
         }
     })
 }
 
-fn help() {
-    println!(
-        "Usage: {} [--server|--client] [--ip <address:port>] [--no-tui]",
-        std::env::args().next().unwrap()
-    );
-    println!("If neither --server nor --client is specified, defaults to --client.");
-    println!("--ip specifies the IP address and port to connect to.");
-    println!("--no-tui disables the terminal user interface.");
-    std::process::exit(0);
+/// Quits on ctrl-c, or SIGTERM from a service manager, by publishing `Exit`.
+fn exit_on_signal(bus: EventBus) {
+    tokio::spawn(async move {
+        let terminate = async {
+            match signal::unix::signal(SignalKind::terminate()) {
+                Ok(mut terminate) => terminate.recv().await,
+                Err(e) => {
+                    error!("Unable to listen for SIGTERM: {}", e);
+                    std::future::pending().await
+                }
+            }
+        };
+        tokio::select! {
+            result = signal::ctrl_c() => match result {
+                Ok(()) => info!("Got ctrl-c, shutting down"),
+                Err(e) => error!("Unable to listen for shutdown signal: {}", e),
+            },
+            _ = terminate => info!("Got SIGTERM, shutting down"),
+        }
+        bus.commands.publish(ClientMessage::Exit);
+    });
+}
+
+/// Hands `command` to the running daemon, see `control::send_command`.
+fn send_to_daemon(command: ClientMessage) {
+    if let Err(e) = control::send_command(command) {
+        eprintln!("{:?}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run_server(args: ServerArgs, settings: ServerSettings) {
+    crash::init_logger(&mut env_logger::Builder::from_env(
+        env_logger::Env::default().filter_or("RUST_LOG", "info"),
+    ));
+    let listener = match UdpSocket::bind(args.bind).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Can't listen on {}: {}", args.bind, e);
+            std::process::exit(1);
+        }
+    };
+    let listener = SecureSocket::server(listener, settings.require_encryption);
+    info!("Listening on {}", args.bind);
+    let (admin_tx, admin_rx) = tokio::sync::mpsc::channel(16);
+    if args.console {
+        tokio::spawn(console::run_console(admin_tx.clone()));
+    }
+    if let Some(addr) = args.admin_listen {
+        let Ok(password) = std::env::var("KOP_AUDIO_ADMIN_PASSWORD") else {
+            eprintln!("--admin-listen requires KOP_AUDIO_ADMIN_PASSWORD to be set");
+            std::process::exit(1);
+        };
+        tokio::spawn(async move {
+            if let Err(e) = admin::run_admin_server(addr, password, admin_tx).await {
+                error!("Admin UI failed: {:?}", e);
+            }
+        });
+    }
+    let schedule = match args.schedule {
+        Some(path) => match Schedule::load(&path, args.remind) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                std::process::exit(1);
+            }
+        },
+        None => Schedule::default(),
+    };
+    let bans = match args.ban_list {
+        Some(path) => match BanList::load(path) {
+            Ok(bans) => bans,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                std::process::exit(1);
+            }
+        },
+        None => BanList::default(),
+    };
+    server::server_loop(listener, admin_rx, schedule, bans, settings).await;
 }
@@ -0,0 +1,405 @@
+use std::{collections::VecDeque, f32::consts::FRAC_PI_2, fs::File, path::Path, time::Duration};
+
+use log::{debug, warn};
+use symphonia::{
+    core::{
+        audio::SampleBuffer,
+        codecs::{Decoder, DecoderOptions},
+        errors::Error as DecodeFailure,
+        formats::{FormatOptions, FormatReader},
+        io::MediaSourceStream,
+        meta::{MetadataOptions, MetadataRevision, StandardTagKey},
+        probe::Hint,
+    },
+    default::{get_codecs, get_probe},
+};
+
+use crate::{
+    AudioProducer, CHANNELS, Error, SAMPLE_RATE,
+    playlist::{Playlist, PlaylistEntry, TrackInfo},
+    resampler::StreamResampler,
+};
+
+/// Interleaved stereo at the call's rate, decoded a bit at a time.
+pub trait TrackSource: Send {
+    /// Appends the next few samples, false once the track is over.
+    fn read(&mut self, out: &mut Vec<f32>) -> bool;
+}
+
+/// A local audio file, in any format symphonia was built with.
+pub struct FileTrack {
+    pub info: TrackInfo,
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    channels: usize,
+    resampler: Option<StreamResampler>,
+    samples: Option<SampleBuffer<f32>>,
+    stereo: Vec<f32>,
+}
+
+impl FileTrack {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let error = |e: &dyn std::fmt::Display| Error::Io(format!("Can't play {}: {}", path, e));
+        let file = File::open(path).map_err(|e| error(&e))?;
+        let mut hint = Hint::new();
+        if let Some(extension) = Path::new(path).extension().and_then(|e| e.to_str()) {
+            hint.with_extension(extension);
+        }
+        let stream = MediaSourceStream::new(Box::new(file), Default::default());
+        // trims encoder delay and padding, so consecutive tracks splice without a gap
+        let options = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let mut probed = get_probe()
+            .format(&hint, stream, &options, &MetadataOptions::default())
+            .map_err(|e| error(&e))?;
+        let mut format = probed.format;
+        // tags can sit in front of the container, e.g. ID3, or in it
+        let info = {
+            let outside = probed.metadata.get();
+            let inside = format.metadata();
+            let revisions = [outside.as_ref().and_then(|m| m.current()), inside.current()];
+            track_info(path, revisions.into_iter().flatten())
+        };
+        let track = format
+            .default_track()
+            .ok_or_else(|| error(&"no audio track"))?;
+        let params = &track.codec_params;
+        let decoder = get_codecs()
+            .make(params, &DecoderOptions::default())
+            .map_err(|e| error(&e))?;
+        let rate = params.sample_rate.unwrap_or(SAMPLE_RATE);
+        let resampler = match rate {
+            SAMPLE_RATE => None,
+            rate => Some(StreamResampler::new(rate, SAMPLE_RATE, 1024)?),
+        };
+        Ok(FileTrack {
+            info,
+            track_id: track.id,
+            channels: params.channels.map(|c| c.count()).unwrap_or(CHANNELS),
+            format,
+            decoder,
+            resampler,
+            samples: None,
+            stereo: Vec::new(),
+        })
+    }
+}
+
+fn track_info<'a>(path: &str, revisions: impl Iterator<Item = &'a MetadataRevision>) -> TrackInfo {
+    let mut title = None;
+    let mut artist = None;
+    for tag in revisions.flat_map(|revision| revision.tags()) {
+        match tag.std_key {
+            Some(StandardTagKey::TrackTitle) if title.is_none() => {
+                title = Some(tag.value.to_string())
+            }
+            Some(StandardTagKey::Artist) if artist.is_none() => {
+                artist = Some(tag.value.to_string())
+            }
+            _ => {}
+        }
+    }
+    let file_name = || {
+        let stem = Path::new(path).file_stem().and_then(|stem| stem.to_str());
+        stem.unwrap_or(path).to_string()
+    };
+    TrackInfo {
+        title: title.unwrap_or_else(file_name),
+        artist,
+    }
+}
+
+impl TrackSource for FileTrack {
+    fn read(&mut self, out: &mut Vec<f32>) -> bool {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(_) => return false,
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // a corrupt frame, the next one is likely fine
+                Err(DecodeFailure::DecodeError(_)) => continue,
+                Err(_) => return false,
+            };
+            let samples = self.samples.get_or_insert_with(|| {
+                SampleBuffer::new(decoded.capacity() as u64, *decoded.spec())
+            });
+            samples.copy_interleaved_ref(decoded);
+            // mono is played on both sides, anything beyond stereo is dropped
+            self.stereo.clear();
+            for frame in samples.samples().chunks_exact(self.channels.max(1)) {
+                let right = frame.get(1).unwrap_or(&frame[0]);
+                self.stereo.extend_from_slice(&[frame[0], *right]);
+            }
+            match &mut self.resampler {
+                Some(resampler) => resampler.process(&self.stereo, out),
+                None => out.extend_from_slice(&self.stereo),
+            }
+            return true;
+        }
+    }
+}
+
+struct Track {
+    id: u32,
+    info: TrackInfo,
+    source: Box<dyn TrackSource>,
+    buffer: VecDeque<f32>,
+    ended: bool,
+    // frames the crossfade into the next track takes, fixed once the end was decoded
+    fade: Option<usize>,
+}
+
+impl Track {
+    fn new(id: u32, info: TrackInfo, source: Box<dyn TrackSource>) -> Self {
+        Track {
+            id,
+            info,
+            source,
+            buffer: VecDeque::new(),
+            ended: false,
+            fade: None,
+        }
+    }
+
+    /// Decodes until `samples` are buffered or the track is over.
+    fn fill(&mut self, samples: usize, scratch: &mut Vec<f32>) {
+        while !self.ended && self.buffer.len() < samples {
+            scratch.clear();
+            self.ended = !self.source.read(scratch);
+            self.buffer.extend(scratch.iter());
+        }
+    }
+
+    fn frame(&mut self, out: &mut [f32], gain: f32) {
+        for sample in out {
+            *sample += self.buffer.pop_front().unwrap_or(0.0) * gain;
+        }
+    }
+}
+
+/// Plays the room playlist's local files as listen-along music. The head of the
+/// next track is decoded while the current one ends, so the two are crossfaded
+/// or, without a crossfade, spliced without a gap.
+pub struct MusicProducer {
+    crossfade_frames: usize,
+    current: Option<Track>,
+    next: Option<Track>,
+    // tracks that played to their end, for the server to move on
+    finished: Vec<u32>,
+    // the track the room was last told about
+    announced: Option<u32>,
+    // the room jumped to another track since the last call to `take_cut`
+    cut: bool,
+    scratch: Vec<f32>,
+}
+
+impl MusicProducer {
+    /// A zero `crossfade` splices the tracks gaplessly.
+    pub fn new(crossfade: Duration) -> Self {
+        MusicProducer {
+            crossfade_frames: SAMPLE_RATE as usize * crossfade.as_millis() as usize / 1000,
+            current: None,
+            next: None,
+            finished: Vec::new(),
+            announced: None,
+            cut: false,
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Follows the room playlist, loading whatever it plays now and next.
+    pub fn set_playlist(&mut self, playlist: &Playlist) {
+        match &playlist.current {
+            None => self.current = None,
+            Some(entry) if self.current.as_ref().is_some_and(|t| t.id == entry.id) => {}
+            // skipped by the room, cut over
+            Some(entry) if self.next.as_ref().is_some_and(|t| t.id == entry.id) => {
+                self.cut = self.current.is_some();
+                self.current = self.next.take();
+            }
+            Some(entry) => {
+                self.cut = self.current.is_some();
+                self.current = self.open(entry);
+            }
+        }
+        match playlist.queue.first() {
+            Some(entry) if self.next.as_ref().is_some_and(|t| t.id == entry.id) => {}
+            Some(entry) => self.next = self.open(entry),
+            None => self.next = None,
+        }
+    }
+
+    fn open(&mut self, entry: &PlaylistEntry) -> Option<Track> {
+        match FileTrack::open(&entry.source) {
+            Ok(source) => {
+                debug!("Loaded {}", entry.source);
+                Some(Track::new(entry.id, source.info.clone(), Box::new(source)))
+            }
+            Err(e) => {
+                // e.g. a URL or a file only someone else has, let the room move on
+                warn!("{:?}", e);
+                self.finished.push(entry.id);
+                None
+            }
+        }
+    }
+
+    /// What plays now, if that changed since the last call.
+    pub fn take_now_playing(&mut self) -> Option<Option<TrackInfo>> {
+        let id = self.current.as_ref().map(|track| track.id);
+        if id == self.announced {
+            return None;
+        }
+        self.announced = id;
+        Some(self.current.as_ref().map(|track| track.info.clone()))
+    }
+
+    /// Whether the music jumped rather than played on since the last call,
+    /// e.g. a skip, its listeners' decoders had better start afresh.
+    pub fn take_cut(&mut self) -> bool {
+        std::mem::take(&mut self.cut)
+    }
+
+    /// Tracks that ended since the last call.
+    pub fn take_finished(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.finished)
+    }
+}
+
+impl AudioProducer for MusicProducer {
+    fn produce(&mut self, data: &mut [f32]) -> Result<(), Error> {
+        data.fill(0.0);
+        let wanted = data.len() + self.crossfade_frames * CHANNELS;
+        for frame in data.chunks_exact_mut(CHANNELS) {
+            let Some(current) = &mut self.current else {
+                break;
+            };
+            current.fill(wanted, &mut self.scratch);
+            if current.ended && current.fade.is_none() {
+                let left = current.buffer.len() / CHANNELS;
+                current.fade = Some(self.crossfade_frames.min(left));
+            }
+            let left = current.buffer.len() / CHANNELS;
+            match (current.fade, &mut self.next) {
+                (Some(fade), Some(next)) if fade > 0 && left <= fade => {
+                    // equal power, so the overlap doesn't dip in loudness
+                    let progress = (fade - left) as f32 / fade as f32;
+                    next.fill(wanted, &mut self.scratch);
+                    current.frame(frame, (progress * FRAC_PI_2).cos());
+                    next.frame(frame, (progress * FRAC_PI_2).sin());
+                }
+                _ => current.frame(frame, 1.0),
+            }
+            if current.ended && current.buffer.is_empty() {
+                self.finished.push(current.id);
+                self.current = self.next.take();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::UserId;
+
+    /// A track of `frames` frames at a constant level, read in small pieces.
+    struct Constant {
+        level: f32,
+        frames: usize,
+    }
+
+    impl TrackSource for Constant {
+        fn read(&mut self, out: &mut Vec<f32>) -> bool {
+            let n = self.frames.min(100);
+            self.frames -= n;
+            out.extend(std::iter::repeat_n(self.level, n * CHANNELS));
+            n > 0
+        }
+    }
+
+    fn track(id: u32, level: f32, frames: usize) -> Option<Track> {
+        let info = TrackInfo {
+            title: format!("track {}", id),
+            artist: None,
+        };
+        Some(Track::new(id, info, Box::new(Constant { level, frames })))
+    }
+
+    fn run(producer: &mut MusicProducer, frames: usize) -> Vec<f32> {
+        let mut out = Vec::new();
+        let mut frame = vec![0.0; 480 * CHANNELS];
+        for _ in 0..frames.div_ceil(480) {
+            producer.produce(&mut frame).unwrap();
+            out.extend_from_slice(&frame);
+        }
+        out
+    }
+
+    #[test]
+    fn splices_tracks_without_a_gap() {
+        let mut producer = MusicProducer::new(Duration::ZERO);
+        producer.current = track(1, 0.5, 1000);
+        producer.next = track(2, 0.25, 1000);
+        let playing = producer.take_now_playing().unwrap().unwrap();
+        assert_eq!(playing.title, "track 1");
+        let out = run(&mut producer, 2000);
+        assert!(out[..1000 * CHANNELS].iter().all(|s| *s == 0.5));
+        assert!(
+            out[1000 * CHANNELS..2000 * CHANNELS]
+                .iter()
+                .all(|s| *s == 0.25)
+        );
+        assert_eq!(producer.take_finished(), vec![1, 2]);
+        // the music stopped
+        assert_eq!(producer.take_now_playing(), Some(None));
+        assert_eq!(producer.take_now_playing(), None);
+    }
+
+    #[test]
+    fn skipping_cuts_over_to_the_next_track() {
+        let entry = |id| PlaylistEntry {
+            id,
+            source: format!("track{}.mp3", id),
+            added_by: UserId([1; 16]),
+        };
+        let mut producer = MusicProducer::new(Duration::ZERO);
+        producer.current = track(1, 0.5, 10_000);
+        producer.next = track(2, 0.25, 10_000);
+        run(&mut producer, 960);
+        assert!(!producer.take_cut());
+        producer.set_playlist(&Playlist {
+            current: Some(entry(2)),
+            queue: Vec::new(),
+            skip_votes: 0,
+            votes_needed: 1,
+        });
+        assert!(producer.take_cut());
+        assert!(!producer.take_cut());
+        assert!(run(&mut producer, 960).iter().all(|s| *s == 0.25));
+    }
+
+    #[test]
+    fn crossfades_into_the_next_track() {
+        let mut producer = MusicProducer::new(Duration::from_millis(10));
+        producer.current = track(1, 1.0, 2000);
+        producer.next = track(2, 1.0, 2000);
+        let out = run(&mut producer, 4000);
+        // the two overlap by 480 frames, and the overlap doesn't dip much
+        let played = out.iter().rposition(|s| *s != 0.0).unwrap() / CHANNELS + 1;
+        assert_eq!(played, 4000 - 480);
+        let mid = (2000 - 240) * CHANNELS;
+        assert!((out[mid] - 2f32.sqrt()).abs() < 0.01);
+        assert!(out[..played * CHANNELS].iter().all(|s| *s >= 0.99));
+        assert_eq!(producer.take_finished(), vec![1, 2]);
+    }
+}
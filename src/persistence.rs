@@ -0,0 +1,129 @@
+use std::{fs, net::SocketAddr, path::PathBuf};
+
+use bincode::{Decode, Encode, config};
+use log::{debug, warn};
+
+use crate::identity::config_file;
+
+/// Everything needed to rejoin the last call after a crash or reboot.
+#[derive(Encode, Decode, Debug, Default, Clone, PartialEq)]
+pub struct SavedSession {
+    pub server: String,
+    pub muted: bool,
+    pub deafened: bool,
+    /// address and display name
+    pub users: Vec<(SocketAddr, String)>,
+    /// display name, volume in percent and local mute of the users we
+    /// changed, by name as their address changes when they reconnect
+    pub volumes: Vec<(String, u32, bool)>,
+}
+
+/// Where kop-audio keeps what outlives a run, e.g. crash reports.
+pub fn state_dir() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(dir.join("kop-audio"))
+}
+
+fn session_path() -> Option<PathBuf> {
+    Some(state_dir()?.join("session"))
+}
+
+impl SavedSession {
+    /// The volume and local mute we gave the user called `name`.
+    pub fn user_volume(&self, name: &str) -> Option<(u32, bool)> {
+        self.volumes
+            .iter()
+            .find(|(user, _, _)| user == name)
+            .map(|(_, volume, muted)| (*volume, *muted))
+    }
+
+    /// Remembers the volume and local mute of the user at `addr`, forgetting
+    /// it once it's back to normal.
+    pub fn set_user_volume(&mut self, addr: SocketAddr, volume: u32, muted: bool) {
+        let Some((_, name)) = self.users.iter().find(|(user, _)| *user == addr) else {
+            return;
+        };
+        self.volumes.retain(|(user, _, _)| user != name);
+        if (volume, muted) != (100, false) {
+            self.volumes.push((name.clone(), volume, muted));
+        }
+    }
+
+    pub fn load() -> Option<SavedSession> {
+        let data = fs::read(session_path()?).ok()?;
+        match bincode::decode_from_slice(&data, config::standard()) {
+            Ok((session, _)) => Some(session),
+            Err(e) => {
+                warn!("Ignoring unreadable session file: {:?}", e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self) {
+        let Some(path) = session_path() else {
+            return;
+        };
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let data = bincode::encode_to_vec(self, config::standard()).unwrap();
+        match fs::write(&path, data) {
+            Ok(_) => debug!("Saved session to {}", path.display()),
+            Err(e) => warn!("Can't save session to {}: {:?}", path.display(), e),
+        }
+    }
+}
+
+/// The output volume in percent last set in the TUI.
+pub fn load_output_volume() -> Option<u32> {
+    let path = config_file("volume")?;
+    let text = fs::read_to_string(&path).ok()?;
+    match text.trim().parse() {
+        Ok(volume) => Some(volume),
+        Err(_) => {
+            warn!("Ignoring malformed volume file {}", path.display());
+            None
+        }
+    }
+}
+
+pub fn save_output_volume(volume: u32) {
+    let Some(path) = config_file("volume") else {
+        return;
+    };
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    if let Err(e) = fs::write(&path, format!("{}\n", volume)) {
+        warn!("Can't save the volume to {}: {:?}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_volumes_by_name_across_addresses() {
+        let alice: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+        let mut saved = SavedSession {
+            users: vec![(alice, "alice".to_string())],
+            ..Default::default()
+        };
+        saved.set_user_volume(alice, 150, false);
+        // someone we don't know the name of yet
+        saved.set_user_volume("10.0.0.3:4000".parse().unwrap(), 50, true);
+        let data = bincode::encode_to_vec(&saved, config::standard()).unwrap();
+        let (mut saved, _): (SavedSession, _) =
+            bincode::decode_from_slice(&data, config::standard()).unwrap();
+        assert_eq!(saved.user_volume("alice"), Some((150, false)));
+        assert_eq!(saved.volumes.len(), 1);
+        saved.users = vec![("10.0.0.2:5000".parse().unwrap(), "alice".to_string())];
+        saved.set_user_volume(saved.users[0].0, 100, false);
+        assert_eq!(saved.user_volume("alice"), None);
+    }
+}
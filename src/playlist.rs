@@ -0,0 +1,198 @@
+use std::collections::HashSet;
+
+use bincode::{Decode, Encode};
+
+use crate::identity::UserId;
+
+// keeps the state small enough for a single datagram
+const MAX_QUEUE: usize = 50;
+const MAX_SOURCE_LEN: usize = 200;
+const MAX_TAG_CHARS: usize = 100;
+
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct PlaylistEntry {
+    pub id: u32,
+    /// file or URL, the listen-along host plays it in whatever player it uses
+    pub source: String,
+    pub added_by: UserId,
+}
+
+/// What a client asks of the room's playlist.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub enum PlaylistCommand {
+    Enqueue(String),
+    /// moves a queued entry one place up (`true`) or down
+    Move(u32, bool),
+    /// votes to skip the current track
+    VoteSkip,
+    /// the listen-along host played the track to its end
+    Finished(u32),
+}
+
+/// The room's playlist as the clients see it.
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Default)]
+pub struct Playlist {
+    pub current: Option<PlaylistEntry>,
+    pub queue: Vec<PlaylistEntry>,
+    pub skip_votes: u32,
+    pub votes_needed: u32,
+}
+
+/// What the listen-along host is playing, from the track's tags.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct TrackInfo {
+    /// the file name if the track has no title tag
+    pub title: String,
+    pub artist: Option<String>,
+}
+
+impl TrackInfo {
+    /// Cut to a length every client can show.
+    pub fn truncated(self) -> Self {
+        TrackInfo {
+            title: self.title.chars().take(MAX_TAG_CHARS).collect(),
+            artist: self
+                .artist
+                .map(|artist| artist.chars().take(MAX_TAG_CHARS).collect()),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match &self.artist {
+            Some(artist) => format!("{} - {}", artist, self.title),
+            None => self.title.clone(),
+        }
+    }
+}
+
+/// The playlist the server keeps for the room.
+#[derive(Debug, Default)]
+pub struct PlaylistQueue {
+    current: Option<PlaylistEntry>,
+    queue: Vec<PlaylistEntry>,
+    skip_votes: HashSet<UserId>,
+    next_id: u32,
+}
+
+impl PlaylistQueue {
+    /// Adds a track at the end, it starts right away if nothing is playing.
+    pub fn enqueue(&mut self, source: String, added_by: UserId) -> bool {
+        let source = source.trim();
+        if source.is_empty() || source.len() > MAX_SOURCE_LEN || self.queue.len() >= MAX_QUEUE {
+            return false;
+        }
+        self.next_id += 1;
+        self.queue.push(PlaylistEntry {
+            id: self.next_id,
+            source: source.to_string(),
+            added_by,
+        });
+        if self.current.is_none() {
+            self.advance();
+        }
+        true
+    }
+
+    pub fn move_entry(&mut self, id: u32, up: bool) -> bool {
+        let Some(pos) = self.queue.iter().position(|entry| entry.id == id) else {
+            return false;
+        };
+        let other = match up {
+            true if pos > 0 => pos - 1,
+            false if pos + 1 < self.queue.len() => pos + 1,
+            _ => return false,
+        };
+        self.queue.swap(pos, other);
+        true
+    }
+
+    /// Counts a skip vote, the track is skipped once a majority of the
+    /// `listeners` voted. Whoever added it can skip it outright, e.g. when it
+    /// ended.
+    pub fn vote_skip(&mut self, user: UserId, listeners: usize) -> bool {
+        let Some(current) = &self.current else {
+            return false;
+        };
+        self.skip_votes.insert(user);
+        if current.added_by == user || self.skip_votes.len() >= votes_needed(listeners) {
+            self.advance();
+        }
+        true
+    }
+
+    /// Moves on once the track `id` ended, unless the room moved on already.
+    pub fn finish(&mut self, id: u32) -> bool {
+        if self.current.as_ref().is_none_or(|current| current.id != id) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn advance(&mut self) {
+        self.skip_votes.clear();
+        self.current = (!self.queue.is_empty()).then(|| self.queue.remove(0));
+    }
+
+    pub fn snapshot(&self, listeners: usize) -> Playlist {
+        Playlist {
+            current: self.current.clone(),
+            queue: self.queue.clone(),
+            skip_votes: self.skip_votes.len() as u32,
+            votes_needed: votes_needed(listeners) as u32,
+        }
+    }
+}
+
+fn votes_needed(listeners: usize) -> usize {
+    listeners / 2 + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_reorders_and_skips_by_majority() {
+        let (alice, bob, carol) = (UserId([1; 16]), UserId([2; 16]), UserId([3; 16]));
+        let mut playlist = PlaylistQueue::default();
+        assert!(playlist.enqueue("first.mp3".into(), alice));
+        assert!(playlist.enqueue("second.mp3".into(), bob));
+        assert!(playlist.enqueue(" https://example.com/third ".into(), bob));
+        assert!(!playlist.enqueue("  ".into(), bob));
+        let third = playlist.snapshot(3).queue[1].id;
+        assert!(playlist.move_entry(third, true));
+        assert!(!playlist.move_entry(third, true));
+        let snapshot = playlist.snapshot(3);
+        assert_eq!(snapshot.current.unwrap().source, "first.mp3");
+        assert_eq!(snapshot.queue[0].source, "https://example.com/third");
+        assert_eq!(snapshot.votes_needed, 2);
+
+        playlist.vote_skip(bob, 3);
+        playlist.vote_skip(bob, 3);
+        assert_eq!(playlist.snapshot(3).skip_votes, 1);
+        playlist.vote_skip(carol, 3);
+        let snapshot = playlist.snapshot(3);
+        assert_eq!(
+            snapshot.current.unwrap().source,
+            "https://example.com/third"
+        );
+        assert_eq!(snapshot.skip_votes, 0);
+
+        // ended on the host, a late report for the skipped first track changes nothing
+        assert!(!playlist.finish(1));
+        assert!(playlist.finish(third));
+        assert_eq!(playlist.snapshot(3).current.unwrap().source, "second.mp3");
+
+        // bob added it, no vote needed
+        playlist.vote_skip(bob, 3);
+        playlist.vote_skip(bob, 3);
+        assert_eq!(
+            playlist.snapshot(3),
+            Playlist {
+                votes_needed: 2,
+                ..Playlist::default()
+            }
+        );
+    }
+}
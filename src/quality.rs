@@ -0,0 +1,632 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bincode::{Decode, Encode};
+use opus::Bitrate;
+
+use crate::settings::{bitrate_bits, step_bitrate};
+
+// packets per loss report, 2s of 20ms frames
+const LOSS_WINDOW: u32 = 100;
+// loss in percent above which the bitrate goes down a step, and below which
+// it may go back up
+const HIGH_LOSS: u8 = 10;
+const LOW_LOSS: u8 = 2;
+// clean reports in a row before the bitrate goes back up, so it doesn't
+// oscillate on a link that is only sometimes bad
+const CLEAN_REPORTS: u32 = 3;
+// more than this and FEC takes up most of the bitrate
+const MAX_EXPECTED_LOSS: u8 = 40;
+// frames in percent that had nothing to play, above this the gaps are heard
+const HIGH_UNDERRUNS: u8 = 5;
+// upper bounds in percent or ms for the grades A to D, above the last is an F
+const LOSS_GRADES: [u32; 4] = [2, 4, 8, 15];
+const UNDERRUN_GRADES: [u32; 4] = [2, 4, 8, 15];
+const RTT_GRADES_MS: [u32; 4] = [100, 200, 300, 500];
+const JITTER_GRADES_MS: [u32; 4] = [10, 20, 40, 80];
+
+/// Audio bandwidth an opus packet was coded with, from narrowband (telephone)
+/// up to fullband.
+#[derive(Encode, Decode, PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum Bandwidth {
+    Narrow,
+    Medium,
+    Wide,
+    SuperWide,
+    Full,
+}
+
+impl Bandwidth {
+    /// Bars of a small signal style icon, one for narrowband up to four for
+    /// fullband.
+    pub fn bars(self) -> usize {
+        match self {
+            Bandwidth::Narrow => 1,
+            Bandwidth::Medium | Bandwidth::Wide => 2,
+            Bandwidth::SuperWide => 3,
+            Bandwidth::Full => 4,
+        }
+    }
+}
+
+/// What a remote stream sounds like, estimated from the packets it sends.
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy)]
+pub struct StreamQuality {
+    pub bandwidth: Bandwidth,
+    pub kbps: u32,
+}
+
+/// Bandwidth and duration of an opus packet, read from its TOC byte (RFC 6716
+/// section 3.1).
+pub fn parse_toc(packet: &[u8]) -> Option<(Bandwidth, Duration)> {
+    let toc = *packet.first()?;
+    let config = toc >> 3;
+    let (bandwidth, frame_us) = match config {
+        // SILK
+        0..=11 => {
+            let bandwidth = match config / 4 {
+                0 => Bandwidth::Narrow,
+                1 => Bandwidth::Medium,
+                _ => Bandwidth::Wide,
+            };
+            (
+                bandwidth,
+                [10_000, 20_000, 40_000, 60_000][config as usize % 4],
+            )
+        }
+        // hybrid
+        12..=15 => {
+            let bandwidth = if config < 14 {
+                Bandwidth::SuperWide
+            } else {
+                Bandwidth::Full
+            };
+            (bandwidth, [10_000, 20_000][config as usize % 2])
+        }
+        // CELT, which has no mediumband
+        _ => {
+            let bandwidth = match (config - 16) / 4 {
+                0 => Bandwidth::Narrow,
+                1 => Bandwidth::Wide,
+                2 => Bandwidth::SuperWide,
+                _ => Bandwidth::Full,
+            };
+            (
+                bandwidth,
+                [2_500, 5_000, 10_000, 20_000][config as usize % 4],
+            )
+        }
+    };
+    let frames = match toc & 0x3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (*packet.get(1)? & 0x3f) as u64,
+    };
+    Some((bandwidth, Duration::from_micros(frame_us * frames)))
+}
+
+/// Averages the bitrate of one stream over about a second of audio and
+/// reports the widest bandwidth seen in that time.
+#[derive(Debug, Default)]
+pub struct QualityEstimator {
+    bytes: usize,
+    audio: Duration,
+    bandwidth: Option<Bandwidth>,
+}
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+impl QualityEstimator {
+    /// Adds a packet, returns an estimate whenever a window is complete.
+    pub fn push(&mut self, packet: &[u8]) -> Option<StreamQuality> {
+        let (bandwidth, duration) = parse_toc(packet)?;
+        self.bytes += packet.len();
+        self.audio += duration;
+        self.bandwidth = self.bandwidth.max(Some(bandwidth));
+        if self.audio < WINDOW {
+            return None;
+        }
+        let kbps = (self.bytes as f64 * 8.0 / self.audio.as_secs_f64() / 1000.0).round() as u32;
+        let quality = StreamQuality {
+            bandwidth: self.bandwidth.take()?,
+            kbps,
+        };
+        *self = QualityEstimator::default();
+        Some(quality)
+    }
+}
+
+/// Round trip time to the server, smoothed like TCP does (RFC 6298) so a
+/// single slow reply doesn't make the display jump.
+#[derive(Debug, Default)]
+pub struct SmoothedRtt {
+    srtt: Option<Duration>,
+}
+
+impl SmoothedRtt {
+    pub fn update(&mut self, sample: Duration) -> Duration {
+        let srtt = match self.srtt {
+            Some(srtt) => srtt * 7 / 8 + sample / 8,
+            None => sample,
+        };
+        self.srtt = Some(srtt);
+        srtt
+    }
+}
+
+/// Packets of one sender the server got and missed, judged by the sequence
+/// numbers.
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy, Default)]
+pub struct LossStats {
+    pub received: u32,
+    pub lost: u32,
+}
+
+impl LossStats {
+    pub fn percent(self) -> u8 {
+        let total = (self.received + self.lost).max(1);
+        (self.lost * 100).div_ceil(total) as u8
+    }
+}
+
+/// Counts the gaps in a sender's sequence numbers and reports them every
+/// few seconds. Senders only number the packets they send, so a pause in
+/// speaking isn't loss.
+#[derive(Debug, Default)]
+pub struct LossCounter {
+    // first sequence number of the current window
+    first: Option<u32>,
+    highest: u32,
+    received: u32,
+}
+
+impl LossCounter {
+    pub fn push(&mut self, seq_number: u32) -> Option<LossStats> {
+        let first = *self.first.get_or_insert(seq_number);
+        if self.received == 0 || (seq_number.wrapping_sub(self.highest) as i32) > 0 {
+            self.highest = seq_number;
+        }
+        self.received += 1;
+        let expected = self.highest.wrapping_sub(first).wrapping_add(1);
+        if expected < LOSS_WINDOW {
+            return None;
+        }
+        // late duplicates can make up for more than was lost
+        let received = self.received.min(expected);
+        self.first = Some(self.highest.wrapping_add(1));
+        self.received = 0;
+        Some(LossStats {
+            received,
+            lost: expected - received,
+        })
+    }
+}
+
+/// How a client's call went since its last report, sent to the server every
+/// few seconds for its dashboard.
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy, Default)]
+pub struct HealthReport {
+    /// frames of the others' streams due to be played
+    pub frames: u32,
+    /// of those, the ones that were lost or came too late and were concealed
+    pub underruns: u32,
+    /// loss on our own stream, as the server reported it
+    pub loss: LossStats,
+    /// smoothed round trip time to the server, 0 before the first probe
+    pub rtt_ms: u32,
+}
+
+impl HealthReport {
+    pub fn underrun_percent(self) -> u8 {
+        (self.underruns * 100).div_ceil(self.frames.max(1)) as u8
+    }
+
+    /// Whether a lower bitrate would likely help, either side of the link
+    /// dropping packets.
+    pub fn struggling(self) -> bool {
+        self.loss.percent() >= HIGH_LOSS || self.underrun_percent() >= HIGH_UNDERRUNS
+    }
+}
+
+/// The reports of everyone in a room taken together, what the admin sees
+/// first.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RoomHealth {
+    pub reporting: usize,
+    pub struggling: usize,
+    /// of all frames due in the room
+    pub underrun_percent: u8,
+    /// on the worst sender's stream
+    pub worst_loss_percent: u8,
+    pub average_rtt_ms: u32,
+}
+
+impl RoomHealth {
+    pub fn new<'a>(reports: impl IntoIterator<Item = &'a HealthReport>) -> Option<RoomHealth> {
+        let mut total = HealthReport::default();
+        let mut health = RoomHealth {
+            reporting: 0,
+            struggling: 0,
+            underrun_percent: 0,
+            worst_loss_percent: 0,
+            average_rtt_ms: 0,
+        };
+        for report in reports {
+            health.reporting += 1;
+            health.struggling += report.struggling() as usize;
+            health.worst_loss_percent = health.worst_loss_percent.max(report.loss.percent());
+            total.frames += report.frames;
+            total.underruns += report.underruns;
+            total.rtt_ms += report.rtt_ms;
+        }
+        if health.reporting == 0 {
+            return None;
+        }
+        health.underrun_percent = total.underrun_percent();
+        health.average_rtt_ms = total.rtt_ms / health.reporting as u32;
+        Some(health)
+    }
+}
+
+/// A new encoder setup for the link.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Adaptation {
+    pub bitrate: i32,
+    /// packet loss in percent FEC is tuned for
+    pub expected_loss: u8,
+}
+
+/// Trades bitrate for robustness while the server reports loss on our
+/// stream, and goes back to the configured bitrate once the link recovers.
+#[derive(Debug)]
+pub struct BitrateAdapter {
+    ceiling: i32,
+    bitrate: i32,
+    base_loss: u8,
+    expected_loss: u8,
+    clean_reports: u32,
+}
+
+impl BitrateAdapter {
+    pub fn new(ceiling: Bitrate, expected_loss: u8) -> Self {
+        BitrateAdapter {
+            ceiling: bitrate_bits(ceiling),
+            bitrate: bitrate_bits(ceiling),
+            base_loss: expected_loss,
+            expected_loss,
+            clean_reports: 0,
+        }
+    }
+
+    /// The bitrate the user picked, adaptation never goes above it.
+    pub fn set_ceiling(&mut self, ceiling: i32) {
+        self.ceiling = ceiling;
+        self.bitrate = ceiling;
+    }
+
+    /// Takes a loss report, returns how the encoder should change, if at all.
+    pub fn update(&mut self, stats: LossStats) -> Option<Adaptation> {
+        let loss = stats.percent();
+        let before = (self.bitrate, self.expected_loss);
+        if loss >= HIGH_LOSS {
+            self.clean_reports = 0;
+            self.bitrate = step_bitrate(Bitrate::Bits(self.bitrate), false);
+        } else if loss < LOW_LOSS {
+            self.clean_reports += 1;
+            if self.bitrate < self.ceiling && self.clean_reports >= CLEAN_REPORTS {
+                self.clean_reports = 0;
+                self.bitrate = step_bitrate(Bitrate::Bits(self.bitrate), true).min(self.ceiling);
+            }
+        } else {
+            self.clean_reports = 0;
+        }
+        self.expected_loss = loss.clamp(self.base_loss, MAX_EXPECTED_LOSS);
+        if before == (self.bitrate, self.expected_loss) {
+            return None;
+        }
+        Some(Adaptation {
+            bitrate: self.bitrate,
+            expected_loss: self.expected_loss,
+        })
+    }
+}
+
+/// Interarrival jitter of one sender's stream, estimated like RTP does
+/// (RFC 3550 section 6.4.1) from the capture timestamps and arrival times.
+#[derive(Debug, Default)]
+pub struct ArrivalJitter {
+    last: Option<(u64, Instant)>,
+    jitter_ms: f32,
+}
+
+impl ArrivalJitter {
+    pub fn push(&mut self, sent_ms: u64, arrived: Instant) {
+        if let Some((last_sent, last_arrived)) = self.last {
+            // negative when the packet overtook the last one
+            let apart = arrived.saturating_duration_since(last_arrived).as_secs_f32()
+                - last_arrived.saturating_duration_since(arrived).as_secs_f32();
+            let apart = apart * 1000.0;
+            let difference = apart - (sent_ms as i64 - last_sent as i64) as f32;
+            self.jitter_ms += (difference.abs() - self.jitter_ms) / 16.0;
+        }
+        self.last = Some((sent_ms, arrived));
+    }
+
+    pub fn ms(&self) -> u32 {
+        self.jitter_ms.round() as u32
+    }
+}
+
+/// How one sender's stream played here since the last report.
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy, Default)]
+pub struct StreamHealth {
+    pub frames: u32,
+    /// of those, the ones that were lost or came too late and were concealed
+    pub underruns: u32,
+    pub jitter_ms: u32,
+}
+
+impl StreamHealth {
+    pub fn grade(self) -> Grade {
+        let underruns = (self.underruns * 100).div_ceil(self.frames.max(1));
+        Grade::new(&[
+            (underruns, UNDERRUN_GRADES),
+            (self.jitter_ms, JITTER_GRADES_MS),
+        ])
+    }
+}
+
+/// A school grade for how a call sounds, from A (nothing to notice) to F
+/// (hardly usable), so anyone can tell whether their own connection or
+/// someone else's is the problem.
+#[derive(Encode, Decode, PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum Grade {
+    A,
+    B,
+    C,
+    D,
+    F,
+}
+
+impl Grade {
+    /// The worst grade of the values, each against its bounds.
+    fn new(values: &[(u32, [u32; 4])]) -> Grade {
+        let grade = |(value, bounds): &(u32, [u32; 4])| {
+            match bounds.iter().position(|bound| value < bound) {
+                Some(0) => Grade::A,
+                Some(1) => Grade::B,
+                Some(2) => Grade::C,
+                Some(3) => Grade::D,
+                _ => Grade::F,
+            }
+        };
+        values.iter().map(grade).max().unwrap_or(Grade::A)
+    }
+
+    pub fn letter(self) -> char {
+        match self {
+            Grade::A => 'A',
+            Grade::B => 'B',
+            Grade::C => 'C',
+            Grade::D => 'D',
+            Grade::F => 'F',
+        }
+    }
+}
+
+/// Grades our connection and the stream of everyone we hear from the reports
+/// of the playback path and the server. What every stream suffers from is
+/// put down to our side, so our grade takes the best of theirs.
+#[derive(Debug, Default)]
+pub struct Watchdog {
+    streams: HashMap<SocketAddr, Grade>,
+    /// the best grade of the streams heard since our last grade
+    best: Option<Grade>,
+    own: Option<Grade>,
+}
+
+impl Watchdog {
+    /// Grades a sender's stream, returns the grade if it changed.
+    pub fn stream(&mut self, addr: SocketAddr, health: StreamHealth) -> Option<Grade> {
+        let grade = health.grade();
+        self.best = Some(self.best.map_or(grade, |best| best.min(grade)));
+        (self.streams.insert(addr, grade) != Some(grade)).then_some(grade)
+    }
+
+    /// Grades our connection with the loss on our stream and the round trip
+    /// time in `report`, returns the grade if it changed.
+    pub fn link(&mut self, report: HealthReport) -> Option<Grade> {
+        let link = Grade::new(&[
+            (report.loss.percent() as u32, LOSS_GRADES),
+            (report.rtt_ms, RTT_GRADES_MS),
+        ]);
+        // nobody talked, nothing to tell about our side
+        let downlink = self.best.take().unwrap_or(Grade::A);
+        let grade = link.max(downlink);
+        (self.own.replace(grade) != Some(grade)).then_some(grade)
+    }
+
+    pub fn remove(&mut self, addr: SocketAddr) {
+        self.streams.remove(&addr);
+    }
+
+    /// Forgets all grades, e.g. when the connection is lost.
+    pub fn clear(&mut self) {
+        *self = Watchdog::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_bandwidth_and_duration_from_toc() {
+        // SILK narrowband 20ms, one frame
+        assert_eq!(
+            parse_toc(&[1 << 3]),
+            Some((Bandwidth::Narrow, Duration::from_millis(20)))
+        );
+        // CELT fullband 20ms, two frames
+        assert_eq!(
+            parse_toc(&[31 << 3 | 1]),
+            Some((Bandwidth::Full, Duration::from_millis(40)))
+        );
+        // hybrid superwideband 10ms, frame count in the second byte
+        assert_eq!(
+            parse_toc(&[12 << 3 | 3, 3]),
+            Some((Bandwidth::SuperWide, Duration::from_millis(30)))
+        );
+        assert_eq!(parse_toc(&[]), None);
+    }
+
+    #[test]
+    fn estimates_bitrate_over_a_second() {
+        let mut estimator = QualityEstimator::default();
+        // 80 bytes every 20ms is 32kbps
+        let packet = [vec![31 << 3], vec![0; 79]].concat();
+        for _ in 0..49 {
+            assert_eq!(estimator.push(&packet), None);
+        }
+        assert_eq!(
+            estimator.push(&packet),
+            Some(StreamQuality {
+                bandwidth: Bandwidth::Full,
+                kbps: 32
+            })
+        );
+    }
+
+    #[test]
+    fn counts_gaps_in_sequence_numbers() {
+        let mut counter = LossCounter::default();
+        // every tenth packet lost, except one that arrives late
+        let mut seqs: Vec<u32> = (1000..1100).filter(|seq| seq % 10 != 5).collect();
+        let after = seqs.iter().position(|seq| *seq == 1020).unwrap();
+        seqs.insert(after + 1, 1015);
+        let reports: Vec<_> = seqs.into_iter().filter_map(|seq| counter.push(seq)).collect();
+        assert_eq!(reports, vec![LossStats { received: 91, lost: 9 }]);
+        assert_eq!(reports[0].percent(), 9);
+        let report = (1100..1200).find_map(|seq| counter.push(seq)).unwrap();
+        assert_eq!(report, LossStats { received: 100, lost: 0 });
+    }
+
+    #[test]
+    fn lowers_bitrate_on_loss_and_recovers_slowly() {
+        let mut adapter = BitrateAdapter::new(Bitrate::Bits(64_000), 10);
+        let loss = |lost| LossStats {
+            received: 100 - lost,
+            lost,
+        };
+        assert_eq!(
+            adapter.update(loss(20)),
+            Some(Adaptation {
+                bitrate: 48_000,
+                expected_loss: 20
+            })
+        );
+        // FEC follows the loss back down to what was configured, the bitrate
+        // waits for a few clean reports
+        assert_eq!(
+            adapter.update(loss(5)),
+            Some(Adaptation {
+                bitrate: 48_000,
+                expected_loss: 10
+            })
+        );
+        assert_eq!(adapter.update(loss(0)), None);
+        assert_eq!(adapter.update(loss(0)), None);
+        assert_eq!(adapter.update(loss(0)).unwrap().bitrate, 64_000);
+        // never above what the user picked
+        for _ in 0..5 {
+            assert_eq!(adapter.update(loss(0)), None);
+        }
+    }
+
+    #[test]
+    fn sums_up_room_health() {
+        let fine = HealthReport {
+            frames: 250,
+            underruns: 1,
+            loss: LossStats { received: 100, lost: 0 },
+            rtt_ms: 30,
+        };
+        // hears gaps in what the others say
+        let choppy = HealthReport {
+            underruns: 25,
+            rtt_ms: 90,
+            ..fine
+        };
+        assert!(!fine.struggling() && choppy.struggling());
+        assert_eq!(
+            RoomHealth::new(&[fine, choppy]),
+            Some(RoomHealth {
+                reporting: 2,
+                struggling: 1,
+                underrun_percent: 6,
+                worst_loss_percent: 0,
+                average_rtt_ms: 60,
+            })
+        );
+        assert_eq!(RoomHealth::new(&[]), None);
+    }
+
+    #[test]
+    fn smooths_round_trip_time() {
+        let mut rtt = SmoothedRtt::default();
+        assert_eq!(rtt.update(Duration::from_millis(40)), Duration::from_millis(40));
+        assert_eq!(rtt.update(Duration::from_millis(120)), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn estimates_jitter_from_arrival_times() {
+        let start = Instant::now();
+        let mut steady = ArrivalJitter::default();
+        let mut bursty = ArrivalJitter::default();
+        for i in 0..100u64 {
+            steady.push(i * 20, start + Duration::from_millis(i * 20));
+            // every other packet 30ms late, a pause in between isn't jitter
+            let late = if i % 2 == 0 { 0 } else { 30 };
+            let sent = i * 20 + if i >= 50 { 5000 } else { 0 };
+            bursty.push(sent, start + Duration::from_millis(sent + late));
+        }
+        assert_eq!(steady.ms(), 0);
+        assert_eq!(bursty.ms(), 30);
+    }
+
+    #[test]
+    fn blames_what_every_stream_suffers_on_our_side() {
+        let alice: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let bob: SocketAddr = "10.0.0.2:1234".parse().unwrap();
+        let clean = StreamHealth {
+            frames: 250,
+            underruns: 0,
+            jitter_ms: 4,
+        };
+        let choppy = StreamHealth {
+            underruns: 15,
+            ..clean
+        };
+        let fine = HealthReport {
+            frames: 500,
+            underruns: 30,
+            loss: LossStats { received: 100, lost: 0 },
+            rtt_ms: 30,
+        };
+        let mut watchdog = Watchdog::default();
+        assert_eq!(watchdog.stream(alice, clean), Some(Grade::A));
+        assert_eq!(watchdog.stream(bob, choppy), Some(Grade::C));
+        assert_eq!(watchdog.stream(bob, choppy), None);
+        // only bob is choppy, so it's on his side
+        assert_eq!(watchdog.link(fine), Some(Grade::A));
+        watchdog.stream(alice, choppy);
+        assert_eq!(watchdog.link(fine), Some(Grade::C));
+        // a slow link is ours whatever the streams do
+        let slow = HealthReport { rtt_ms: 600, ..fine };
+        assert_eq!(watchdog.link(slow), Some(Grade::F));
+        assert_eq!(Grade::F.letter(), 'F');
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    time::{Duration, Instant},
+};
+
+/// Seconds in a row a source may go over the limit before it is banned.
+const STRIKES: u32 = 3;
+/// How long an abusive source stays banned.
+pub const TEMP_BAN: Duration = Duration::from_secs(600);
+const WINDOW: Duration = Duration::from_secs(1);
+// sources not heard from for this long are forgotten, a flood from spoofed
+// addresses would fill the table otherwise
+const FORGET_AFTER: Duration = Duration::from_secs(10);
+// sources tracked at most, a new one pushes out the one heard from least
+// recently so spoofing can't grow it until the next sweep
+const MAX_SOURCES: usize = 16 * 1024;
+
+/// What becomes of a packet, see `RateLimiter::check`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Verdict {
+    Pass,
+    /// over the limit for this second
+    Drop,
+    /// the source is banned for abuse, true for the packet that got it banned
+    Banned(bool),
+}
+
+struct Source {
+    window_start: Instant,
+    packets: u32,
+    bytes: u64,
+    // seconds in a row it went over the limit
+    strikes: u32,
+    banned_until: Option<Instant>,
+}
+
+/// Packets and bytes per second of every source address, checked before a
+/// packet is even decoded. A source that stays over the limit for `STRIKES`
+/// seconds is banned for `TEMP_BAN`. IPv6 sources are counted per /64, the
+/// block a single host usually gets.
+pub struct RateLimiter {
+    max_packets: u32,
+    max_bytes: u64,
+    max_sources: usize,
+    sources: HashMap<IpAddr, Source>,
+    last_sweep: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(max_packets: u32, max_bytes: u64) -> Self {
+        RateLimiter {
+            max_packets,
+            max_bytes,
+            max_sources: MAX_SOURCES,
+            sources: HashMap::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+
+    /// Counts a packet of `len` bytes from `ip` received at `now`.
+    pub fn check(&mut self, ip: IpAddr, len: usize, now: Instant) -> Verdict {
+        self.sweep(now);
+        let key = source_key(ip);
+        if self.sources.len() >= self.max_sources && !self.sources.contains_key(&key) {
+            // bans are kept, they'd be lifted by flooding from spoofed addresses
+            let stalest = self
+                .sources
+                .iter()
+                .filter(|(_, source)| source.banned_until.is_none_or(|until| until <= now))
+                .min_by_key(|(_, source)| source.window_start)
+                .map(|(key, _)| *key);
+            match stalest {
+                Some(stalest) => {
+                    self.sources.remove(&stalest);
+                }
+                None => return Verdict::Drop,
+            }
+        }
+        let source = self.sources.entry(key).or_insert(Source {
+            window_start: now,
+            packets: 0,
+            bytes: 0,
+            strikes: 0,
+            banned_until: None,
+        });
+        if let Some(until) = source.banned_until {
+            if now < until {
+                return Verdict::Banned(false);
+            }
+            source.banned_until = None;
+            source.strikes = 0;
+        }
+        if now.duration_since(source.window_start) >= WINDOW {
+            let over = source.packets > self.max_packets || source.bytes > self.max_bytes;
+            source.strikes = if over { source.strikes + 1 } else { 0 };
+            source.window_start = now;
+            source.packets = 0;
+            source.bytes = 0;
+            if source.strikes >= STRIKES {
+                source.banned_until = Some(now + TEMP_BAN);
+                return Verdict::Banned(true);
+            }
+        }
+        source.packets += 1;
+        source.bytes += len as u64;
+        if source.packets > self.max_packets || source.bytes > self.max_bytes {
+            Verdict::Drop
+        } else {
+            Verdict::Pass
+        }
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        if now.duration_since(self.last_sweep) < FORGET_AFTER {
+            return;
+        }
+        self.last_sweep = now;
+        self.sources.retain(|_, source| {
+            source.banned_until.is_some_and(|until| until > now)
+                || now.duration_since(source.window_start) < FORGET_AFTER
+        });
+    }
+}
+
+/// What `ip` is counted as, its /64 for IPv6.
+fn source_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from_bits(v6.to_bits() & (u128::MAX << 64))),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_floods_and_bans_sources_that_keep_them_up() {
+        let mut limiter = RateLimiter::new(10, 1000);
+        let flood: IpAddr = "192.0.2.1".parse().unwrap();
+        let client: IpAddr = "192.0.2.2".parse().unwrap();
+        let start = Instant::now();
+        let mut banned_at = None;
+        for second in 0..5u32 {
+            for packet in 0..20u32 {
+                let now = start + WINDOW * second + Duration::from_millis(packet as u64 * 10);
+                let verdict = limiter.check(flood, 100, now);
+                match verdict {
+                    Verdict::Banned(true) => banned_at = Some(second),
+                    Verdict::Banned(false) => assert!(banned_at.is_some()),
+                    Verdict::Pass => assert!(packet < 10, "{} passed", packet),
+                    Verdict::Drop => assert!(packet >= 10),
+                }
+                // a large packet now and then is fine
+                let len = if packet == 0 { 900 } else { 10 };
+                if packet < 5 {
+                    assert_eq!(limiter.check(client, len, now), Verdict::Pass);
+                }
+            }
+        }
+        assert_eq!(banned_at, Some(STRIKES));
+        let later = start + WINDOW * 5 + TEMP_BAN;
+        assert_eq!(limiter.check(flood, 100, later), Verdict::Pass);
+    }
+
+    #[test]
+    fn counts_ipv6_per_block_and_forgets_the_stalest_when_full() {
+        let mut limiter = RateLimiter::new(2, 1000);
+        limiter.max_sources = 2;
+        let now = Instant::now();
+        // hosts of one /64 share its budget
+        for host in ["2001:db8:0:1::1", "2001:db8:0:1::2", "2001:db8:0:1:ffff::9"] {
+            let verdict = limiter.check(host.parse().unwrap(), 10, now);
+            let expected = if host.ends_with("::9") {
+                Verdict::Drop
+            } else {
+                Verdict::Pass
+            };
+            assert_eq!(verdict, expected, "{}", host);
+        }
+        let client: IpAddr = "192.0.2.2".parse().unwrap();
+        assert_eq!(limiter.check(client, 10, now), Verdict::Pass);
+        // full, a new source takes the place of the one heard from least
+        // recently, the /64 whose window started first
+        let later = now + WINDOW;
+        assert_eq!(limiter.check(client, 10, later), Verdict::Pass);
+        let newcomer: IpAddr = "192.0.2.3".parse().unwrap();
+        assert_eq!(limiter.check(newcomer, 10, later), Verdict::Pass);
+        assert_eq!(limiter.sources.len(), 2);
+        assert!(!limiter.sources.contains_key(&source_key("2001:db8:0:1::1".parse().unwrap())));
+        assert_eq!(limiter.check(client, 10, later), Verdict::Pass);
+    }
+
+    #[test]
+    fn keeps_bans_when_full() {
+        let mut limiter = RateLimiter::new(1, 1000);
+        limiter.max_sources = 1;
+        let flood: IpAddr = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+        for second in 0..=STRIKES {
+            for _ in 0..3 {
+                limiter.check(flood, 10, start + WINDOW * second);
+            }
+        }
+        assert_eq!(limiter.check(flood, 10, start), Verdict::Banned(false));
+        let spoofed: IpAddr = "192.0.2.3".parse().unwrap();
+        assert_eq!(limiter.check(spoofed, 10, start), Verdict::Drop);
+        assert_eq!(limiter.check(flood, 10, start), Verdict::Banned(false));
+    }
+}
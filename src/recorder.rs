@@ -0,0 +1,319 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime},
+};
+
+use log::{error, info};
+
+use crate::{CHANNELS, Error, SAMPLE_RATE, admin::json_string, audio::samples};
+
+const BITS: u16 = 16;
+const HEADER_LEN: u32 = 44;
+
+/// 16 bit PCM at the call's rate, the sizes in the header are filled in when
+/// it is dropped.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    frames: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        let file = File::create(path)
+            .map_err(|e| Error::Io(format!("Can't create {}: {}", path.display(), e)))?;
+        let mut writer = WavWriter {
+            file: BufWriter::new(file),
+            frames: 0,
+        };
+        writer
+            .write_header()
+            .map_err(|e| Error::Io(e.to_string()))?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let block_align = CHANNELS as u16 * BITS / 8;
+        let data_len = self.frames * block_align as u32;
+        let header = [
+            &b"RIFF"[..],
+            &(HEADER_LEN - 8 + data_len).to_le_bytes(),
+            b"WAVEfmt ",
+            &16u32.to_le_bytes(),
+            // PCM
+            &1u16.to_le_bytes(),
+            &(CHANNELS as u16).to_le_bytes(),
+            &SAMPLE_RATE.to_le_bytes(),
+            &(SAMPLE_RATE * block_align as u32).to_le_bytes(),
+            &block_align.to_le_bytes(),
+            &BITS.to_le_bytes(),
+            b"data",
+            &data_len.to_le_bytes(),
+        ]
+        .concat();
+        self.file.write_all(&header)
+    }
+
+    pub fn write(&mut self, pcm: &[f32]) -> std::io::Result<()> {
+        for sample in pcm {
+            self.file
+                .write_all(&samples::to_i16(*sample).to_le_bytes())?;
+        }
+        self.frames += (pcm.len() / CHANNELS) as u32;
+        Ok(())
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            error!("Error finishing recording: {:?}", e);
+        }
+    }
+}
+
+/// Where and since when a call is recorded. Cloned into every thread that
+/// records, so all their files share a prefix and a time line.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    dir: PathBuf,
+    prefix: String,
+    start: Instant,
+    // the same moment on the wall clock, for marks taken on other threads
+    start_ms: u64,
+}
+
+impl Recording {
+    pub fn new(dir: PathBuf) -> Self {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        Recording {
+            dir,
+            prefix: format!("kop-audio-{}", now.as_secs()),
+            start: Instant::now(),
+            start_ms: now.as_millis() as u64,
+        }
+    }
+
+    /// `<dir>/<prefix>-<name>.<extension>`
+    pub fn path(&self, name: &str, extension: &str) -> PathBuf {
+        let name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        self.dir
+            .join(format!("{}-{}.{}", self.prefix, name, extension))
+    }
+
+    /// Frames since the recording started.
+    pub fn position(&self) -> u32 {
+        (self.start.elapsed().as_secs_f64() * SAMPLE_RATE as f64) as u32
+    }
+}
+
+/// One lossless file per speaker, taken before encoding or after decoding
+/// rather than from the mix. Silence is filled in, so all files of a call
+/// line up when laid next to each other in an editor.
+pub struct Recorder {
+    recording: Recording,
+    tracks: HashMap<String, WavWriter>,
+    // named positions in frames, e.g. something to edit out later
+    markers: Vec<(String, u32)>,
+}
+
+impl Recorder {
+    pub fn new(recording: Recording) -> Self {
+        Recorder {
+            recording,
+            tracks: HashMap::new(),
+            markers: Vec::new(),
+        }
+    }
+
+    /// Adds a marker at `at_ms`, milliseconds since the epoch, and rewrites
+    /// the markers file next to the recording.
+    pub fn mark(&mut self, name: String, at_ms: u64) -> Result<PathBuf, Error> {
+        let ms = at_ms.saturating_sub(self.recording.start_ms);
+        self.markers
+            .push((name, (ms * SAMPLE_RATE as u64 / 1000) as u32));
+        let path = self.recording.path("markers", "json");
+        let json = markers_json(&self.recording.prefix, &self.markers);
+        std::fs::write(&path, json)
+            .map_err(|e| Error::Io(format!("Can't write {}: {}", path.display(), e)))?;
+        Ok(path)
+    }
+
+    /// Adds `pcm`, which ends about now, to the track of `name`.
+    pub fn write(&mut self, name: &str, pcm: &[f32]) {
+        let position = self.recording.position();
+        let writer = match self.tracks.get_mut(name) {
+            Some(writer) => writer,
+            None => {
+                let path = self.recording.path(name, "wav");
+                match WavWriter::create(&path) {
+                    Ok(writer) => {
+                        info!("Recording {} to {}", name, path.display());
+                        self.tracks.entry(name.to_string()).or_insert(writer)
+                    }
+                    Err(e) => {
+                        error!("{:?}", e);
+                        return;
+                    }
+                }
+            }
+        };
+        let frames = (pcm.len() / CHANNELS) as u32;
+        // scheduling jitter of up to a frame isn't a pause
+        let starts_at = position.saturating_sub(frames);
+        if starts_at > writer.frames() + frames {
+            let silence = vec![0.0; (starts_at - writer.frames()) as usize * CHANNELS];
+            if let Err(e) = writer.write(&silence) {
+                error!("Error recording {}: {:?}", name, e);
+            }
+        }
+        if let Err(e) = writer.write(pcm) {
+            error!("Error recording {}: {:?}", name, e);
+        }
+    }
+}
+
+fn markers_json(prefix: &str, markers: &[(String, u32)]) -> String {
+    let markers: Vec<String> = markers
+        .iter()
+        .map(|(name, frame)| {
+            format!(
+                "{{\"name\":{},\"frame\":{},\"seconds\":{:.3}}}",
+                json_string(name),
+                frame,
+                *frame as f64 / SAMPLE_RATE as f64
+            )
+        })
+        .collect();
+    format!(
+        "{{\"recording\":{},\"sample_rate\":{},\"markers\":[{}]}}\n",
+        json_string(prefix),
+        SAMPLE_RATE,
+        markers.join(",")
+    )
+}
+
+/// Reads back the markers written next to a recording.
+pub fn read_markers(path: &Path) -> Result<Vec<(String, u32)>, Error> {
+    let json = std::fs::read_to_string(path)
+        .map_err(|e| Error::Io(format!("Can't read {}: {}", path.display(), e)))?;
+    parse_markers(&json)
+        .ok_or_else(|| Error::Invalid(format!("{} is not a markers file", path.display())))
+}
+
+// only has to understand what `markers_json` writes
+fn parse_markers(json: &str) -> Option<Vec<(String, u32)>> {
+    let mut markers = Vec::new();
+    let mut rest = json.split_once("\"markers\":[")?.1;
+    while let Some((_, marker)) = rest.split_once("{\"name\":") {
+        let (name, marker) = parse_json_string(marker)?;
+        let marker = marker.strip_prefix(",\"frame\":")?;
+        let digits = marker.find(|c: char| !c.is_ascii_digit())?;
+        markers.push((name, marker[..digits].parse().ok()?));
+        rest = &marker[digits..];
+    }
+    Some(markers)
+}
+
+/// The string `s` starts with and what follows it.
+fn parse_json_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut out = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((out, &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                    out.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                c => out.push(c),
+            },
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::read_wav;
+
+    #[test]
+    fn writes_a_readable_wav() {
+        let dir = std::env::temp_dir().join(format!("kop-audio-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tone.wav");
+        let tone: Vec<f32> = (0..480 * CHANNELS)
+            .map(|i| (i % 100) as f32 / 200.0)
+            .collect();
+        {
+            let mut writer = WavWriter::create(&path).unwrap();
+            writer.write(&tone).unwrap();
+            writer.write(&tone).unwrap();
+        }
+        let wav = read_wav(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!((wav.rate, wav.channels), (SAMPLE_RATE, CHANNELS));
+        assert_eq!(wav.samples.len(), 2 * tone.len());
+        assert!(crate::testutil::max_difference(&wav.samples[..tone.len()], &tone) < 1e-4);
+    }
+
+    #[test]
+    fn names_files_safely() {
+        let recording = Recording {
+            dir: PathBuf::from("/tmp"),
+            prefix: "call".into(),
+            start: Instant::now(),
+            start_ms: 0,
+        };
+        assert_eq!(
+            recording.path("127.0.0.1:4000", "wav"),
+            PathBuf::from("/tmp/call-127_0_0_1_4000.wav")
+        );
+    }
+
+    #[test]
+    fn lists_markers_as_json() {
+        let markers = [
+            ("cough".to_string(), 48_000),
+            ("edit \"this\"".to_string(), 72_000),
+        ];
+        let mut with_control = markers.to_vec();
+        with_control.push(("tab\there".to_string(), 96_000));
+        assert_eq!(
+            parse_markers(&markers_json("call", &with_control)).unwrap(),
+            with_control
+        );
+        assert_eq!(
+            markers_json("call", &markers),
+            "{\"recording\":\"call\",\"sample_rate\":48000,\"markers\":[\
+             {\"name\":\"cough\",\"frame\":48000,\"seconds\":1.000},\
+             {\"name\":\"edit \\\"this\\\"\",\"frame\":72000,\"seconds\":1.500}]}\n"
+        );
+    }
+}
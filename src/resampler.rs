@@ -0,0 +1,101 @@
+use rubato::{FftFixedInOut, Resampler};
+
+use crate::{CHANNELS, Error};
+
+/// Resamples a continuous stream of interleaved samples between the
+/// device rate and the rate opus runs at. Input that doesn't fill a whole
+/// resampler chunk is kept until the next call.
+pub struct StreamResampler {
+    resampler: FftFixedInOut<f32>,
+    pending: Vec<Vec<f32>>,
+}
+
+impl StreamResampler {
+    /// `chunk_size` is the preferred number of input samples per channel per call
+    pub fn new(from_rate: u32, to_rate: u32, chunk_size: usize) -> Result<Self, Error> {
+        let resampler =
+            FftFixedInOut::<f32>::new(from_rate as usize, to_rate as usize, chunk_size, CHANNELS)
+                .map_err(|e| Error::Audio(e.to_string()))?;
+        Ok(StreamResampler {
+            resampler,
+            pending: vec![Vec::new(); CHANNELS],
+        })
+    }
+
+    /// number of input samples per channel consumed by one resampler pass
+    pub fn input_frames(&self) -> usize {
+        self.resampler.input_frames_next()
+    }
+
+    /// number of output samples per channel produced by one resampler pass
+    pub fn output_frames(&self) -> usize {
+        self.resampler.output_frames_next()
+    }
+
+    /// Appends the resampled version of `input` to `output`.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        for frame in input.chunks_exact(CHANNELS) {
+            for (channel, sample) in frame.iter().enumerate() {
+                self.pending[channel].push(*sample);
+            }
+        }
+        while self.pending[0].len() >= self.input_frames() {
+            let needed = self.input_frames();
+            let chunk: Vec<Vec<f32>> = self
+                .pending
+                .iter_mut()
+                .map(|channel| channel.drain(..needed).collect())
+                .collect();
+            let resampled = match self.resampler.process(&chunk, None) {
+                Ok(resampled) => resampled,
+                Err(_) => return,
+            };
+            for i in 0..resampled[0].len() {
+                for channel in &resampled {
+                    output.push(channel[i]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{max_difference, read_fixture};
+
+    #[test]
+    fn resamples_tone_to_48k() {
+        let input = read_fixture("sine_440_44k1.wav");
+        let golden = read_fixture("sine_440_48k.wav");
+        assert_eq!(input.rate, 44100);
+        assert_eq!(golden.channels, CHANNELS);
+
+        let mut resampler = StreamResampler::new(44100, 48000, 882).unwrap();
+        let mut output = Vec::new();
+        // feed odd sized chunks to exercise the pending buffer
+        for chunk in input.samples.chunks(1000 * CHANNELS) {
+            resampler.process(chunk, &mut output);
+        }
+        let delay = resampler.resampler.output_delay() * CHANNELS;
+        let resampled = &output[delay..];
+
+        // skip the edges where the filter hasn't settled yet
+        let start = 960 * CHANNELS;
+        let end = resampled.len().min(golden.samples.len()) - 960 * CHANNELS;
+        assert!(end > start);
+        let diff = max_difference(&resampled[start..end], &golden.samples[start..end]);
+        assert!(diff < 0.01, "max difference {}", diff);
+    }
+
+    #[test]
+    fn keeps_partial_chunks_for_later() {
+        let mut resampler = StreamResampler::new(44100, 48000, 882).unwrap();
+        let mut output = Vec::new();
+        let needed = resampler.input_frames() * CHANNELS;
+        resampler.process(&vec![0.0; needed - CHANNELS], &mut output);
+        assert!(output.is_empty());
+        resampler.process(&[0.0; CHANNELS], &mut output);
+        assert_eq!(output.len(), resampler.output_frames() * CHANNELS);
+    }
+}
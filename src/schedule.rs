@@ -0,0 +1,127 @@
+use std::fs;
+
+use crate::Error;
+
+/// An event that takes place every day at the same local time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledEvent {
+    /// minutes since local midnight
+    pub start: u32,
+    pub title: String,
+}
+
+/// Daily events read from the server's schedule file, one `HH:MM title` per
+/// line. Announces each event `remind_minutes` before it starts and when it
+/// starts.
+#[derive(Debug, Default)]
+pub struct Schedule {
+    events: Vec<ScheduledEvent>,
+    remind_minutes: u32,
+    // last minute announcements were sent for, so each one goes out only once
+    last_checked: Option<u32>,
+}
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+impl Schedule {
+    pub fn load(path: &str, remind_minutes: u32) -> Result<Schedule, Error> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| Error::Io(format!("Can't read {}: {}", path, e)))?;
+        Schedule::parse(&text, remind_minutes)
+    }
+
+    fn parse(text: &str, remind_minutes: u32) -> Result<Schedule, Error> {
+        let mut events = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                Error::Invalid(format!(
+                    "Invalid schedule line {}: {}",
+                    i + 1,
+                    line
+                ))
+            };
+            let (time, title) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let (hours, minutes) = time.split_once(':').ok_or_else(invalid)?;
+            let hours: u32 = hours.parse().map_err(|_| invalid())?;
+            let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+            if hours >= 24 || minutes >= 60 {
+                return Err(invalid());
+            }
+            events.push(ScheduledEvent {
+                start: hours * 60 + minutes,
+                title: title.trim().to_string(),
+            });
+        }
+        Ok(Schedule {
+            events,
+            remind_minutes,
+            last_checked: None,
+        })
+    }
+
+    /// Announcements due at `now`, minutes since local midnight. Minutes
+    /// skipped since the last call are caught up on.
+    pub fn due(&mut self, now: u32) -> Vec<String> {
+        let Some(last) = self.last_checked.replace(now) else {
+            return Vec::new();
+        };
+        let mut announcements = Vec::new();
+        let mut minute = last;
+        while minute != now {
+            minute = (minute + 1) % MINUTES_PER_DAY;
+            for event in &self.events {
+                if self.remind_minutes > 0
+                    && (event.start + MINUTES_PER_DAY - self.remind_minutes) % MINUTES_PER_DAY
+                        == minute
+                {
+                    announcements.push(format!(
+                        "{} starts in {} minutes",
+                        event.title, self.remind_minutes
+                    ));
+                }
+                if event.start == minute {
+                    announcements.push(format!("{} starts now", event.title));
+                }
+            }
+        }
+        announcements
+    }
+}
+
+/// Minutes since midnight in the server's local time zone.
+pub fn local_minute_of_day() -> u32 {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        libc::localtime_r(&now, &mut tm);
+    }
+    tm.tm_hour as u32 * 60 + tm.tm_min as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announces_reminder_and_start_once() {
+        let mut schedule = Schedule::parse("# weekly raid\n20:00 Raid night\n", 10).unwrap();
+        assert!(schedule.due(19 * 60 + 45).is_empty());
+        assert_eq!(
+            schedule.due(19 * 60 + 50),
+            vec!["Raid night starts in 10 minutes"]
+        );
+        assert!(schedule.due(19 * 60 + 50).is_empty());
+        // a late tick still catches the start
+        assert_eq!(schedule.due(20 * 60 + 2), vec!["Raid night starts now"]);
+    }
+
+    #[test]
+    fn rejects_invalid_times() {
+        assert!(Schedule::parse("25:00 Too late\n", 10).is_err());
+        assert!(Schedule::parse("Stand-up\n", 10).is_err());
+    }
+}
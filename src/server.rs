@@ -1,38 +1,619 @@
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use crate::BUF_SIZE;
+use crate::{BUF_SIZE, CHANNELS, Error, SAMPLE_RATE};
+use crate::admin::{AdminCommand, ClientStatus, ServerStatus};
+use crate::banlist::BanList;
+use crate::codec::{CODEC2_MODES, CODEC2_RATE, CodecKind};
+use crate::crypto::{
+    SENDER_TAG_LEN, SecureSocket, join_token, verify_join_token, verify_sender_tag,
+};
+use crate::floor::Floor;
+use crate::header::{AudioDelta, HeaderExpander};
+use crate::listen_along::MusicVote;
+use crate::identity::{Identity, IdentityKey, UserId};
+use crate::mailbox::{MAX_CLIP_PACKETS, Mailbox, StoredClip};
+use crate::playlist::{Playlist, PlaylistCommand, PlaylistQueue, TrackInfo};
+use crate::quality::{HealthReport, LossCounter, LossStats};
+use crate::ratelimit::{RateLimiter, TEMP_BAN, Verdict};
+use crate::schedule::{Schedule, local_minute_of_day};
+use crate::settings::ServerSettings;
 use bincode::{Decode, Encode, config};
 use log::{debug, error, info, warn};
-use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
 
-#[derive(Encode, Decode, PartialEq, Debug)]
+const MAX_NAME_CHARS: usize = 32;
+// a few lines in the TUI, and well within a datagram
+const MAX_CHAT_CHARS: usize = 500;
+// how long a listen-along host may stay quiet before someone else can take over
+const MUSIC_HOST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+// how often the audio path tells the control task that a client is still
+// sending, the AFK and inactivity checks don't need it any finer
+const REPORT: std::time::Duration = std::time::Duration::from_secs(1);
+// scheduled announcements, expiring voice messages and dropping clients
+const HOUSEKEEPING: std::time::Duration = std::time::Duration::from_secs(10);
+// how long a join challenge can be answered, and how many may be open at once
+const CHALLENGE_TTL: std::time::Duration = std::time::Duration::from_secs(10);
+const MAX_CHALLENGES: usize = 256;
+// a client that keeps struggling is reminded at most this often
+const SUGGESTION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+/// Largest UDP payload over IPv4, what every receive buffer is sized for so
+/// nothing is cut off. Larger datagrams are fragmented on the way, only text
+/// that grows with the room may come close.
+pub const MAX_MESSAGE: usize = 65_507;
+// chat, rosters, playlists and other text, leaves room for sealing it
+const MAX_TEXT_MESSAGE: usize = 32 * 1024;
+
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
 pub struct AudioData {
     pub timestamp: u64,
     pub seq_number: u32,
     pub data: Vec<u8>,
 }
 
-#[derive(Encode, Decode, PartialEq, Debug)]
+/// Told apart the streams of successive clients, even ones that reuse an
+/// address, assigned by the server when a client shows up.
+pub type SessionId = u32;
+
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct Hello {
+    pub identity: Identity,
+    /// whether this endpoint plays audio, endpoints of the same identity that
+    /// aren't sinks (e.g. a phone used as remote control) only get control messages
+    pub audio_sink: bool,
+    /// proves the server password, sent in answer to `Message::JoinChallenge`,
+    /// see `crypto::join_token`
+    pub token: Option<[u8; 32]>,
+    /// proves the identity, sent in answer to `Message::JoinChallenge`, see
+    /// `IdentityKey::prove`
+    pub proof: Option<[u8; 64]>,
+    /// shown to the others instead of the address
+    pub name: String,
+    /// the most this client sends its voice as, see `RoomCodec::negotiate`
+    pub profile: RoomCodec,
+}
+
+impl Hello {
+    /// The hello answering a server's `Message::JoinChallenge`, signed with
+    /// `key`, the key of our identity. An error if the server wants a password
+    /// and we have none.
+    pub fn answer(
+        &self,
+        key: &IdentityKey,
+        password: Option<&str>,
+        challenge: &JoinChallenge,
+    ) -> Result<Hello, Error> {
+        let token = match challenge.password {
+            true => {
+                let password =
+                    password.ok_or(Error::Rejected(JoinRejection::PasswordRequired))?;
+                Some(join_token(password, &self.identity, &challenge.nonce))
+            }
+            false => None,
+        };
+        Ok(Hello {
+            token,
+            proof: Some(key.prove(&challenge.nonce)),
+            ..self.clone()
+        })
+    }
+}
+
+/// What the server asks a joining client to sign, see `Hello::answer`.
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy)]
+pub struct JoinChallenge {
+    pub nonce: [u8; 32],
+    /// whether the server has a password the answer has to prove as well
+    pub password: bool,
+}
+
+/// What a server runs, sent to a client right after acknowledging its hello so
+/// a client and server of different versions can be told apart.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct ServerInfo {
+    pub version: String,
+    /// optional behaviour the server was started with, see `ServerSettings::features`
+    pub features: Vec<String>,
+}
+
+/// How everyone in the room encodes their voice, declared when the server
+/// starts and sent right after the hello ack, e.g. mono at 24kbps for a radio
+/// room next to a stereo music room on another port. Each client gets it
+/// narrowed down to its profile, see `negotiate`.
+#[derive(Encode, Decode, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct RoomCodec {
+    pub kind: CodecKind,
+    /// rate voices are encoded at, 8, 12, 16, 24 or 48kHz, lower cuts the highs
+    pub sample_rate: u32,
+    pub channels: u8,
+    /// bits per second voices are held to, `None` leaves it to the clients.
+    /// The mode for Codec2
+    pub max_bitrate: Option<u32>,
+}
+
+impl Default for RoomCodec {
+    fn default() -> Self {
+        RoomCodec {
+            kind: CodecKind::Opus,
+            sample_rate: SAMPLE_RATE,
+            channels: CHANNELS as u8,
+            max_bitrate: None,
+        }
+    }
+}
+
+impl RoomCodec {
+    /// Parses `--room-codec`: mono or stereo, then optionally the bitrate in
+    /// kbps and the sample rate in kHz, e.g. `mono,24` or `stereo,128,48`.
+    /// Prefixed with `pcm` voices go out uncompressed and there is no bitrate,
+    /// e.g. `pcm,mono,16`. `codec2` is always mono at 8kHz, optionally
+    /// followed by the mode in bits per second, e.g. `codec2,1200`.
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut parts = spec.split(',').map(str::trim).peekable();
+        let kind = match parts.next_if(|part| matches!(*part, "pcm" | "opus" | "codec2")) {
+            Some("pcm") => CodecKind::Pcm,
+            Some("codec2") => CodecKind::Codec2,
+            _ => CodecKind::Opus,
+        };
+        if kind == CodecKind::Codec2 {
+            let bits = match parts.next() {
+                Some(bits) => bits.parse().ok().filter(|bits| CODEC2_MODES.contains(bits))?,
+                None => CODEC2_MODES[0],
+            };
+            if parts.next().is_some() {
+                return None;
+            }
+            return Some(RoomCodec {
+                kind,
+                sample_rate: CODEC2_RATE,
+                channels: 1,
+                max_bitrate: Some(bits),
+            });
+        }
+        let channels = match parts.next()? {
+            "mono" => 1,
+            "stereo" => 2,
+            _ => return None,
+        };
+        // what opus can do
+        let max_bitrate = match parts.next_if(|_| kind == CodecKind::Opus) {
+            Some(kbps) => match kbps.parse::<u32>().ok()? {
+                kbps @ 6..=510 => Some(kbps * 1000),
+                _ => return None,
+            },
+            None => None,
+        };
+        let sample_rate = match parts.next() {
+            Some(khz) => match khz.parse::<u32>().ok()? {
+                khz @ (8 | 12 | 16 | 24 | 48) => khz * 1000,
+                _ => return None,
+            },
+            None => SAMPLE_RATE,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(RoomCodec {
+            kind,
+            sample_rate,
+            channels,
+            max_bitrate,
+        })
+    }
+
+    /// What a client with `profile` encodes its voice as in this room: the
+    /// fewer channels, the lower rate and bitrate of the two. PCM and Codec2
+    /// rooms stay as they are, everyone decodes the others by the room's
+    /// format there.
+    pub fn negotiate(&self, profile: &RoomCodec) -> RoomCodec {
+        if self.kind != CodecKind::Opus {
+            return *self;
+        }
+        let max_bitrate = match (self.max_bitrate, profile.max_bitrate) {
+            (Some(room), Some(client)) => Some(room.min(client)),
+            (room, client) => room.or(client),
+        };
+        RoomCodec {
+            kind: self.kind,
+            sample_rate: self.sample_rate.min(profile.sample_rate),
+            channels: self.channels.min(profile.channels),
+            max_bitrate,
+        }
+    }
+
+    /// e.g. "mono, 16kHz, up to 24kbps", "PCM, stereo, 48kHz" or
+    /// "Codec2, mono, 8kHz, 1200bit/s"
+    pub fn describe(&self) -> String {
+        let channels = if self.channels == 1 { "mono" } else { "stereo" };
+        let mut description = format!("{}, {}kHz", channels, self.sample_rate / 1000);
+        match self.kind {
+            CodecKind::Opus => {}
+            CodecKind::Pcm => description.insert_str(0, "PCM, "),
+            CodecKind::Codec2 => description.insert_str(0, "Codec2, "),
+        }
+        match (self.kind, self.max_bitrate) {
+            (CodecKind::Codec2, Some(bits)) => description.push_str(&format!(", {}bit/s", bits)),
+            (_, Some(bits)) => description.push_str(&format!(", up to {}kbps", bits / 1000)),
+            (_, None) => {}
+        }
+        description
+    }
+}
+
+/// Which of a client's streams a codec reset is for.
+#[derive(Encode, Decode, PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum CodecStream {
+    Voice,
+    /// listen-along music
+    Music,
+}
+
+/// A chat message as the server passes it on, with who sent it.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct ChatMessage {
+    pub from: SocketAddr,
+    pub name: String,
+    pub text: String,
+}
+
+/// What the clients show about the room they are in. Set through the admin UI
+/// and sent to every client whenever it changes.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct RoomInfo {
+    pub name: String,
+    pub topic: String,
+    /// free form key/value pairs, e.g. the agenda or the game lobby code
+    pub metadata: Vec<(String, String)>,
+    /// whether the server tells everyone to play a cue when someone joins or leaves
+    pub cues: bool,
+}
+
+impl Default for RoomInfo {
+    fn default() -> Self {
+        RoomInfo {
+            name: "Lobby".to_string(),
+            topic: String::new(),
+            metadata: Vec::new(),
+            cues: false,
+        }
+    }
+}
+
+/// Sounds played on every client when someone joins or leaves the room.
+#[derive(Encode, Decode, PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum Cue {
+    Join,
+    Leave,
+}
+
+/// Why the server turned away a join request.
+#[derive(Encode, Decode, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum JoinRejection {
+    /// `--max-clients` are connected
+    Full,
+    WrongPassword,
+    /// an admin banned the address it joins from
+    Banned,
+    /// the server challenged us for a password and we have none
+    PasswordRequired,
+    /// the answer to the join challenge wasn't signed with the identity's key
+    IdentityNotProven,
+}
+
+impl JoinRejection {
+    pub fn describe(self) -> &'static str {
+        match self {
+            JoinRejection::Full => "the server is full",
+            JoinRejection::WrongPassword => "wrong server password",
+            JoinRejection::Banned => "banned from this server",
+            JoinRejection::PasswordRequired => "the server needs a password, see --password",
+            JoinRejection::IdentityNotProven => "couldn't prove our identity",
+        }
+    }
+}
+
+/// One opus packet of a room's custom chime for a cue.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct ChimeChunk {
+    pub cue: Cue,
+    pub index: u16,
+    pub last: bool,
+    pub packet: Vec<u8>,
+}
+
+/// One packet of a voice message for a user that isn't connected.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct VoiceChunk {
+    /// the recipient when sent to the server, the sender when delivered
+    pub peer: UserId,
+    /// picked by the sender to tell its clips apart
+    pub clip: u32,
+    pub index: u16,
+    pub last: bool,
+    pub packet: Vec<u8>,
+}
+
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
 pub enum Message {
     Audio(AudioData), // decoded audio packet
-    AudioFrom(std::net::SocketAddr, AudioData),
+    /// audio with a header relative to the sender's last full one
+    AudioDelta(AudioDelta),
+    AudioFrom(std::net::SocketAddr, SessionId, AudioData),
+    /// listen-along music, timestamped with when it was captured
+    Music(AudioData),
+    /// listen-along music of the host, timestamped with when everyone plays it
+    MusicFrom(std::net::SocketAddr, AudioData),
+    /// a listener's vote, passed on to the host that tallies it
+    MusicVote(MusicVote),
+    MusicVoteFrom(std::net::SocketAddr, MusicVote),
+    /// a vote passed, sent by the host and passed on to everyone
+    MusicVoteResult(MusicVote),
     Ping,
-    Hello(std::net::SocketAddr), // maybe UTF-8
-    NewClient(std::net::SocketAddr),
-    DeleteClient(std::net::SocketAddr),
+    /// asks for a `LatencyReply` with the same client timestamp, unlike
+    /// `Ping` it doesn't count as activity
+    LatencyProbe(u64),
+    LatencyReply(u64),
+    Hello(Hello), // join request, acknowledged by echoing it back
+    /// a client and its display name
+    NewClient(std::net::SocketAddr, String),
+    DeleteClient(std::net::SocketAddr, String),
     Bye,
+    RoomInfo(RoomInfo),
+    /// system message shown to everyone, e.g. a scheduled event reminder
+    Announcement(String),
+    /// tells a client it was moved to or back from the AFK room
+    MovedToAfk(bool),
+    /// tells the others that a client went to or came back from the AFK room
+    ClientAfk(std::net::SocketAddr, bool),
+    VoiceChunk(VoiceChunk),
+    /// users that have been here before but aren't connected now
+    OfflineUsers(Vec<UserId>),
+    /// Noise handshake message, see `crypto`
+    Handshake(Vec<u8>),
+    /// another message encrypted with the keys of the handshake, and its nonce
+    Sealed(u64, Vec<u8>),
+    /// the server turned away an unencrypted message
+    EncryptionRequired,
+    /// the server turned away a join request, and why, from servers that
+    /// don't send `JoinRejected` yet
+    Rejected(String),
+    /// play the cue for someone joining or leaving
+    Cue(Cue),
+    /// custom chime of the room, an empty last chunk resets to the built-in one
+    Chime(ChimeChunk),
+    /// a line of text chat, passed on to everyone in the room as `ChatFrom`
+    Chat(String),
+    ChatFrom(ChatMessage),
+    PlaylistCommand(PlaylistCommand),
+    /// the room's playlist, sent to everyone whenever it changes
+    Playlist(Playlist),
+    /// what the listen-along host plays, the server passes it on to everyone
+    NowPlaying(Option<TrackInfo>),
+    /// loss on a client's voice stream as the server sees it, sent back to
+    /// the client every few seconds while it talks
+    Stats(LossStats),
+    /// follows the hello ack, added last so older clients still decode
+    /// everything before it
+    ServerInfo(ServerInfo),
+    /// how a client's call is going, sent every few seconds for the dashboard
+    ClientStats(HealthReport),
+    /// the server saw a client's stats and thinks a lower bitrate would help
+    SuggestLowerBitrate,
+    /// how the room encodes voice, follows the server info
+    RoomCodec(RoomCodec),
+    /// a client reset its encoder for a stream, passed on to the others as
+    /// `ResetCodecFrom` so they reset their decoder of it too
+    ResetCodec(CodecStream),
+    ResetCodecFrom(std::net::SocketAddr, CodecStream),
+    /// asks the client at the address to reset its encoder, e.g. after a
+    /// listener lost a long stretch of the stream, passed on to it with the
+    /// address of whoever asked
+    RequestCodecReset(std::net::SocketAddr, CodecStream),
+    /// follows the hello ack to a client that doesn't encrypt, the key it
+    /// signs its voice and music with
+    SenderKey([u8; 32]),
+    /// an encoded `Audio`, `AudioDelta` or `Music` of a client that doesn't
+    /// encrypt and its tag, see `crypto::sender_tag`
+    Signed([u8; SENDER_TAG_LEN], Vec<u8>),
+    /// a client that sent nothing else for a while is still there, keeps
+    /// the NAT bindings on the way open
+    Keepalive,
+    /// the server turned away a join request
+    JoinRejected(JoinRejection),
+    /// an admin removed the client, it shouldn't come straight back
+    Kicked,
+    /// an admin muted or unmuted the client, the server drops its voice
+    /// while it is muted
+    AdminMuted(bool),
+    /// the server asks a joining client to answer with a hello proving its
+    /// identity, and the password if there is one, for this nonce
+    JoinChallenge(JoinChallenge),
     Unknown(Vec<u8>),
 }
 
+impl Message {
+    /// Largest this kind of message may be encoded, in bytes.
+    pub fn max_size(&self) -> usize {
+        match self {
+            Message::Hello(_)
+            | Message::NewClient(..)
+            | Message::DeleteClient(..)
+            | Message::RoomInfo(_)
+            | Message::Announcement(_)
+            | Message::OfflineUsers(_)
+            | Message::Rejected(_)
+            | Message::Chat(_)
+            | Message::ChatFrom(_)
+            | Message::PlaylistCommand(_)
+            | Message::Playlist(_)
+            | Message::NowPlaying(_)
+            | Message::ServerInfo(_)
+            | Message::ClientStats(_) => MAX_TEXT_MESSAGE,
+            // any of the others, encrypted or signed
+            Message::Sealed(..) | Message::Signed(..) | Message::Unknown(_) => MAX_MESSAGE,
+            _ => BUF_SIZE as usize,
+        }
+    }
+}
+
 struct ClientInfo {
     addr: std::net::SocketAddr,
+    session: SessionId,
     last_active: std::time::Instant,
+    // unknown until the client said hello
+    identity: Option<Identity>,
+    // what the others know the identity as
+    user: Option<UserId>,
+    // the address until the client said hello
+    name: String,
+    audio_sink: bool,
+    // last time the user talked or used a control, unlike `last_active` this
+    // ignores background traffic
+    last_activity: std::time::Instant,
+    // idle users sit in the AFK room, they neither hear nor are heard
+    afk: bool,
+    // the last stats the client sent, and when it was last told to lower its bitrate
+    health: Option<HealthReport>,
+    suggested_lower_bitrate: Option<std::time::Instant>,
+    // what the voice and music of a client that doesn't encrypt is signed with
+    sender_key: Option<[u8; 32]>,
+    // an admin muted it, nobody hears its voice
+    muted: bool,
 }
 
-pub async fn server_loop(socket: UdpSocket) {
-    let mut buf = [0u8; BUF_SIZE as usize];
-    let mut clients: Vec<ClientInfo> = Vec::new();
-    let mut check_counter = 0;
+impl ClientInfo {
+    fn route(&self) -> Route {
+        Route {
+            addr: self.addr,
+            session: self.session,
+            identity: self.identity,
+            audio_sink: self.audio_sink,
+            afk: self.afk,
+            sender_key: self.sender_key,
+            muted: self.muted,
+        }
+    }
+}
+
+/// A joined client as the audio path sees it.
+#[derive(Debug, Clone, PartialEq)]
+struct Route {
+    addr: SocketAddr,
+    session: SessionId,
+    identity: Option<Identity>,
+    audio_sink: bool,
+    afk: bool,
+    sender_key: Option<[u8; 32]>,
+    muted: bool,
+}
+
+/// What the audio path forwards by, published by the control task whenever
+/// it changes.
+#[derive(Debug, Clone, PartialEq)]
+struct Routing {
+    routes: Vec<Route>,
+    max_speakers: Option<usize>,
+}
+
+/// What the audio path hands to the control task.
+enum Inbound {
+    /// anything but audio, or anything from a client that hasn't joined
+    Message(SocketAddr, Message),
+    /// a client sent voice or music, at most once per `REPORT`
+    Active(SocketAddr),
+    /// a client streams the listen-along music, at most once per `REPORT`
+    MusicHost(SocketAddr),
+    /// a client is still there, without counting as activity for the AFK room
+    Seen(SocketAddr),
+    /// the address went over the rate limit for too long and is banned for
+    /// a while, its clients are to go
+    Abusive(IpAddr),
+}
+
+/// Packet counts for the admin status, kept by the audio path.
+#[derive(Default)]
+struct Traffic {
+    received: AtomicU64,
+    forwarded: AtomicU64,
+    // voice and music not signed by the client at its address
+    spoofed: AtomicU64,
+    // anything over the rate limit of its source or from a source banned for it
+    limited: AtomicU64,
+}
+
+fn publish_routing(
+    routing: &watch::Sender<Routing>,
+    clients: &[ClientInfo],
+    max_speakers: Option<usize>,
+) {
+    let next = Routing {
+        routes: clients.iter().map(ClientInfo::route).collect(),
+        max_speakers,
+    };
+    routing.send_if_modified(|current| {
+        if *current == next {
+            return false;
+        }
+        *current = next;
+        true
+    });
+}
+
+/// Runs the server on `socket`. Voice and music are forwarded as they come
+/// in, see `forward_audio`, everything else is handled by a task of its own,
+/// see `control_loop`, so a join or a chat message never holds up a packet.
+pub async fn server_loop(
+    socket: SecureSocket,
+    admin: mpsc::Receiver<AdminCommand>,
+    schedule: Schedule,
+    bans: BanList,
+    settings: ServerSettings,
+) {
+    // shared with the control task and the ones delivering voice messages
+    let socket = Arc::new(socket);
+    let traffic = Arc::new(Traffic::default());
+    let (routing_tx, routing_rx) = watch::channel(Routing {
+        routes: Vec::new(),
+        max_speakers: settings.max_speakers,
+    });
+    let (inbound_tx, inbound_rx) = mpsc::unbounded_channel();
+    let delay = settings.listen_along_delay;
+    let limiter = RateLimiter::new(settings.max_packet_rate, settings.max_byte_rate);
+    tokio::spawn(control_loop(
+        socket.clone(),
+        inbound_rx,
+        routing_tx,
+        admin,
+        schedule,
+        bans,
+        settings,
+        traffic.clone(),
+    ));
+    forward_audio(socket, routing_rx, inbound_tx, delay, limiter, traffic).await;
+}
+
+/// The audio path. Forwards voice and music of joined clients straight away
+/// and hands everything else to the control task. Who is joined comes from
+/// the control task's routing, the per-packet state lives here.
+async fn forward_audio(
+    socket: Arc<SecureSocket>,
+    mut routing: watch::Receiver<Routing>,
+    control: mpsc::UnboundedSender<Inbound>,
+    listen_along_delay: std::time::Duration,
+    mut limiter: RateLimiter,
+    traffic: Arc<Traffic>,
+) {
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    let mut current = routing.borrow_and_update().clone();
+    let mut floor = Floor::new(current.max_speakers);
+    let mut headers: HashMap<SocketAddr, HeaderExpander> = HashMap::new();
+    let mut loss: HashMap<SocketAddr, LossCounter> = HashMap::new();
+    // when the control task last heard that a client is active
+    let mut reported: HashMap<SocketAddr, std::time::Instant> = HashMap::new();
+    let mut music_host: Option<(SocketAddr, std::time::Instant)> = None;
+    let mut music_reported: Option<std::time::Instant> = None;
     loop {
         let (len, addr) = match socket.recv_from(&mut buf).await {
             Ok(res) => res,
@@ -41,6 +622,414 @@ pub async fn server_loop(socket: UdpSocket) {
                 continue;
             }
         };
+        traffic.received.fetch_add(1, Ordering::Relaxed);
+        let now = std::time::Instant::now();
+        match limiter.check(addr.ip(), len, now) {
+            Verdict::Pass => {}
+            Verdict::Drop | Verdict::Banned(false) => {
+                traffic.limited.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            Verdict::Banned(true) => {
+                warn!(
+                    "Banning {} for {}s, it keeps sending more than allowed",
+                    addr.ip(),
+                    TEMP_BAN.as_secs()
+                );
+                traffic.limited.fetch_add(1, Ordering::Relaxed);
+                let _ = control.send(Inbound::Abusive(addr.ip()));
+                continue;
+            }
+        }
+        let msg = match decode_checked(&buf[..len]) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Dropping message from {}: {:?}", addr, e);
+                continue;
+            }
+        };
+        if routing.has_changed().unwrap_or(false) {
+            current = routing.borrow_and_update().clone();
+            if current.max_speakers != floor.limit() {
+                floor.set_limit(current.max_speakers);
+            }
+            let joined = |addr: &SocketAddr| current.routes.iter().any(|r| r.addr == *addr);
+            headers.retain(|addr, _| joined(addr));
+            loss.retain(|addr, _| joined(addr));
+            reported.retain(|addr, _| joined(addr));
+        }
+        let sender = current.routes.iter().find(|route| route.addr == addr);
+        if let Message::LatencyProbe(sent) = msg {
+            let reply = encode_message(&Message::LatencyReply(sent));
+            if let Err(e) = socket.send_to(&reply, addr).await {
+                error!("Error answering latency probe from {}: {:?}", addr, e);
+            }
+            // a few seconds apart, keeps a listener that doesn't talk joined.
+            // Anyone else gets an answer too, e.g. the doctor
+            if sender.is_some() {
+                let _ = control.send(Inbound::Seen(addr));
+            }
+            continue;
+        }
+        let Some(sender) = sender else {
+            // the control task decides who joins
+            if control.send(Inbound::Message(addr, msg)).is_err() {
+                return;
+            }
+            continue;
+        };
+        let Some(msg) = verify_sender(sender, msg) else {
+            debug!("Dropping audio from {} not signed by the client there", addr);
+            traffic.spoofed.fetch_add(1, Ordering::Relaxed);
+            continue;
+        };
+        let audio = match msg {
+            Message::AudioDelta(delta) => {
+                match headers
+                    .get_mut(&addr)
+                    .and_then(|headers| headers.expand(delta))
+                {
+                    Some(audio) => audio,
+                    // before the first full header
+                    None => continue,
+                }
+            }
+            Message::Audio(audio) => {
+                headers.entry(addr).or_default().full(&audio);
+                audio
+            }
+            Message::Music(music) => {
+                // muted by an admin, it can't play to the room or take it over
+                if sender.muted {
+                    report_activity(&mut reported, addr, now, &control);
+                    continue;
+                }
+                // one host at a time, it keeps the stream until it stops sending
+                if let Some((host, last)) = music_host
+                    && host != addr
+                    && now.duration_since(last) < MUSIC_HOST_TIMEOUT
+                {
+                    continue;
+                }
+                let new_host = music_host.is_none_or(|(host, _)| host != addr);
+                if new_host {
+                    info!("{} started a listen-along", addr);
+                }
+                music_host = Some((addr, now));
+                if new_host || music_reported.is_none_or(|at| now.duration_since(at) >= REPORT) {
+                    music_reported = Some(now);
+                    let _ = control.send(Inbound::MusicHost(addr));
+                }
+                // everyone plays a frame at the same time, far enough out for
+                // it to reach the slowest listener first
+                let music = AudioData {
+                    timestamp: music.timestamp + listen_along_delay.as_millis() as u64,
+                    ..music
+                };
+                let buf = encode_message(&Message::MusicFrom(addr, music));
+                for route in &current.routes {
+                    if route.addr != addr && route.audio_sink && !route.afk {
+                        match socket.send_to(&buf, route.addr).await {
+                            Ok(_) => {
+                                traffic.forwarded.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(e) => error!("Error forwarding music to {}: {:?}", route.addr, e),
+                        }
+                    }
+                }
+                report_activity(&mut reported, addr, now, &control);
+                continue;
+            }
+            Message::Keepalive => {
+                let _ = control.send(Inbound::Seen(addr));
+                continue;
+            }
+            msg => {
+                if control.send(Inbound::Message(addr, msg)).is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+        report_activity(&mut reported, addr, now, &control);
+        if sender.muted {
+            continue;
+        }
+        if let Some(stats) = loss.entry(addr).or_default().push(audio.seq_number)
+            && let Err(e) = socket
+                .send_to(&encode_message(&Message::Stats(stats)), addr)
+                .await
+        {
+            error!("Error sending loss stats to {}: {:?}", addr, e);
+        }
+        if !floor.admit(addr, audio.data.len(), now) {
+            continue;
+        }
+        // the opus packet goes out as it came in, however many people
+        // talk, the server never decodes, mixes or re-encodes audio
+        let buf = encode_message(&Message::AudioFrom(addr, sender.session, audio));
+        for route in &current.routes {
+            // don't echo audio back to other devices of the same user
+            let same_user = sender.identity.is_some() && route.identity == sender.identity;
+            if route.addr != addr && !same_user && route.audio_sink && !route.afk {
+                match socket.send_to(&buf, route.addr).await {
+                    Ok(_) => {
+                        traffic.forwarded.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => error!("Error forwarding audio to {}: {:?}", route.addr, e),
+                }
+            }
+        }
+    }
+}
+
+/// Voice and music of a client that doesn't encrypt if its tag proves the
+/// client sent it, anyone can send from a faked address. Encryption proves
+/// that already, and anything but audio passes as it is.
+fn verify_sender(sender: &Route, msg: Message) -> Option<Message> {
+    let Some(key) = &sender.sender_key else {
+        return Some(msg);
+    };
+    match msg {
+        Message::Signed(tag, signed) if verify_sender_tag(key, &signed, &tag) => {
+            match decode_checked(&signed) {
+                Ok(msg @ (Message::Audio(_) | Message::AudioDelta(_) | Message::Music(_))) => {
+                    Some(msg)
+                }
+                _ => None,
+            }
+        }
+        Message::Signed(..) | Message::Audio(_) | Message::AudioDelta(_) | Message::Music(_) => {
+            None
+        }
+        msg => Some(msg),
+    }
+}
+
+/// Tells the control task that `addr` sends audio, at most once per `REPORT`.
+fn report_activity(
+    reported: &mut HashMap<SocketAddr, std::time::Instant>,
+    addr: SocketAddr,
+    now: std::time::Instant,
+    control: &mpsc::UnboundedSender<Inbound>,
+) {
+    if reported
+        .get(&addr)
+        .is_none_or(|at| now.duration_since(*at) >= REPORT)
+    {
+        reported.insert(addr, now);
+        let _ = control.send(Inbound::Active(addr));
+    }
+}
+
+/// Joins and leaves, the roster, chat, the playlist, voice messages and the
+/// admin commands. Publishes who audio goes to whenever that changes.
+#[allow(clippy::too_many_arguments)]
+async fn control_loop(
+    socket: Arc<SecureSocket>,
+    mut inbound: mpsc::UnboundedReceiver<Inbound>,
+    routing: watch::Sender<Routing>,
+    mut admin: mpsc::Receiver<AdminCommand>,
+    mut schedule: Schedule,
+    mut bans: BanList,
+    settings: ServerSettings,
+    traffic: Arc<Traffic>,
+) {
+    // everyone who said hello since the server started
+    let mut known: Vec<UserId> = Vec::new();
+    // makes the user ids of this server its own, see `Identity::user_id`
+    let user_salt: [u8; 32] = rand::random();
+    let mut mailbox = Mailbox::default();
+    let mut clients: Vec<ClientInfo> = Vec::new();
+    let mut next_session: SessionId = 0;
+    let started = std::time::Instant::now();
+    let mut room = RoomInfo::default();
+    let mut max_speakers = settings.max_speakers;
+    let mut chimes: HashMap<Cue, Vec<Vec<u8>>> = HashMap::new();
+    // set by admins, muted identities stay muted when they rejoin
+    let mut muted: Vec<Identity> = Vec::new();
+    // nonces joining clients were challenged with, and when
+    let mut challenges: HashMap<SocketAddr, ([u8; 32], std::time::Instant)> = HashMap::new();
+    // client whose music everyone listens along to, and when the audio path
+    // last reported it sending some
+    let mut music_host: Option<(SocketAddr, std::time::Instant)> = None;
+    let mut playlist = PlaylistQueue::default();
+    let mut now_playing: Option<TrackInfo> = None;
+    // often enough that a client that is gone doesn't linger much past its timeout
+    let mut housekeeping = tokio::time::interval(HOUSEKEEPING.min(settings.client_timeout / 3));
+    let server_info = ServerInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        features: settings.features(),
+    };
+    info!(
+        "kop-audio {} server, features: {}",
+        server_info.version,
+        server_info.features.join(", ")
+    );
+    loop {
+        publish_routing(&routing, &clients, max_speakers);
+        let (addr, msg) = tokio::select! {
+            inbound = inbound.recv() => match inbound {
+                Some(Inbound::Message(addr, msg)) => (addr, msg),
+                Some(Inbound::Active(addr)) => {
+                    let now = std::time::Instant::now();
+                    let Some(client) = clients.iter_mut().find(|client| client.addr == addr) else {
+                        continue;
+                    };
+                    client.last_active = now;
+                    client.last_activity = now;
+                    if client.afk {
+                        info!("Moving {} back from the AFK room", addr);
+                        set_afk(&mut clients, addr, false, &socket).await;
+                    }
+                    continue;
+                }
+                Some(Inbound::MusicHost(addr)) => {
+                    music_host = Some((addr, std::time::Instant::now()));
+                    continue;
+                }
+                Some(Inbound::Seen(addr)) => {
+                    if let Some(client) = clients.iter_mut().find(|client| client.addr == addr) {
+                        client.last_active = std::time::Instant::now();
+                    }
+                    continue;
+                }
+                Some(Inbound::Abusive(ip)) => {
+                    kick_address(&mut clients, ip, &socket, &known, room.cues).await;
+                    continue;
+                }
+                // the audio path is gone
+                None => return,
+            },
+            Some(cmd) = admin.recv() => {
+                match cmd {
+                    AdminCommand::Status(reply) => {
+                        let now = std::time::Instant::now();
+                        let _ = reply.send(ServerStatus {
+                            uptime: started.elapsed(),
+                            packets_received: traffic.received.load(Ordering::Relaxed),
+                            packets_forwarded: traffic.forwarded.load(Ordering::Relaxed),
+                            packets_spoofed: traffic.spoofed.load(Ordering::Relaxed),
+                            packets_limited: traffic.limited.load(Ordering::Relaxed),
+                            room: room.clone(),
+                            max_speakers,
+                            clients: clients
+                                .iter()
+                                .map(|client| ClientStatus {
+                                    addr: client.addr,
+                                    session: client.session,
+                                    identity: client.identity,
+                                    name: client.name.clone(),
+                                    audio_sink: client.audio_sink,
+                                    idle: now.duration_since(client.last_active),
+                                    afk: client.afk,
+                                    muted: client.muted,
+                                    health: client.health,
+                                })
+                                .collect(),
+                            banned: bans.addresses().to_vec(),
+                        });
+                    }
+                    AdminCommand::Kick(addr) => {
+                        kick(&mut clients, addr, &socket, &known, room.cues).await
+                    }
+                    AdminCommand::Ban(ip) => {
+                        if let Err(e) = bans.ban(ip) {
+                            error!("Can't save the ban of {}: {}", ip, e);
+                        }
+                        kick_address(&mut clients, ip, &socket, &known, room.cues).await;
+                    }
+                    AdminCommand::Unban(ip) => {
+                        if let Err(e) = bans.unban(ip) {
+                            error!("Can't save the unban of {}: {}", ip, e);
+                        }
+                    }
+                    AdminCommand::Mute(addr, mute) => {
+                        let Some(client) = clients.iter_mut().find(|client| client.addr == addr)
+                        else {
+                            continue;
+                        };
+                        client.muted = mute;
+                        if let Some(identity) = client.identity {
+                            muted.retain(|muted| *muted != identity);
+                            if mute {
+                                muted.push(identity);
+                            }
+                        }
+                        if let Err(e) = socket
+                            .send_to(&encode_message(&Message::AdminMuted(mute)), addr)
+                            .await
+                        {
+                            error!("Error sending admin mute to {}: {:?}", addr, e);
+                        }
+                    }
+                    AdminCommand::Announce(text) => {
+                        broadcast(&clients, &Message::Announcement(text), &socket).await;
+                    }
+                    AdminCommand::SetTopic(topic) => {
+                        room.topic = topic;
+                        broadcast(&clients, &Message::RoomInfo(room.clone()), &socket).await;
+                    }
+                    AdminCommand::SetMetadata(key, value) => {
+                        room.metadata.retain(|(k, _)| *k != key);
+                        if !value.is_empty() {
+                            room.metadata.push((key, value));
+                        }
+                        broadcast(&clients, &Message::RoomInfo(room.clone()), &socket).await;
+                    }
+                    AdminCommand::SetMaxSpeakers(limit) => max_speakers = limit,
+                    AdminCommand::SetCues(cues) => {
+                        room.cues = cues;
+                        broadcast(&clients, &Message::RoomInfo(room.clone()), &socket).await;
+                    }
+                    AdminCommand::SetChime(cue, packets) => {
+                        if packets.is_empty() {
+                            chimes.remove(&cue);
+                        } else {
+                            chimes.insert(cue, packets.clone());
+                        }
+                        let addrs = clients.iter().map(|client| client.addr).collect();
+                        tokio::spawn(deliver_chimes(
+                            socket.clone(),
+                            addrs,
+                            HashMap::from([(cue, packets)]),
+                        ));
+                    }
+                }
+                continue;
+            }
+            _ = housekeeping.tick() => {
+                mailbox.expire(settings.voice_message_ttl);
+                challenges.retain(|_, (_, sent)| sent.elapsed() < CHALLENGE_TTL);
+                for announcement in schedule.due(local_minute_of_day()) {
+                    info!("Announcing: {}", announcement);
+                    broadcast(&clients, &Message::Announcement(announcement), &socket).await;
+                }
+                if let Some(timeout) = settings.afk_timeout {
+                    let idle: Vec<SocketAddr> = clients
+                        .iter()
+                        .filter(|client| !client.afk && client.last_activity.elapsed() >= timeout)
+                        .map(|client| client.addr)
+                        .collect();
+                    for addr in idle {
+                        info!("Moving idle client {} to the AFK room", addr);
+                        set_afk(&mut clients, addr, true, &socket).await;
+                    }
+                }
+                let inactive: Vec<SocketAddr> = clients
+                    .iter()
+                    .filter(|client| client.last_active.elapsed() >= settings.client_timeout)
+                    .map(|client| client.addr)
+                    .collect();
+                for addr in &inactive {
+                    remove_client(&mut clients, addr, &socket, &known, room.cues).await;
+                }
+                if !inactive.is_empty() {
+                    debug!("Removed {} inactive clients", inactive.len());
+                }
+                continue;
+            }
+        };
         let mut is_new_client = true;
         for client in &mut clients {
             if client.addr == addr {
@@ -48,73 +1037,222 @@ pub async fn server_loop(socket: UdpSocket) {
                 is_new_client = false;
             }
         }
+        if is_new_client && bans.contains(addr.ip()) {
+            if matches!(msg, Message::Hello(_)) {
+                reject(&socket, addr, JoinRejection::Banned).await;
+            }
+            continue;
+        }
+        if is_new_client && settings.max_clients.is_some_and(|max| clients.len() >= max) {
+            if matches!(msg, Message::Hello(_)) {
+                reject(&socket, addr, JoinRejection::Full).await;
+            }
+            continue;
+        }
+        // only a join request that proves its identity, and the password if
+        // there is one, gets a client in, and each proof only once
+        if is_new_client {
+            let challenge = challenges
+                .remove(&addr)
+                .filter(|(_, sent)| sent.elapsed() < CHALLENGE_TTL);
+            match (&msg, challenge) {
+                (
+                    Message::Hello(Hello {
+                        identity,
+                        proof: Some(proof),
+                        token,
+                        ..
+                    }),
+                    Some((nonce, _)),
+                ) => {
+                    if !identity.verify(&nonce, proof) {
+                        reject(&socket, addr, JoinRejection::IdentityNotProven).await;
+                        continue;
+                    }
+                    if let Some(password) = &settings.password
+                        && !verify_join_token(password, identity, &nonce, token.as_ref())
+                    {
+                        reject(&socket, addr, JoinRejection::WrongPassword).await;
+                        continue;
+                    }
+                }
+                (Message::Hello(_), challenge) => {
+                    // the same nonce for every copy of the hello, whichever
+                    // the client answers
+                    let nonce = match challenge {
+                        Some((nonce, sent)) => {
+                            challenges.insert(addr, (nonce, sent));
+                            nonce
+                        }
+                        None if challenges.len() >= MAX_CHALLENGES => {
+                            debug!("Too many joins at once, ignoring {}", addr);
+                            continue;
+                        }
+                        None => {
+                            let nonce = rand::random();
+                            challenges.insert(addr, (nonce, std::time::Instant::now()));
+                            nonce
+                        }
+                    };
+                    let buf = encode_message(&Message::JoinChallenge(JoinChallenge {
+                        nonce,
+                        password: settings.password.is_some(),
+                    }));
+                    if let Err(e) = socket.send_to(&buf, addr).await {
+                        error!("Error sending a join challenge to {}: {:?}", addr, e);
+                    }
+                    continue;
+                }
+                _ => {
+                    debug!("Ignoring message from {} that hasn't joined", addr);
+                    continue;
+                }
+            }
+        }
         if is_new_client {
             info!("New client connected: {}", addr);
+            next_session = next_session.wrapping_add(1);
             clients.push(ClientInfo {
                 addr,
+                session: next_session,
                 last_active: std::time::Instant::now(),
+                identity: None,
+                user: None,
+                name: addr.to_string(),
+                audio_sink: true,
+                last_activity: std::time::Instant::now(),
+                afk: false,
+                health: None,
+                suggested_lower_bitrate: None,
+                sender_key: (!socket.encrypts(&addr)).then(rand::random),
+                muted: false,
             });
         }
-        check_counter += 1;
-        if check_counter >= 100 {
-            let now = std::time::Instant::now();
-            let to_remove: Vec<std::net::SocketAddr> = clients
-                .iter()
-                .filter(|client| now.duration_since(client.last_active).as_secs() >= 500)
-                .map(|client| client.addr)
-                .collect();
-            for addr in &to_remove {
-                remove_client(&mut clients, addr, &socket).await;
-            }
-            debug!(
-                "Cleaned up inactive clients. Before: {}, After: {}",
-                to_remove.len(),
-                clients.len()
-            );
-            check_counter = 0;
-        }
-        let msg = decode_message(&buf[..len]);
-        match msg {
-            Message::Audio(data) => {
-                debug!(
-                    "Received audio packet of {} bytes from {}",
-                    data.data.len(),
-                    addr
-                );
-                let msg = Message::AudioFrom(addr, data);
-                let buf = encode_message(&msg);
-                for client in &clients {
-                    if client.addr != addr {
-                        match socket.send_to(&buf, client.addr).await {
-                            Ok(_) => println!("Forwarded audio packet to {}", client.addr),
-                            Err(e) => error!("Error forwarding audio to {}: {:?}", client.addr, e),
-                        }
-                    }
-                }
-                // Here you would handle the audio data, e.g., play it or forward it
+        // voice and music are reported by the audio path
+        if matches!(
+            msg,
+            Message::Ping | Message::Hello(_) | Message::PlaylistCommand(_) | Message::Chat(_)
+        ) {
+            let mut back_from_afk = false;
+            if let Some(client) = clients.iter_mut().find(|client| client.addr == addr) {
+                client.last_activity = std::time::Instant::now();
+                back_from_afk = client.afk;
+            }
+            if back_from_afk {
+                info!("Moving {} back from the AFK room", addr);
+                set_afk(&mut clients, addr, false, &socket).await;
             }
+        }
+        match msg {
             Message::Ping => {
                 debug!("Received ping from {}", addr);
                 // Handle ping
             }
-            Message::Hello(text) => {
-                info!("Received hello from {}: {}", addr, text);
+            Message::Hello(hello) => {
+                // proven when the client joined, a later hello can't change it
+                let proven = clients
+                    .iter()
+                    .find(|client| client.addr == addr)
+                    .and_then(|client| client.identity);
+                if proven.is_some_and(|identity| identity != hello.identity) {
+                    debug!("Ignoring hello from {} with another identity", addr);
+                    continue;
+                }
+                info!(
+                    "Received hello from {}: {} (audio sink: {})",
+                    addr, hello.identity, hello.audio_sink
+                );
+                let user = hello.identity.user_id(&user_salt);
+                let name = display_name(&hello.name, user);
+                let mut sender_key = None;
+                let mut still_muted = false;
+                if let Some(client) = clients.iter_mut().find(|client| client.addr == addr) {
+                    client.identity = Some(hello.identity);
+                    client.user = Some(user);
+                    client.name = name.clone();
+                    client.audio_sink = hello.audio_sink;
+                    sender_key = client.sender_key;
+                    still_muted = muted.contains(&hello.identity);
+                    client.muted = still_muted;
+                }
+                let codec = settings.codec.negotiate(&hello.profile);
                 // send all clients the new client's hello message
                 match socket
-                    .send_to(&encode_message(&Message::Hello(text)), addr)
+                    .send_to(&encode_message(&Message::Hello(hello)), addr)
                     .await
                 {
                     Ok(_) => debug!("Sent hello ack to {}", addr),
                     Err(e) => error!("Error sending hello ack to {}: {:?}", addr, e),
                 }
+                if let Some(key) = sender_key
+                    && let Err(e) = socket
+                        .send_to(&encode_message(&Message::SenderKey(key)), addr)
+                        .await
+                {
+                    error!("Error sending the sender key to {}: {:?}", addr, e);
+                }
+                if let Err(e) = socket
+                    .send_to(&encode_message(&Message::ServerInfo(server_info.clone())), addr)
+                    .await
+                {
+                    error!("Error sending server info to {}: {:?}", addr, e);
+                }
+                if let Err(e) = socket
+                    .send_to(&encode_message(&Message::RoomCodec(codec)), addr)
+                    .await
+                {
+                    error!("Error sending the room codec to {}: {:?}", addr, e);
+                }
+                if let Err(e) = socket
+                    .send_to(&encode_message(&Message::RoomInfo(room.clone())), addr)
+                    .await
+                {
+                    error!("Error sending room info to {}: {:?}", addr, e);
+                }
+                let snapshot = playlist.snapshot(listener_count(&clients));
+                if let Err(e) = socket
+                    .send_to(&encode_message(&Message::Playlist(snapshot)), addr)
+                    .await
+                {
+                    error!("Error sending playlist to {}: {:?}", addr, e);
+                }
+                if let Some(info) = &now_playing {
+                    let msg = Message::NowPlaying(Some(info.clone()));
+                    if let Err(e) = socket.send_to(&encode_message(&msg), addr).await {
+                        error!("Error sending now playing to {}: {:?}", addr, e);
+                    }
+                }
+                if still_muted
+                    && let Err(e) = socket
+                        .send_to(&encode_message(&Message::AdminMuted(true)), addr)
+                        .await
+                {
+                    error!("Error sending admin mute to {}: {:?}", addr, e);
+                }
+                if !known.contains(&user) {
+                    known.push(user);
+                }
+                let clips = mailbox.take_for(user, socket.peer_key(&addr));
+                if !clips.is_empty() {
+                    info!("Delivering {} voice messages to {}", clips.len(), addr);
+                    tokio::spawn(deliver_clips(socket.clone(), addr, clips));
+                }
+                if !chimes.is_empty() {
+                    tokio::spawn(deliver_chimes(socket.clone(), vec![addr], chimes.clone()));
+                }
+                send_offline_users(&clients, &known, &socket).await;
                 // if client list already contains the addr, don't notify others
                 if is_new_client {
                     debug!("Got new client {}", addr);
+                    if room.cues {
+                        play_cue(&clients, addr, Cue::Join, &socket).await;
+                    }
                     // Notify other clients about the new client, and the new client about existing clients
                     for client in &clients {
                         if client.addr != addr {
                             // Notify existing clients about the new client
-                            let new_client_msg = encode_message(&Message::NewClient(addr));
+                            let new_client_msg =
+                                encode_message(&Message::NewClient(addr, name.clone()));
                             match socket.send_to(&new_client_msg, client.addr).await {
                                 Ok(_) => debug!("Sent new client message to {}", client.addr),
                                 Err(e) => {
@@ -125,7 +1263,10 @@ pub async fn server_loop(socket: UdpSocket) {
                                 }
                             }
 
-                            let new_client_msg = encode_message(&Message::NewClient(client.addr));
+                            let new_client_msg = encode_message(&Message::NewClient(
+                                client.addr,
+                                client.name.clone(),
+                            ));
                             match socket.send_to(&new_client_msg, addr).await {
                                 Ok(_) => debug!("Sent new client message to {}", addr),
                                 Err(e) => {
@@ -136,9 +1277,142 @@ pub async fn server_loop(socket: UdpSocket) {
                     }
                 }
             }
+            Message::VoiceChunk(chunk) => {
+                let sender = clients
+                    .iter()
+                    .find(|client| client.addr == addr)
+                    .and_then(|client| client.user);
+                match sender {
+                    Some(user) => mailbox.add_chunk(addr, user, chunk),
+                    None => warn!("Dropping voice message chunk from unknown client {}", addr),
+                }
+            }
+            Message::MusicVote(vote) => {
+                let Some((host, _)) = music_host else {
+                    debug!("Dropping music vote from {}, nobody is hosting", addr);
+                    continue;
+                };
+                let buf = encode_message(&Message::MusicVoteFrom(addr, vote));
+                if let Err(e) = socket.send_to(&buf, host).await {
+                    error!("Error passing music vote to {}: {:?}", host, e);
+                }
+            }
+            // only the host tallies votes
+            Message::MusicVoteResult(vote) if music_host.is_some_and(|(host, _)| host == addr) => {
+                info!("Listen-along vote passed: {}", vote.describe());
+                broadcast(&clients, &Message::MusicVoteResult(vote), &socket).await;
+            }
+            Message::NowPlaying(info) => {
+                // from the host, or whoever is about to become it
+                let allowed = clients.iter().any(|client| client.addr == addr)
+                    && music_host.is_none_or(|(host, last)| {
+                        host == addr || last.elapsed() >= MUSIC_HOST_TIMEOUT
+                    });
+                if !allowed {
+                    continue;
+                }
+                now_playing = info.map(TrackInfo::truncated);
+                broadcast(&clients, &Message::NowPlaying(now_playing.clone()), &socket).await;
+            }
+            Message::Chat(text) => {
+                let Some(sender) = clients.iter().find(|client| client.addr == addr) else {
+                    continue;
+                };
+                let text: String = text
+                    .chars()
+                    .filter(|c| !c.is_control())
+                    .take(MAX_CHAT_CHARS)
+                    .collect();
+                if text.trim().is_empty() {
+                    continue;
+                }
+                let chat = ChatMessage {
+                    from: addr,
+                    name: sender.name.clone(),
+                    text,
+                };
+                broadcast(&clients, &Message::ChatFrom(chat), &socket).await;
+            }
+            Message::PlaylistCommand(command) => {
+                let sender = clients
+                    .iter()
+                    .find(|client| client.addr == addr)
+                    .and_then(|client| client.user);
+                let Some(user) = sender else {
+                    warn!("Dropping playlist command from unknown client {}", addr);
+                    continue;
+                };
+                let listeners = listener_count(&clients);
+                let changed = match command {
+                    PlaylistCommand::Enqueue(source) => playlist.enqueue(source, user),
+                    PlaylistCommand::Move(id, up) => playlist.move_entry(id, up),
+                    PlaylistCommand::VoteSkip => playlist.vote_skip(user, listeners),
+                    // the host knows when a track ended, anyone while nobody
+                    // streams, e.g. the host couldn't open it
+                    PlaylistCommand::Finished(id) => {
+                        let allowed = music_host.is_none_or(|(host, last)| {
+                            host == addr || last.elapsed() >= MUSIC_HOST_TIMEOUT
+                        });
+                        allowed && playlist.finish(id)
+                    }
+                };
+                if changed {
+                    let snapshot = playlist.snapshot(listeners);
+                    broadcast(&clients, &Message::Playlist(snapshot), &socket).await;
+                }
+            }
+            Message::ClientStats(report) => {
+                let Some(client) = clients.iter_mut().find(|client| client.addr == addr) else {
+                    continue;
+                };
+                client.health = Some(report);
+                let recently = client
+                    .suggested_lower_bitrate
+                    .is_some_and(|at| at.elapsed() < SUGGESTION_INTERVAL);
+                if report.struggling() && !client.afk && !recently {
+                    info!(
+                        "Suggesting a lower bitrate to {}: {}% loss, {}% underruns",
+                        client.name,
+                        report.loss.percent(),
+                        report.underrun_percent()
+                    );
+                    client.suggested_lower_bitrate = Some(std::time::Instant::now());
+                    if let Err(e) = socket
+                        .send_to(&encode_message(&Message::SuggestLowerBitrate), addr)
+                        .await
+                    {
+                        error!("Error sending bitrate suggestion to {}: {:?}", addr, e);
+                    }
+                }
+            }
+            Message::ResetCodec(stream) => {
+                if !clients.iter().any(|client| client.addr == addr) {
+                    continue;
+                }
+                debug!("{} reset its {:?} encoder", addr, stream);
+                let buf = encode_message(&Message::ResetCodecFrom(addr, stream));
+                for client in &clients {
+                    if client.addr != addr
+                        && client.audio_sink
+                        && let Err(e) = socket.send_to(&buf, client.addr).await
+                    {
+                        error!("Error passing codec reset to {}: {:?}", client.addr, e);
+                    }
+                }
+            }
+            Message::RequestCodecReset(sender, stream) => {
+                let connected = |at| clients.iter().any(|client| client.addr == at);
+                if !connected(addr) || !connected(sender) {
+                    continue;
+                }
+                let buf = encode_message(&Message::RequestCodecReset(addr, stream));
+                if let Err(e) = socket.send_to(&buf, sender).await {
+                    error!("Error passing codec reset request to {}: {:?}", sender, e);
+                }
+            }
             Message::Bye => {
                 info!("Received bye from {}", addr);
-                remove_client(&mut clients, &addr, &socket).await;
+                remove_client(&mut clients, &addr, &socket, &known, room.cues).await;
             }
             Message::Unknown(data) => {
                 warn!(
@@ -152,6 +1426,107 @@ pub async fn server_loop(socket: UdpSocket) {
     }
 }
 
+async fn reject(socket: &SecureSocket, addr: SocketAddr, rejection: JoinRejection) {
+    warn!("Rejected join request from {}: {}", addr, rejection.describe());
+    let rejected = encode_message(&Message::JoinRejected(rejection));
+    if let Err(e) = socket.send_to(&rejected, addr).await {
+        error!("Error sending rejection to {}: {:?}", addr, e);
+    }
+}
+
+/// Tells the client at `addr` that an admin removed it and removes it.
+async fn kick(
+    clients: &mut Vec<ClientInfo>,
+    addr: SocketAddr,
+    socket: &SecureSocket,
+    known: &[UserId],
+    cues: bool,
+) {
+    if !contains_client(clients, &addr) {
+        return;
+    }
+    info!("Kicking {}", addr);
+    // before `remove_client` forgets its keys
+    if let Err(e) = socket.send_to(&encode_message(&Message::Kicked), addr).await {
+        error!("Error telling {} it was kicked: {:?}", addr, e);
+    }
+    remove_client(clients, &addr, socket, known, cues).await;
+}
+
+/// Kicks everyone joined from `ip`.
+async fn kick_address(
+    clients: &mut Vec<ClientInfo>,
+    ip: IpAddr,
+    socket: &SecureSocket,
+    known: &[UserId],
+    cues: bool,
+) {
+    let addrs: Vec<SocketAddr> = clients
+        .iter()
+        .map(|client| client.addr)
+        .filter(|addr| addr.ip() == ip)
+        .collect();
+    for addr in addrs {
+        kick(clients, addr, socket, known, cues).await;
+    }
+}
+
+async fn set_afk(clients: &mut [ClientInfo], addr: SocketAddr, afk: bool, socket: &SecureSocket) {
+    let Some(client) = clients.iter_mut().find(|client| client.addr == addr) else {
+        return;
+    };
+    client.afk = afk;
+    if let Err(e) = socket
+        .send_to(&encode_message(&Message::MovedToAfk(afk)), addr)
+        .await
+    {
+        error!("Error sending AFK move to {}: {:?}", addr, e);
+    }
+    let msg = encode_message(&Message::ClientAfk(addr, afk));
+    for client in clients.iter().filter(|client| client.addr != addr) {
+        if let Err(e) = socket.send_to(&msg, client.addr).await {
+            error!("Error sending AFK state to {}: {:?}", client.addr, e);
+        }
+    }
+}
+
+/// What the others see of a client, falling back to its user id if it didn't
+/// name itself. Control characters would mess up the TUI.
+fn display_name(name: &str, user: UserId) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_CHARS)
+        .collect();
+    match name.trim() {
+        "" => user.to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Clients that hear the room, the ones whose vote counts for skipping a track.
+fn listener_count(clients: &[ClientInfo]) -> usize {
+    clients
+        .iter()
+        .filter(|client| client.audio_sink && !client.afk)
+        .count()
+}
+
+async fn broadcast(clients: &[ClientInfo], msg: &Message, socket: &SecureSocket) {
+    let buf = match encode_checked(msg) {
+        Ok(buf) => buf,
+        Err(e) => {
+            error!("Not broadcasting {:?}: {:?}", std::mem::discriminant(msg), e);
+            return;
+        }
+    };
+    for client in clients {
+        if let Err(e) = socket.send_to(&buf, client.addr).await {
+            error!("Error sending to {}: {:?}", client.addr, e);
+        }
+    }
+}
+
 fn contains_client(clients: &Vec<ClientInfo>, addr: &SocketAddr) -> bool {
     for client in clients {
         if &client.addr == addr {
@@ -161,11 +1536,95 @@ fn contains_client(clients: &Vec<ClientInfo>, addr: &SocketAddr) -> bool {
     return false;
 }
 
+/// Sends the stored clips one chunk at a time, paced so the burst doesn't
+/// overflow socket buffers on the way.
+async fn deliver_clips(socket: Arc<SecureSocket>, addr: SocketAddr, clips: Vec<StoredClip>) {
+    for (clip_index, clip) in clips.into_iter().enumerate() {
+        let count = clip.packets.len().min(MAX_CLIP_PACKETS as usize);
+        for (index, packet) in clip.packets.into_iter().take(count).enumerate() {
+            let chunk = VoiceChunk {
+                peer: clip.from,
+                clip: clip_index as u32,
+                index: index as u16,
+                last: index + 1 == count,
+                packet,
+            };
+            if let Err(e) = socket
+                .send_to(&encode_message(&Message::VoiceChunk(chunk)), addr)
+                .await
+            {
+                error!("Error delivering voice message to {}: {:?}", addr, e);
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+    }
+}
+
+/// Sends the room's chimes to `addrs` one packet at a time, paced like voice
+/// messages. A cue without packets goes out as a single empty chunk, which
+/// resets it to the built-in chime.
+async fn deliver_chimes(
+    socket: Arc<SecureSocket>,
+    addrs: Vec<SocketAddr>,
+    chimes: HashMap<Cue, Vec<Vec<u8>>>,
+) {
+    for (cue, packets) in chimes {
+        let count = packets.len().max(1);
+        let packets = packets.into_iter().chain(std::iter::repeat(Vec::new()));
+        for (index, packet) in packets.take(count).enumerate() {
+            let chunk = ChimeChunk {
+                cue,
+                index: index as u16,
+                last: index + 1 == count,
+                packet,
+            };
+            let buf = encode_message(&Message::Chime(chunk));
+            for addr in &addrs {
+                if let Err(e) = socket.send_to(&buf, *addr).await {
+                    error!("Error sending chime to {}: {:?}", addr, e);
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+    }
+}
+
+/// Tells everyone who hears the room except `addr` to play `cue` for it.
+async fn play_cue(clients: &[ClientInfo], addr: SocketAddr, cue: Cue, socket: &SecureSocket) {
+    let buf = encode_message(&Message::Cue(cue));
+    for client in clients {
+        if client.addr != addr
+            && client.audio_sink
+            && !client.afk
+            && let Err(e) = socket.send_to(&buf, client.addr).await
+        {
+            error!("Error sending cue to {}: {:?}", client.addr, e);
+        }
+    }
+}
+
+async fn send_offline_users(clients: &[ClientInfo], known: &[UserId], socket: &SecureSocket) {
+    let offline: Vec<UserId> = known
+        .iter()
+        .filter(|user| !clients.iter().any(|client| client.user == Some(**user)))
+        .copied()
+        .collect();
+    broadcast(clients, &Message::OfflineUsers(offline), socket).await;
+}
+
 async fn remove_client(
     clients: &mut Vec<ClientInfo>,
     addr: &std::net::SocketAddr,
-    socket: &UdpSocket,
+    socket: &SecureSocket,
+    known: &[UserId],
+    cues: bool,
 ) {
+    let name = clients
+        .iter()
+        .find(|client| &client.addr == addr)
+        .map(|client| client.name.clone())
+        .unwrap_or_default();
     let size_before = clients.len();
     clients.retain(|client| {
         if &client.addr == addr {
@@ -177,12 +1636,13 @@ async fn remove_client(
     });
     if clients.len() < size_before {
         let bye_msg = encode_message(&Message::Bye);
-        match socket.send_to(&bye_msg, addr).await {
+        match socket.send_to(&bye_msg, *addr).await {
             Ok(_) => debug!("Sent bye message to {}", addr),
             Err(e) => error!("Error sending bye message to {}: {:?}", addr, e),
         }
+        socket.forget(addr);
         for client in clients.iter() {
-            let delete_msg = encode_message(&Message::DeleteClient(*addr));
+            let delete_msg = encode_message(&Message::DeleteClient(*addr, name.clone()));
             match socket.send_to(&delete_msg, client.addr).await {
                 Ok(_) => debug!("Sent delete client message to {}", client.addr),
                 Err(e) => error!(
@@ -191,6 +1651,28 @@ async fn remove_client(
                 ),
             }
         }
+        send_offline_users(clients, known, socket).await;
+        if cues {
+            play_cue(clients, *addr, Cue::Leave, socket).await;
+        }
+    }
+}
+
+/// Decodes a received message, refusing one larger than its kind may be.
+pub fn decode_checked(buf: &[u8]) -> Result<Message, Error> {
+    let msg = decode_message(buf);
+    match msg.max_size() {
+        limit if buf.len() > limit => Err(Error::MessageTooLarge(buf.len(), limit)),
+        _ => Ok(msg),
+    }
+}
+
+/// Encodes a message to send, refusing one larger than its kind may be.
+pub fn encode_checked(msg: &Message) -> Result<Vec<u8>, Error> {
+    let buf = encode_message(msg);
+    match msg.max_size() {
+        limit if buf.len() > limit => Err(Error::MessageTooLarge(buf.len(), limit)),
+        _ => Ok(buf),
     }
 }
 
@@ -207,3 +1689,340 @@ pub fn decode_message(buf: &[u8]) -> Message {
 pub fn encode_message(msg: &Message) -> Vec<u8> {
     bincode::encode_to_vec(msg, config::standard()).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    fn hello_from(key: &IdentityKey, name: &str) -> Hello {
+        Hello {
+            identity: key.identity(),
+            audio_sink: true,
+            token: None,
+            proof: None,
+            name: name.to_string(),
+            profile: RoomCodec::default(),
+        }
+    }
+
+    /// Says hello to the server at `server` as the user of `key` and answers
+    /// its challenge, what the server says to that is left to read.
+    async fn join(server: SocketAddr, key: &IdentityKey) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server).await.unwrap();
+        let hello = hello_from(key, "client");
+        socket.send(&encode_message(&Message::Hello(hello.clone()))).await.unwrap();
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let Message::JoinChallenge(challenge) = decode_message(&buf[..len]) else {
+            panic!("no challenge");
+        };
+        let answer = hello.answer(key, None, &challenge).unwrap();
+        socket.send(&encode_message(&Message::Hello(answer))).await.unwrap();
+        socket
+    }
+
+    #[tokio::test]
+    async fn forwards_audio_between_joined_clients() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let (admin, admin_rx) = mpsc::channel(1);
+        tokio::spawn(server_loop(
+            SecureSocket::plain(socket),
+            admin_rx,
+            Schedule::default(),
+            BanList::default(),
+            ServerSettings::default(),
+        ));
+        let alice = join(server_addr, &IdentityKey::generate()).await;
+        let bob = join(server_addr, &IdentityKey::generate()).await;
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        let key = loop {
+            let len = alice.recv(&mut buf).await.unwrap();
+            if let Message::SenderKey(key) = decode_message(&buf[..len]) {
+                break key;
+            }
+        };
+        let audio = encode_message(&Message::Audio(AudioData {
+            timestamp: 0,
+            seq_number: 0,
+            data: vec![1, 2, 3],
+        }));
+        let signed = |key, audio: &Vec<u8>| {
+            let tag = crate::crypto::sender_tag(key, audio);
+            encode_message(&Message::Signed(tag, audio.clone()))
+        };
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                // dropped until the control task has published alice's route
+                alice.send(&signed(&key, &audio)).await.unwrap();
+                let wait = Duration::from_millis(50);
+                while let Ok(Ok(len)) = tokio::time::timeout(wait, bob.recv(&mut buf)).await {
+                    if let Message::AudioFrom(from, _, audio) = decode_message(&buf[..len]) {
+                        return (from, audio.data);
+                    }
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(received, (alice.local_addr().unwrap(), vec![1, 2, 3]));
+
+        // as if someone else sent it from alice's address
+        alice.send(&audio).await.unwrap();
+        alice.send(&signed(&[0; 32], &audio)).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let (reply, status) = tokio::sync::oneshot::channel();
+                admin.send(AdminCommand::Status(reply)).await.unwrap();
+                if status.await.unwrap().packets_spoofed == 2 {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("both packets counted as spoofed");
+    }
+
+    #[tokio::test]
+    async fn drops_music_from_muted_clients() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let (admin, admin_rx) = mpsc::channel(1);
+        tokio::spawn(server_loop(
+            SecureSocket::plain(socket),
+            admin_rx,
+            Schedule::default(),
+            BanList::default(),
+            ServerSettings::default(),
+        ));
+        let alice = join(server_addr, &IdentityKey::generate()).await;
+        let bob = join(server_addr, &IdentityKey::generate()).await;
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        let key = loop {
+            let len = alice.recv(&mut buf).await.unwrap();
+            if let Message::SenderKey(key) = decode_message(&buf[..len]) {
+                break key;
+            }
+        };
+        let music = encode_message(&Message::Music(AudioData {
+            timestamp: 0,
+            seq_number: 0,
+            data: vec![1, 2, 3],
+        }));
+        let music = encode_message(&Message::Signed(
+            crate::crypto::sender_tag(&key, &music),
+            music,
+        ));
+        // waits for the control task to tell alice, then for it to go round
+        // its loop once more, by then the forwarding sees the new routes
+        let mute = async |mute: bool, buf: &mut Vec<u8>| {
+            let alice_addr = alice.local_addr().unwrap();
+            admin.send(AdminCommand::Mute(alice_addr, mute)).await.unwrap();
+            loop {
+                let len = alice.recv(buf).await.unwrap();
+                if decode_message(&buf[..len]) == Message::AdminMuted(mute) {
+                    break;
+                }
+            }
+            let (reply, status) = tokio::sync::oneshot::channel();
+            admin.send(AdminCommand::Status(reply)).await.unwrap();
+            status.await.unwrap();
+        };
+        let music_from = async |buf: &mut Vec<u8>, wait| {
+            while let Ok(Ok(len)) = tokio::time::timeout(wait, bob.recv(buf)).await {
+                if let Message::MusicFrom(..) = decode_message(&buf[..len]) {
+                    return true;
+                }
+            }
+            false
+        };
+
+        mute(true, &mut buf).await;
+        for _ in 0..5 {
+            alice.send(&music).await.unwrap();
+        }
+        assert!(!music_from(&mut buf, Duration::from_millis(300)).await);
+
+        mute(false, &mut buf).await;
+        let forwarded = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                alice.send(&music).await.unwrap();
+                if music_from(&mut buf, Duration::from_millis(50)).await {
+                    return;
+                }
+            }
+        })
+        .await;
+        assert!(forwarded.is_ok(), "music not forwarded once unmuted");
+    }
+
+    #[tokio::test]
+    async fn turns_away_joins_once_full() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let (_admin, admin_rx) = mpsc::channel(1);
+        let settings = ServerSettings {
+            max_clients: Some(1),
+            ..ServerSettings::default()
+        };
+        tokio::spawn(server_loop(
+            SecureSocket::plain(socket),
+            admin_rx,
+            Schedule::default(),
+            BanList::default(),
+            settings,
+        ));
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        let mut answer = async |socket: &UdpSocket| {
+            let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            decode_message(&buf[..len])
+        };
+        let alice = join(server_addr, &IdentityKey::generate()).await;
+        assert!(matches!(answer(&alice).await, Message::Hello(_)));
+        let bob = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        bob.connect(server_addr).await.unwrap();
+        let hello = hello_from(&IdentityKey::generate(), "bob");
+        bob.send(&encode_message(&Message::Hello(hello))).await.unwrap();
+        assert_eq!(
+            answer(&bob).await,
+            Message::JoinRejected(JoinRejection::Full)
+        );
+    }
+
+    #[tokio::test]
+    async fn kicks_banned_clients_and_turns_them_away() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let (admin, admin_rx) = mpsc::channel(1);
+        tokio::spawn(server_loop(
+            SecureSocket::plain(socket),
+            admin_rx,
+            Schedule::default(),
+            BanList::default(),
+            ServerSettings::default(),
+        ));
+        let key = IdentityKey::generate();
+        let socket = join(server_addr, &key).await;
+        let hello = encode_message(&Message::Hello(hello_from(&key, "troll")));
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        let mut next = async |expected: fn(&Message) -> bool| {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    let len = socket.recv(&mut buf).await.unwrap();
+                    let msg = decode_message(&buf[..len]);
+                    if expected(&msg) {
+                        return msg;
+                    }
+                }
+            })
+            .await
+            .unwrap()
+        };
+        next(|msg| matches!(msg, Message::Hello(_))).await;
+        admin
+            .send(AdminCommand::Ban("127.0.0.1".parse().unwrap()))
+            .await
+            .unwrap();
+        next(|msg| *msg == Message::Kicked).await;
+        socket.send(&hello).await.unwrap();
+        let rejected = next(|msg| matches!(msg, Message::JoinRejected(_))).await;
+        assert_eq!(rejected, Message::JoinRejected(JoinRejection::Banned));
+    }
+
+    #[tokio::test]
+    async fn challenges_joins_for_the_password() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let (_admin, admin_rx) = mpsc::channel(1);
+        let settings = ServerSettings {
+            password: Some("hunter2".to_string()),
+            ..ServerSettings::default()
+        };
+        tokio::spawn(server_loop(
+            SecureSocket::plain(socket),
+            admin_rx,
+            Schedule::default(),
+            BanList::default(),
+            settings,
+        ));
+        let key = IdentityKey::generate();
+        let hello = hello_from(&key, "alice");
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        let mut exchange = async |socket: &UdpSocket, msg: &Message| {
+            socket.send(&encode_message(msg)).await.unwrap();
+            let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            decode_message(&buf[..len])
+        };
+        let alice = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        alice.connect(server_addr).await.unwrap();
+        let Message::JoinChallenge(challenge) =
+            exchange(&alice, &Message::Hello(hello.clone())).await
+        else {
+            panic!("no challenge");
+        };
+        assert!(challenge.password);
+        assert!(hello.answer(&key, None, &challenge).is_err());
+        let answer = Message::Hello(hello.answer(&key, Some("hunter2"), &challenge).unwrap());
+        assert!(matches!(exchange(&alice, &answer).await, Message::Hello(_)));
+
+        // someone who saw the answer go by gets a challenge of their own
+        let eve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        eve.connect(server_addr).await.unwrap();
+        let Message::JoinChallenge(other) = exchange(&eve, &answer).await else {
+            panic!("replayed answer accepted");
+        };
+        assert_ne!(other.nonce, challenge.nonce);
+        // and can't answer it as alice without her key, even knowing the password
+        let eve_key = IdentityKey::generate();
+        let forged = Message::Hello(hello.answer(&eve_key, Some("hunter2"), &other).unwrap());
+        assert_eq!(
+            exchange(&eve, &forged).await,
+            Message::JoinRejected(JoinRejection::IdentityNotProven)
+        );
+        let eve_hello = Message::Hello(hello_from(&eve_key, "eve"));
+        let Message::JoinChallenge(other) = exchange(&eve, &eve_hello).await else {
+            panic!("no challenge");
+        };
+        let guess = hello_from(&eve_key, "eve").answer(&eve_key, Some("hunter3"), &other);
+        assert_eq!(
+            exchange(&eve, &Message::Hello(guess.unwrap())).await,
+            Message::JoinRejected(JoinRejection::WrongPassword)
+        );
+    }
+
+    #[test]
+    fn refuses_messages_over_the_limit_of_their_kind() {
+        let audio = |bytes| {
+            Message::Audio(AudioData {
+                timestamp: 0,
+                seq_number: 0,
+                data: vec![0; bytes],
+            })
+        };
+        // a roster or a chat may be larger than any audio packet
+        let chat = Message::Chat("a".repeat(20_000));
+        let buf = encode_checked(&chat).unwrap();
+        assert_eq!(decode_checked(&buf).unwrap(), chat);
+        assert!(encode_checked(&audio(4_000)).is_ok());
+        assert!(matches!(
+            encode_checked(&audio(20_000)),
+            Err(Error::MessageTooLarge(_, limit)) if limit == BUF_SIZE as usize
+        ));
+        let oversize = encode_message(&audio(20_000));
+        assert!(decode_checked(&oversize).is_err());
+        assert!(encode_checked(&Message::Chat("a".repeat(40_000))).is_err());
+    }
+}
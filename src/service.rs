@@ -0,0 +1,294 @@
+use std::path::PathBuf;
+
+const UNIT: &str = "kop-audio.service";
+const LABEL: &str = "dev.kopatz.kop-audio";
+
+/// Where this user's service definition goes, a launchd agent on macOS and a
+/// systemd user unit elsewhere.
+pub fn path() -> Option<PathBuf> {
+    let home = PathBuf::from(std::env::var_os("HOME")?);
+    if cfg!(target_os = "macos") {
+        return Some(
+            home.join("Library/LaunchAgents")
+                .join(format!("{}.plist", LABEL)),
+        );
+    }
+    let config = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home.join(".config"),
+    };
+    Some(config.join("systemd/user").join(UNIT))
+}
+
+/// A service that runs `exe` with `args` at login and again whenever it
+/// fails, a stop through the control socket exits cleanly and ends it.
+pub fn definition(exe: &str, args: &[String]) -> String {
+    if cfg!(target_os = "macos") {
+        launchd_agent(exe, args)
+    } else {
+        systemd_unit(exe, args)
+    }
+}
+
+/// What starts the service once its definition is written to `path`.
+pub fn start_command(path: &std::path::Path) -> String {
+    if cfg!(target_os = "macos") {
+        format!("launchctl load -w {}", path.display())
+    } else {
+        "systemctl --user daemon-reload && systemctl --user enable --now kop-audio".to_string()
+    }
+}
+
+fn systemd_unit(exe: &str, args: &[String]) -> String {
+    // quoted, and % would be a specifier
+    let quote = |arg: &str| {
+        let escaped = arg
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('%', "%%");
+        format!("\"{}\"", escaped)
+    };
+    let command: Vec<String> = std::iter::once(exe)
+        .chain(args.iter().map(String::as_str))
+        .map(quote)
+        .collect();
+    format!(
+        "[Unit]\n\
+         Description=kop-audio voice chat\n\
+         After=pipewire-pulse.service pulseaudio.service\n\
+         \n\
+         [Service]\n\
+         ExecStart={}\n\
+         Restart=on-failure\n\
+         RestartSec=5\n\
+         \n\
+         [Install]\n\
+         WantedBy=default.target\n",
+        command.join(" ")
+    )
+}
+
+fn launchd_agent(exe: &str, args: &[String]) -> String {
+    let escape = |arg: &str| {
+        arg.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    };
+    let arguments: String = std::iter::once(exe)
+        .chain(args.iter().map(String::as_str))
+        .map(|arg| format!("        <string>{}</string>\n", escape(arg)))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \
+         \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n\
+         <dict>\n\
+         \x20   <key>Label</key>\n\
+         \x20   <string>{}</string>\n\
+         \x20   <key>ProgramArguments</key>\n\
+         \x20   <array>\n\
+         {}\
+         \x20   </array>\n\
+         \x20   <key>RunAtLoad</key>\n\
+         \x20   <true/>\n\
+         \x20   <key>KeepAlive</key>\n\
+         \x20   <dict>\n\
+         \x20       <key>SuccessfulExit</key>\n\
+         \x20       <false/>\n\
+         \x20   </dict>\n\
+         </dict>\n\
+         </plist>\n",
+        LABEL, arguments
+    )
+}
+
+/// The Windows service, which starts with the computer and is restarted after
+/// a failure. It runs `client --daemon` and stops it through the control
+/// socket, so the daemon says bye to the server like on `kop-audio stop`.
+#[cfg(windows)]
+pub mod windows {
+    use std::{
+        ffi::OsString,
+        process::Command,
+        sync::{OnceLock, mpsc},
+        time::Duration,
+    };
+
+    use log::error;
+    use windows_service::{
+        Error, define_windows_service,
+        service::{
+            ServiceAccess, ServiceAction, ServiceActionType, ServiceControl, ServiceControlAccept,
+            ServiceErrorControl, ServiceExitCode, ServiceFailureActions, ServiceFailureResetPeriod,
+            ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+        },
+        service_control_handler::{self, ServiceControlHandlerResult},
+        service_dispatcher,
+        service_manager::{ServiceManager, ServiceManagerAccess},
+    };
+
+    use crate::{client::ClientMessage, control};
+
+    const NAME: &str = "kop-audio";
+    const RESTART_DELAY: Duration = Duration::from_secs(5);
+    // how often the service looks whether the daemon is still running
+    const POLL: Duration = Duration::from_millis(500);
+
+    // the arguments of the daemon, `run` learns them before the dispatcher
+    // calls `service_main`
+    static DAEMON_ARGS: OnceLock<Vec<String>> = OnceLock::new();
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Installs the service, which joins `server` or the config file's one.
+    /// It runs as LocalSystem, so the config file is the one of that account.
+    pub fn install(server: Option<String>) -> Result<(), Error> {
+        let manager = ServiceManager::local_computer(
+            None::<&str>,
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+        )?;
+        let mut launch_arguments = vec![OsString::from("windows-service")];
+        if let Some(server) = server {
+            launch_arguments.extend([OsString::from("--ip"), OsString::from(server)]);
+        }
+        let info = ServiceInfo {
+            name: OsString::from(NAME),
+            display_name: OsString::from("kop-audio voice chat"),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: std::env::current_exe().map_err(Error::Winapi)?,
+            launch_arguments,
+            dependencies: Vec::new(),
+            account_name: None,
+            account_password: None,
+        };
+        let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+        service.set_description("Keeps the kop-audio daemon connected")?;
+        service.update_failure_actions(ServiceFailureActions {
+            reset_period: ServiceFailureResetPeriod::After(Duration::from_secs(24 * 60 * 60)),
+            reboot_msg: None,
+            command: None,
+            actions: Some(vec![ServiceAction {
+                action_type: ServiceActionType::Restart,
+                delay: RESTART_DELAY,
+            }]),
+        })?;
+        // the daemon exiting with an error counts as a failure, not just a crash
+        service.set_failure_actions_on_non_crash_failures(true)
+    }
+
+    /// Removes the service, it has to be stopped first.
+    pub fn remove() -> Result<(), Error> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        manager.open_service(NAME, ServiceAccess::DELETE)?.delete()
+    }
+
+    /// Hands the process to the service manager, which started it with the
+    /// arguments `install` gave it. Returns once the service stopped.
+    pub fn run(server: Option<String>) -> Result<(), Error> {
+        let mut args = vec!["client".to_string(), "--daemon".to_string()];
+        if let Some(server) = server {
+            args.extend(["--ip".to_string(), server]);
+        }
+        let _ = DAEMON_ARGS.set(args);
+        service_dispatcher::start(NAME, ffi_service_main)
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        if let Err(e) = run_daemon() {
+            error!("Service failed: {:?}", e);
+        }
+    }
+
+    fn run_daemon() -> Result<(), Error> {
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let status = service_control_handler::register(NAME, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = stop_tx.send(());
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+        let args = DAEMON_ARGS.get().cloned().unwrap_or_default();
+        let spawned = std::env::current_exe().and_then(|exe| Command::new(exe).args(args).spawn());
+        let mut daemon = match spawned {
+            Ok(daemon) => daemon,
+            Err(e) => {
+                status.set_service_status(stopped(true))?;
+                return Err(Error::Winapi(e));
+            }
+        };
+        status.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: ServiceState::Running,
+            controls_accepted: ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        })?;
+        let failed = loop {
+            match daemon.try_wait() {
+                // stopped through the control socket, or gave up
+                Ok(Some(exit)) => break !exit.success(),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Lost track of the daemon: {}", e);
+                    break true;
+                }
+            }
+            if stop_rx.recv_timeout(POLL).is_ok() {
+                if let Err(e) = control::send_command(ClientMessage::Exit) {
+                    error!("Killing the daemon: {:?}", e);
+                    let _ = daemon.kill();
+                }
+                let _ = daemon.wait();
+                break false;
+            }
+        };
+        status.set_service_status(stopped(failed))
+    }
+
+    fn stopped(failed: bool) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: ServiceState::Stopped,
+            controls_accepted: ServiceControlAccept::empty(),
+            // an error has the service manager restart it
+            exit_code: if failed {
+                ServiceExitCode::ServiceSpecific(1)
+            } else {
+                ServiceExitCode::Win32(0)
+            },
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_the_daemon_with_its_arguments_quoted() {
+        let args = [
+            "client".to_string(),
+            "--daemon".to_string(),
+            "--name".to_string(),
+            "50% <me>".to_string(),
+        ];
+        let unit = systemd_unit("/usr/bin/kop-audio", &args);
+        assert!(unit.contains(
+            "ExecStart=\"/usr/bin/kop-audio\" \"client\" \"--daemon\" \"--name\" \"50%% <me>\"\n"
+        ));
+        assert!(unit.contains("Restart=on-failure"));
+        let agent = launchd_agent("/usr/bin/kop-audio", &args);
+        assert!(agent.contains("        <string>50% &lt;me&gt;</string>\n"));
+        assert!(agent.contains("<key>SuccessfulExit</key>"));
+    }
+}
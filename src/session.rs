@@ -0,0 +1,223 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use tokio::task::JoinHandle;
+
+use crate::{
+    Error,
+    aec::{EchoCanceller, EchoReference},
+    audio::{SharedAudio, play_audio, record_audio, send_audio, send_queue, voice_encoder},
+    bus::EventBus,
+    client::{ClientMessage, NetworkClient},
+    implementations::pulseaudio::PulseAudioAppProducer,
+    music::MusicProducer,
+    recorder::Recording,
+    settings::AudioSettings,
+};
+
+// how long the tasks get to finish and the bye to go out when quitting
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A call on one server. Owns the socket, the codecs and the audio devices for
+/// as long as it is running, the coordinator decides when to start and stop it.
+pub struct Session {
+    server: String,
+    settings: AudioSettings,
+    bus: EventBus,
+    network: Option<NetworkClient>,
+    tasks: Vec<JoinHandle<()>>,
+    running: Arc<AtomicBool>,
+    /// capture, encoding and playback, which can stop and start again while
+    /// the session runs, see `suspend_audio`
+    audio_tasks: Vec<JoinHandle<()>>,
+    audio_running: Arc<AtomicBool>,
+    audio_suspended: bool,
+}
+
+impl Session {
+    pub fn new(server: String, settings: AudioSettings, bus: EventBus) -> Self {
+        Session {
+            server,
+            settings,
+            bus,
+            network: None,
+            tasks: Vec::new(),
+            running: Arc::new(AtomicBool::new(false)),
+            audio_tasks: Vec::new(),
+            audio_running: Arc::new(AtomicBool::new(false)),
+            audio_suspended: false,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    pub async fn start(&mut self) -> Result<(), Error> {
+        if self.is_running() {
+            return Ok(());
+        }
+        info!("Starting session on {}", self.server);
+        let network = NetworkClient::new(&self.server, self.bus.clone(), &self.settings).await?;
+        // a fresh flag per start, so tasks of a previous run can't be revived
+        self.running = Arc::new(AtomicBool::new(true));
+        if self.settings.audio_sink
+            && !self.audio_suspended
+            && let Err(e) = self.start_audio()
+        {
+            self.running.store(false, Ordering::Relaxed);
+            return Err(e);
+        }
+        self.tasks.extend(network.start());
+        self.network = Some(network);
+        Ok(())
+    }
+
+    fn start_audio(&mut self) -> Result<(), Error> {
+        let mut producer = self.settings.backend.capture(&self.settings)?;
+        let mut consumer = self.settings.backend.playback(&self.settings)?;
+        if self.settings.echo_cancellation {
+            let echo = EchoReference::default();
+            consumer.set_echo_reference(echo.clone());
+            producer.set_echo_canceller(EchoCanceller::new(echo));
+        }
+        let bus = self.bus.clone();
+        let send_bus = self.bus.clone();
+        let playback_bus = self.bus.clone();
+        let rx_record = self.bus.record.subscribe();
+        let rx_send = self.bus.record.subscribe();
+        let rx_playback = self.bus.playback.subscribe();
+        // opus until the server declares the room's codec
+        let encoder = voice_encoder(&self.settings, &self.settings.profile.codec());
+        // a reconnect starts new files rather than overwriting the last ones
+        self.settings.recording = self.settings.record_dir.clone().map(Recording::new);
+        let record_settings = self.settings.clone();
+        let send_settings = self.settings.clone();
+        let playback_settings = self.settings.clone();
+        // a fresh flag per start, like the session's
+        self.audio_running = Arc::new(AtomicBool::new(true));
+        let running = self.audio_running.clone();
+        let (frames_tx, frames_rx) = send_queue();
+        self.audio_tasks.push(tokio::task::spawn_blocking(move || {
+            // talks to PulseAudio through a mainloop that can't leave its thread
+            let shared = match &record_settings.share_app {
+                _ if record_settings.play_queue => {
+                    Some(SharedAudio::Playlist(MusicProducer::new(record_settings.crossfade)))
+                }
+                Some(app) => PulseAudioAppProducer::new(app, &record_settings)
+                    .map_err(|e| error!("Can't share application audio: {:?}", e))
+                    .ok()
+                    .map(SharedAudio::App),
+                None => None,
+            };
+            let result = record_audio(
+                bus.clone(),
+                &mut producer,
+                rx_record,
+                frames_tx,
+                &record_settings,
+                running,
+                shared,
+            );
+            if let Err(e) = result {
+                bus.commands.publish(ClientMessage::SessionFailed(e));
+            }
+        }));
+        // encoding is kept off the capture thread, which only reads the device
+        self.audio_tasks.push(tokio::spawn(send_audio(
+            send_bus,
+            rx_send,
+            frames_rx,
+            encoder,
+            send_settings,
+        )));
+        self.audio_tasks.push(tokio::task::spawn_blocking(move || {
+            play_audio(playback_bus, rx_playback, &mut consumer, &playback_settings)
+        }));
+        Ok(())
+    }
+
+    /// Records from `device` whenever the audio starts again, after it was
+    /// picked while running.
+    pub fn select_input(&mut self, device: &str) {
+        self.settings.mics = vec![device.to_string()];
+    }
+
+    /// Plays on `device` whenever the audio starts again.
+    pub fn select_output(&mut self, device: &str) {
+        self.settings.output_device = Some(device.to_string());
+    }
+
+    /// Closes the audio devices until `resume_audio`, also across restarts of
+    /// the session. The network keeps running.
+    pub fn suspend_audio(&mut self) {
+        if self.audio_suspended {
+            return;
+        }
+        self.audio_suspended = true;
+        if self.is_running() {
+            debug!("Closing the audio devices");
+            self.stop_audio();
+        }
+    }
+
+    /// Opens the audio devices again after `suspend_audio`, true if the audio
+    /// tasks started and need to be told what changed since the settings were
+    /// read.
+    pub fn resume_audio(&mut self) -> Result<bool, Error> {
+        if !self.audio_suspended {
+            return Ok(false);
+        }
+        self.audio_suspended = false;
+        if !self.is_running() || !self.settings.audio_sink {
+            return Ok(false);
+        }
+        debug!("Opening the audio devices again");
+        self.start_audio()?;
+        Ok(true)
+    }
+
+    /// Capture and playback are blocking and finish on their own, releasing
+    /// the devices, aborting ends the encoder.
+    fn stop_audio(&mut self) {
+        self.audio_running.store(false, Ordering::Relaxed);
+        self.bus.playback.publish(ClientMessage::Disconnect);
+        for task in self.audio_tasks.drain(..) {
+            task.abort();
+        }
+    }
+
+    /// Waits for the tasks to finish once the bus' shutdown was triggered, then
+    /// stops whatever didn't in time.
+    pub async fn shutdown(&mut self) {
+        let deadline = tokio::time::Instant::now() + SHUTDOWN_TIMEOUT;
+        for task in self.tasks.iter_mut().chain(&mut self.audio_tasks) {
+            if tokio::time::timeout_at(deadline, task).await.is_err() {
+                warn!("Session on {} didn't shut down in time", self.server);
+                break;
+            }
+        }
+        self.stop();
+    }
+
+    /// Stops all tasks of the session and releases socket and devices.
+    pub fn stop(&mut self) {
+        if !self.is_running() {
+            return;
+        }
+        debug!("Stopping session on {}", self.server);
+        self.running.store(false, Ordering::Relaxed);
+        self.stop_audio();
+        // aborting ends the network tasks
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        self.network = None;
+    }
+}
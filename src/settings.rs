@@ -0,0 +1,629 @@
+use std::{fmt, net::SocketAddr, path::PathBuf, time::Duration};
+
+use opus::{Application, Bitrate};
+
+use crate::{
+    CHANNELS, FRAME_SIZE, SAMPLE_RATE,
+    implementations::{AudioBackend, BACKENDS},
+    loudness::{DEFAULT_MUSIC_LOUDNESS, DEFAULT_VOICE_LOUDNESS},
+    recorder::Recording,
+    server::RoomCodec,
+};
+
+/// Bitrates the TUI steps through, from narrowband voice to transparent music.
+const BITRATE_STEPS: [i32; 8] = [
+    16_000, 24_000, 32_000, 48_000, 64_000, 96_000, 128_000, 192_000,
+];
+
+/// How the encoder spends its bitrate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BitrateMode {
+    /// varies with the signal, best quality for the average rate
+    #[default]
+    Vbr,
+    /// varies, but stays within about one frame's worth of the target
+    Constrained,
+    /// the same size for every packet, hides the signal from traffic analysis
+    Cbr,
+}
+
+impl BitrateMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "vbr" => Some(BitrateMode::Vbr),
+            "cvbr" => Some(BitrateMode::Constrained),
+            "cbr" => Some(BitrateMode::Cbr),
+            _ => None,
+        }
+    }
+}
+
+/// `bitrate` in bits per second, the encoder's automatic choice counts as
+/// 64kbps and the maximum as the top step.
+pub fn bitrate_bits(bitrate: Bitrate) -> i32 {
+    match bitrate {
+        Bitrate::Bits(bits) => bits,
+        Bitrate::Auto => 64_000,
+        Bitrate::Max => BITRATE_STEPS[BITRATE_STEPS.len() - 1],
+    }
+}
+
+/// Frame lengths in milliseconds opus is run with, see `with_frame_ms`.
+pub const FRAME_MS: [usize; 4] = [10, 20, 40, 60];
+
+/// The most the client sends its voice as, see `--profile`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Profile {
+    /// mono at 16kHz in 40ms frames, for constrained links
+    Low,
+    /// stereo at 48kHz in 20ms frames
+    #[default]
+    Normal,
+    /// like normal, at a bitrate high enough for music in the background
+    High,
+}
+
+impl Profile {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "low" => Some(Profile::Low),
+            "normal" => Some(Profile::Normal),
+            "high" => Some(Profile::High),
+            _ => None,
+        }
+    }
+
+    /// Sent to the server with the hello, which narrows the room's codec down
+    /// to it for this connection, see `RoomCodec::negotiate`.
+    pub fn codec(self) -> RoomCodec {
+        match self {
+            Profile::Low => RoomCodec {
+                sample_rate: 16_000,
+                channels: 1,
+                max_bitrate: Some(16_000),
+                ..RoomCodec::default()
+            },
+            Profile::Normal | Profile::High => RoomCodec::default(),
+        }
+    }
+}
+
+/// Which of the server's addresses the client connects to, see `-4` and `-6`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpFamily {
+    /// whichever the resolver lists first, it orders them by what the host
+    /// can reach
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    /// The first of `addrs` of this family.
+    pub fn pick(self, addrs: impl IntoIterator<Item = SocketAddr>) -> Option<SocketAddr> {
+        addrs.into_iter().find(|addr| match self {
+            IpFamily::Any => true,
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 => addr.is_ipv6(),
+        })
+    }
+}
+
+impl fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IpFamily::Any => "any",
+            IpFamily::V4 => "IPv4",
+            IpFamily::V6 => "IPv6",
+        })
+    }
+}
+
+/// `bitrate` held to a room's limit, see `RoomCodec`.
+pub fn capped_bitrate(bitrate: Bitrate, cap: Option<u32>) -> Bitrate {
+    match (bitrate, cap) {
+        (Bitrate::Bits(bits), Some(cap)) => Bitrate::Bits(bits.min(cap as i32)),
+        (_, Some(cap)) => Bitrate::Bits(cap as i32),
+        (bitrate, None) => bitrate,
+    }
+}
+
+/// Parses `--bitrate`: auto, max or kbps between 6 and 510, what opus takes.
+pub fn parse_bitrate(value: &str) -> Option<Bitrate> {
+    match value {
+        "auto" => Some(Bitrate::Auto),
+        "max" => Some(Bitrate::Max),
+        kbps => match kbps.parse::<i32>().ok()? {
+            kbps @ 6..=510 => Some(Bitrate::Bits(kbps * 1000)),
+            _ => None,
+        },
+    }
+}
+
+/// The step above or below `bitrate`.
+pub fn step_bitrate(bitrate: Bitrate, up: bool) -> i32 {
+    let bits = bitrate_bits(bitrate);
+    let step = match up {
+        true => BITRATE_STEPS.iter().find(|step| **step > bits),
+        false => BITRATE_STEPS.iter().rev().find(|step| **step < bits),
+    };
+    step.copied().unwrap_or(bits)
+}
+
+/// Runtime audio parameters shared by the capture, playback and codec paths.
+#[derive(Debug, Clone)]
+pub struct AudioSettings {
+    /// samples per channel in one opus frame
+    pub frame_size: usize,
+    pub application: Application,
+    /// number of frames the jitter buffer tries to keep queued
+    pub jitter_target: usize,
+    /// number of frames PulseAudio buffers on playback
+    pub playback_frames: u32,
+    /// number of frames PulseAudio may prebuffer before starting playback
+    pub prebuf_frames: u32,
+    pub bitrate: Bitrate,
+    pub bitrate_mode: BitrateMode,
+    /// only transmit while the voice activity detection hears something
+    pub vad: bool,
+    /// input level in dBFS below which the voice activity detection hears silence
+    pub vad_threshold_db: f32,
+    /// frames still sent after the level dropped below the threshold, so
+    /// word endings and short pauses aren't cut
+    pub vad_hangover: u32,
+    /// remove what the speakers play from the microphone
+    pub echo_cancellation: bool,
+    /// how loud everything is played, in percent
+    pub output_volume: u32,
+    /// only transmit while the push-to-talk key is held, overrides `vad`
+    pub push_to_talk: bool,
+    /// deafening also mutes the microphone
+    pub deafen_mutes: bool,
+    /// close the audio devices while muted and deafened, so other applications
+    /// can have the microphone and the laptop can save power
+    pub release_devices: bool,
+    /// how long a sender may stay silent before its decoder state is dropped
+    pub stream_timeout: Duration,
+    /// how long after the last packet a sender is still shown as speaking
+    pub speaking_timeout: Duration,
+    /// whether this endpoint captures and plays audio, a linked device used as
+    /// remote control only takes part in the control traffic
+    pub audio_sink: bool,
+    /// capture devices by index or name in order of preference, `default` for
+    /// PulseAudio's default, empty to pick one by its form factor
+    pub mics: Vec<String>,
+    /// what the voice is sent as at most, see `with_profile`
+    pub profile: Profile,
+    /// what the devices are opened with, see `--backend`
+    pub backend: &'static dyn AudioBackend,
+    /// playback device by index or name, PulseAudio's default if unset
+    pub output_device: Option<String>,
+    /// application whose playback is mixed into the microphone, by stream index or name
+    pub share_app: Option<String>,
+    pub shared_mix: ProducerMix,
+    /// send the shared application as a listen-along stream of its own
+    pub listen_along: bool,
+    /// loudness in LUFS listen-along music is normalized to, `None` leaves it as is
+    pub music_loudness: Option<f32>,
+    /// loudness in LUFS each voice is normalized to before mixing, `None`
+    /// plays them as sent
+    pub voice_loudness: Option<f32>,
+    /// how wide stereo is played, 1 as sent, 0 mono for single-ear headsets
+    pub stereo_width: f32,
+    /// peak level in dBFS playback is limited to, 0 for full scale
+    pub output_ceiling_db: f32,
+    /// play the room playlist's local files as the listen-along stream instead
+    /// of a shared application
+    pub play_queue: bool,
+    /// overlap between consecutive playlist tracks, zero splices them gaplessly
+    pub crossfade: Duration,
+    /// packet loss in percent the encoder prepares for with in-band FEC, 0 turns FEC off
+    pub expected_loss: u8,
+    /// lower the bitrate and raise FEC while the server reports loss
+    pub adaptive_bitrate: bool,
+    /// directory calls are recorded to, one lossless file per speaker
+    pub record_dir: Option<PathBuf>,
+    /// the recording of the running session, started with it from `record_dir`
+    pub recording: Option<Recording>,
+    /// speech-to-text command our voice is posted to the chat with, `{}`
+    /// stands for a WAV file of what was said, it prints the text
+    pub speech_to_chat: Option<String>,
+    /// run the encryption handshake with the server and encrypt all traffic
+    pub encrypt: bool,
+    /// which of the server's addresses to connect to
+    pub ip_family: IpFamily,
+    /// how long nothing may be sent before a keepalive is, so routers on the
+    /// way don't forget the connection while everyone is silent
+    pub keepalive: Duration,
+    /// how long the server may stay silent before the connection counts as
+    /// lost, it answers the latency probes every few seconds
+    pub server_timeout: Duration,
+    /// password of the server, if it has one
+    pub password: Option<String>,
+    /// shown to the others in the user list
+    pub name: String,
+}
+
+/// How the audio of one producer goes into the outgoing mix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProducerMix {
+    /// linear gain
+    pub gain: f32,
+    /// fold both channels into the centre, e.g. for games that pan hard
+    pub mono: bool,
+}
+
+impl Default for ProducerMix {
+    fn default() -> Self {
+        ProducerMix {
+            gain: 1.0,
+            mono: false,
+        }
+    }
+}
+
+impl ProducerMix {
+    /// Linear gain for `background` (-12dB), `quiet` (-6dB), `full` (0dB) or a
+    /// gain in dB.
+    pub fn gain_preset(name: &str) -> Option<f32> {
+        let db = match name {
+            "background" => -12.0,
+            "quiet" => -6.0,
+            "full" => 0.0,
+            db => db.parse::<f32>().ok()?,
+        };
+        Some(10f32.powf(db / 20.0))
+    }
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        AudioSettings {
+            frame_size: FRAME_SIZE,
+            application: Application::Voip,
+            jitter_target: 3,
+            playback_frames: 3,
+            prebuf_frames: 2,
+            bitrate: Bitrate::Auto,
+            bitrate_mode: BitrateMode::Vbr,
+            vad: true,
+            // 200 in 16 bit samples
+            vad_threshold_db: -44.0,
+            vad_hangover: 10,
+            echo_cancellation: true,
+            output_volume: 100,
+            push_to_talk: false,
+            deafen_mutes: true,
+            release_devices: false,
+            stream_timeout: Duration::from_millis(1000),
+            speaking_timeout: Duration::from_millis(500),
+            audio_sink: true,
+            mics: Vec::new(),
+            profile: Profile::default(),
+            backend: BACKENDS[0],
+            output_device: None,
+            share_app: None,
+            shared_mix: ProducerMix::default(),
+            listen_along: false,
+            music_loudness: Some(DEFAULT_MUSIC_LOUDNESS),
+            voice_loudness: Some(DEFAULT_VOICE_LOUDNESS),
+            stereo_width: 1.0,
+            output_ceiling_db: 0.0,
+            play_queue: false,
+            crossfade: Duration::from_secs(2),
+            expected_loss: 10,
+            adaptive_bitrate: true,
+            record_dir: None,
+            recording: None,
+            speech_to_chat: None,
+            encrypt: true,
+            ip_family: IpFamily::Any,
+            keepalive: Duration::from_secs(15),
+            server_timeout: Duration::from_secs(10),
+            password: None,
+            name: std::env::var("USER").unwrap_or_default(),
+        }
+    }
+}
+
+impl AudioSettings {
+    /// 10ms frames, the smallest buffers PulseAudio can sensibly run with and
+    /// the restricted low-delay opus mode, which skips most of the look-ahead.
+    pub fn low_latency(self) -> Self {
+        AudioSettings {
+            frame_size: FRAME_SIZE / 2,
+            application: Application::LowDelay,
+            jitter_target: 1,
+            playback_frames: 2,
+            prebuf_frames: 1,
+            ..self
+        }
+    }
+
+    /// Full band stereo for music, transmitted continuously so quiet passages
+    /// aren't cut off by the voice activity detection.
+    pub fn music(self) -> Self {
+        AudioSettings {
+            application: Application::Audio,
+            bitrate: Bitrate::Bits(128_000),
+            vad: false,
+            ..self
+        }
+    }
+
+    /// Frames of `ms` milliseconds, one of `FRAME_MS`. The jitter and playback
+    /// buffers hold about as long as with the default 20ms, at least a frame.
+    pub fn with_frame_ms(self, ms: usize) -> Self {
+        let frame_size = SAMPLE_RATE as usize * ms / 1000;
+        let default = AudioSettings::default();
+        let frames = |default_frames: usize| (default_frames * FRAME_SIZE).div_ceil(frame_size);
+        AudioSettings {
+            frame_size,
+            jitter_target: frames(default.jitter_target),
+            playback_frames: frames(default.playback_frames as usize) as u32,
+            prebuf_frames: frames(default.prebuf_frames as usize) as u32,
+            ..self
+        }
+    }
+
+    /// Frame length and bitrate of `profile`, the channels and sample rate
+    /// are agreed on with the server, see `Profile::codec`.
+    pub fn with_profile(self, profile: Profile) -> Self {
+        let settings = AudioSettings { profile, ..self };
+        match profile {
+            // half the packets, and so half the header overhead
+            Profile::Low => AudioSettings {
+                bitrate: Bitrate::Bits(16_000),
+                ..settings.with_frame_ms(40)
+            },
+            Profile::Normal => settings,
+            Profile::High => AudioSettings {
+                bitrate: Bitrate::Bits(128_000),
+                ..settings
+            },
+        }
+    }
+
+    /// size in bytes of one frame of 16 bit interleaved samples
+    pub fn buf_size(&self) -> u32 {
+        (self.frame_size * CHANNELS * std::mem::size_of::<i16>()) as u32
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_micros(self.frame_size as u64 * 1_000_000 / SAMPLE_RATE as u64)
+    }
+
+    pub fn frame_ms(&self) -> f32 {
+        self.frame_size as f32 * 1000.0 / SAMPLE_RATE as f32
+    }
+
+    /// Rough mouth-to-ear estimate excluding the network: one frame of capture,
+    /// the encoder look-ahead, the jitter buffer and the playback buffer.
+    pub fn latency_estimate_ms(&self) -> f32 {
+        let lookahead_ms = match self.application {
+            Application::LowDelay => 2.5,
+            _ => 6.5,
+        };
+        let frames = 1 + self.jitter_target as u32 + self.playback_frames;
+        frames as f32 * self.frame_ms() + lookahead_ms
+    }
+}
+
+/// Runtime options of the server.
+#[derive(Debug, Clone)]
+pub struct ServerSettings {
+    /// move users that neither talked nor used a control for this long to the AFK room
+    pub afk_timeout: Option<Duration>,
+    /// how long voice messages wait for their recipient
+    pub voice_message_ttl: Duration,
+    /// turn away clients that don't encrypt their traffic
+    pub require_encryption: bool,
+    /// only clients that know it may join
+    pub password: Option<String>,
+    /// how far behind the host listen-along music is played
+    pub listen_along_delay: Duration,
+    /// voices forwarded at once, the rest wait until one of them stops
+    pub max_speakers: Option<usize>,
+    /// clients connected at once, anyone joining beyond is turned away
+    pub max_clients: Option<usize>,
+    /// how long a client may send nothing before it is dropped, clients
+    /// probe the latency every few seconds and send keepalives while silent
+    pub client_timeout: Duration,
+    /// how the clients encode their voice in this room
+    pub codec: RoomCodec,
+    /// packets and bytes per second one address may send, anything beyond is
+    /// dropped and keeping it up for a few seconds gets it banned for a while
+    pub max_packet_rate: u32,
+    pub max_byte_rate: u64,
+}
+
+impl ServerSettings {
+    /// What clients are told the server does beyond the basics, e.g. to tell
+    /// why one can't join without a password.
+    pub fn features(&self) -> Vec<String> {
+        let mut features = Vec::new();
+        if self.require_encryption {
+            features.push("encryption required".to_string());
+        }
+        if self.password.is_some() {
+            features.push("password".to_string());
+        }
+        if let Some(timeout) = self.afk_timeout {
+            features.push(format!("AFK after {}s", timeout.as_secs()));
+        }
+        features.push(format!(
+            "voice messages kept {}h",
+            self.voice_message_ttl.as_secs() / 3600
+        ));
+        features.push(format!(
+            "listen-along delay {}ms",
+            self.listen_along_delay.as_millis()
+        ));
+        if let Some(max) = self.max_speakers {
+            features.push(format!("up to {} speakers at once", max));
+        }
+        if let Some(max) = self.max_clients {
+            features.push(format!("up to {} clients", max));
+        }
+        features.push(format!(
+            "drops clients silent for {}s",
+            self.client_timeout.as_secs()
+        ));
+        if self.codec != RoomCodec::default() {
+            features.push(format!("{} voice", self.codec.describe()));
+        }
+        features
+    }
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            afk_timeout: None,
+            voice_message_ttl: Duration::from_secs(24 * 60 * 60),
+            require_encryption: false,
+            password: None,
+            listen_along_delay: Duration::from_millis(400),
+            max_speakers: None,
+            max_clients: None,
+            client_timeout: Duration::from_secs(30),
+            codec: RoomCodec::default(),
+            // a client sends at most a few hundred, voice, music and probes
+            // together, this leaves room for a few behind the same NAT
+            max_packet_rate: 1000,
+            max_byte_rate: 1024 * 1024,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_bitrate_within_bounds() {
+        assert_eq!(step_bitrate(Bitrate::Auto, true), 96_000);
+        assert_eq!(step_bitrate(Bitrate::Bits(40_000), false), 32_000);
+        assert_eq!(step_bitrate(Bitrate::Bits(16_000), false), 16_000);
+        assert_eq!(step_bitrate(Bitrate::Max, true), 192_000);
+    }
+
+    #[test]
+    fn picks_an_address_of_the_family() {
+        let addrs: Vec<SocketAddr> = vec![
+            "[::1]:1234".parse().unwrap(),
+            "127.0.0.1:1234".parse().unwrap(),
+        ];
+        assert_eq!(IpFamily::Any.pick(addrs.clone()), Some(addrs[0]));
+        assert_eq!(IpFamily::V4.pick(addrs.clone()), Some(addrs[1]));
+        assert_eq!(IpFamily::V6.pick(addrs[1..].to_vec()), None);
+    }
+
+    #[test]
+    fn parses_room_codecs() {
+        let radio = RoomCodec::parse("mono,24").unwrap();
+        assert_eq!((radio.channels, radio.sample_rate), (1, SAMPLE_RATE));
+        assert_eq!(radio.max_bitrate, Some(24_000));
+        let narrow = RoomCodec::parse("mono, 12, 8").unwrap();
+        assert_eq!(narrow.describe(), "mono, 8kHz, up to 12kbps");
+        assert_eq!(RoomCodec::parse("stereo"), Some(RoomCodec::default()));
+        assert_eq!(RoomCodec::parse("opus,stereo"), Some(RoomCodec::default()));
+        let lan = RoomCodec::parse("pcm,mono,16").unwrap();
+        assert_eq!(lan.describe(), "PCM, mono, 16kHz");
+        assert_eq!(lan.max_bitrate, None);
+        let packet_radio = RoomCodec::parse("codec2,1200").unwrap();
+        assert_eq!(packet_radio.describe(), "Codec2, mono, 8kHz, 1200bit/s");
+        assert_eq!(packet_radio.negotiate(&Profile::Low.codec()), packet_radio);
+        assert_eq!(RoomCodec::parse("codec2").unwrap().max_bitrate, Some(3200));
+        for invalid in [
+            "",
+            "quad",
+            "mono,2",
+            "mono,24,44",
+            "mono,24,48,1",
+            "pcm",
+            "pcm,mono,24,16",
+            "codec2,mono",
+            "codec2,3000",
+            "codec2,3200,8",
+        ] {
+            assert_eq!(RoomCodec::parse(invalid), None, "{}", invalid);
+        }
+        assert_eq!(
+            capped_bitrate(Bitrate::Bits(64_000), radio.max_bitrate),
+            Bitrate::Bits(24_000)
+        );
+        assert_eq!(
+            capped_bitrate(Bitrate::Max, radio.max_bitrate),
+            Bitrate::Bits(24_000)
+        );
+        assert_eq!(
+            capped_bitrate(Bitrate::Bits(16_000), radio.max_bitrate),
+            Bitrate::Bits(16_000)
+        );
+    }
+
+    #[test]
+    fn negotiates_the_room_codec_down_to_the_profile() {
+        let low = Profile::parse("low").unwrap().codec();
+        let radio = RoomCodec::parse("mono,24").unwrap();
+        assert_eq!(
+            radio.negotiate(&low).describe(),
+            "mono, 16kHz, up to 16kbps"
+        );
+        assert_eq!(RoomCodec::default().negotiate(&low), low);
+        let normal = Profile::Normal.codec();
+        assert_eq!(radio.negotiate(&normal), radio);
+        let lan = RoomCodec::parse("pcm,stereo").unwrap();
+        assert_eq!(lan.negotiate(&low), lan);
+
+        let settings = AudioSettings::default().with_profile(Profile::Low);
+        assert_eq!(settings.frame_ms(), 40.0);
+        assert_eq!((settings.jitter_target, settings.playback_frames), (2, 2));
+        assert_eq!(Profile::parse("medium"), None);
+    }
+
+    #[test]
+    fn scales_the_buffers_with_the_frame_length() {
+        for ms in FRAME_MS {
+            let settings = AudioSettings::default().with_frame_ms(ms);
+            assert_eq!(settings.frame_ms(), ms as f32);
+            // never less buffered than by default, nor a frame more than needed
+            let jitter_ms = settings.jitter_target * ms;
+            assert!((60..60 + ms).contains(&jitter_ms), "{}ms", ms);
+            assert!(settings.prebuf_frames >= 1);
+        }
+        let short = AudioSettings::default().with_frame_ms(10);
+        assert_eq!(
+            (
+                short.jitter_target,
+                short.playback_frames,
+                short.prebuf_frames
+            ),
+            (6, 6, 4)
+        );
+        // 60ms of 16 bit stereo still fits an audio message
+        let long = AudioSettings::default().with_frame_ms(60);
+        assert!((long.buf_size() as usize) < crate::BUF_SIZE as usize);
+    }
+
+    #[test]
+    fn server_features_leave_out_the_password() {
+        let settings = ServerSettings {
+            password: Some("hunter2".into()),
+            afk_timeout: Some(Duration::from_secs(300)),
+            ..ServerSettings::default()
+        };
+        assert_eq!(
+            settings.features(),
+            [
+                "password",
+                "AFK after 300s",
+                "voice messages kept 24h",
+                "listen-along delay 400ms",
+                "drops clients silent for 30s"
+            ]
+        );
+    }
+}
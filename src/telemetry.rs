@@ -0,0 +1,212 @@
+use std::{fmt::Write, time::Duration};
+
+use log::{debug, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{Instant, interval_at},
+};
+
+use crate::{
+    Error, admin::json_string, bus::Subscriber, client::ClientMessage, quality::HealthReport,
+};
+
+// how much of a call one report sums up, a call that ends before is not reported
+const REPORT_INTERVAL: Duration = Duration::from_secs(300);
+// upper bounds of the round trip time buckets, above the last is one more
+const RTT_BUCKETS_MS: [u32; 4] = [50, 100, 200, 400];
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where reports are posted to, from `--telemetry http://host[:port][/path]`.
+/// Only plain http, the reports hold nothing worth encrypting.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Endpoint {
+    /// host and port to connect to
+    pub addr: String,
+    pub host: String,
+    pub path: String,
+}
+
+impl Endpoint {
+    pub fn parse(url: &str) -> Result<Self, Error> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            return Err(Error::Invalid(format!(
+                "Can't use {} for telemetry, only http:// endpoints are supported",
+                url
+            )));
+        };
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(Error::Invalid(format!("No host in {}", url)));
+        }
+        let addr = match host.contains(':') {
+            true => host.to_string(),
+            false => format!("{}:80", host),
+        };
+        Ok(Endpoint {
+            addr,
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+/// The health reports of a report interval taken together. Only counts and
+/// buckets, nothing about who is in the call, what they said or where the
+/// server is.
+#[derive(Debug, Default, PartialEq)]
+pub struct Aggregate {
+    reports: u32,
+    frames: u64,
+    underruns: u64,
+    received: u64,
+    lost: u64,
+    /// reports by round trip time, one more bucket than bounds
+    rtt: [u32; RTT_BUCKETS_MS.len() + 1],
+}
+
+impl Aggregate {
+    pub fn add(&mut self, report: &HealthReport) {
+        self.reports += 1;
+        self.frames += report.frames as u64;
+        self.underruns += report.underruns as u64;
+        self.received += report.loss.received as u64;
+        self.lost += report.loss.lost as u64;
+        // no probe answered yet
+        if report.rtt_ms > 0 {
+            let bucket = RTT_BUCKETS_MS
+                .iter()
+                .take_while(|&&bound| report.rtt_ms >= bound)
+                .count();
+            self.rtt[bucket] += 1;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reports == 0
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        let _ = write!(
+            json,
+            "{{\"version\":{},\"os\":{},\"arch\":{},\"reports\":{},\"frames\":{},\"underruns\":{},\"received\":{},\"lost\":{},\"rtt_ms\":{{",
+            json_string(env!("CARGO_PKG_VERSION")),
+            json_string(std::env::consts::OS),
+            json_string(std::env::consts::ARCH),
+            self.reports,
+            self.frames,
+            self.underruns,
+            self.received,
+            self.lost,
+        );
+        let mut lower = 0;
+        for (i, count) in self.rtt.iter().enumerate() {
+            let bucket = match RTT_BUCKETS_MS.get(i) {
+                Some(upper) => format!("{}-{}", lower, upper),
+                None => format!("{}+", lower),
+            };
+            let separator = if i == 0 { "" } else { "," };
+            let _ = write!(json, "{}{}:{}", separator, json_string(&bucket), count);
+            lower = RTT_BUCKETS_MS.get(i).copied().unwrap_or(lower);
+        }
+        json.push_str("}}");
+        json
+    }
+}
+
+/// Posts the call quality to `endpoint` every few minutes while in a call.
+/// Only runs with `--telemetry`, a report that can't be sent is dropped.
+pub async fn run_telemetry(endpoint: Endpoint, mut events: Subscriber<ClientMessage>) {
+    let mut aggregate = Aggregate::default();
+    let mut interval = interval_at(Instant::now() + REPORT_INTERVAL, REPORT_INTERVAL);
+    loop {
+        tokio::select! {
+            msg = events.recv() => match msg {
+                Some(ClientMessage::Health(report)) => aggregate.add(&report),
+                Some(_) => {}
+                None => return,
+            },
+            _ = interval.tick() => {
+                if aggregate.is_empty() {
+                    continue;
+                }
+                let body = std::mem::take(&mut aggregate).to_json();
+                match tokio::time::timeout(SEND_TIMEOUT, post(&endpoint, &body)).await {
+                    Ok(Ok(())) => debug!("Sent telemetry to {}", endpoint.host),
+                    Ok(Err(e)) => warn!("Can't send telemetry: {}", e),
+                    Err(_) => warn!("Can't send telemetry: {} doesn't answer", endpoint.host),
+                }
+            }
+        }
+    }
+}
+
+async fn post(endpoint: &Endpoint, body: &str) -> Result<(), Error> {
+    let error = |e: std::io::Error| Error::Network(format!("{}: {}", endpoint.host, e));
+    let mut stream = TcpStream::connect(&endpoint.addr).await.map_err(error)?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: kop-audio/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        endpoint.path,
+        endpoint.host,
+        env!("CARGO_PKG_VERSION"),
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await.map_err(error)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.map_err(error)?;
+    let status = String::from_utf8_lossy(&response);
+    let status = status.lines().next().unwrap_or_default();
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(Error::Network(format!(
+            "{} answered {:?}",
+            endpoint.host, status
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quality::LossStats;
+
+    #[test]
+    fn sums_up_reports_without_identifying_anyone() {
+        let mut aggregate = Aggregate::default();
+        for rtt_ms in [0, 30, 120, 900] {
+            aggregate.add(&HealthReport {
+                frames: 250,
+                underruns: 5,
+                loss: LossStats {
+                    received: 240,
+                    lost: 10,
+                },
+                rtt_ms,
+            });
+        }
+        let json = aggregate.to_json();
+        assert!(json.contains("\"reports\":4,\"frames\":1000,\"underruns\":20"));
+        assert!(json.contains("\"received\":960,\"lost\":40"));
+        assert!(json.ends_with(
+            "\"rtt_ms\":{\"0-50\":1,\"50-100\":0,\"100-200\":1,\"200-400\":0,\"400+\":1}}"
+        ));
+    }
+
+    #[test]
+    fn parses_http_endpoints_only() {
+        let endpoint = Endpoint::parse("http://telemetry.example:8080/v1/report").unwrap();
+        assert_eq!(endpoint.addr, "telemetry.example:8080");
+        assert_eq!(endpoint.path, "/v1/report");
+        assert_eq!(
+            Endpoint::parse("http://example.org").unwrap().addr,
+            "example.org:80"
+        );
+        assert!(Endpoint::parse("https://example.org").is_err());
+        assert!(Endpoint::parse("http:///report").is_err());
+    }
+}
@@ -0,0 +1,58 @@
+/// A decoded WAV fixture: interleaved samples in [-1.0, 1.0].
+pub struct Wav {
+    pub rate: u32,
+    pub channels: usize,
+    pub samples: Vec<f32>,
+}
+
+/// Reads a 16 bit PCM fixture from `tests/fixtures`.
+pub fn read_fixture(name: &str) -> Wav {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    read_wav(std::path::Path::new(&path))
+}
+
+/// Reads a 16 bit PCM file.
+pub fn read_wav(path: &std::path::Path) -> Wav {
+    let data =
+        std::fs::read(path).unwrap_or_else(|e| panic!("Can't read {}: {}", path.display(), e));
+    assert_eq!(&data[0..4], b"RIFF");
+    assert_eq!(&data[8..12], b"WAVE");
+
+    let mut pos = 12;
+    let mut format = None;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let body = &data[pos + 8..pos + 8 + len];
+        match id {
+            b"fmt " => {
+                let channels = u16::from_le_bytes([body[2], body[3]]) as usize;
+                let rate = u32::from_le_bytes(body[4..8].try_into().unwrap());
+                let bits = u16::from_le_bytes([body[14], body[15]]);
+                assert_eq!(bits, 16, "only 16 bit fixtures are supported");
+                format = Some((rate, channels));
+            }
+            b"data" => {
+                let (rate, channels) = format.expect("fmt chunk before data chunk");
+                let samples = crate::audio::samples::read_le(body).collect();
+                return Wav {
+                    rate,
+                    channels,
+                    samples,
+                };
+            }
+            _ => {}
+        }
+        pos += 8 + len + len % 2;
+    }
+    panic!("{} has no data chunk", path.display());
+}
+
+/// Largest absolute difference between two signals of the same length.
+pub fn max_difference(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).abs())
+        .fold(0.0, f32::max)
+}
@@ -0,0 +1,225 @@
+use std::{
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use log::{debug, warn};
+use tokio::{
+    sync::mpsc::{
+        Receiver, Sender, UnboundedReceiver, UnboundedSender, channel, unbounded_channel,
+    },
+    time::sleep,
+};
+
+use crate::{
+    CHANNELS, Error, SAMPLE_RATE, audio::level_db, bot::command_line, bus::EventBus,
+    client::ClientMessage, recorder::WavWriter, settings::AudioSettings,
+};
+
+/// A pause this long ends what was said, whether the gate closed or not.
+const PAUSE: Duration = Duration::from_millis(800);
+/// Longer speech is cut here, speech-to-text models take about this much at once.
+const MAX_UTTERANCE: Duration = Duration::from_secs(25);
+/// Less speech than this is a cough or a click rather than words.
+const MIN_SPEECH: Duration = Duration::from_millis(300);
+// a second of frames, more means the command can't keep up
+const QUEUE_FRAMES: usize = 100;
+
+/// Starts posting what we say to the chat with `command`, see
+/// `--speech-to-chat`. Takes the voice frames as they are sent, so nothing
+/// said while muted or below the VAD gate is transcribed.
+pub fn speech_to_chat(
+    command: String,
+    settings: &AudioSettings,
+    bus: EventBus,
+) -> Sender<Vec<f32>> {
+    let (frames_tx, frames_rx) = channel(QUEUE_FRAMES);
+    let (utterances_tx, utterances_rx) = unbounded_channel();
+    let frames = |duration: Duration| {
+        (duration.as_micros() / settings.frame_duration().as_micros()).max(1) as usize
+    };
+    let utterance = Utterance {
+        pause_frames: frames(PAUSE),
+        max_frames: frames(MAX_UTTERANCE),
+        min_speech_frames: frames(MIN_SPEECH),
+        ..Utterance::default()
+    };
+    let threshold_db = settings.vad_threshold_db;
+    tokio::spawn(cut_utterances(
+        frames_rx,
+        utterance,
+        threshold_db,
+        utterances_tx,
+    ));
+    tokio::spawn(post_transcripts(utterances_rx, command, bus));
+    frames_tx
+}
+
+/// Hands on each utterance as it ends, at a pause in the frames or in the
+/// voice.
+async fn cut_utterances(
+    mut frames: Receiver<Vec<f32>>,
+    mut utterance: Utterance,
+    threshold_db: f32,
+    utterances: UnboundedSender<Vec<f32>>,
+) {
+    loop {
+        tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(pcm) => {
+                    let voiced = level_db(&pcm) >= threshold_db;
+                    if let Some(speech) = utterance.push(&pcm, voiced) {
+                        let _ = utterances.send(speech);
+                    }
+                }
+                None => break,
+            },
+            // the gate closed, nothing comes until we talk again
+            _ = sleep(PAUSE), if !utterance.is_empty() => {
+                if let Some(speech) = utterance.take() {
+                    let _ = utterances.send(speech);
+                }
+            }
+        }
+    }
+}
+
+/// One utterance after the other, so the chat has them in the order they
+/// were said.
+async fn post_transcripts(
+    mut utterances: UnboundedReceiver<Vec<f32>>,
+    command: String,
+    bus: EventBus,
+) {
+    while let Some(speech) = utterances.recv().await {
+        let command = command.clone();
+        let text = match tokio::task::spawn_blocking(move || transcribe(&command, &speech)).await {
+            Ok(Ok(text)) => text,
+            Ok(Err(e)) => {
+                warn!("{}", e);
+                continue;
+            }
+            Err(_) => continue,
+        };
+        // one line, whatever the command's output looks like
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if text.is_empty() {
+            debug!("Nothing recognized in an utterance");
+            continue;
+        }
+        bus.commands.publish(ClientMessage::SendChat(text));
+    }
+}
+
+/// Writes `speech` to a WAV file, runs `command` with `{}` replaced by it and
+/// returns what it printed.
+fn transcribe(command: &str, speech: &[f32]) -> Result<String, Error> {
+    // only we can read it, and it's gone whichever way this returns
+    let file = tempfile::Builder::new()
+        .prefix("kop-audio-stt-")
+        .suffix(".wav")
+        .tempfile()
+        .map_err(|e| Error::Io(format!("Can't create a temporary file: {}", e)))?;
+    let path = file.path();
+    // closed, and its header complete, before the command reads it
+    WavWriter::create(path)?
+        .write(speech)
+        .map_err(|e| Error::Io(format!("Can't write {}: {}", path.display(), e)))?;
+    let (program, args) = command_line(command, &path.to_string_lossy())?;
+    let output = Command::new(&program)
+        .args(&args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| Error::Io(format!("Can't run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(Error::Io(format!(
+            "Can't run {}: {}",
+            program, output.status
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// What was said since the last pause, in frames as they were sent.
+#[derive(Default)]
+struct Utterance {
+    samples: Vec<f32>,
+    frames: usize,
+    voiced_frames: usize,
+    // quiet frames since the last voiced one
+    quiet_frames: usize,
+    pause_frames: usize,
+    max_frames: usize,
+    min_speech_frames: usize,
+}
+
+impl Utterance {
+    fn is_empty(&self) -> bool {
+        self.frames == 0
+    }
+
+    /// Adds a frame, the utterance if it ended with it.
+    fn push(&mut self, pcm: &[f32], voiced: bool) -> Option<Vec<f32>> {
+        if !voiced && self.is_empty() {
+            return None;
+        }
+        self.samples.extend_from_slice(pcm);
+        self.frames += 1;
+        if voiced {
+            self.voiced_frames += 1;
+            self.quiet_frames = 0;
+        } else {
+            self.quiet_frames += 1;
+        }
+        if self.quiet_frames >= self.pause_frames || self.frames >= self.max_frames {
+            return self.take();
+        }
+        None
+    }
+
+    /// Ends the utterance, the samples unless there was too little speech.
+    fn take(&mut self) -> Option<Vec<f32>> {
+        let enough = self.voiced_frames >= self.min_speech_frames;
+        let samples = std::mem::take(&mut self.samples);
+        self.frames = 0;
+        self.voiced_frames = 0;
+        self.quiet_frames = 0;
+        debug!(
+            "Utterance of {}ms ended",
+            samples.len() as u64 * 1000 / (SAMPLE_RATE as u64 * CHANNELS as u64)
+        );
+        enough.then_some(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cuts_speech_at_pauses_and_drops_clicks() {
+        let mut utterance = Utterance {
+            pause_frames: 3,
+            max_frames: 10,
+            min_speech_frames: 2,
+            ..Utterance::default()
+        };
+        let voice = [0.5; 4];
+        let quiet = [0.0; 4];
+        assert_eq!(utterance.push(&quiet, false), None);
+        assert_eq!(utterance.push(&voice, true), None);
+        assert_eq!(utterance.push(&voice, true), None);
+        assert_eq!(utterance.push(&quiet, false), None);
+        assert_eq!(utterance.push(&quiet, false), None);
+        let speech = utterance.push(&quiet, false).unwrap();
+        assert_eq!(speech.len(), 5 * 4);
+        // a click followed by a pause
+        assert_eq!(utterance.push(&voice, true), None);
+        assert_eq!(utterance.take(), None);
+        assert!(utterance.is_empty());
+        // talking on and on
+        let cut = (0..10).find_map(|_| utterance.push(&voice, true)).unwrap();
+        assert_eq!(cut.len(), 10 * 4);
+    }
+}
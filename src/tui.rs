@@ -2,26 +2,39 @@ use log::debug;
 use ratatui::{
     DefaultTerminal, Frame,
     buffer::Buffer,
-    crossterm::event::{self, Event, KeyEventKind},
+    crossterm::{
+        event::{
+            self, Event, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+            PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+        },
+        execute,
+        terminal::supports_keyboard_enhancement,
+    },
     layout::{Constraint, Layout, Rect},
     style::Stylize,
     symbols::border,
-    text::{Line, Text},
+    text::{Line, Span, Text},
     widgets::{Block, Paragraph, Widget},
 };
 use std::{
-    io::Result,
+    io::{Result, stdout},
+    collections::VecDeque,
     net,
-    sync::{
-        Arc,
-        mpsc::{Receiver, Sender},
-    },
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::{
     ClientState,
+    bus::{EventBus, Subscriber},
     client::{self, ClientMessage},
+    config::Keys,
+    connection::{ConnectionCommand, ConnectionState},
+    header::PacketStats,
+    identity::UserId,
+    listen_along::MusicVote,
+    playlist::{Playlist, PlaylistCommand, TrackInfo},
+    quality::{Grade, LossStats, StreamQuality},
+    server::{ChatMessage, RoomInfo, ServerInfo},
 };
 
 #[derive(Debug)]
@@ -29,38 +42,105 @@ pub struct App {
     client_state: ClientState,
 
     main_widget: UserListWidget,
+    stats_widget: StatsWidget,
+    chat_widget: ChatWidget,
+    /// shown instead of the stats while a device is picked
+    device_picker: Option<DevicePicker>,
 
-    rx: Receiver<client::ClientMessage>,
-    tx_coordinator: Sender<client::ClientMessage>,
+    rx: Subscriber<client::ClientMessage>,
+    bus: EventBus,
+    /// latest system message from the server
+    announcement: Option<String>,
+    /// last press or repeat of the push-to-mute key while it is held
+    hold_key: Option<Instant>,
+    /// the same for the push-to-talk key
+    talk_key: Option<Instant>,
+    /// name of the recording marker being typed and when its key was pressed
+    marker: Option<(String, u64)>,
+    /// whether the terminal reports key releases, otherwise a held key is
+    /// recognized by its auto-repeat
+    release_events: bool,
+    /// keys bound to other actions in the config
+    keys: Keys,
 }
 
+// a bit more than the usual delay before a held key starts repeating
+const HOLD_RELEASE_TIMEOUT: Duration = Duration::from_millis(600);
+// chat lines kept for scrolling back, older ones are dropped
+const CHAT_HISTORY: usize = 200;
+// dB and frames per key press when tuning the voice activity detection
+const VAD_THRESHOLD_STEP: f32 = 2.0;
+const VAD_HANGOVER_STEP: i32 = 5;
+// percent per key press when changing how loud someone or everything is played
+const USER_VOLUME_STEP: i32 = 10;
+const OUTPUT_VOLUME_STEP: i32 = 5;
+
 impl App {
-    pub fn new(rx: Receiver<client::ClientMessage>, tx_coordinator: Sender<client::ClientMessage>) {
+    pub fn new(rx: Subscriber<client::ClientMessage>, bus: EventBus, keys: Keys) {
         let mut app = App {
             client_state: ClientState::default(),
             rx,
-            tx_coordinator,
-            main_widget: UserListWidget { users: vec![] },
+            bus,
+            announcement: None,
+            hold_key: None,
+            talk_key: None,
+            marker: None,
+            release_events: false,
+            keys,
+            main_widget: UserListWidget {
+                users: vec![],
+                room: None,
+                offline: vec![],
+                selected_offline: 0,
+                selected_user: None,
+                voice_messages: vec![],
+                playlist: Playlist::default(),
+                now_playing: None,
+                selected_track: 0,
+            },
+            stats_widget: StatsWidget::default(),
+            chat_widget: ChatWidget::default(),
+            device_picker: None,
         };
         let terminal = ratatui::init();
+        app.release_events = supports_keyboard_enhancement().unwrap_or(false)
+            && execute!(
+                stdout(),
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )
+            .is_ok();
         let result = app.run(terminal);
+        if app.release_events {
+            let _ = execute!(stdout(), PopKeyboardEnhancementFlags);
+        }
         ratatui::restore();
     }
 
     fn run(&mut self, mut terminal: DefaultTerminal) -> Result<()> {
         let mut should_draw = true;
-        while !self.client_state.exit {
+        while !self.client_state.exit && !self.bus.shutdown.is_triggered() {
             if should_draw {
                 terminal.draw(|frame| self.draw(frame))?;
             }
             should_draw = self.handle_tui_messages();
+            if !self.release_events
+                && self
+                    .hold_key
+                    .is_some_and(|last| last.elapsed() > HOLD_RELEASE_TIMEOUT)
+            {
+                self.release_hold_mute();
+            }
+            if !self.release_events
+                && self
+                    .talk_key
+                    .is_some_and(|last| last.elapsed() > HOLD_RELEASE_TIMEOUT)
+            {
+                self.release_push_to_talk();
+            }
             if let Ok(true) = event::poll(Duration::from_millis(100)) {
                 self.handle_event(event::read()?);
                 should_draw = true;
             }
-            if set_speaking_flags(&mut self.main_widget.users) {
-                should_draw = true;
-            }
         }
         Ok(())
     }
@@ -71,45 +151,201 @@ impl App {
             .constraints(vec![Constraint::Min(5), Constraint::Percentage(100)])
             .spacing(-1)
             .split(frame.area());
+        let main_layout = Layout::default()
+            .direction(ratatui::layout::Direction::Horizontal)
+            .constraints(vec![Constraint::Percentage(70), Constraint::Percentage(30)])
+            .split(layout[1]);
+        let left_layout = Layout::default()
+            .direction(ratatui::layout::Direction::Vertical)
+            .constraints(vec![Constraint::Percentage(60), Constraint::Percentage(40)])
+            .split(main_layout[0]);
         frame.render_widget(self, layout[0]);
-        frame.render_widget(&self.main_widget, layout[1]);
+        frame.render_widget(&self.main_widget, left_layout[0]);
+        frame.render_widget(&self.chat_widget, left_layout[1]);
+        match &self.device_picker {
+            Some(picker) => frame.render_widget(picker, main_layout[1]),
+            None => frame.render_widget(&self.stats_widget, main_layout[1]),
+        }
     }
 
     fn handle_tui_messages(&mut self) -> bool {
         let mut updated = false;
-        while let Ok(message) = self.rx.try_recv() {
+        while let Some(message) = self.rx.try_recv() {
             match message {
-                client::ClientMessage::Connect => {
-                    self.client_state.connected = true;
-                }
-                client::ClientMessage::Disconnect => {
-                    self.client_state.connected = false;
-                    self.client_state.sending_audio = false;
+                ClientMessage::ConnectionState(state) => {
+                    self.client_state.connection = state;
+                    if state != ConnectionState::Connected {
+                        self.client_state.grade = None;
+                        self.client_state.sending_audio = false;
+                    }
                 }
                 client::ClientMessage::TransmitAudio(sending) => {
                     self.client_state.sending_audio = sending;
                 }
-                client::ClientMessage::NewClient(addr) => {
+                ClientMessage::HoldMute(held) => {
+                    self.client_state.held_mute = held;
+                }
+                ClientMessage::PushToTalkMode(on) => {
+                    self.client_state.push_to_talk = on;
+                }
+                ClientMessage::PushToTalk(held) => {
+                    self.client_state.talking = held;
+                }
+                client::ClientMessage::Muted(muted) => {
+                    self.client_state.mute = muted;
+                }
+                client::ClientMessage::Deafened(deafened) => {
+                    self.client_state.deafen = deafened;
+                }
+                ClientMessage::Bitrate(bits) => {
+                    self.client_state.bitrate = Some(bits);
+                }
+                ClientMessage::Latency(rtt) => {
+                    self.client_state.latency = Some(rtt);
+                }
+                ClientMessage::Grade(grade) => {
+                    self.client_state.grade = Some(grade);
+                }
+                client::ClientMessage::LatencyEstimate(ms) => {
+                    self.stats_widget.latency_estimate_ms = Some(ms);
+                }
+                ClientMessage::PacketStats(stats) => {
+                    self.stats_widget.packet_stats = Some(stats);
+                }
+                ClientMessage::LossStats(stats) => {
+                    self.stats_widget.loss = Some(stats);
+                }
+                ClientMessage::Vad(threshold, hangover) => {
+                    self.stats_widget.vad = Some((threshold, hangover));
+                }
+                ClientMessage::InputLevel(level) => {
+                    self.stats_widget.input_level = Some(level);
+                }
+                ClientMessage::ServerInfo(server) => {
+                    self.stats_widget.server = Some(server);
+                }
+                ClientMessage::Devices(inputs, outputs) => {
+                    if let Some(picker) = &mut self.device_picker {
+                        *picker = DevicePicker {
+                            inputs,
+                            outputs,
+                            loaded: true,
+                            selected: 0,
+                        };
+                    }
+                }
+                ClientMessage::OutputVolume(volume) => {
+                    self.client_state.output_volume = Some(volume);
+                }
+                client::ClientMessage::NewClient(addr, name) => {
+                    self.main_widget
+                        .users
+                        .retain(|user| user.addr != addr);
                     self.main_widget.users.push(UserListEntry {
-                        addr: addr.to_string(),
+                        addr,
+                        name,
                         is_speaking: false,
-                        last_spoke: None,
+                        is_afk: false,
+                        quality: None,
+                        grade: None,
+                        volume: 100,
+                        muted: false,
                     });
                 }
+                ClientMessage::OfflineUsers(users) => {
+                    self.main_widget.offline = users;
+                    self.main_widget.selected_offline = 0;
+                }
+                ClientMessage::RecordingVoiceMessage(recording) => {
+                    self.client_state.recording_voice = recording;
+                }
+                ClientMessage::VoiceMessages(messages) => {
+                    self.main_widget.voice_messages = messages;
+                }
+                ClientMessage::Playlist(playlist) => {
+                    let selected = self.main_widget.selected_track;
+                    self.main_widget.selected_track =
+                        selected.min(playlist.queue.len().saturating_sub(1));
+                    self.main_widget.playlist = playlist;
+                }
+                ClientMessage::NowPlaying(info) => {
+                    self.main_widget.now_playing = info;
+                }
+                ClientMessage::MovedToAfk(afk) => {
+                    self.client_state.afk = afk;
+                }
+                ClientMessage::ClientAfk(addr, afk) => {
+                    if let Some(user) = self
+                        .main_widget
+                        .users
+                        .iter_mut()
+                        .find(|user| user.addr == addr)
+                    {
+                        user.is_afk = afk;
+                    }
+                }
+                ClientMessage::StreamQuality(addr, quality) => {
+                    if let Some(user) = self
+                        .main_widget
+                        .users
+                        .iter_mut()
+                        .find(|user| user.addr == addr)
+                    {
+                        user.quality = Some(quality);
+                    }
+                }
+                ClientMessage::UserGrade(addr, grade) => {
+                    if let Some(user) = self
+                        .main_widget
+                        .users
+                        .iter_mut()
+                        .find(|user| user.addr == addr)
+                    {
+                        user.grade = Some(grade);
+                    }
+                }
+                ClientMessage::Announcement(text) => {
+                    self.announcement = Some(text);
+                }
+                ClientMessage::Chat(chat) => {
+                    if self.chat_widget.messages.len() >= CHAT_HISTORY {
+                        self.chat_widget.messages.pop_front();
+                    }
+                    self.chat_widget.messages.push_back(chat);
+                }
+                ClientMessage::RoomInfo(room) => {
+                    self.main_widget.room = Some(room);
+                }
                 client::ClientMessage::DeleteClient(addr) => {
                     self.main_widget
                         .users
-                        .retain(|user| user.addr != addr.to_string());
+                        .retain(|user| user.addr != addr);
+                    let users = self.main_widget.users.len();
+                    self.main_widget.selected_user = self
+                        .main_widget
+                        .selected_user
+                        .filter(|_| users > 0)
+                        .map(|i| i.min(users.saturating_sub(1)));
                 }
-                ClientMessage::ShowActive(addr) => {
+                ClientMessage::UserVolume(addr, volume, muted) => {
                     if let Some(user) = self
                         .main_widget
                         .users
                         .iter_mut()
-                        .find(|user| user.addr == addr.to_string())
+                        .find(|user| user.addr == addr)
                     {
-                        user.is_speaking = true;
-                        user.last_spoke = Some(std::time::Instant::now());
+                        user.volume = volume;
+                        user.muted = muted;
+                    }
+                }
+                ClientMessage::Speaking(addr, speaking) => {
+                    if let Some(user) = self
+                        .main_widget
+                        .users
+                        .iter_mut()
+                        .find(|user| user.addr == addr)
+                    {
+                        user.is_speaking = speaking;
                     }
                 }
                 _ => {}
@@ -119,27 +355,301 @@ impl App {
         updated
     }
 
-    fn handle_event(&mut self, event: Event) {
+    /// Keys while the user list has focus, the rest of the controls wait
+    /// until it is left with U or Esc.
+    fn handle_user_list_key(&mut self, code: event::KeyCode) {
+        let users = &self.main_widget.users;
+        let Some(selected) = self.main_widget.selected_user.filter(|i| *i < users.len()) else {
+            self.main_widget.selected_user = None;
+            return;
+        };
+        let addr = users[selected].addr;
+        match code {
+            event::KeyCode::Up => self.main_widget.selected_user = Some(selected.saturating_sub(1)),
+            event::KeyCode::Down => {
+                self.main_widget.selected_user = Some((selected + 1).min(users.len() - 1));
+            }
+            event::KeyCode::Char('+') => {
+                self.bus.commands.publish(ClientMessage::AdjustUserVolume(addr, USER_VOLUME_STEP));
+            }
+            event::KeyCode::Char('-') => {
+                self.bus.commands.publish(ClientMessage::AdjustUserVolume(addr, -USER_VOLUME_STEP));
+            }
+            event::KeyCode::Char('m') | event::KeyCode::Char('M') => {
+                self.bus.commands.publish(ClientMessage::ToggleUserMute(addr));
+            }
+            event::KeyCode::Char('u') | event::KeyCode::Char('U') | event::KeyCode::Esc => {
+                self.main_widget.selected_user = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_device_picker_key(&mut self, code: event::KeyCode) {
+        let Some(picker) = &mut self.device_picker else {
+            return;
+        };
+        let devices = picker.inputs.len() + picker.outputs.len();
+        match code {
+            event::KeyCode::Up => picker.selected = picker.selected.saturating_sub(1),
+            event::KeyCode::Down => {
+                picker.selected = (picker.selected + 1).min(devices.saturating_sub(1));
+            }
+            event::KeyCode::Enter => {
+                if let Some(selection) = picker.selection() {
+                    self.bus.commands.publish(selection);
+                }
+                self.device_picker = None;
+            }
+            event::KeyCode::Char('o') | event::KeyCode::Char('O') | event::KeyCode::Esc => {
+                self.device_picker = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn release_hold_mute(&mut self) {
+        self.hold_key = None;
+        self.bus.commands.publish(ClientMessage::HoldMute(false));
+    }
+
+    fn release_push_to_talk(&mut self) {
+        self.talk_key = None;
+        self.bus.commands.publish(ClientMessage::PushToTalk(false));
+    }
+
+    /// Leaves the TUI, the coordinator says bye to the server meanwhile.
+    fn quit(&mut self) {
+        self.client_state.exit = true;
+        self.bus.commands.publish(client::ClientMessage::Exit);
+        debug!("Exiting TUI upon user request");
+    }
+
+    fn handle_event(&mut self, mut event: Event) {
+        // only the commands can be rebound, not what is typed
+        if let Event::Key(key_event) = &mut event
+            && !key_event.modifiers.contains(KeyModifiers::CONTROL)
+            && self.marker.is_none()
+            && self.chat_widget.input.is_none()
+            && self.device_picker.is_none()
+            && self.main_widget.selected_user.is_none()
+            && let event::KeyCode::Char(key) = key_event.code
+        {
+            key_event.code = match self.keys.translate(key) {
+                Some(key) => event::KeyCode::Char(key),
+                None => event::KeyCode::Null,
+            };
+        }
         match event {
+            // the terminal is in raw mode, so ctrl-c arrives as a key rather than a signal
+            Event::Key(key_event)
+                if key_event.modifiers.contains(KeyModifiers::CONTROL)
+                    && matches!(key_event.code, event::KeyCode::Char('c')) =>
+            {
+                match key_event.kind {
+                    KeyEventKind::Press => self.quit(),
+                    KeyEventKind::Repeat | KeyEventKind::Release => {}
+                }
+            }
+            Event::Key(key_event)
+                if self.marker.is_some() && key_event.kind != KeyEventKind::Release =>
+            {
+                let (name, _) = self.marker.as_mut().unwrap();
+                match key_event.code {
+                    event::KeyCode::Enter => {
+                        let (name, at) = self.marker.take().unwrap();
+                        let name = match name.trim() {
+                            "" => "Marker".to_string(),
+                            name => name.to_string(),
+                        };
+                        self.bus.commands.publish(ClientMessage::AddMarker(name, at));
+                    }
+                    event::KeyCode::Esc => self.marker = None,
+                    event::KeyCode::Backspace => {
+                        name.pop();
+                    }
+                    event::KeyCode::Char(c) => name.push(c),
+                    _ => {}
+                }
+            }
+            // while typing every key goes into the chat input
+            Event::Key(key_event)
+                if self.chat_widget.input.is_some() && key_event.kind != KeyEventKind::Release =>
+            {
+                let input = self.chat_widget.input.as_mut().unwrap();
+                match key_event.code {
+                    event::KeyCode::Enter => {
+                        let text = std::mem::take(input);
+                        self.chat_widget.input = None;
+                        if !text.trim().is_empty() {
+                            self.bus.commands.publish(ClientMessage::SendChat(text));
+                        }
+                    }
+                    event::KeyCode::Esc => self.chat_widget.input = None,
+                    event::KeyCode::Backspace => {
+                        input.pop();
+                    }
+                    event::KeyCode::Char(c) => input.push(c),
+                    _ => {}
+                }
+            }
+            Event::Key(key_event)
+                if self.device_picker.is_some() && key_event.kind == KeyEventKind::Press =>
+            {
+                self.handle_device_picker_key(key_event.code);
+            }
+            Event::Key(key_event)
+                if self.main_widget.selected_user.is_some() && key_event.kind == KeyEventKind::Press =>
+            {
+                self.handle_user_list_key(key_event.code);
+            }
+            Event::Key(key_event)
+                if matches!(key_event.code, event::KeyCode::Char('c') | event::KeyCode::Char('C')) =>
+            {
+                match key_event.kind {
+                    KeyEventKind::Press | KeyEventKind::Repeat => {
+                        if self.hold_key.is_none() {
+                            self.bus.commands.publish(ClientMessage::HoldMute(true));
+                        }
+                        self.hold_key = Some(Instant::now());
+                    }
+                    KeyEventKind::Release => self.release_hold_mute(),
+                }
+            }
+            Event::Key(key_event)
+                if self.client_state.push_to_talk && key_event.code == event::KeyCode::Char(' ') =>
+            {
+                match key_event.kind {
+                    KeyEventKind::Press | KeyEventKind::Repeat => {
+                        if self.talk_key.is_none() {
+                            self.bus.commands.publish(ClientMessage::PushToTalk(true));
+                        }
+                        self.talk_key = Some(Instant::now());
+                    }
+                    KeyEventKind::Release => self.release_push_to_talk(),
+                }
+            }
             // it's important to check that the event is a key press event as
             // crossterm also emits key release and repeat events on Windows.
             Event::Key(key_event) if key_event.kind == KeyEventKind::Press => {
                 match key_event.code {
                     event::KeyCode::Char('d') | event::KeyCode::Char('D') => {
-                        self.client_state.deafen = !self.client_state.deafen;
-                        let _ = self
-                            .tx_coordinator
-                            .send(client::ClientMessage::ToggleDeafen);
+                        self.bus.commands.publish(client::ClientMessage::ToggleDeafen);
                     }
                     event::KeyCode::Char('m') | event::KeyCode::Char('M') => {
-                        self.client_state.mute = !self.client_state.mute;
-                        let _ = self.tx_coordinator.send(client::ClientMessage::ToggleMute);
+                        self.bus.commands.publish(client::ClientMessage::ToggleMute);
+                    }
+                    event::KeyCode::Tab => {
+                        let offline = self.main_widget.offline.len();
+                        if offline > 0 {
+                            self.main_widget.selected_offline =
+                                (self.main_widget.selected_offline + 1) % offline;
+                        }
+                    }
+                    event::KeyCode::Char('v') | event::KeyCode::Char('V') => {
+                        let to = match self.client_state.recording_voice {
+                            true => None,
+                            false => match self
+                                .main_widget
+                                .offline
+                                .get(self.main_widget.selected_offline)
+                            {
+                                Some(to) => Some(*to),
+                                None => return,
+                            },
+                        };
+                        self.bus.commands.publish(ClientMessage::RecordVoiceMessage(to));
+                    }
+                    event::KeyCode::Char('p') | event::KeyCode::Char('P') => {
+                        self.bus.commands.publish(ClientMessage::PlayVoiceMessage);
+                    }
+                    event::KeyCode::Char('o') | event::KeyCode::Char('O') => {
+                        self.device_picker = Some(DevicePicker::default());
+                        self.bus.commands.publish(ClientMessage::ListDevices);
+                    }
+                    event::KeyCode::Char('u') | event::KeyCode::Char('U')
+                        if !self.main_widget.users.is_empty() =>
+                    {
+                        self.main_widget.selected_user = Some(0);
+                    }
+                    event::KeyCode::Enter => self.chat_widget.input = Some(String::new()),
+                    event::KeyCode::Char('k') | event::KeyCode::Char('K') => {
+                        // the marker goes where the key was pressed, not where
+                        // typing its name ended
+                        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+                        self.marker = Some((String::new(), now.as_millis() as u64));
+                    }
+                    event::KeyCode::Up | event::KeyCode::Down => {
+                        let queued = self.main_widget.playlist.queue.len();
+                        let selected = &mut self.main_widget.selected_track;
+                        *selected = match key_event.code {
+                            event::KeyCode::Up => selected.saturating_sub(1),
+                            _ => (*selected + 1).min(queued.saturating_sub(1)),
+                        };
+                    }
+                    event::KeyCode::Char('+') | event::KeyCode::Char('-') => {
+                        let queue = &self.main_widget.playlist.queue;
+                        let Some(track) = queue.get(self.main_widget.selected_track) else {
+                            return;
+                        };
+                        let up = key_event.code == event::KeyCode::Char('+');
+                        self.bus
+                            .commands
+                            .publish(ClientMessage::PlaylistCommand(PlaylistCommand::Move(track.id, up)));
+                        // the selection moves along with the track
+                        self.main_widget.selected_track = match up {
+                            true => self.main_widget.selected_track.saturating_sub(1),
+                            false => (self.main_widget.selected_track + 1).min(queue.len() - 1),
+                        };
+                    }
+                    event::KeyCode::Char('n') | event::KeyCode::Char('N') => {
+                        self.bus.commands.publish(ClientMessage::MusicVote(MusicVote::Skip));
+                    }
+                    event::KeyCode::Char('x') | event::KeyCode::Char('X') => {
+                        self.bus.commands.publish(ClientMessage::MusicVote(MusicVote::Pause));
+                    }
+                    event::KeyCode::Char('>') => {
+                        self.bus.commands.publish(ClientMessage::MusicVote(MusicVote::Louder));
                     }
-                    event::KeyCode::Char('q') | event::KeyCode::Char('Q') => {
-                        self.client_state.exit = true;
-                        let _ = self.tx_coordinator.send(client::ClientMessage::Exit);
-                        debug!("Exiting TUI upon user request");
+                    event::KeyCode::Char('<') => {
+                        self.bus.commands.publish(ClientMessage::MusicVote(MusicVote::Quieter));
                     }
+                    event::KeyCode::Char(']') => {
+                        self.bus.commands.publish(ClientMessage::ChangeBitrate(true));
+                    }
+                    event::KeyCode::Char('[') => {
+                        self.bus.commands.publish(ClientMessage::ChangeBitrate(false));
+                    }
+                    event::KeyCode::Char('9') => {
+                        self.bus.commands.publish(ClientMessage::AdjustOutputVolume(-OUTPUT_VOLUME_STEP));
+                    }
+                    event::KeyCode::Char('0') => {
+                        self.bus.commands.publish(ClientMessage::AdjustOutputVolume(OUTPUT_VOLUME_STEP));
+                    }
+                    event::KeyCode::Char('(') => {
+                        self.bus.commands.publish(ClientMessage::AdjustVad(-VAD_THRESHOLD_STEP, 0));
+                    }
+                    event::KeyCode::Char(')') => {
+                        self.bus.commands.publish(ClientMessage::AdjustVad(VAD_THRESHOLD_STEP, 0));
+                    }
+                    event::KeyCode::Char('{') => {
+                        self.bus.commands.publish(ClientMessage::AdjustVad(0.0, -VAD_HANGOVER_STEP));
+                    }
+                    event::KeyCode::Char('}') => {
+                        self.bus.commands.publish(ClientMessage::AdjustVad(0.0, VAD_HANGOVER_STEP));
+                    }
+                    event::KeyCode::Char('s') | event::KeyCode::Char('S') => {
+                        self.bus
+                            .commands
+                            .publish(ClientMessage::PlaylistCommand(PlaylistCommand::VoteSkip));
+                    }
+                    event::KeyCode::Char('r') | event::KeyCode::Char('R') => {
+                        // also joins again after leaving the call
+                        self.bus
+                            .commands
+                            .publish(ClientMessage::Connection(ConnectionCommand::Reconnect));
+                    }
+                    event::KeyCode::Char('q') | event::KeyCode::Char('Q') => self.quit(),
                     _ => {}
                 }
             }
@@ -148,40 +658,35 @@ impl App {
     }
 }
 
-fn set_speaking_flags(users: &mut Vec<UserListEntry>) -> bool {
-    let mut updated = false;
-    let now = std::time::Instant::now();
-    for user in users.iter_mut() {
-        if user.is_speaking {
-            if let Some(last_spoke) = user.last_spoke {
-                if now.duration_since(last_spoke) > Duration::from_millis(500) {
-                    user.is_speaking = false;
-                    updated = true;
-                }
-            }
-        }
-    }
-    updated
-}
-
 impl Widget for &App {
     fn render(self, area: Rect, buf: &mut Buffer) {
         let mut status_line = vec![" WapplaTalk ".bold()];
-        let mutOrDeafen = self.client_state.mute || self.client_state.deafen;
+        let mutOrDeafen =
+            self.client_state.mute || self.client_state.held_mute || self.client_state.deafen;
         status_line.push("| ".into());
-        if self.client_state.connected {
-            status_line.push("Connected ".green())
-        } else {
-            status_line.push("Disconnected ".red())
-        };
+        status_line.push(match self.client_state.connection {
+            ConnectionState::Connected => "Connected ".green(),
+            ConnectionState::Connecting => "Connecting ".yellow(),
+            ConnectionState::Reconnecting => "Reconnecting ".yellow(),
+            ConnectionState::Disconnected => "Disconnected ".red(),
+            ConnectionState::ShuttingDown => "Leaving ".dim(),
+        });
+        if self.client_state.afk {
+            status_line.push("AFK ".dim())
+        }
+        if self.client_state.recording_voice {
+            status_line.push("Recording voice message ".red().bold())
+        }
         if mutOrDeafen {
             status_line.push("(".into());
         }
         if self.client_state.mute {
             status_line.push(" Muted".yellow())
+        } else if self.client_state.held_mute {
+            status_line.push(" Muted (held)".yellow())
         }
         if self.client_state.deafen {
-            if self.client_state.mute {
+            if self.client_state.mute || self.client_state.held_mute {
                 status_line.push(",".into());
             }
             status_line.push(" Deafened".yellow())
@@ -190,20 +695,74 @@ impl Widget for &App {
             status_line.push(" ) ".into());
         }
         status_line.push("| ".into());
+        if self.client_state.push_to_talk && !self.client_state.talking {
+            status_line.push("Hold <Space> to talk ".yellow())
+        }
         if self.client_state.sending_audio {
             status_line.push("Sending Audio ".green())
         } else {
             status_line.push("Not Sending Audio ".red())
         };
+        if let Some(rtt) = self.client_state.latency {
+            status_line.push("| ".into());
+            status_line.push(format!("{}ms ", rtt.as_millis()).into());
+        }
+        if let Some(grade) = self.client_state.grade {
+            status_line.push("| Connection ".into());
+            status_line.push(grade_badge(grade));
+            status_line.push(" ".into());
+        }
+        if let Some(bits) = self.client_state.bitrate {
+            status_line.push("| ".into());
+            status_line.push(format!("{}kbps ", bits / 1000).into());
+        }
+        if let Some(volume) = self.client_state.output_volume {
+            status_line.push("| ".into());
+            status_line.push(format!("Vol {}% ", volume).into());
+        }
 
         let status_line = Line::from(status_line);
+        let key = |default| format!("<{}>", self.keys.label(default)).blue().bold();
         let instructions = Line::from(vec![
             " Mute ".into(),
-            "<M>".blue().bold(),
+            key('m'),
             " Deafen ".into(),
-            "<D>".blue().bold(),
+            key('d'),
+            " Hold to mute ".into(),
+            key('c'),
+            " Voice message ".into(),
+            key('v'),
+            " Play ".into(),
+            key('p'),
+            " Skip track ".into(),
+            key('s'),
+            " Vote next ".into(),
+            key('n'),
+            " pause ".into(),
+            key('x'),
+            " volume ".into(),
+            "<</>>".blue().bold(),
+            " Output volume ".into(),
+            "<9/0>".blue().bold(),
+            " Bitrate ".into(),
+            "<[/]>".blue().bold(),
+            " Gate ".into(),
+            "<(/)>".blue().bold(),
+            " Hold ".into(),
+            "<{/}>".blue().bold(),
+            " Chat ".into(),
+            "<Enter>".blue().bold(),
+            " Mark ".into(),
+            key('k'),
+            " Users ".into(),
+            key('u'),
+            " Devices ".into(),
+            key('o'),
+            " Reconnect ".into(),
+            key('r'),
             " Quit ".into(),
-            "<Q> ".blue().bold(),
+            key('q'),
+            " ".into(),
         ]);
 
         let layout = Layout::default()
@@ -213,39 +772,335 @@ impl Widget for &App {
             .split(area);
 
         Paragraph::new(status_line.centered()).render(layout[0], buf);
-        Paragraph::new(instructions.centered()).render(layout[1], buf);
+        let mut lines = Vec::new();
+        if let Some(announcement) = &self.announcement {
+            lines.push(Line::from(announcement.as_str().magenta()).centered());
+        }
+        if let Some((name, _)) = &self.marker {
+            lines.push(Line::from(format!("Marker name: {}_", name).yellow()).centered());
+        }
+        lines.push(instructions.centered());
+        Paragraph::new(Text::from(lines)).render(layout[1], buf);
     }
 }
 
 #[derive(Debug)]
 struct UserListWidget {
     users: Vec<UserListEntry>,
+    room: Option<RoomInfo>,
+    /// users that can get a voice message, one of them selected with tab
+    offline: Vec<UserId>,
+    selected_offline: usize,
+    /// the user whose volume up/down, +/- and M change while the list has focus
+    selected_user: Option<usize>,
+    voice_messages: Vec<(UserId, u32)>,
+    /// queued tracks are selected with up/down and moved with +/-
+    playlist: Playlist,
+    selected_track: usize,
+    /// tags of what the listen-along host plays
+    now_playing: Option<TrackInfo>,
 }
 
 #[derive(Debug)]
 struct UserListEntry {
-    addr: String,
+    addr: net::SocketAddr,
+    name: String,
     is_speaking: bool,
-    last_spoke: Option<std::time::Instant>,
+    is_afk: bool,
+    quality: Option<StreamQuality>,
+    /// how their stream plays here, from A to F
+    grade: Option<Grade>,
+    /// how loud they are played here in percent, and whether only we muted them
+    volume: u32,
+    muted: bool,
 }
 
 impl Widget for &UserListWidget {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        let block = Block::bordered().title("Users").border_set(border::THICK);
+        let title = match &self.room {
+            Some(room) => room.name.as_str(),
+            None => "Users",
+        };
+        let block = Block::bordered().title(title).border_set(border::THICK);
         let inner_area = block.inner(area);
-        let user_lines: Vec<Line> = self
-            .users
-            .iter()
-            .map(|user| {
-                if user.is_speaking {
-                    Line::from(user.addr.as_str().green())
+        let mut user_lines: Vec<Line> = Vec::new();
+        if let Some(room) = &self.room {
+            if !room.topic.is_empty() {
+                user_lines.push(Line::from(room.topic.as_str().italic()));
+            }
+            for (key, value) in &room.metadata {
+                user_lines.push(Line::from(format!("{}: {}", key, value).dim()));
+            }
+            if !user_lines.is_empty() {
+                user_lines.push(Line::from(""));
+            }
+        }
+        user_lines.extend(self.users.iter().enumerate().map(|(i, user)| {
+            let mut line = if user.is_afk {
+                Line::from(format!("{} (AFK)", user.name).dim())
+            } else if user.is_speaking {
+                Line::from(user.name.as_str().green())
+            } else {
+                Line::from(user.name.as_str())
+            };
+            if let Some(quality) = user.quality {
+                line.push_span(" ");
+                line.push_span(quality_icon(quality));
+            }
+            if let Some(grade) = user.grade {
+                line.push_span(" ");
+                line.push_span(grade_badge(grade));
+            }
+            if user.muted {
+                line.push_span(" (muted)".yellow());
+            } else if user.volume != 100 {
+                line.push_span(format!(" {}%", user.volume).dim());
+            }
+            if self.selected_user == Some(i) {
+                line.spans.insert(0, "> ".into());
+                line.push_span("  +/- volume, M mute".dim());
+            }
+            line
+        }));
+        if !self.offline.is_empty() {
+            user_lines.push(Line::from(""));
+            user_lines.push(Line::from("Offline".bold()));
+            for (i, identity) in self.offline.iter().enumerate() {
+                if i == self.selected_offline {
+                    user_lines.push(Line::from(format!("> {}", identity).dim()));
                 } else {
-                    Line::from(user.addr.as_str())
+                    user_lines.push(Line::from(format!("  {}", identity).dim()));
                 }
-            })
-            .collect();
+            }
+        }
+        if !self.voice_messages.is_empty() {
+            user_lines.push(Line::from(""));
+            user_lines.push(Line::from("Voice messages".bold()));
+            for (from, secs) in &self.voice_messages {
+                user_lines.push(Line::from(format!("{} ({}s)", from, secs)));
+            }
+        }
+        if let Some(info) = self.now_playing.as_ref().filter(|_| self.playlist.current.is_none()) {
+            user_lines.push(Line::from(""));
+            user_lines.push(Line::from(vec!["Now playing: ".into(), info.describe().green()]));
+        }
+        if let Some(current) = &self.playlist.current {
+            // the tags once the host announced them, the file or URL until then
+            let playing = match &self.now_playing {
+                Some(info) => info.describe(),
+                None => current.source.clone(),
+            };
+            user_lines.push(Line::from(""));
+            user_lines.push(Line::from("Playlist".bold()));
+            user_lines.push(Line::from(vec![
+                "Now playing: ".into(),
+                playing.green(),
+                format!(
+                    " (skip {}/{})",
+                    self.playlist.skip_votes, self.playlist.votes_needed
+                )
+                .dim(),
+            ]));
+            for (i, track) in self.playlist.queue.iter().enumerate() {
+                let marker = if i == self.selected_track { ">" } else { " " };
+                user_lines.push(Line::from(format!("{} {}. {}", marker, i + 1, track.source)));
+            }
+        }
         let paragraph = Paragraph::new(Text::from(user_lines));
         block.render(area, buf);
         paragraph.render(inner_area, buf);
     }
 }
+
+/// A bar from -60 to 0 dBFS, green above the voice activity threshold, with
+/// the threshold marked.
+fn level_meter(level: f32, threshold: Option<f32>) -> Span<'static> {
+    const WIDTH: usize = 20;
+    let position = |db: f32| (((db + 60.0) / 60.0).clamp(0.0, 1.0) * WIDTH as f32) as usize;
+    let filled = position(level);
+    let mut bar: String = (0..WIDTH).map(|i| if i < filled { '█' } else { '░' }).collect();
+    if let Some(threshold) = threshold {
+        let mark = position(threshold).min(WIDTH - 1);
+        bar.replace_range(bar.char_indices().nth(mark).map(|(i, c)| i..i + c.len_utf8()).unwrap(), "|");
+    }
+    match threshold.is_none_or(|threshold| level >= threshold) {
+        true => bar.green(),
+        false => bar.dim(),
+    }
+}
+
+/// Signal style bars for the sender's bandwidth plus its bitrate.
+fn quality_icon(quality: StreamQuality) -> Span<'static> {
+    let bars = quality.bandwidth.bars();
+    let icon: String = "▂▄▆█"
+        .chars()
+        .enumerate()
+        .map(|(i, bar)| if i < bars { bar } else { '·' })
+        .collect();
+    let text = format!("{} {}kbps", icon, quality.kbps);
+    match bars {
+        1 => text.red(),
+        2 => text.yellow(),
+        _ => text.green(),
+    }
+}
+
+/// The grade as a letter, green while nothing is to be heard of it.
+fn grade_badge(grade: Grade) -> Span<'static> {
+    let letter = grade.letter().to_string().bold();
+    match grade {
+        Grade::A | Grade::B => letter.green(),
+        Grade::C => letter.yellow(),
+        Grade::D | Grade::F => letter.red(),
+    }
+}
+
+/// Capture and playback devices of the machine in the call, one of them
+/// selected with up/down.
+#[derive(Debug, Default)]
+struct DevicePicker {
+    /// name and description of each
+    inputs: Vec<(String, String)>,
+    outputs: Vec<(String, String)>,
+    loaded: bool,
+    /// counted across the inputs, then the outputs
+    selected: usize,
+}
+
+impl DevicePicker {
+    fn selection(&self) -> Option<ClientMessage> {
+        match self.inputs.get(self.selected) {
+            Some((name, _)) => Some(ClientMessage::SelectInput(name.clone())),
+            None => self
+                .outputs
+                .get(self.selected - self.inputs.len())
+                .map(|(name, _)| ClientMessage::SelectOutput(name.clone())),
+        }
+    }
+}
+
+impl Widget for &DevicePicker {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let block = Block::bordered().title("Devices").border_set(border::THICK);
+        let inner_area = block.inner(area);
+        let mut lines = Vec::new();
+        if !self.loaded {
+            lines.push(Line::from("Looking for devices...".dim()));
+        }
+        let entries = [("Microphone", &self.inputs), ("Speakers", &self.outputs)];
+        let mut index = 0;
+        for (title, devices) in entries.into_iter().filter(|_| self.loaded) {
+            lines.push(Line::from(title.bold()));
+            for (_, description) in devices {
+                match index == self.selected {
+                    true => lines.push(Line::from(format!("> {}", description).green())),
+                    false => lines.push(Line::from(format!("  {}", description))),
+                }
+                index += 1;
+            }
+        }
+        lines.push(Line::from(""));
+        lines.push(Line::from("Enter switches, Esc closes".dim()));
+        let paragraph = Paragraph::new(Text::from(lines));
+        block.render(area, buf);
+        paragraph.render(inner_area, buf);
+    }
+}
+
+#[derive(Debug, Default)]
+struct ChatWidget {
+    messages: VecDeque<ChatMessage>,
+    /// line being typed, `None` while keys control the call
+    input: Option<String>,
+}
+
+impl Widget for &ChatWidget {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let block = Block::bordered().title("Chat").border_set(border::THICK);
+        let inner_area = block.inner(area);
+        let input = match &self.input {
+            Some(input) => Line::from(vec!["> ".bold(), input.as_str().into(), "_".slow_blink()]),
+            None => Line::from("Press Enter to chat".dim()),
+        };
+        // the newest lines that fit above the input line
+        let rows = inner_area.height.saturating_sub(1) as usize;
+        let skip = self.messages.len().saturating_sub(rows);
+        let mut lines: Vec<Line> = self
+            .messages
+            .iter()
+            .skip(skip)
+            .map(|chat| Line::from(vec![format!("{}: ", chat.name).bold(), chat.text.as_str().into()]))
+            .collect();
+        lines.resize(rows, Line::from(""));
+        lines.push(input);
+        let paragraph = Paragraph::new(Text::from(lines));
+        block.render(area, buf);
+        paragraph.render(inner_area, buf);
+    }
+}
+
+#[derive(Debug, Default)]
+struct StatsWidget {
+    latency_estimate_ms: Option<u32>,
+    packet_stats: Option<PacketStats>,
+    /// loss on our stream at the server
+    loss: Option<LossStats>,
+    /// voice activity threshold in dBFS and hangover in frames
+    vad: Option<(f32, u32)>,
+    input_level: Option<f32>,
+    server: Option<ServerInfo>,
+}
+
+impl Widget for &StatsWidget {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let block = Block::bordered().title("Stats").border_set(border::THICK);
+        let inner_area = block.inner(area);
+        let mut lines = Vec::new();
+        if let Some(server) = &self.server {
+            // a different version is the first suspect when something is off
+            let version = match server.version == env!("CARGO_PKG_VERSION") {
+                true => server.version.as_str().bold(),
+                false => format!("{} (we run {})", server.version, env!("CARGO_PKG_VERSION"))
+                    .yellow()
+                    .bold(),
+            };
+            lines.push(Line::from(vec!["Server: ".into(), version]));
+            if !server.features.is_empty() {
+                lines.push(Line::from(server.features.join(", ").dim()));
+            }
+        }
+        if let Some(ms) = self.latency_estimate_ms {
+            lines.push(Line::from(vec![
+                "Latency estimate: ".into(),
+                format!("~{}ms", ms).bold(),
+            ]));
+        }
+        if let Some(stats) = self.packet_stats {
+            lines.push(Line::from(vec![
+                "Packets: ".into(),
+                format!(
+                    "{}B avg, {}B max, {}B overhead",
+                    stats.payload_avg, stats.payload_max, stats.overhead_avg
+                )
+                .bold(),
+            ]));
+        }
+        if let Some(level) = self.input_level {
+            let mut spans = vec!["Input: ".into(), level_meter(level, self.vad.map(|(t, _)| t))];
+            spans.push(format!(" {:.0}dB", level).bold());
+            if let Some((threshold, hangover)) = self.vad {
+                spans.push(format!(", gate {:.0}dB, hold {} frames", threshold, hangover).into());
+            }
+            lines.push(Line::from(spans));
+        }
+        if let Some(loss) = self.loss {
+            lines.push(Line::from(vec![
+                "Loss: ".into(),
+                format!("{}% of our packets", loss.percent()).bold(),
+            ]));
+        }
+        let paragraph = Paragraph::new(Text::from(lines));
+        block.render(area, buf);
+        paragraph.render(inner_area, buf);
+    }
+}
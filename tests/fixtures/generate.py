@@ -0,0 +1,138 @@
+#!/usr/bin/env python3
+"""Regenerates the PCM fixtures used by the audio pipeline unit tests.
+
+All files are 16 bit little endian stereo WAVs. The generated signals are
+deterministic, so rerunning this script must not change any checked in file.
+"""
+import math
+import os
+import random
+import struct
+import wave
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+# of the mixer and output stage, see src/loudness.rs
+HEADROOM_ATTACK_DB = 0.1
+HEADROOM_RELEASE_DB = 0.05
+KNEE = 0.8
+LIMITER_RELEASE_DB_PER_SEC = 10.0
+
+
+def write_wav(name, rate, frames):
+    with wave.open(os.path.join(HERE, name), "wb") as wav:
+        wav.setnchannels(2)
+        wav.setsampwidth(2)
+        wav.setframerate(rate)
+        data = b"".join(
+            struct.pack("<hh", *(max(-32768, min(32767, round(s * 32767))) for s in frame))
+            for frame in frames
+        )
+        wav.writeframes(data)
+
+
+def f32(x):
+    """`x` rounded to single precision, like the client computes."""
+    return struct.unpack("<f", struct.pack("<f", x))[0]
+
+
+def as_read(frames):
+    """The frames as the tests read them back from a fixture."""
+    return [
+        tuple(max(-32768, min(32767, round(s * 32767))) / 32768 for s in frame)
+        for frame in frames
+    ]
+
+
+def db_to_gain(db):
+    return f32(10 ** (db / 20))
+
+
+def headroom(frames, voices, frame_size):
+    """MixHeadroom::process on each frame of `frame_size`, all with `voices`."""
+    gain_db = 0.0
+    out = []
+    for start in range(0, len(frames), frame_size):
+        chunk = frames[start : start + frame_size]
+        before = db_to_gain(gain_db)
+        wanted = -10 * math.log10(voices)
+        step = HEADROOM_ATTACK_DB if wanted < gain_db else HEADROOM_RELEASE_DB
+        gain_db = f32(gain_db + max(-step, min(step, wanted - gain_db)))
+        after = db_to_gain(gain_db)
+        for i, frame in enumerate(chunk):
+            gain = f32(before + (after - before) * (i + 1) / len(chunk))
+            out.append(tuple(f32(s * gain) for s in frame))
+    return out
+
+
+def soft_clip(sample):
+    level = abs(sample)
+    if level <= KNEE:
+        return sample
+    over = (level - KNEE) / (1 - KNEE)
+    return math.copysign(KNEE + (1 - KNEE) * math.tanh(over), sample)
+
+
+def output_stage(frames, width, ceiling_db):
+    """OutputStage::process, with its limiter gain kept in single precision."""
+    ceiling = db_to_gain(min(ceiling_db, 0.0))
+    release = db_to_gain(LIMITER_RELEASE_DB_PER_SEC / 48000)
+    gain = 1.0
+    out = []
+    for left, right in frames:
+        mid = f32((left + right) / 2)
+        side = f32(f32((left - right) / 2) * width)
+        peak = f32(f32(abs(mid) + abs(side)) * gain)
+        if peak > ceiling:
+            gain = f32(gain * f32(ceiling / peak))
+        out.append((f32(f32(mid + side) * gain), f32(f32(mid - side) * gain)))
+        gain = min(f32(gain * release), 1.0)
+    return out
+
+
+def sine(rate, freq, amplitude, seconds):
+    n = int(rate * seconds)
+    return [
+        (amplitude * math.sin(2 * math.pi * freq * i / rate),) * 2 for i in range(n)
+    ]
+
+
+def main():
+    # 200ms of a 440Hz tone, clearly above the voice activity threshold
+    tone = sine(48000, 440, 0.5, 0.2)
+    write_wav("sine_440_48k.wav", 48000, tone)
+
+    # the same tone at 44.1kHz, input for the resampler
+    write_wav("sine_440_44k1.wav", 44100, sine(44100, 440, 0.5, 0.2))
+
+    # quiet noise floor, well below the voice activity threshold
+    rng = random.Random(1234)
+    noise = [(rng.uniform(-0.002, 0.002),) * 2 for _ in range(9600)]
+    write_wav("noise_floor_48k.wav", 48000, noise)
+
+    # first 20ms of the tone faded in over 240 samples, as Fade does for a 5ms ramp
+    ramp = 240
+    faded = []
+    for i, (left, right) in enumerate(tone[:960]):
+        gain = min(1.0, (i + 1) / ramp)
+        faded.append((left * gain, right * gain))
+    write_wav("sine_440_48k_fade_in.wav", 48000, faded)
+
+    # a second voice, and the stages its mix with the first goes through
+    # before the speakers, each on the fixtures as the tests read them
+    write_wav("sine_660_48k.wav", 48000, sine(48000, 660, 0.5, 0.2))
+    first = as_read(tone)
+    second = as_read(sine(48000, 660, 0.5, 0.2))
+    mix = [(a[0] + b[0], a[1] + b[1]) for a, b in zip(first, second)]
+    write_wav("sine_440_660_48k_mix.wav", 48000, mix)
+    write_wav("sine_440_660_48k_headroom.wav", 48000, headroom(as_read(mix), 2, 960))
+    clipped = [tuple(soft_clip(s) for s in frame) for frame in as_read(mix)]
+    write_wav("sine_440_660_48k_soft_clip.wav", 48000, clipped)
+    # the first voice on the left and the second on the right, half as wide
+    # and held under -9dBFS
+    stereo = [(a[0], b[0]) for a, b in zip(first, second)]
+    write_wav("sine_440_660_48k_output.wav", 48000, output_stage(stereo, 0.5, -9.0))
+
+
+if __name__ == "__main__":
+    main()